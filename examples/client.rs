@@ -28,10 +28,12 @@ fn main() {
         message::Action::Write {
             key: key.to_owned(),
             content: vec![1, 2, 3],
+            ttl: None,
         },
         message::Action::Write {
             key: key.to_owned(),
             content: vec![4, 5, 6],
+            ttl: None,
         },
         message::Action::Read {
             key: key.to_owned(),
@@ -42,6 +44,7 @@ fn main() {
         message::Action::Write {
             key: key2.to_owned(),
             content: vec![0; 2048],
+            ttl: None,
         },
         message::Action::Read {
             key: key2.to_owned(),
@@ -60,6 +63,9 @@ fn main() {
         let content = message::Request {
             action: m,
             consistency: message::Consistency::One,
+            timestamp: None,
+            deadline: None,
+            context: None,
         };
 
         let r = client.send(&content).await().unwrap();
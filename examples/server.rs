@@ -3,7 +3,9 @@ extern crate sbahn;
 extern crate log;
 extern crate env_logger;
 
+use sbahn::constants::DEFAULT_VNODES;
 use sbahn::handler;
+use sbahn::message::Ring;
 use sbahn::storage::HashMapBackend;
 use sbahn::storage_node::StorageNode;
 use std::net::{Ipv4Addr, SocketAddrV4};
@@ -18,28 +20,26 @@ fn main() {
         vec![SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 1030), SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 1031), SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 1032)],
     ];
 
-    let e = &shards;
-    let z = e.clone();
+    let ring = Ring::new(shards, DEFAULT_VNODES);
+    let z = ring.clone();
 
     thread::spawn(move || {
         let addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 1100);
-        let _shards = &z.to_owned();
         println!("Handler Node @ {:?}", &addr);
-        let _ = handler::listen(&addr, &_shards);
+        let _ = handler::listen(&addr, &z);
     });
 
-    let y = &shards.clone();
-    let x = y.iter();
+    let x = ring.shards().iter();
 
 
     // Create SHARD_SIZE storage nodes.
     for (pos, addresses) in x.enumerate() {
         for addr in addresses {
             let addr = addr.to_owned();
-            let shard_count = shards.len();
+            let ring = ring.clone();
             thread::spawn(move || {
                 println!("Storage Node {:?} @ {:?}", &pos, &addr);
-                let mut sn: StorageNode<HashMapBackend>= StorageNode::new(&addr, pos, shard_count);
+                let mut sn: StorageNode<HashMapBackend>= StorageNode::new(&addr, &ring);
                 &sn.listen();
             });
         }
@@ -4,6 +4,7 @@ extern crate sbahn;
 
 use eventual::*;
 use sbahn::client;
+use sbahn::constants::DEFAULT_VNODES;
 use sbahn::message::*;
 use sbahn::message;
 use sbahn::storage::HashMapBackend;
@@ -16,8 +17,8 @@ fn main() {
     let _ = env_logger::init();
     let addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 1050);
     thread::spawn(move || {
-        let pos = 0;
-        let mut sn: StorageNode<HashMapBackend>= StorageNode::new(&addr, pos, 1);
+        let ring = Ring::new(vec![vec![addr]], DEFAULT_VNODES);
+        let mut sn: StorageNode<HashMapBackend>= StorageNode::new(&addr, &ring);
         &sn.listen();
     });
     thread::sleep(Duration::from_millis(500));
@@ -33,12 +34,13 @@ fn main() {
                 value: Value::Value {
                     content: vec![i],
                     timestamp: 10000000,
+                    expires: None,
                 },
             };
             let r = client::Client::send_to_node(&addr, &content).await();
             match r {
                 Ok(r) => match r {
-                    InternodeResponse::WriteAck {key, timestamp} => {
+                    InternodeResponse::WriteAck {key, timestamp, ..} => {
                         assert_eq!(key, insert_key);
                         assert_eq!(timestamp, 10000000);
                     },
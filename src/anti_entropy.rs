@@ -0,0 +1,111 @@
+use message::{Key, Value};
+use std::hash::{Hash, Hasher, SipHasher};
+use storage::StorageBackend;
+
+/// A Merkle tree over a replica's entries, for replicas to find out which of
+/// their entries differ without sending them all.
+///
+/// Entries go to one of the `2^depth` leaves by their `Key`'s ring hash. A
+/// leaf's hash is the sum of its entries' hashes, so it doesn't depend on the
+/// order they're visited in, and every other node hashes its two children.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MerkleTree {
+    depth: u32,
+    /// Every node of the tree, root first, with the children of the `i`th at
+    /// `2i + 1` and `2i + 2`.
+    hashes: Vec<u64>,
+}
+
+impl MerkleTree {
+    /// Build the tree of `depth` over the entries in `map` that `owns` says
+    /// belong in it.
+    pub fn build<Backend: StorageBackend>(map: &Backend, depth: u32, owns: &Fn(&Key) -> bool) -> MerkleTree {
+        let mut hashes = vec![0u64; MerkleTree::len(depth)];
+        let first_leaf = MerkleTree::first_leaf(depth);
+        map.for_each(&mut |key, value| {
+            if owns(key) {
+                let i = first_leaf + MerkleTree::leaf(key, depth);
+                hashes[i] = hashes[i].wrapping_add(entry_hash(key, value));
+            }
+        });
+        for i in (0..first_leaf).rev() {
+            let mut h = SipHasher::new();
+            hashes[2 * i + 1].hash(&mut h);
+            hashes[2 * i + 2].hash(&mut h);
+            hashes[i] = h.finish();
+        }
+        MerkleTree {
+            depth: depth,
+            hashes: hashes,
+        }
+    }
+
+    /// The tree of `depth` with the given `hashes`, if there are as many as
+    /// it has nodes.
+    pub fn from_hashes(depth: u32, hashes: Vec<u64>) -> Option<MerkleTree> {
+        if hashes.len() == MerkleTree::len(depth) {
+            Some(MerkleTree {
+                depth: depth,
+                hashes: hashes,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// The leaf `key` goes to in a tree of `depth`.
+    pub fn leaf(key: &Key, depth: u32) -> usize {
+        if depth == 0 {
+            0
+        } else {
+            (key.hash() >> (64 - depth)) as usize
+        }
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    pub fn hashes(&self) -> &Vec<u64> {
+        &self.hashes
+    }
+
+    /// Leaves whose hash differs from `other`'s, only descending into the
+    /// subtrees that differ.
+    pub fn differing_leaves(&self, other: &MerkleTree) -> Vec<usize> {
+        let mut leaves = vec![];
+        if self.depth != other.depth {
+            return (0..1 << self.depth).collect();
+        }
+        let first_leaf = MerkleTree::first_leaf(self.depth);
+        let mut pending = vec![0];
+        while let Some(i) = pending.pop() {
+            if self.hashes[i] == other.hashes[i] {
+                continue;
+            }
+            if i >= first_leaf {
+                leaves.push(i - first_leaf);
+            } else {
+                pending.push(2 * i + 2);
+                pending.push(2 * i + 1);
+            }
+        }
+        leaves
+    }
+
+    fn len(depth: u32) -> usize {
+        (1 << (depth + 1)) - 1
+    }
+
+    fn first_leaf(depth: u32) -> usize {
+        (1 << depth) - 1
+    }
+}
+
+fn entry_hash(key: &Key, value: &Value) -> u64 {
+    let mut h = SipHasher::new();
+    // `Key::hash` is the ring hash.
+    Hash::hash(key, &mut h);
+    value.hash(&mut h);
+    h.finish()
+}
@@ -1,20 +1,124 @@
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
-use std::io::prelude::*;
+use std::io;
 use std::net::{SocketAddrV4, TcpStream};
-use std::time::Duration;
+use std::cmp;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use coordinator::{self, Coordinator};
+use hlc::{self, Clock};
 use eventual::*;
-use message::{Action, Buffer, Consistency, Error, Key, Request, Result, ResponseMessage};
+use network::{NetworkRead, NetworkWrite};
+use pool::ConnectionPool;
+use rebalance::Topology;
+use message::{Action, Buffer, Consistency, Error, Key, Request, Response, Result, ResponseMessage,
+              Value};
+use std::result;
 use bincode::rustc_serialize::{encode, decode};
 use rustc_serialize::{Encodable, Decodable};
 use bincode::SizeLimit;
+use vclock::VectorClock;
+
+/// How a `Client` retries requests that couldn't reach their handler.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts made at most for a request, counting the first one.
+    pub max_attempts: u32,
+    /// Time to wait before the first retry, doubled before every following
+    /// one. A handler that couldn't be reached is avoided for as long,
+    /// doubled for every consecutive failure.
+    pub base_backoff: Duration,
+    /// Longest time to wait before a retry, or to avoid a handler.
+    pub max_backoff: Duration,
+    /// Whether writes and deletes are retried too. Their timestamp is then
+    /// taken from the client's clock, so that an attempt that did reach the
+    /// storage nodes isn't applied twice, which relies on the client's clock
+    /// being in sync with the handlers'.
+    pub retry_writes: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_millis(1000),
+            retry_writes: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Time to wait before the `n`th retry, with up to half of it randomly
+    /// taken off so that clients don't retry in lockstep.
+    fn backoff(&self, n: u32) -> Duration {
+        let mut backoff = self.max_backoff;
+        if n < 32 {
+            let exp = self.base_backoff * (1 << (n - 1));
+            if exp < backoff {
+                backoff = exp;
+            }
+        }
+        let nanos = backoff.as_secs() * 1_000_000_000 + backoff.subsec_nanos() as u64;
+        let jitter = coordinator::random() % (nanos / 2 + 1);
+        backoff - Duration::new(jitter / 1_000_000_000, (jitter % 1_000_000_000) as u32)
+    }
+}
+
+/// Consecutive failures to reach a handler, and until when it's avoided.
+#[derive(Debug, Clone, Copy)]
+struct Health {
+    failures: u32,
+    avoid_until: Instant,
+}
 
 /// An sbahn client.
+///
+/// Requests are spread among `handlers` in turn, skipping those that
+/// recently couldn't be reached. Requests that can't reach their handler are
+/// retried on the next one according to the `retry_policy`.
+///
+/// A `token_aware` client skips the handlers, and performs requests on the
+/// storage nodes itself, the way handlers do.
+#[derive(Clone)]
 pub struct Client {
     /// List of addresses to frontend request handlers
     pub handlers: Vec<SocketAddrV4>,
+    /// Time to wait for a handler to answer each attempt at a read.
     pub read_timeout: Option<Duration>,
+    /// Time to wait for a handler to answer each attempt at a write, a
+    /// delete or a topology change.
     pub write_timeout: Option<Duration>,
-    pub consistency: Consistency,
+    /// Time to wait for a request to succeed, retries included. Handlers
+    /// give up on a request once its time is up.
+    pub request_timeout: Option<Duration>,
+    /// Consistency of reads, scans and key listings.
+    pub read_consistency: Consistency,
+    /// Consistency of writes and deletes.
+    pub write_consistency: Consistency,
+    /// Persistent connections to the handlers.
+    pub pool: Arc<ConnectionPool>,
+    pub retry_policy: RetryPolicy,
+    /// Handlers that recently couldn't be reached.
+    health: Arc<Mutex<HashMap<SocketAddrV4, Health>>>,
+    /// Turn of the next request, used to pick its handler.
+    turn: Arc<AtomicUsize>,
+    /// Routing state, when token aware.
+    token_aware: Option<Arc<TokenAware>>,
+    /// Timestamps retried writes, and keeps up with the timestamps of the
+    /// responses. Its node ID is random, and only checked with the storage
+    /// nodes when token aware.
+    clock: Arc<Clock>,
+}
+
+/// What a `token_aware` `Client` needs to perform requests itself.
+struct TokenAware {
+    /// Topology to route requests with.
+    topology: RwLock<Topology>,
+    /// Performs requests on the replicas.
+    coordinator: Coordinator,
 }
 
 pub type MessageResult = Result<ResponseMessage>;
@@ -25,7 +129,15 @@ impl Client {
             handlers: handlers,
             read_timeout: Some(Duration::from_millis(300)),
             write_timeout: Some(Duration::from_millis(300)),
-            consistency: Consistency::Latest,
+            request_timeout: None,
+            read_consistency: Consistency::Latest,
+            write_consistency: Consistency::Latest,
+            pool: Arc::new(ConnectionPool::new()),
+            retry_policy: RetryPolicy::default(),
+            health: Arc::new(Mutex::new(HashMap::new())),
+            turn: Arc::new(AtomicUsize::new(0)),
+            token_aware: None,
+            clock: Arc::new(Clock::new(coordinator::random() as u16)),
         }
     }
 
@@ -34,47 +146,513 @@ impl Client {
                          write_timeout: Duration)
                          -> Client {
         Client {
-            handlers: handlers,
             read_timeout: Some(read_timeout),
             write_timeout: Some(write_timeout),
-            consistency: Consistency::Latest,
+            ..Client::new(handlers)
         }
     }
 
+    /// A client reading and writing with `consistency`.
     pub fn with_consistency(handlers: Vec<SocketAddrV4>, consistency: Consistency) -> Client {
+        Client::with_consistency_levels(handlers, consistency.to_owned(), consistency)
+    }
+
+    pub fn with_consistency_levels(handlers: Vec<SocketAddrV4>,
+                                   read_consistency: Consistency,
+                                   write_consistency: Consistency)
+                                   -> Client {
         Client {
-            handlers: handlers,
-            read_timeout: Some(Duration::from_millis(300)),
-            write_timeout: Some(Duration::from_millis(300)),
-            consistency: consistency,
+            read_consistency: read_consistency,
+            write_consistency: write_consistency,
+            ..Client::new(handlers)
+        }
+    }
+
+    /// A client sharing its handler connections with every other user of
+    /// `pool`.
+    pub fn with_pool(handlers: Vec<SocketAddrV4>, pool: Arc<ConnectionPool>) -> Client {
+        Client { pool: pool, ..Client::new(handlers) }
+    }
+
+    pub fn with_retry_policy(handlers: Vec<SocketAddrV4>, retry_policy: RetryPolicy) -> Client {
+        Client { retry_policy: retry_policy, ..Client::new(handlers) }
+    }
+
+    /// A client that fetches the cluster topology from `handlers`, and then
+    /// sends requests straight to the replicas of their `Key`, saving the
+    /// hop through a handler. Topology changes still go through `handlers`.
+    pub fn token_aware(handlers: Vec<SocketAddrV4>) -> Result<Client> {
+        Client::token_aware_versioning(handlers, HashSet::new())
+    }
+
+    /// A `token_aware` client keeping concurrent writes to
+    /// `versioned_datasets` as siblings, which should be the same datasets
+    /// the handlers version.
+    pub fn token_aware_versioning(handlers: Vec<SocketAddrV4>,
+                                  versioned_datasets: HashSet<Buffer>)
+                                  -> Result<Client> {
+        let mut client = Client::new(handlers);
+        let topology = try!(client.fetch_topology());
+        let mut coordinator = Coordinator::new(client.pool.clone());
+        coordinator.clock = client.clock.clone();
+        coordinator.versioned_datasets = versioned_datasets;
+        // It timestamps writes like a handler, so its ID is checked likewise.
+        coordinator::claim_node_id(&client.pool, &topology.ring, coordinator.node, &client.clock);
+        client.token_aware = Some(Arc::new(TokenAware {
+            topology: RwLock::new(topology),
+            coordinator: coordinator,
+        }));
+        Ok(client)
+    }
+
+    /// Fetch the topology of a `token_aware` client from the handlers again.
+    /// Returns whether it changed.
+    pub fn refresh_topology(&self) -> bool {
+        let token_aware = match self.token_aware {
+            Some(ref t) => t,
+            None => return false,
+        };
+        match self.fetch_topology() {
+            Ok(fetched) => {
+                let mut current = token_aware.topology.write().unwrap();
+                let changed = current.ring != fetched.ring || current.pending != fetched.pending;
+                *current = fetched;
+                changed
+            }
+            Err(e) => {
+                error!("Couldn't refresh topology: {:?}", e);
+                false
+            }
+        }
+    }
+
+    fn fetch_topology(&self) -> Result<Topology> {
+        let request = Request::new(Action::GetTopology, self.read_consistency.to_owned());
+        match self.send_to_handlers(&request).await() {
+            Ok(ResponseMessage {message: Response::Topology {ring, pending}, ..}) => {
+                Ok(Topology {
+                    ring: Arc::new(ring),
+                    pending: pending.map(Arc::new),
+                })
+            }
+            Ok(r) => {
+                error!("Unexpected response: {:?}", r);
+                Err(Error::UnexpectedResponse)
+            }
+            Err(e) => Err(e.take().unwrap_or(Error::ConnectionError)),
         }
     }
 
-    pub fn insert(&self, key: &Key, value: &Buffer) -> Future<ResponseMessage, Error> {
-        let content = Request {
-            action: Action::Write {
-                key: key.to_owned(),
-                content: value.to_owned(),
-            },
-            consistency: self.consistency.clone(),
+    /// Store `value` under `key`, returning the timestamp it was written
+    /// with.
+    pub fn insert(&self, key: &Key, value: &Buffer) -> Future<u64, Error> {
+        self.insert_with_consistency(key, value, &self.write_consistency)
+    }
+
+    pub fn insert_with_consistency(&self,
+                                   key: &Key,
+                                   value: &Buffer,
+                                   consistency: &Consistency)
+                                   -> Future<u64, Error> {
+        let action = Action::Write {
+            key: key.to_owned(),
+            content: value.to_owned(),
+            ttl: None,
+        };
+        self.request(action, consistency, write_ack)
+    }
+
+    /// Store `value` under `key` until `ttl` passes, returning the timestamp
+    /// it was written with.
+    pub fn insert_with_ttl(&self, key: &Key, value: &Buffer, ttl: Duration) -> Future<u64, Error> {
+        let action = Action::Write {
+            key: key.to_owned(),
+            content: value.to_owned(),
+            ttl: Some(millis(ttl)),
+        };
+        self.request(action, &self.write_consistency, write_ack)
+    }
+
+    /// Have the value stored under `key` expire once `ttl` passes from now,
+    /// or never if there's no `ttl`, returning the timestamp it was
+    /// rewritten with.
+    pub fn touch(&self, key: &Key, ttl: Option<Duration>) -> Future<u64, Error> {
+        let action = Action::Touch {
+            key: key.to_owned(),
+            ttl: ttl.map(millis),
+        };
+        self.request(action, &self.write_consistency, write_ack)
+    }
+
+    /// Store `value` under `key` of a versioned dataset, superseding the
+    /// siblings `context` covers, as returned by `get_versions`.
+    pub fn insert_with_context(&self, key: &Key, value: &Buffer, context: Option<&VectorClock>) -> Future<u64, Error> {
+        let action = Action::Write {
+            key: key.to_owned(),
+            content: value.to_owned(),
+            ttl: None,
         };
-        self.send(&content)
+        let mut request = Request::new(action, self.write_consistency.to_owned());
+        request.context = context.map(|c| c.to_owned());
+        self.request_with(request, write_ack)
+    }
+
+    /// Get the value stored under `key`, if there's any. Of the siblings of a
+    /// versioned one, that's the newest one.
+    pub fn get(&self, key: &Key) -> Future<Option<Buffer>, Error> {
+        self.get_with_consistency(key, &self.read_consistency)
+    }
+
+    pub fn get_with_consistency(&self, key: &Key, consistency: &Consistency) -> Future<Option<Buffer>, Error> {
+        let action = Action::Read { key: key.to_owned() };
+        self.request(action, consistency, |response| {
+            match response {
+                Response::Value {value, ..} => Ok(value.into_contents().pop()),
+                r => Err(r),
+            }
+        })
+    }
+
+    /// Get the value stored under `key`, if there's any, along with the time
+    /// it has left to live if it expires.
+    pub fn get_with_ttl(&self, key: &Key) -> Future<Option<(Buffer, Option<Duration>)>, Error> {
+        let action = Action::Read { key: key.to_owned() };
+        self.request(action, &self.read_consistency, |response| {
+            match response {
+                Response::Value {value, ttl, ..} => {
+                    Ok(value.into_contents().pop().map(|content| (content, ttl.map(Duration::from_millis))))
+                }
+                r => Err(r),
+            }
+        })
+    }
+
+    /// Get every sibling stored under `key` of a versioned dataset, oldest
+    /// first, along with the causal context to write with to resolve them.
+    pub fn get_versions(&self, key: &Key) -> Future<(Vec<Buffer>, Option<VectorClock>), Error> {
+        let action = Action::Read { key: key.to_owned() };
+        self.request(action, &self.read_consistency, |response| {
+            match response {
+                Response::Value {value, context, ..} => Ok((value.into_contents(), context)),
+                r => Err(r),
+            }
+        })
+    }
+
+    /// Delete the value stored under `key`, returning the timestamp of the
+    /// deletion.
+    pub fn delete(&self, key: &Key) -> Future<u64, Error> {
+        self.delete_with_consistency(key, &self.write_consistency)
+    }
+
+    pub fn delete_with_consistency(&self, key: &Key, consistency: &Consistency) -> Future<u64, Error> {
+        let action = Action::Delete { key: key.to_owned() };
+        self.request(action, consistency, write_ack)
+    }
+
+    /// Delete the value stored under `key` of a versioned dataset,
+    /// superseding the siblings `context` covers.
+    pub fn delete_with_context(&self, key: &Key, context: Option<&VectorClock>) -> Future<u64, Error> {
+        let action = Action::Delete { key: key.to_owned() };
+        let mut request = Request::new(action, self.write_consistency.to_owned());
+        request.context = context.map(|c| c.to_owned());
+        self.request_with(request, write_ack)
     }
 
-    pub fn get(&self, key: &Key) -> Future<ResponseMessage, Error> {
-        let content = Request {
-            action: Action::Read { key: key.to_owned() },
-            consistency: self.consistency.clone(),
+    /// Store `value` under `key` only if the value stored there was written
+    /// at the `expected` timestamp, or only if there's none when no
+    /// timestamp is expected, as described by `Action::CompareAndSet`.
+    /// Returns the timestamp it was written with, or the value found
+    /// instead.
+    pub fn compare_and_set(&self,
+                           key: &Key,
+                           expected: Option<u64>,
+                           value: &Buffer)
+                           -> Future<result::Result<u64, Value>, Error> {
+        let action = Action::CompareAndSet {
+            key: key.to_owned(),
+            expected: expected,
+            content: value.to_owned(),
         };
-        self.send(&content)
+        self.request(action, &self.write_consistency, |response| {
+            match response {
+                Response::WriteAck {timestamp, ..} => Ok(Ok(timestamp)),
+                Response::Conflict {value, ..} => Ok(Err(value)),
+                r => Err(r),
+            }
+        })
+    }
+
+    /// Scan the rows of the `pkey` partition of `dataset`, as described by
+    /// `Action::Scan`. Returns the `Key` and value of each row, along with
+    /// the `lkey` to resume the scan from unless it's exhausted.
+    pub fn scan(&self,
+                dataset: &Buffer,
+                pkey: &Buffer,
+                start_lkey: &Buffer,
+                end_lkey: Option<&Buffer>,
+                limit: usize,
+                reverse: bool)
+                -> Future<(Vec<(Key, Buffer)>, Option<Buffer>), Error> {
+        let action = Action::Scan {
+            dataset: dataset.to_owned(),
+            pkey: pkey.to_owned(),
+            start_lkey: start_lkey.to_owned(),
+            end_lkey: end_lkey.map(|l| l.to_owned()),
+            limit: limit,
+            reverse: reverse,
+        };
+        self.request(action, &self.read_consistency, |response| {
+            match response {
+                Response::Rows {rows, last_lkey} => {
+                    let rows = rows.into_iter()
+                                   .filter_map(|(key, value)| value.into_contents().pop().map(|c| (key, c)))
+                                   .collect();
+                    Ok((rows, last_lkey))
+                }
+                r => Err(r),
+            }
+        })
+    }
+
+    /// List a page of the `Key`s stored in `dataset`, as described by
+    /// `Action::ListKeys`. Returns the `Key`s along with the cursor to list
+    /// the next page from, unless they were all listed.
+    pub fn list_keys(&self,
+                     dataset: &Buffer,
+                     cursor: Option<&Key>,
+                     page_size: usize,
+                     include_tombstones: bool)
+                     -> Future<(Vec<Key>, Option<Key>), Error> {
+        let action = Action::ListKeys {
+            dataset: dataset.to_owned(),
+            cursor: cursor.map(|k| k.to_owned()),
+            page_size: page_size,
+            include_tombstones: include_tombstones,
+        };
+        self.request(action, &self.read_consistency, |response| {
+            match response {
+                Response::Keys {keys, cursor} => Ok((keys, cursor)),
+                r => Err(r),
+            }
+        })
+    }
+
+    /// The counters of the handler's `Metrics`, by name.
+    pub fn metrics(&self) -> Future<Vec<(String, u64)>, Error> {
+        self.request(Action::GetMetrics, &self.read_consistency, |response| {
+            match response {
+                Response::Metrics {counters} => Ok(counters),
+                r => Err(r),
+            }
+        })
+    }
+
+    /// Send `action` and turn its `Response` into a `T` with `f`, which gives
+    /// back any `Response` it doesn't expect. `Response::Error`s become
+    /// `Error::RequestError`s.
+    fn request<T, F>(&self, action: Action, consistency: &Consistency, f: F) -> Future<T, Error>
+        where T: Send + 'static,
+              F: FnOnce(Response) -> result::Result<T, Response> + Send + 'static
+    {
+        self.request_with(Request::new(action, consistency.to_owned()), f)
+    }
+
+    /// Like `request`, sending `request` as is.
+    fn request_with<T, F>(&self, request: Request, f: F) -> Future<T, Error>
+        where T: Send + 'static,
+              F: FnOnce(Response) -> result::Result<T, Response> + Send + 'static
+    {
+        self.send(&request).and_then(move |r| {
+            match r.message {
+                Response::Error {message, ..} => Err(Error::RequestError(message)),
+                Response::TopologyError {..} => Err(Error::TopologyError),
+                message => {
+                    f(message).map_err(|r| {
+                        error!("Unexpected response: {:?}", r);
+                        Error::UnexpectedResponse
+                    })
+                }
+            }
+        })
     }
 
     pub fn send(&self, message: &Request) -> Future<ResponseMessage, Error> {
-        let target = &self.handlers[0];
-        Self::send_to_node(target, &message)
+        let token_aware = match message.action {
+            Action::PrepareTopology {..} | Action::CommitTopology {..} | Action::GetTopology |
+            Action::GetMetrics => None,
+            _ => self.token_aware.clone(),
+        };
+        match token_aware {
+            Some(token_aware) => self.coordinate(token_aware, message),
+            None => self.send_to_handlers(message),
+        }
+    }
+
+    fn timeout_for(&self, action: &Action) -> Option<Duration> {
+        match *action {
+            Action::Read {..} | Action::Scan {..} | Action::ListKeys {..} | Action::GetTopology |
+            Action::GetMetrics => {
+                self.read_timeout
+            }
+            _ => self.write_timeout,
+        }
+    }
+
+    /// Perform `message` on the replicas the topology assigns to it. If that
+    /// fails, the topology might be stale: it's fetched again, and `message`
+    /// retried once if it changed and the `retry_policy` allows it.
+    fn coordinate(&self, token_aware: Arc<TokenAware>, message: &Request) -> Future<ResponseMessage, Error> {
+        let mut request = message.to_owned();
+        let retry = if request.action.is_idempotent() {
+            true
+        } else if self.retry_policy.retry_writes {
+            if request.timestamp.is_none() {
+                request.timestamp = Some(self.clock.now());
+            }
+            true
+        } else {
+            false
+        };
+        let timeout = self.timeout_for(&request.action);
+        let deadline = self.request_timeout.map(|t| hlc::wall_clock_us() + micros(t));
+        let client = self.clone();
+
+        let (complete, future) = Future::pair();
+        complete.receive(move |c| {
+            if let Ok(c) = c {
+                let mut refreshed = false;
+                loop {
+                    request.deadline = attempt_deadline(timeout, deadline, hlc::wall_clock_us());
+                    let current = token_aware.topology.read().unwrap().clone();
+                    let r = token_aware.coordinator.coordinate(&current, request.clone());
+                    let failed = match r {
+                        Ok(ResponseMessage {message: Response::Error {..}, ..}) | Err(_) => true,
+                        _ => false,
+                    };
+                    if failed && !refreshed && client.refresh_topology() && retry {
+                        debug!("Topology changed, retrying {:?}", request);
+                        refreshed = true;
+                        continue;
+                    }
+                    return match r {
+                        Ok(response) => c.complete(response),
+                        Err(e) => c.fail(e),
+                    };
+                }
+            }
+        });
+        future
     }
 
-    /// Sends a message that can be binary encoded to the Storage Node at `target`.
+    /// Send `message` to one of the handlers, retrying it on the others if
+    /// it can't reach it or times out, and the `retry_policy` allows it.
+    fn send_to_handlers(&self, message: &Request) -> Future<ResponseMessage, Error> {
+        let mut request = message.to_owned();
+        let timeout = self.timeout_for(&request.action);
+        let deadline = self.request_timeout.map(|t| hlc::wall_clock_us() + micros(t));
+        let attempts = if request.action.is_idempotent() {
+            self.retry_policy.max_attempts
+        } else if self.retry_policy.retry_writes {
+            if request.timestamp.is_none() {
+                request.timestamp = Some(self.clock.now());
+            }
+            self.retry_policy.max_attempts
+        } else {
+            1
+        };
+        let handlers = self.handlers.clone();
+        let pool = self.pool.clone();
+        let policy = self.retry_policy.clone();
+        let health = self.health.clone();
+        let turn = self.turn.clone();
+        let clock = self.clock.clone();
+
+        let (complete, future) = Future::pair();
+        complete.receive(move |c| {
+            if let Ok(c) = c {
+                let mut attempt = 1;
+                loop {
+                    let now = hlc::wall_clock_us();
+                    if deadline.map_or(false, |d| d <= now) {
+                        return c.fail(Error::Timeout);
+                    }
+                    request.deadline = attempt_deadline(timeout, deadline, now);
+                    let attempt_timeout = request.deadline.map(|d| from_micros(d - now));
+
+                    let target = match Self::pick_handler(&handlers, &health, &turn) {
+                        Some(target) => target,
+                        None => return c.fail(Error::NoHandlers),
+                    };
+                    let r: Future<ResponseMessage, Error> = pool.send_to_node(&target, &request, attempt_timeout);
+                    match r.await() {
+                        Ok(response) => {
+                            health.lock().unwrap().remove(&target);
+                            observe(&clock, &response.message);
+                            return c.complete(response);
+                        }
+                        Err(e) => {
+                            let e = e.take().unwrap_or(Error::ConnectionError);
+                            if e != Error::ConnectionError && e != Error::Timeout {
+                                return c.fail(e);
+                            }
+                            Self::mark_unreachable(&target, &health, &policy);
+                            if attempt >= attempts {
+                                return c.fail(e);
+                            }
+                            debug!("Couldn't reach handler {:?}, retrying {:?}", target, request);
+                            thread::sleep(policy.backoff(attempt));
+                            attempt += 1;
+                        }
+                    }
+                }
+            }
+        });
+        future
+    }
+
+    /// The handler whose turn it is, skipping those being avoided. If every
+    /// handler is, the one to stop being avoided the soonest. None if there
+    /// are no handlers at all.
+    fn pick_handler(handlers: &Vec<SocketAddrV4>,
+                    health: &Mutex<HashMap<SocketAddrV4, Health>>,
+                    turn: &AtomicUsize)
+                    -> Option<SocketAddrV4> {
+        let start = turn.fetch_add(1, Ordering::SeqCst);
+        let health = health.lock().unwrap();
+        let now = Instant::now();
+        let mut best: Option<(SocketAddrV4, Instant)> = None;
+        for i in 0..handlers.len() {
+            let handler = handlers[(start + i) % handlers.len()];
+            match health.get(&handler) {
+                Some(h) if h.avoid_until > now => {
+                    if best.map_or(true, |(_, until)| h.avoid_until < until) {
+                        best = Some((handler, h.avoid_until));
+                    }
+                }
+                _ => return Some(handler),
+            }
+        }
+        best.map(|(handler, _)| handler)
+    }
+
+    /// Avoid `handler` for a while, as it couldn't be reached or didn't
+    /// answer in time.
+    fn mark_unreachable(handler: &SocketAddrV4,
+                        health: &Mutex<HashMap<SocketAddrV4, Health>>,
+                        policy: &RetryPolicy) {
+        let mut health = health.lock().unwrap();
+        let failures = health.get(handler).map_or(0, |h| h.failures) + 1;
+        error!("Couldn't reach handler {:?}, {} consecutive failures", handler, failures);
+        health.insert(handler.to_owned(),
+                      Health {
+                          failures: failures,
+                          avoid_until: Instant::now() + policy.backoff(failures),
+                      });
+    }
+
+    /// Sends a message that can be binary encoded to the Storage Node at
+    /// `target` over a new, single use, connection.
     pub fn send_to_node<T, K>(target: &SocketAddrV4, message: &T) -> Future<K, Error>
         where T: Debug + Encodable,
               K: Debug + Decodable + Send
@@ -82,7 +660,8 @@ impl Client {
         Self::send_to_node_with_timeout(target, message, None)
     }
 
-    /// Sends a message that can be binary encoded to the Storage Node at `target`.
+    /// Sends a message that can be binary encoded to the Storage Node at
+    /// `target` over a new, single use, connection.
     pub fn send_to_node_with_timeout<T, K>(target: &SocketAddrV4,
                                            message: &T,
                                            timeout: Option<Duration>)
@@ -104,34 +683,51 @@ impl Client {
         }
     }
 
-    /// Sends a binary encoded message to the Storage Node at `target`.
+    /// Sends a binary encoded message to the Storage Node at `target` over a
+    /// new, single use, connection.
     pub fn send_buffer(target: &SocketAddrV4,
                        message: Vec<u8>,
                        timeout: Option<Duration>)
                        -> Future<Vec<u8>, Error> {
         let target = target.to_owned();
-        Future::lazy(move || {
-            match TcpStream::connect(target) {
-                Ok(stream) => {
-                    let _ = stream.set_read_timeout(timeout);
-                    let _ = stream.set_write_timeout(timeout);
-
-                    let mut stream = stream;
-                    if stream.write(&message).is_err() {
-                        return Err(Error::ConnectionError);
-                    }
-                    let mut val: Buffer = vec![];
-                    if stream.read_to_end(&mut val).is_err() {
-                        return Err(Error::ConnectionError);
-                    }
-                    Ok(val)
+        // `Future::lazy` can't fail, so complete the future by hand once
+        // somebody is interested in the response.
+        let (complete, future) = Future::pair();
+        complete.receive(move |c| {
+            if let Ok(c) = c {
+                match Self::exchange(&target, &message, timeout) {
+                    Ok(val) => c.complete(val),
+                    Err(e) => c.fail(e),
+                }
+            }
+        });
+        future
+    }
+
+    /// Send `message` to `target` and wait for its framed response.
+    fn exchange(target: &SocketAddrV4,
+                message: &[u8],
+                timeout: Option<Duration>)
+                -> Result<Buffer> {
+        match TcpStream::connect(target) {
+            Ok(stream) => {
+                let _ = stream.set_read_timeout(timeout);
+                let _ = stream.set_write_timeout(timeout);
+
+                let mut stream = stream;
+                if let Err(e) = stream.write_message(0, message) {
+                    return Err(io_error(e));
                 }
-                Err(e) => {
-                    error!("{:?}", e);
-                    Err(Error::ConnectionError)
+                match stream.read_message() {
+                    Ok((_, val)) => Ok(val),
+                    Err(e) => Err(io_error(e)),
                 }
             }
-        })
+            Err(e) => {
+                error!("{:?}", e);
+                Err(Error::ConnectionError)
+            }
+        }
     }
 
     pub fn set_timeouts(&mut self, timeout: Duration) {
@@ -139,3 +735,55 @@ impl Client {
         self.write_timeout = Some(timeout);
     }
 }
+
+/// The timestamp a write or a delete was performed at.
+fn write_ack(response: Response) -> result::Result<u64, Response> {
+    match response {
+        Response::WriteAck {timestamp, ..} => Ok(timestamp),
+        r => Err(r),
+    }
+}
+
+/// Have `clock` take into account the timestamp `response` carries, if any.
+fn observe(clock: &Clock, response: &Response) {
+    let timestamp = match *response {
+        Response::Value {ref value, ..} |
+        Response::Conflict {ref value, ..} => value.get_timestamp(),
+        Response::WriteAck {timestamp, ..} => Some(timestamp),
+        _ => None,
+    };
+    if let Some(timestamp) = timestamp {
+        let _ = clock.update(timestamp);
+    }
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + (d.subsec_nanos() / 1000) as u64
+}
+
+fn from_micros(us: u64) -> Duration {
+    Duration::new(us / 1_000_000, (us % 1_000_000) as u32 * 1000)
+}
+
+/// Deadline of an attempt at a request: `timeout` from `now`, as long as
+/// it's within the `deadline` of the whole request.
+fn attempt_deadline(timeout: Option<Duration>, deadline: Option<u64>, now: u64) -> Option<u64> {
+    match (timeout.map(|t| now + micros(t)), deadline) {
+        (Some(a), Some(d)) => Some(cmp::min(a, d)),
+        (a, None) => a,
+        (None, d) => d,
+    }
+}
+
+/// The `Error` for a failed exchange with a node: `Timeout` if a socket
+/// timeout ran out.
+fn io_error(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Error::Timeout,
+        _ => Error::ConnectionError,
+    }
+}
@@ -1 +1,75 @@
-pub const BUFFER_SIZE: usize = 1024;
+/// Version of the wire protocol spoken between sbahn nodes.
+pub const PROTOCOL_VERSION: u8 = 2;
+/// Size in bytes of the `FrameHeader` preceding every message.
+pub const HEADER_SIZE: usize = 14;
+/// Largest payload accepted in a single frame.
+pub const MAX_FRAME_SIZE: u32 = 64 * 1024 * 1024;
+/// Default amount of virtual nodes each storage node gets on a `Ring`.
+pub const DEFAULT_VNODES: usize = 64;
+/// Amount of entries sent in each `InternodeRequest::Transfer` while
+/// resharding.
+pub const TRANSFER_BATCH_SIZE: usize = 128;
+/// Milliseconds a handler waits for storage nodes to answer, unless the
+/// `Request`'s deadline is sooner.
+pub const STORAGE_TIMEOUT_MS: u64 = 300;
+/// Probability of a read sending the newest `Value` it found to the replicas
+/// that returned an older one.
+pub const READ_REPAIR_CHANCE: f64 = 0.1;
+/// Milliseconds between checks of whether the nodes that missed writes are
+/// back.
+pub const HINT_PROBE_MS: u64 = 500;
+/// Milliseconds a write a replica missed is kept for it.
+pub const HINT_MAX_AGE_MS: u64 = 3 * 3600 * 1000;
+/// Size in bytes of all the writes replicas missed that are kept for them.
+pub const HINT_MAX_SIZE: u64 = 64 * 1024 * 1024;
+/// Milliseconds between a storage node's anti-entropy rounds with the other
+/// replicas of its shard.
+pub const ANTI_ENTROPY_INTERVAL_MS: u64 = 60_000;
+/// Milliseconds to wait for each step of an anti-entropy round.
+pub const ANTI_ENTROPY_TIMEOUT_MS: u64 = 10_000;
+/// Depth of the Merkle trees replicas compare during anti-entropy, which
+/// have `2^MERKLE_DEPTH` leaves.
+pub const MERKLE_DEPTH: u32 = 10;
+/// Deepest Merkle tree a storage node builds when asked to.
+pub const MAX_MERKLE_DEPTH: u32 = 20;
+/// Milliseconds to wait for each step of a topology change.
+pub const REBALANCE_TIMEOUT_MS: u64 = 60_000;
+/// Size in bytes after which a `LogBackend` starts a new segment file.
+pub const SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+/// Size in bytes an `LsmBackend`'s memtable grows to before being flushed to
+/// an SSTable.
+pub const MEMTABLE_SIZE: usize = 4 * 1024 * 1024;
+/// Size in bytes of the blocks an SSTable's entries are grouped in.
+pub const BLOCK_SIZE: usize = 4096;
+/// Amount of SSTables of the same tier merged together by compaction.
+pub const TIER_FANOUT: usize = 4;
+/// Microseconds a `Value::Tombstone` is kept for before compaction may drop
+/// it.
+pub const TOMBSTONE_GRACE_PERIOD: u64 = 10 * 24 * 3600 * 1_000_000;
+/// Size in bytes a storage node's write-ahead log grows to before it is
+/// checkpointed, when its backend is persistent.
+pub const WAL_CHECKPOINT_SIZE: u64 = 64 * 1024 * 1024;
+/// Paxos rounds a compare-and-set attempts at most before giving up on
+/// contention.
+pub const PAXOS_MAX_ROUNDS: u32 = 10;
+/// Milliseconds a compare-and-set waits at most before its next Paxos round,
+/// times the rounds attempted so far.
+pub const PAXOS_BACKOFF_MS: u64 = 10;
+/// Size in bytes the log of a Paxos acceptor grows to before it's rewritten
+/// with only the latest ballots of each `Key`.
+pub const PAXOS_LOG_SIZE: u64 = 16 * 1024 * 1024;
+/// Milliseconds between a Raft leader's heartbeats.
+pub const RAFT_HEARTBEAT_MS: u64 = 50;
+/// Milliseconds a Raft follower waits at least to hear from its leader before
+/// standing for election, up to twice as long at random.
+pub const RAFT_ELECTION_TIMEOUT_MS: u64 = 300;
+/// Entries kept in a Raft log once applied to the backend, which doubles as
+/// the snapshot of everything before them.
+pub const RAFT_MAX_LOG: usize = 1024;
+/// Milliseconds a Raft leader waits for a read or a write to be committed.
+pub const RAFT_COMMIT_TIMEOUT_MS: u64 = 250;
+/// Milliseconds between a storage node's sweeps for expired values.
+pub const EXPIRY_SWEEP_INTERVAL_MS: u64 = 10_000;
+/// Node IDs a node joining the ring tries at most before giving up on
+/// finding one no other node has.
+pub const NODE_ID_CLAIMS: u32 = 16;
@@ -0,0 +1,1288 @@
+use client;
+use constants::{NODE_ID_CLAIMS, PAXOS_BACKOFF_MS, PAXOS_MAX_ROUNDS, RAFT_COMMIT_TIMEOUT_MS,
+                READ_REPAIR_CHANCE, STORAGE_TIMEOUT_MS};
+use eventual::*;
+use hints::{HintLimits, HintedHandoff};
+use hlc::{self, Clock};
+use message::*;
+use metrics::Metrics;
+use pool::ConnectionPool;
+use rebalance::Topology;
+use std::collections::{BTreeMap, HashSet};
+use std::hash::{Hash, Hasher, SipHasher};
+use std::io;
+use std::net::SocketAddrV4;
+use std::path::Path;
+use std::result;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use std::u64;
+use time;
+use vclock::VectorClock;
+
+/// When reads repair the stale replicas they come across.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RepairMode {
+    /// Before responding.
+    Foreground,
+    /// While responding.
+    Background,
+}
+
+/// How reads send the newest `Value` they found to the replicas that
+/// returned an older one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReadRepair {
+    pub mode: RepairMode,
+    /// Probability of a read repairing, from 0 to 1.
+    pub chance: f64,
+}
+
+impl Default for ReadRepair {
+    fn default() -> ReadRepair {
+        ReadRepair {
+            mode: RepairMode::Background,
+            chance: READ_REPAIR_CHANCE,
+        }
+    }
+}
+
+/// Performs requests on the `StorageNode`s, the same way for every handler
+/// and token aware client.
+#[derive(Debug, Clone)]
+pub struct Coordinator {
+    /// Connections to the `StorageNode`s.
+    pub pool: Arc<ConnectionPool>,
+    /// Replays the writes replicas miss while they're down.
+    pub hints: HintedHandoff,
+    pub read_repair: ReadRepair,
+    pub metrics: Arc<Metrics>,
+    /// Timestamps writes, and keeps up with the timestamps storage nodes
+    /// answer with. Its node ID is checked with `claim_node_id` by handlers
+    /// and token aware clients.
+    pub clock: Arc<Clock>,
+    /// Datasets whose `Key`s keep concurrent writes as siblings, instead of
+    /// the last writer winning.
+    pub versioned_datasets: HashSet<Buffer>,
+    /// ID of the coordinator in the vector clocks of versioned values.
+    pub node: u64,
+}
+
+static RANDOM_SEQ: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// A random enough number, for jitter and the like.
+pub fn random() -> u64 {
+    let mut h = SipHasher::new();
+    time::precise_time_ns().hash(&mut h);
+    RANDOM_SEQ.fetch_add(1, Ordering::SeqCst).hash(&mut h);
+    h.finish()
+}
+
+/// Claim the node ID of `clock` for `claimant`, a node's ID in vector clocks,
+/// with every storage node of `ring`, switching to a random one for as long
+/// as any of them has it or gave it to another node. Storage nodes that
+/// can't be reached are skipped: IDs are only checked against the nodes up
+/// when joining.
+pub fn claim_node_id(pool: &ConnectionPool, ring: &Ring, claimant: u64, clock: &Clock) {
+    let timeout = Some(Duration::from_millis(STORAGE_TIMEOUT_MS));
+    for _ in 0..NODE_ID_CLAIMS {
+        let node = clock.node();
+        let request = InternodeRequest::ClaimNodeId {
+            node: node,
+            claimant: claimant,
+        };
+        let responses: Vec<Future<InternodeResponse, Error>> = ring.nodes()
+                                                                   .iter()
+                                                                   .map(|n| pool.send_to_node(n, &request, timeout))
+                                                                   .collect();
+        let refused = responses.into_iter()
+                               .filter_map(|r| {
+                                   match r.await() {
+                                       Ok(InternodeResponse::NodeIdClaimed {granted: false}) => Some(()),
+                                       _ => None,
+                                   }
+                               })
+                               .count();
+        if refused == 0 {
+            debug!("Claimed node ID {} for {}", node, claimant);
+            return;
+        }
+        let next = random() as u16;
+        info!("Node ID {} of {} is taken, switching to {}", node, claimant, next);
+        if let Err(e) = clock.set_node(next) {
+            error!("Couldn't keep node ID {} of {}: {:?}", next, claimant, e);
+            return;
+        }
+    }
+    error!("Couldn't find a node ID for {} that no other node has", claimant);
+}
+
+/// Whether something with probability `p` happens.
+fn chance(p: f64) -> bool {
+    p >= 1.0 || (random() as f64) < p * (u64::MAX as f64)
+}
+
+/// How long to wait for storage nodes to answer: `STORAGE_TIMEOUT_MS`, but
+/// no longer than until `deadline`.
+fn storage_timeout(deadline: &Option<u64>) -> Duration {
+    timeout_until(Duration::from_millis(STORAGE_TIMEOUT_MS), deadline)
+}
+
+/// How long to wait for the Raft group of a shard: long enough for a node to
+/// forward the request to the leader and for it to commit, but no longer
+/// than until `deadline`.
+fn raft_timeout(deadline: &Option<u64>) -> Duration {
+    timeout_until(Duration::from_millis(2 * RAFT_COMMIT_TIMEOUT_MS), deadline)
+}
+
+fn timeout_until(timeout: Duration, deadline: &Option<u64>) -> Duration {
+    match *deadline {
+        Some(d) => {
+            let left = d.saturating_sub(hlc::wall_clock_us());
+            let left = Duration::new(left / 1_000_000, (left % 1_000_000) as u32 * 1000);
+            if left < timeout { left } else { timeout }
+        }
+        None => timeout,
+    }
+}
+
+/// Whether `deadline` passed, so the client stopped waiting for a response.
+pub fn is_expired(deadline: &Option<u64>) -> bool {
+    deadline.map_or(false, |d| d <= hlc::wall_clock_us())
+}
+
+/// Refuse a request whose `consistency` can't be satisfied by a shard of
+/// `replicas` `StorageNode`s.
+fn unsatisfiable(key: &Key, consistency: &Consistency, replicas: usize) -> client::MessageResult {
+    info!("{:?} consistency can't be satisfied by {} replicas", consistency, replicas);
+    Ok(ResponseMessage {
+        message: Response::Error {
+            key: key.to_owned(),
+            message: format!("{:?} consistency can't be satisfied by {} replicas",
+                             consistency,
+                             replicas),
+        },
+        consistency: consistency.to_owned(),
+    })
+}
+
+/// Obtain the first valid response among the shard responses, without
+/// waiting for the rest.
+fn read_one(key: &Key, responses: Vec<Future<InternodeResponse, Error>>) -> client::MessageResult {
+    debug!("Reading one");
+    let (sender, receiver) = mpsc::channel();
+    for response in responses {
+        let sender = sender.clone();
+        response.receive(move |r| {
+            let _ = sender.send(r);
+        });
+    }
+    drop(sender);
+
+    // Replies that arrive later on are ignored.
+    for r in receiver.iter() {
+        match r {
+            Ok(m @ InternodeResponse::Value {..}) => {
+                return Ok(ResponseMessage {
+                    message: m.to_response(key),
+                    consistency: Consistency::One,
+                });
+            }
+            r => debug!("Read of {:?} failed: {:?}", key, r),
+        }
+    }
+    Ok(ResponseMessage {
+        message: Response::Error {
+            key: key.to_owned(),
+            message: "All the storage nodes replied with errors.".to_string(),
+        },
+        consistency: Consistency::One,
+    })
+}
+
+/// Merge the `Value`s stored in this shard's `StorageNode`s, at least
+/// `responses_needed` of which must reply, having one or not: the newest one, read as a
+/// deletion once it expired, or every sibling that isn't superseded if
+/// they're versioned. Replicas missing some of it might be repaired, as
+/// configured by the `coordinator`.
+fn read_latest(coordinator: &Coordinator,
+               key: &Key,
+               responses: Vec<(SocketAddrV4, Future<InternodeResponse, Error>)>,
+               responses_needed: usize,
+               consistency: &Consistency,
+               timeout: Duration)
+               -> client::MessageResult {
+    debug!("Reading latest");
+    let mut success_count = 0;
+    let mut latest: Option<Value> = None;
+    // Every replica that replied, and what it has.
+    let mut replied: Vec<(SocketAddrV4, Value)> = vec![];
+    for (shard, response) in responses {
+        match response.await() {
+            Ok(InternodeResponse::Value {value, ..}) => {
+                // Having nothing is an answer too.
+                success_count += 1;
+                if value.get_timestamp().is_some() {
+                    if let Some(merged) = Value::merge(latest.as_ref(), &value) {
+                        latest = Some(merged);
+                    }
+                    debug!("Merged value so far: {:?}", latest);
+                }
+                replied.push((shard, value));
+            }
+            Ok(r) => debug!("Read failed: {:?}", r),
+            // A replica that couldn't be reached just doesn't count.
+            Err(e) => debug!("Read failed: {:?}", e),
+        }
+    }
+
+    debug!("Quorum read final response: {:?}", latest);
+    debug!("Nodes responed successfully: {:?}", success_count);
+    debug!("Nodes needed for succesfull read: {:?}", responses_needed);
+
+    if success_count < responses_needed {
+        info!("Not enough storage nodes succeeded: {:?} of at least {:?}",
+              success_count,
+              responses_needed);
+        Ok(ResponseMessage {
+            message: Response::Error {
+                key: key.to_owned(),
+                message: "Not enough storage nodes succeeded to give a response".to_string(),
+            },
+            consistency: consistency.to_owned(),
+        })
+    } else {
+        match latest {
+            Some(value) => {
+                let stale: Vec<SocketAddrV4> = replied.into_iter()
+                                                      .filter(|&(_, ref v)| Value::merge(Some(v), &value).is_some())
+                                                      .map(|(shard, _)| shard)
+                                                      .collect();
+                if !stale.is_empty() && chance(coordinator.read_repair.chance) {
+                    read_repair(coordinator, key, &value, stale, timeout);
+                }
+                // Replicas whose clocks lag behind might still return an
+                // expired value.
+                let m = InternodeResponse::Value {
+                    key: key.to_owned(),
+                    value: value.unexpired(hlc::wall_clock_ms()),
+                };
+                Ok(ResponseMessage {
+                    message: m.to_response(key),
+                    consistency: consistency.to_owned(),
+                })
+            }
+            None => {
+                let m = InternodeResponse::Value {
+                    key: key.to_owned(),
+                    value: Value::None,
+                };
+                Ok(ResponseMessage {
+                    message: m.to_response(key),
+                    consistency: consistency.to_owned(),
+                })
+            }
+        }
+    }
+}
+
+/// Send the newest `value` of `key` to the `stale` replicas that returned an
+/// older one.
+fn read_repair(coordinator: &Coordinator, key: &Key, value: &Value, stale: Vec<SocketAddrV4>, timeout: Duration) {
+    debug!("Repairing {:?} on {:?}", key, stale);
+    let (sender, receiver) = mpsc::channel();
+    for shard in stale {
+        let sender = sender.clone();
+        let metrics = coordinator.metrics.clone();
+        let key = key.to_owned();
+        write_to_other_storage_node(&coordinator.pool, &shard, &key, value, timeout).receive(move |r| {
+            match r {
+                Ok(InternodeResponse::WriteAck {..}) => {
+                    metrics.read_repairs.fetch_add(1, Ordering::SeqCst);
+                }
+                r => {
+                    debug!("Couldn't repair {:?} on {:?}: {:?}", key, shard, r);
+                    metrics.failed_read_repairs.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+            let _ = sender.send(());
+        });
+    }
+    drop(sender);
+    if coordinator.read_repair.mode == RepairMode::Foreground {
+        for _ in receiver.iter() {}
+    }
+}
+
+/// Read from all nodes for this `Key`'s shard, and use `consistency` to
+/// collate the `StorageNode`'s responses.
+fn read(coordinator: &Coordinator,
+        shards: &Vec<SocketAddrV4>,
+        key: &Key,
+        consistency: &Consistency,
+        timeout: Duration)
+        -> client::MessageResult {
+    debug!("Read {:?} with {:?} consistency.", key, consistency);
+    let needed = match consistency.required(shards.len()) {
+        Some(n) => n,
+        None => return unsatisfiable(key, consistency, shards.len()),
+    };
+    let mut responses: Vec<(SocketAddrV4, Future<InternodeResponse, Error>)> = vec![];
+    for shard in shards {
+        let response = read_from_other_storage_node(&coordinator.pool, &shard, &key, timeout);
+        responses.push((shard.to_owned(), response));
+    }
+    let r = match consistency {
+        &Consistency::One => read_one(key, responses.into_iter().map(|(_, r)| r).collect()),
+        _ => read_latest(coordinator, key, responses, needed, consistency, timeout),
+    };
+    if let Ok(ResponseMessage {message: Response::Value {ref value, ..}, ..}) = r {
+        if let Some(timestamp) = value.get_timestamp() {
+            observe(&coordinator.clock, timestamp);
+        }
+    }
+    r
+}
+
+/// Have `clock` take into account `timestamp`, read from a storage node.
+fn observe(clock: &Clock, timestamp: u64) {
+    if let Err(ahead) = clock.update(timestamp) {
+        error!("Ignoring timestamp {} from {}ms in the future", timestamp, ahead);
+    }
+}
+
+/// Progress of a write, shared with its replicas' pending replies.
+struct WriteOutcome {
+    /// Whether the write was acknowledged, once it's been decided.
+    acknowledged: Option<bool>,
+    /// Replicas that failed the write before it was decided.
+    failed: Vec<SocketAddrV4>,
+}
+
+/// Write to all nodes for this `Key`'s shard, and use `consistency` to
+/// determine when to acknowledge the write to the client. Replicas that fail
+/// an acknowledged write get it through the `coordinator`'s hints. While resharding
+/// there is more than one set of replicas, and the write has to succeed in
+/// as many of each of them as `consistency` requires.
+fn write(coordinator: &Coordinator,
+         replica_sets: &Vec<Vec<SocketAddrV4>>,
+         key: &Key,
+         value: &Value,
+         consistency: &Consistency,
+         timeout: Duration)
+         -> client::MessageResult {
+    let request = InternodeRequest::Write {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    };
+    write_request(coordinator, replica_sets, key, &request, value, consistency, timeout)
+}
+
+/// Like `write`, sending the replicas `request`, which has them store
+/// `value` or part of it. Those that fail get `value` through the hints.
+fn write_request(coordinator: &Coordinator,
+                 replica_sets: &Vec<Vec<SocketAddrV4>>,
+                 key: &Key,
+                 request: &InternodeRequest,
+                 value: &Value,
+                 consistency: &Consistency,
+                 timeout: Duration)
+                 -> client::MessageResult {
+    let mut needed = vec![];
+    for replicas in replica_sets {
+        match consistency.required(replicas.len()) {
+            Some(n) => needed.push(n),
+            None => return unsatisfiable(key, consistency, replicas.len()),
+        }
+    }
+    let mut shards: Vec<SocketAddrV4> = vec![];
+    for replicas in replica_sets {
+        for replica in replicas {
+            if !shards.contains(replica) {
+                shards.push(replica.to_owned());
+            }
+        }
+    }
+
+    // Replies are handled in whatever order they arrive, and the write is
+    // acknowledged as soon as enough of them succeeded, while the rest finish
+    // in the background.
+    let (sender, receiver) = mpsc::channel();
+    let outcome = Arc::new(Mutex::new(WriteOutcome {
+        acknowledged: None,
+        failed: vec![],
+    }));
+    for shard in &shards {
+        debug!("Forwarding {:?} to shard at {:?}.", request, shard);
+        let response: Future<InternodeResponse, Error> = coordinator.pool.send_to_node(shard, request, Some(timeout));
+        let sender = sender.clone();
+        let outcome = outcome.clone();
+        let hints = coordinator.hints.clone();
+        let clock = coordinator.clock.clone();
+        let (shard, key, value) = (shard.to_owned(), key.to_owned(), value.to_owned());
+        response.receive(move |r| {
+            let succeeded = match r {
+                Ok(InternodeResponse::WriteAck {timestamp, ..}) => {
+                    // A superseded write comes back with the newer timestamp,
+                    // which the next one has to beat.
+                    observe(&clock, timestamp);
+                    true
+                }
+                r => {
+                    debug!("Write of {:?} to {:?} failed: {:?}", key, shard, r);
+                    false
+                }
+            };
+            if !succeeded {
+                let mut outcome = outcome.lock().unwrap();
+                match outcome.acknowledged {
+                    Some(true) => hints.submit(&shard, &key, &value),
+                    Some(false) => (),
+                    None => outcome.failed.push(shard),
+                }
+            }
+            let _ = sender.send((shard, succeeded));
+        });
+    }
+    drop(sender);
+
+    let mut acked: Vec<SocketAddrV4> = vec![];
+    let mut quorum = false;
+    for (shard, succeeded) in receiver.iter() {
+        if succeeded {
+            acked.push(shard);
+            quorum = replica_sets.iter().zip(&needed).all(|(replicas, &needed)| {
+                let write_count = replicas.iter().filter(|r| acked.contains(r)).count();
+                write_count >= needed
+            });
+            if quorum {
+                break;
+            }
+        }
+    }
+
+    // Replicas that missed an acknowledged write get it later on.
+    {
+        let mut outcome = outcome.lock().unwrap();
+        outcome.acknowledged = Some(quorum);
+        if quorum {
+            for shard in outcome.failed.drain(..) {
+                coordinator.hints.submit(&shard, key, value);
+            }
+        }
+    }
+
+    let message = if quorum {
+        debug!("Successful write to enough replicas for {:?}", key);
+        Response::WriteAck {
+            key: key.to_owned(),
+            timestamp: value.get_timestamp().unwrap_or(0),
+        }
+    } else {
+        Response::Error {
+            key: key.to_owned(),
+            message: "Quorum write could not be accomplished.".to_string(),
+        }
+    };
+    let r = ResponseMessage {
+        message: message,
+        consistency: consistency.to_owned(),
+    };
+
+    Ok(r)
+}
+
+
+fn read_from_other_storage_node(pool: &ConnectionPool,
+                                target: &SocketAddrV4,
+                                key: &Key,
+                                timeout: Duration)
+                                -> Future<InternodeResponse, Error> {
+    debug!("Forwarding read request for {:?} to shard at {:?}.",
+           key,
+           target);
+    let content = InternodeRequest::Read { key: key.to_owned() };
+    pool.send_to_node(target, &content, Some(timeout))
+}
+
+fn write_to_other_storage_node(pool: &ConnectionPool,
+                               target: &SocketAddrV4,
+                               key: &Key,
+                               value: &Value,
+                               timeout: Duration)
+                               -> Future<InternodeResponse, Error> {
+    debug!("Forwarding write request for {:?} to shard at {:?}.",
+           key,
+           target);
+    let request = InternodeRequest::Write {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    };
+    pool.send_to_node(target, &request, Some(timeout))
+}
+
+/// Merge entries found by several nodes in `Key` order (or reverse order),
+/// the way `Value::merge` does. Every reply comes along with
+/// whether it's complete: an incomplete one may be missing any entry past its
+/// last one, so the merge stops there. Returns up to `limit` merged entries,
+/// tombstones included, and whether there are no more entries past them.
+fn merge_entries(replies: Vec<(Vec<(Key, Value)>, bool)>,
+                 limit: usize,
+                 reverse: bool)
+                 -> (Vec<(Key, Value)>, bool) {
+    let mut cutoff: Option<Key> = None;
+    let mut merged: BTreeMap<Key, Value> = BTreeMap::new();
+    for (entries, complete) in replies {
+        if !complete {
+            if let Some(&(ref last, _)) = entries.last() {
+                let closer = match cutoff {
+                    Some(ref c) => if reverse { last > c } else { last < c },
+                    None => true,
+                };
+                if closer {
+                    cutoff = Some(last.to_owned());
+                }
+            }
+        }
+        for (key, value) in entries {
+            if let Some(v) = Value::merge(merged.get(&key), &value) {
+                merged.insert(key, v);
+            }
+        }
+    }
+
+    let mut found: Vec<(Key, Value)> = if reverse {
+        merged.into_iter().rev().collect()
+    } else {
+        merged.into_iter().collect()
+    };
+    if let Some(ref c) = cutoff {
+        found.retain(|&(ref k, _)| if reverse { k >= c } else { k <= c });
+    }
+    let exhausted = cutoff.is_none() && found.len() <= limit;
+    found.truncate(limit);
+    (found, exhausted)
+}
+
+/// Send `request` to every one of `replicas`.
+fn send_to_replicas(pool: &ConnectionPool,
+                    replicas: &Vec<SocketAddrV4>,
+                    request: &InternodeRequest,
+                    timeout: Duration)
+                    -> Vec<Future<InternodeResponse, Error>> {
+    replicas.iter()
+            .map(|replica| pool.send_to_node(replica, request, Some(timeout)))
+            .collect()
+}
+
+/// Collect the entries of the `InternodeResponse::Rows` in `responses`, from
+/// as many replicas as `consistency` needs. Each reply is complete unless it
+/// holds `limit` entries.
+fn collect_rows(responses: Vec<Future<InternodeResponse, Error>>,
+                limit: usize,
+                consistency: &Consistency)
+                -> Option<Vec<(Vec<(Key, Value)>, bool)>> {
+    let needed = match consistency.required(responses.len()) {
+        Some(n) => n,
+        None => return None,
+    };
+
+    let mut replies = vec![];
+    for response in responses {
+        match response.await() {
+            Ok(InternodeResponse::Rows {rows}) => {
+                let complete = rows.len() < limit;
+                replies.push((rows, complete));
+            }
+            r => debug!("Reading rows failed: {:?}", r),
+        }
+        if replies.len() >= needed {
+            if let Consistency::One = *consistency {
+                break;
+            }
+        }
+    }
+    if replies.len() < needed {
+        info!("Not enough storage nodes succeeded: {:?} of at least {:?}",
+              replies.len(),
+              needed);
+        None
+    } else {
+        Some(replies)
+    }
+}
+
+/// Send the `InternodeRequest::Scan` `request` to every one of `replicas`,
+/// and use `consistency` to collate their rows.
+fn scan(pool: &ConnectionPool,
+        replicas: &Vec<SocketAddrV4>,
+        start: &Key,
+        request: &InternodeRequest,
+        limit: usize,
+        reverse: bool,
+        consistency: &Consistency,
+        timeout: Duration)
+        -> client::MessageResult {
+    debug!("Scan {:?} with {:?} consistency.", request, consistency);
+    if consistency.required(replicas.len()).is_none() {
+        return unsatisfiable(start, consistency, replicas.len());
+    }
+    let responses = send_to_replicas(pool, replicas, request, timeout);
+    let message = match collect_rows(responses, limit, consistency) {
+        Some(replies) => {
+            let (found, exhausted) = merge_entries(replies, limit, reverse);
+            let last_lkey = if exhausted {
+                None
+            } else {
+                found.last().map(|&(ref k, _)| k.lkey.to_owned())
+            };
+            Response::Rows {
+                rows: found.into_iter().filter(|&(_, ref v)| !v.is_deleted()).collect(),
+                last_lkey: last_lkey,
+            }
+        }
+        None => {
+            Response::Error {
+                key: start.to_owned(),
+                message: "Not enough storage nodes succeeded to give a response".to_string(),
+            }
+        }
+    };
+    Ok(ResponseMessage {
+        message: message,
+        consistency: consistency.to_owned(),
+    })
+}
+
+/// List a page of the `Key`s stored in `dataset` across every shard of
+/// `ring`, using `consistency` to collate the responses of each shard's
+/// replicas.
+fn list_keys(pool: &ConnectionPool,
+             ring: &Ring,
+             dataset: &Buffer,
+             cursor: &Option<Key>,
+             page_size: usize,
+             include_tombstones: bool,
+             consistency: &Consistency,
+             timeout: Duration)
+             -> client::MessageResult {
+    debug!("List keys of {:?} after {:?} with {:?} consistency.", dataset, cursor, consistency);
+    let dataset_key = Key {
+        dataset: dataset.to_owned(),
+        pkey: vec![],
+        lkey: vec![],
+    };
+    for replicas in ring.shards() {
+        if consistency.required(replicas.len()).is_none() {
+            return unsatisfiable(&dataset_key, consistency, replicas.len());
+        }
+    }
+    let request = InternodeRequest::ListKeys {
+        dataset: dataset.to_owned(),
+        cursor: cursor.to_owned(),
+        limit: page_size,
+    };
+    // Every shard is asked at once.
+    let pending: Vec<Vec<Future<InternodeResponse, Error>>> = ring.shards()
+                                                                  .iter()
+                                                                  .map(|replicas| send_to_replicas(pool, replicas, &request, timeout))
+                                                                  .collect();
+    let mut shards = vec![];
+    for responses in pending {
+        match collect_rows(responses, page_size, consistency) {
+            Some(replies) => shards.push(merge_entries(replies, page_size, false)),
+            None => {
+                return Ok(ResponseMessage {
+                    message: Response::Error {
+                        key: dataset_key,
+                        message: "Not enough storage nodes succeeded to give a response".to_string(),
+                    },
+                    consistency: consistency.to_owned(),
+                });
+            }
+        }
+    }
+
+    // Shards hold disjoint sets of `Key`s, so they're merged just like
+    // replicas are.
+    let (found, exhausted) = merge_entries(shards, page_size, false);
+    let cursor = if exhausted {
+        None
+    } else {
+        found.last().map(|&(ref k, _)| k.to_owned())
+    };
+    let keys = found.into_iter()
+                    .filter(|&(_, ref v)| include_tombstones || !v.is_deleted())
+                    .map(|(k, _)| k)
+                    .collect();
+    Ok(ResponseMessage {
+        message: Response::Keys {
+            keys: keys,
+            cursor: cursor,
+        },
+        consistency: consistency.to_owned(),
+    })
+}
+
+/// Whether `value` is what a compare-and-set `expected`: a `Value` with that
+/// timestamp, or none at all.
+fn matches(value: &Value, expected: &Option<u64>) -> bool {
+    match *expected {
+        Some(timestamp) => !value.is_deleted() && value.get_timestamp() == Some(timestamp),
+        None => value.is_deleted() || value.get_timestamp().is_none(),
+    }
+}
+
+/// Send a Paxos `request` with `ballot` to every one of `replicas`, and
+/// whether at least `needed` of them answered with `Accepted` or `WriteAck`.
+/// Higher ballots some of them promised are taken into account by `clock`,
+/// for the next round to beat them.
+fn paxos_phase(pool: &ConnectionPool,
+               clock: &Clock,
+               replicas: &Vec<SocketAddrV4>,
+               request: &InternodeRequest,
+               needed: usize,
+               timeout: Duration)
+               -> bool {
+    let mut succeeded = 0;
+    for response in send_to_replicas(pool, replicas, request, timeout) {
+        match response.await() {
+            Ok(InternodeResponse::Accepted {..}) | Ok(InternodeResponse::WriteAck {..}) => succeeded += 1,
+            Ok(InternodeResponse::Rejected {promised, ..}) => observe(clock, promised),
+            r => debug!("Paxos {:?} failed: {:?}", request, r),
+        }
+    }
+    succeeded >= needed
+}
+
+/// Write `content` for `key` if the `Value` stored on `replicas` matches
+/// `expected`, as told by a Paxos round on a majority of them. A round that
+/// finds another one's accepted proposal not committed yet finishes it
+/// first, and rounds that lose against higher ballots are attempted again
+/// after a while, until `deadline`.
+fn compare_and_set(coordinator: &Coordinator,
+                   replicas: &Vec<SocketAddrV4>,
+                   key: &Key,
+                   expected: &Option<u64>,
+                   content: &Buffer,
+                   consistency: &Consistency,
+                   deadline: &Option<u64>)
+                   -> client::MessageResult {
+    debug!("Compare-and-set {:?} expecting {:?}", key, expected);
+    let needed = replicas.len() / 2 + 1;
+    let respond = |message| {
+        Ok(ResponseMessage {
+            message: message,
+            consistency: consistency.to_owned(),
+        })
+    };
+    // Ballots this compare-and-set proposed its value with. Once one is
+    // accepted by a majority the value is chosen, even if the replies got
+    // lost, and a later round finishing it must not take it for a conflict.
+    let mut proposed = vec![];
+    for round in 0..PAXOS_MAX_ROUNDS {
+        if round > 0 {
+            thread::sleep(Duration::from_millis(random() % (PAXOS_BACKOFF_MS * round as u64 + 1)));
+        }
+        if is_expired(deadline) {
+            break;
+        }
+        let timeout = storage_timeout(deadline);
+        let ballot = coordinator.clock.now();
+
+        // Prepare, reading what's stored along the way.
+        let request = InternodeRequest::Prepare {
+            key: key.to_owned(),
+            ballot: ballot,
+        };
+        let mut promises = 0;
+        let mut current: Option<Value> = None;
+        let mut in_progress: Option<(u64, Value)> = None;
+        for response in send_to_replicas(&coordinator.pool, replicas, &request, timeout) {
+            match response.await() {
+                Ok(InternodeResponse::Promise {value, accepted, ..}) => {
+                    promises += 1;
+                    if let Some(merged) = Value::merge(current.as_ref(), &value) {
+                        current = Some(merged);
+                    }
+                    if accepted.as_ref().map(|&(b, _)| b) > in_progress.as_ref().map(|&(b, _)| b) {
+                        in_progress = accepted;
+                    }
+                }
+                Ok(InternodeResponse::Rejected {promised, ..}) => observe(&coordinator.clock, promised),
+                r => debug!("Prepare of {:?} failed: {:?}", key, r),
+            }
+        }
+        if promises < needed {
+            debug!("Only {} replicas promised ballot {} for {:?}", promises, ballot, key);
+            continue;
+        }
+
+        let ours = |value: &Value| value.get_timestamp().map_or(false, |t| proposed.contains(&t));
+        let (value, finishing) = match in_progress {
+            Some((_, value)) => {
+                debug!("Finishing the Paxos round left unfinished for {:?}", key);
+                let finishing = !ours(&value);
+                (value, finishing)
+            }
+            None => {
+                let current = current.unwrap_or(Value::None);
+                match current.get_timestamp() {
+                    Some(timestamp) if proposed.contains(&timestamp) => {
+                        return respond(Response::WriteAck {
+                            key: key.to_owned(),
+                            timestamp: timestamp,
+                        });
+                    }
+                    _ => {}
+                }
+                if !matches(&current, expected) {
+                    return respond(Response::Conflict {
+                        key: key.to_owned(),
+                        value: current,
+                    });
+                }
+                let context = current.context();
+                (coordinator.write_value(key, Some(content.to_owned()), ballot, context, None), false)
+            }
+        };
+
+        // A value of this compare-and-set is timestamped with the ballot it's
+        // first proposed with.
+        let written = match value.get_timestamp() {
+            Some(timestamp) => timestamp,
+            None => {
+                error!("Unfinished Paxos round for {:?} left a value without a timestamp: {:?}", key, value);
+                return respond(Response::Error {
+                    key: key.to_owned(),
+                    message: "Compare-and-set found a value without a timestamp".to_string(),
+                });
+            }
+        };
+        if written == ballot {
+            proposed.push(ballot);
+        }
+        let propose = InternodeRequest::Propose {
+            key: key.to_owned(),
+            ballot: ballot,
+            value: value.to_owned(),
+        };
+        if !paxos_phase(&coordinator.pool, &coordinator.clock, replicas, &propose, needed, timeout) {
+            continue;
+        }
+        // Once accepted by a majority the value is chosen, and any later
+        // round would finish committing it.
+        let commit = InternodeRequest::Commit {
+            key: key.to_owned(),
+            ballot: ballot,
+            value: value,
+        };
+        if !paxos_phase(&coordinator.pool, &coordinator.clock, replicas, &commit, needed, timeout) {
+            debug!("Commit of ballot {} for {:?} didn't reach a majority", ballot, key);
+        }
+        if !finishing {
+            return respond(Response::WriteAck {
+                key: key.to_owned(),
+                timestamp: written,
+            });
+        }
+    }
+    respond(Response::Error {
+        key: key.to_owned(),
+        message: "Compare-and-set couldn't get a majority of replicas to agree".to_string(),
+    })
+}
+
+/// Have the Raft group of the shard perform `request`, through the first of
+/// its `replicas` that replies with something else than an error. Any of them
+/// can, as followers forward requests to the leader.
+fn linearizable(coordinator: &Coordinator,
+                replicas: &Vec<SocketAddrV4>,
+                key: &Key,
+                request: &InternodeRequest,
+                deadline: &Option<u64>)
+                -> client::MessageResult {
+    debug!("Linearizable {:?}", request);
+    for replica in replicas {
+        if is_expired(deadline) {
+            break;
+        }
+        let response: Future<InternodeResponse, Error> = coordinator.pool
+                                                                    .send_to_node(replica,
+                                                                                  request,
+                                                                                  Some(raft_timeout(deadline)));
+        match response.await() {
+            Ok(r @ InternodeResponse::Value {..}) |
+            Ok(r @ InternodeResponse::WriteAck {..}) => {
+                if let Some(timestamp) = r.get_timestamp() {
+                    observe(&coordinator.clock, timestamp);
+                }
+                return Ok(ResponseMessage {
+                    message: r.to_response(key),
+                    consistency: Consistency::Linearizable,
+                });
+            }
+            r => debug!("{:?} failed on {:?}: {:?}", request, replica, r),
+        }
+    }
+    Ok(ResponseMessage {
+        message: Response::Error {
+            key: key.to_owned(),
+            message: "No Raft leader could perform the request".to_string(),
+        },
+        consistency: Consistency::Linearizable,
+    })
+}
+
+/// Whether a read should be retried on the replicas of the ring being moved
+/// away from, as the `Key` might not have been streamed to its new replicas
+/// yet.
+fn needs_fallback(result: &client::MessageResult) -> bool {
+    match *result {
+        Ok(ResponseMessage {message: Response::Value {value: Value::None, ..}, ..}) |
+        Ok(ResponseMessage {message: Response::Error {..}, ..}) |
+        Err(_) => true,
+        _ => false,
+    }
+}
+
+impl Coordinator {
+    pub fn new(pool: Arc<ConnectionPool>) -> Coordinator {
+        Coordinator::with_read_repair(pool, ReadRepair::default())
+    }
+
+    pub fn with_read_repair(pool: Arc<ConnectionPool>, read_repair: ReadRepair) -> Coordinator {
+        let metrics = Arc::new(Metrics::new());
+        Coordinator {
+            hints: HintedHandoff::new(pool.clone(), metrics.clone(), HintLimits::default()),
+            pool: pool,
+            read_repair: read_repair,
+            metrics: metrics,
+            clock: Arc::new(Clock::new(random() as u16)),
+            versioned_datasets: HashSet::new(),
+            node: random(),
+        }
+    }
+
+    /// A coordinator keeping the writes replicas miss in `hints_dir`, up to
+    /// `limits`, so that they're replayed even after a restart. Its node ID
+    /// is kept there too.
+    pub fn with_hints(pool: Arc<ConnectionPool>,
+                      read_repair: ReadRepair,
+                      hints_dir: &Path,
+                      limits: HintLimits)
+                      -> io::Result<Coordinator> {
+        let metrics = Arc::new(Metrics::new());
+        let hints = try!(HintedHandoff::open(hints_dir, pool.clone(), metrics.clone(), limits));
+        let clock = try!(Clock::persisted(hints_dir));
+        Ok(Coordinator {
+            hints: hints,
+            pool: pool,
+            read_repair: read_repair,
+            metrics: metrics,
+            clock: Arc::new(clock),
+            versioned_datasets: HashSet::new(),
+            node: random(),
+        })
+    }
+
+    /// What a write to `key` at `timestamp` stores: `content`, expiring after
+    /// `ttl` milliseconds if there's one, or a deletion if there's none. In a
+    /// versioned dataset it's a sibling superseding those `context` covers.
+    fn write_value(&self,
+                   key: &Key,
+                   content: Option<Buffer>,
+                   timestamp: u64,
+                   context: Option<VectorClock>,
+                   ttl: Option<u64>)
+                   -> Value {
+        if self.versioned_datasets.contains(&key.dataset) {
+            Value::Versioned {
+                siblings: vec![Sibling {
+                                   node: self.node,
+                                   timestamp: timestamp,
+                                   context: context.unwrap_or_default(),
+                                   content: content,
+                               }],
+            }
+        } else {
+            match content {
+                Some(content) => {
+                    Value::Value {
+                        content: content,
+                        timestamp: timestamp,
+                        expires: ttl.map(|ttl| hlc::expiry(timestamp, ttl)),
+                    }
+                }
+                None => Value::Tombstone { timestamp: timestamp },
+            }
+        }
+    }
+
+    /// The timestamp of a write to `key`: the client's if it chose one, unless
+    /// it's too far ahead of the `clock`, or a new one.
+    fn write_timestamp(&self, key: &Key, timestamp: Option<u64>) -> result::Result<u64, Response> {
+        match timestamp {
+            Some(t) => {
+                match self.clock.update(t) {
+                    Ok(()) => Ok(t),
+                    Err(ahead) => {
+                        Err(Response::Error {
+                            key: key.to_owned(),
+                            message: format!("Timestamp is {}ms ahead of the handler's clock", ahead),
+                        })
+                    }
+                }
+            }
+            None => Ok(self.clock.now()),
+        }
+    }
+
+    /// Read `key` from the replicas `current` assigns to it, falling back to
+    /// those of the ring being moved away from while resharding.
+    fn read(&self,
+            current: &Topology,
+            key: &Key,
+            consistency: &Consistency,
+            deadline: &Option<u64>)
+            -> client::MessageResult {
+        let replica_sets = current.replica_sets(key);
+        let r = read(self,
+                     replica_sets.last().unwrap(),
+                     key,
+                     consistency,
+                     storage_timeout(deadline));
+        if replica_sets.len() > 1 && needs_fallback(&r) && !is_expired(deadline) {
+            debug!("Falling back to the current ring's replicas for {:?}", key);
+            read(self, &replica_sets[0], key, consistency, storage_timeout(deadline))
+        } else {
+            r
+        }
+    }
+
+    /// Perform a client's `Request` on the `StorageNode`s `current` assigns to
+    /// it, and collate their responses according to the `Request`'s
+    /// `Consistency`. Requests for the handler itself are refused.
+    pub fn coordinate(&self, current: &Topology, request: Request) -> client::MessageResult {
+        if request.consistency == Consistency::Linearizable {
+            let internode = match request.action {
+                Action::Read {ref key} => {
+                    Some((key.to_owned(),
+                          InternodeRequest::RaftRead {
+                        key: key.to_owned(),
+                        forwarded: false,
+                    }))
+                }
+                Action::Write {ref key, ref content, ttl} => {
+                    Some((key.to_owned(),
+                          InternodeRequest::RaftWrite {
+                        key: key.to_owned(),
+                        content: Some(content.to_owned()),
+                        ttl: ttl,
+                        forwarded: false,
+                    }))
+                }
+                Action::Delete {ref key} => {
+                    Some((key.to_owned(),
+                          InternodeRequest::RaftWrite {
+                        key: key.to_owned(),
+                        content: None,
+                        ttl: None,
+                        forwarded: false,
+                    }))
+                }
+                _ => None,
+            };
+            if let Some((key, internode)) = internode {
+                let replica_sets = current.replica_sets(&key);
+                if replica_sets.len() > 1 {
+                    return Ok(ResponseMessage {
+                        message: Response::Error {
+                            key: key,
+                            message: "Linearizable requests aren't available while resharding".to_string(),
+                        },
+                        consistency: request.consistency,
+                    });
+                }
+                return linearizable(self, &replica_sets[0], &key, &internode, &request.deadline);
+            }
+        }
+        match request.action {
+            Action::Read {key} => self.read(current, &key, &request.consistency, &request.deadline),
+            Action::Write {key, content, ttl} => {
+                if ttl.is_some() && self.versioned_datasets.contains(&key.dataset) {
+                    return Ok(ResponseMessage {
+                        message: Response::Error {
+                            key: key,
+                            message: "Versioned datasets don't support TTLs".to_string(),
+                        },
+                        consistency: request.consistency,
+                    });
+                }
+                let timestamp = match self.write_timestamp(&key, request.timestamp) {
+                    Ok(t) => t,
+                    Err(message) => {
+                        return Ok(ResponseMessage {
+                            message: message,
+                            consistency: request.consistency,
+                        })
+                    }
+                };
+                let value = self.write_value(&key, Some(content), timestamp, request.context, ttl);
+                write(self,
+                      &current.replica_sets(&key),
+                      &key,
+                      &value,
+                      &request.consistency,
+                      storage_timeout(&request.deadline))
+            }
+            Action::Delete {key} => {
+                let timestamp = match self.write_timestamp(&key, request.timestamp) {
+                    Ok(t) => t,
+                    Err(message) => {
+                        return Ok(ResponseMessage {
+                            message: message,
+                            consistency: request.consistency,
+                        })
+                    }
+                };
+                let value = self.write_value(&key, None, timestamp, request.context, None);
+                write(self,
+                      &current.replica_sets(&key),
+                      &key,
+                      &value,
+                      &request.consistency,
+                      storage_timeout(&request.deadline))
+            }
+            Action::Touch {key, ttl} => {
+                if self.versioned_datasets.contains(&key.dataset) {
+                    return Ok(ResponseMessage {
+                        message: Response::Error {
+                            key: key,
+                            message: "Versioned datasets don't support TTLs".to_string(),
+                        },
+                        consistency: request.consistency,
+                    });
+                }
+                let value = match self.read(current, &key, &request.consistency, &request.deadline) {
+                    Ok(ResponseMessage {message: Response::Value {value, ..}, ..}) => value,
+                    r => return r,
+                };
+                let (content, timestamp, expiry) = match value {
+                    Value::Value {content, timestamp, expires} => (content, timestamp, expires),
+                    _ => {
+                        return Ok(ResponseMessage {
+                            message: Response::Error {
+                                key: key,
+                                message: "There's no value to refresh the TTL of".to_string(),
+                            },
+                            consistency: request.consistency,
+                        });
+                    }
+                };
+                let now = match self.write_timestamp(&key, request.timestamp) {
+                    Ok(t) => t,
+                    Err(message) => {
+                        return Ok(ResponseMessage {
+                            message: message,
+                            consistency: request.consistency,
+                        })
+                    }
+                };
+                let expires = ttl.map(|ttl| hlc::expiry(now, ttl));
+                // Of two expiries of the same write, replicas keep the later.
+                if expires.unwrap_or(u64::max_value()) < expiry.unwrap_or(u64::max_value()) {
+                    return Ok(ResponseMessage {
+                        message: Response::Error {
+                            key: key,
+                            message: "A TTL can't be shortened without rewriting the value".to_string(),
+                        },
+                        consistency: request.consistency,
+                    });
+                }
+                let touch = InternodeRequest::Touch {
+                    key: key.to_owned(),
+                    timestamp: timestamp,
+                    expires: expires,
+                };
+                let value = Value::Value {
+                    content: content,
+                    timestamp: timestamp,
+                    expires: expires,
+                };
+                write_request(self,
+                              &current.replica_sets(&key),
+                              &key,
+                              &touch,
+                              &value,
+                              &request.consistency,
+                              storage_timeout(&request.deadline))
+            }
+            Action::CompareAndSet {key, expected, content} => {
+                let replica_sets = current.replica_sets(&key);
+                if replica_sets.len() > 1 {
+                    return Ok(ResponseMessage {
+                        message: Response::Error {
+                            key: key,
+                            message: "Compare-and-set isn't available while resharding".to_string(),
+                        },
+                        consistency: request.consistency,
+                    });
+                }
+                compare_and_set(self,
+                                &replica_sets[0],
+                                &key,
+                                &expected,
+                                &content,
+                                &request.consistency,
+                                &request.deadline)
+            }
+            Action::Scan {dataset, pkey, start_lkey, end_lkey, limit, reverse} => {
+                let (start, _) = Key::lkey_range(&dataset, &pkey, &start_lkey, &end_lkey);
+                // While resharding, the current ring's replicas still have every
+                // row, as writes go to the replicas of both rings.
+                let replicas = current.replica_sets(&start).swap_remove(0);
+                let internode = InternodeRequest::Scan {
+                    dataset: dataset,
+                    pkey: pkey,
+                    start_lkey: start_lkey,
+                    end_lkey: end_lkey,
+                    limit: limit,
+                    reverse: reverse,
+                };
+                scan(&self.pool,
+                     &replicas,
+                     &start,
+                     &internode,
+                     limit,
+                     reverse,
+                     &request.consistency,
+                     storage_timeout(&request.deadline))
+            }
+            Action::ListKeys {dataset, cursor, page_size, include_tombstones} => {
+                // While resharding, the current ring's replicas still have every
+                // `Key`.
+                list_keys(&self.pool,
+                          &current.ring,
+                          &dataset,
+                          &cursor,
+                          page_size,
+                          include_tombstones,
+                          &request.consistency,
+                          storage_timeout(&request.deadline))
+            }
+            Action::PrepareTopology {..} | Action::CommitTopology {..} | Action::GetTopology |
+            Action::GetMetrics => {
+                Ok(ResponseMessage {
+                    message: Response::TopologyError {
+                        message: "Only handlers perform this request".to_string(),
+                    },
+                    consistency: request.consistency,
+                })
+            }
+        }
+    }
+}
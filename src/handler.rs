@@ -1,296 +1,175 @@
 use bincode::SizeLimit;
 use bincode::rustc_serialize::{encode, decode};
 use client;
+use coordinator::{self, Coordinator, ReadRepair};
 use eventual::*;
+use hlc::{self, Clock};
 use message::*;
-use network::NetworkRead;
+use network::{NetworkRead, NetworkWrite};
+use pool::ConnectionPool;
+use rebalance::Topology;
 use std::fmt::Debug;
-use std::io::Write;
 use std::net::{SocketAddrV4, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::time::Duration;
-use time;
-
-
-/// Current Unix timestamp
-fn get_now() -> u64 {
-    let now = time::get_time();
-    let sec = now.sec;
-    let nsec = now.nsec;
-    // Consider using the Timespec directly instead.
-    ((sec as u64) * 1_000_000) + (nsec as u64 / 1000)
-}
-
-/// Obtain one (any) valid response from all the shard responses.
-fn read_one(key: &Key, responses: Vec<Future<InternodeResponse, Error>>) -> client::MessageResult {
-    debug!("Reading one");
-
-
-    Ok(sequence(responses)
-           .await()
-           .unwrap()
-           .iter()
-           .next()
-           .map(|&(ref m, _)| {
-               ResponseMessage {
-                   message: m.clone().to_response(),
-                   consistency: Consistency::One,
-               }
-           })
-           .unwrap_or(ResponseMessage {
-               message: Response::Error {
-                   key: key.to_owned(),
-                   message: "All the storage nodes replied with errors.".to_string(),
-               },
-               consistency: Consistency::One,
-           }))
-}
-
-/// Obtain the newest `Value` among those stored in this shard's `StorageNode`s.
-fn read_latest(key: &Key,
-               responses: Vec<Future<InternodeResponse, Error>>)
-               -> client::MessageResult {
-    debug!("Reading latest");
-    let responses_needed = responses.len() / 2;
-    let ((_, success_count), latest) =
-        sequence(responses)
-            .reduce(((0, 0), None), |last, r| {
-                let ((max_timestamp, success_count), max_response) = last;
-                debug!("Max timestamp so far: {:?}", max_timestamp);
-                debug!("Max max_response so far: {:?}", max_response);
-                match r.get_timestamp() {
-                    Some(ts) => {
-                        if ts >= max_timestamp {
-                            ((ts, success_count + 1), Some(r.clone()))
-                        } else {
-                            ((max_timestamp, success_count + 1), max_response)
-                        }
-                    }
-                    None => ((max_timestamp, success_count), max_response),
-                }
-            })
-            .await()
-            .unwrap();
-
-    debug!("Quorum read final response: {:?}", latest);
-    debug!("Nodes responed successfully: {:?}", success_count);
-    debug!("Nodes needed for succesfull read: {:?}", responses_needed);
-
-    if success_count <= responses_needed {
-        info!("Not enough storage nodes succeeded: {:?} of at least {:?}",
-              success_count,
-              responses_needed);
-        Ok(ResponseMessage {
-            message: Response::Error {
-                key: key.to_owned(),
-                message: "Not enough storage nodes succeeded to give a response".to_string(),
-            },
-            consistency: Consistency::Latest,
-        })
-    } else {
-        match latest {
-            Some(m) => {
-                Ok(ResponseMessage {
-                    message: m.to_response(),
-                    consistency: Consistency::Latest,
-                })
-            }
-            None => {
-                Ok(ResponseMessage {
-                    message: Response::Error {
-                        key: key.to_owned(),
-                        message: "?".to_string(),
-                    },
-                    consistency: Consistency::Latest,
-                })
-            }
+use vclock;
+
+
+/// Perform a client's `Request`: change `topology` as told, or have the
+/// `coordinator` perform it in the appropriate shard.
+pub fn handle_request(coordinator: &Coordinator,
+                      topology: &RwLock<Topology>,
+                      request: Request)
+                      -> client::MessageResult {
+    let message = match request.action {
+        Action::PrepareTopology {ref ring} => {
+            topology.write().unwrap().prepare(ring.to_owned());
+            Response::TopologyAck
         }
-    }
-}
-
-/// Read from all nodes for this `Key`'s shard, and use `consistency` to
-/// collate the `StorageNode`'s responses.
-fn read(shards: &Vec<SocketAddrV4>, key: &Key, consistency: &Consistency) -> client::MessageResult {
-    debug!("Read {:?} with {:?} consistency.", key, consistency);
-    let mut responses: Vec<Future<InternodeResponse, Error>> = vec![];
-    for shard in shards {
-        let response = read_from_other_storage_node(&shard, &key);
-        responses.push(response);
-    }
-    match consistency {
-        &Consistency::One => read_one(key, responses),
-        &Consistency::Latest => read_latest(key, responses),
-    }
-}
-
-/// Write to all nodes for this `Key`'s shard, and use `consistency` to
-/// determine when to acknowledge the write to the client.
-fn write(shards: &Vec<SocketAddrV4>,
-         key: &Key,
-         value: &Value,
-         consistency: &Consistency)
-         -> client::MessageResult {
-    let mut responses: Vec<Future<InternodeResponse, Error>> = vec![];
-    for shard in shards {
-        let response = write_to_other_storage_node(&shard, &key, &value);
-        debug!("Write response for {:?}, {:?} @ Shard {:?}: {:?}",
-               key,
-               value,
-               shard,
-               response);
-        responses.push(response);
-    }
-    let mut message = Response::Error {
-        key: key.to_owned(),
-        message: "Quorum write could not be accomplished.".to_string(),
-    };
-
-    let mut write_count = 0;
-    for response_result in responses {
-        match response_result.await() {
-            Ok(response) => {
-                match response {
-                    InternodeResponse::WriteAck {key, timestamp} => {
-                        write_count += 1;
-                        if write_count >= (shards.len() / 2) + 1 {
-                            debug!("Successful write to mayority of shards for {:?}", key);
-                            message = Response::WriteAck {
-                                key: key,
-                                timestamp: timestamp,
-                            };
-                        }
-                    }
-                    _ => (),
-                }
+        Action::CommitTopology {ref ring} => {
+            topology.write().unwrap().commit(ring.to_owned());
+            Response::TopologyAck
+        }
+        Action::GetTopology => {
+            let current = topology.read().unwrap();
+            Response::Topology {
+                ring: (*current.ring).clone(),
+                pending: current.pending.as_ref().map(|r| (**r).clone()),
             }
-            Err(_) => (),
         }
-    }
-    let r = ResponseMessage {
-        message: message,
-        consistency: consistency.to_owned(),
+        Action::GetMetrics => Response::Metrics { counters: coordinator.metrics.counters() },
+        _ => {
+            let current = topology.read().unwrap().clone();
+            return coordinator.coordinate(&current, request);
+        }
     };
-
-    Ok(r)
-}
-
-
-fn read_from_other_storage_node(target: &SocketAddrV4,
-                                key: &Key)
-                                -> Future<InternodeResponse, Error> {
-    debug!("Forwarding read request for {:?} to shard at {:?}.",
-           key,
-           target);
-    let content = InternodeRequest::Read { key: key.to_owned() };
-    let timeout = Some(Duration::from_millis(300));
-    client::Client::send_to_node_with_timeout(target, &content, timeout)
+    Ok(ResponseMessage {
+        message: message,
+        consistency: request.consistency,
+    })
 }
 
-fn write_to_other_storage_node(target: &SocketAddrV4,
-                               key: &Key,
-                               value: &Value)
-                               -> Future<InternodeResponse, Error> {
-    debug!("Forwarding write request for {:?} to shard at {:?}.",
-           key,
-           target);
-    let request = InternodeRequest::Write {
-        key: key.to_owned(),
-        value: value.to_owned(),
+/// Perform every client `Request` received through `stream`, responding to
+/// each one with a `ResponseMessage` as soon as it is ready. Requests are
+/// handled concurrently, so responses may go out in a different order than
+/// their requests came in.
+pub fn handle_client(stream: &mut TcpStream,
+                     topology: &Arc<RwLock<Topology>>,
+                     coordinator: &Coordinator) {
+    let writer = match stream.try_clone() {
+        Ok(s) => Arc::new(Mutex::new(s)),
+        Err(e) => {
+            error!("Couldn't clone stream: {:?}", e);
+            return;
+        }
     };
 
-    let timeout = Some(Duration::from_millis(300));
-    client::Client::send_to_node_with_timeout(target, &request, timeout)
-}
-
-/// Perform a client's `Request` in the appropriate shard and respond to the
-/// client with a ResponseMessage.
-pub fn handle_client(stream: &mut TcpStream, shards: &Vec<Vec<SocketAddrV4>>) {
-    let mut value: Buffer = vec![];
-
-    if stream.read_to_message_end(&mut value).is_err() {
-        panic!("Couldn't read from stream.");
-    }
-
-    let request: Request = match decode(&value) {
-        Ok(m) => m,
-        Err(e) => panic!("Message decoding error! {:?}", e),
-    };
+    loop {
+        let (request_id, value) = match stream.read_message() {
+            Ok(m) => m,
+            Err(e) => {
+                debug!("Client connection closed: {:?}", e);
+                break;
+            }
+        };
 
-    debug!("Message received: {:?}", request);
+        let request: Request = match decode(&value) {
+            Ok(m) => m,
+            Err(e) => panic!("Message decoding error! {:?}", e),
+        };
 
-    let timestamp = get_now();
-    let r = match request.action {
-        Action::Read {key} => {
-            let msg_shard = key.shard(shards.len());
-            read(&shards[msg_shard], &key, &request.consistency)
-        }
-        Action::Write {key, content} => {
-            let value = Value::Value {
-                content: content.to_owned(),
-                timestamp: timestamp,
-            };
-            let msg_shard = key.shard(shards.len());
-            write(&shards[msg_shard], &key, &value, &request.consistency)
+        debug!("Message received: {:?}", request);
+        if coordinator::is_expired(&request.deadline) {
+            debug!("Dropping request past its deadline: {:?}", request);
+            continue;
         }
-        Action::Delete {key} => {
-            let value = Value::Tombstone { timestamp: timestamp };
-            let msg_shard = key.shard(shards.len());
-            write(&shards[msg_shard], &key, &value, &request.consistency)
-        }
-    };
-    debug!("Response to be sent: {:?}", r);
-    let _ = match r {
-        Ok(message) => {
-            let encoded = encode(&message, SizeLimit::Infinite);
-            match encoded {
-                Ok(b) => {
-                    match stream.write(&b) {
-                        Ok(size) => debug!("Response sent (size: {})", size),
-                        Err(e) => error!("Error sending message: {:?}, {:?}", message, e),
+
+        let topology = topology.clone();
+        let coordinator = coordinator.clone();
+        let writer = writer.clone();
+        thread::spawn(move || {
+            let r = handle_request(&coordinator, &topology, request);
+            debug!("Response to be sent: {:?}", r);
+            match r {
+                Ok(message) => {
+                    let encoded = encode(&message, SizeLimit::Infinite);
+                    match encoded {
+                        Ok(b) => {
+                            match writer.lock().unwrap().write_message(request_id, &b) {
+                                Ok(_) => debug!("Response sent (size: {})", b.len()),
+                                Err(e) => error!("Error sending message: {:?}, {:?}", message, e),
+                            }
+                        }
+                        Err(e) => panic!("Message encoding error! {:?}", e),
                     }
                 }
-                Err(e) => panic!("Message encoding error! {:?}", e),
+                Err(e) => panic!("Communication error! {:?}", e),
             }
-        }
-        Err(e) => panic!("Communication error! {:?}", e),
-    };
+        });
+    }
 }
 
 
 trait ClientHandler where Self: Debug {
-    fn handle(&mut self, shards: &Vec<Vec<SocketAddrV4>>);
+    fn handle(&mut self, topology: &Arc<RwLock<Topology>>, coordinator: &Coordinator);
 }
 
 /// An sbahn aware stream
 impl ClientHandler for TcpStream {
-    fn handle(&mut self, shards: &Vec<Vec<SocketAddrV4>>) {
+    fn handle(&mut self, topology: &Arc<RwLock<Topology>>, coordinator: &Coordinator) {
         debug!("Starting listener stream: {:?}", self);
-        handle_client(self, &shards);
+        handle_client(self, topology, coordinator);
     }
 }
 
-/// Listen on `address` for incoming client requests, and perform them on the appropriate shards.
-pub fn listen(address: &SocketAddrV4, shards: &Vec<Vec<SocketAddrV4>>) -> Future<(), ()> {
+/// Listen on `address` for incoming client requests, and perform them on the
+/// replicas `ring` assigns to their `Key`, until told to move to another ring.
+pub fn listen(address: &SocketAddrV4, ring: &Ring) -> Future<(), ()> {
+    listen_with_read_repair(address, ring, ReadRepair::default())
+}
+
+/// Like `listen`, with reads repairing stale replicas as `read_repair` says.
+pub fn listen_with_read_repair(address: &SocketAddrV4, ring: &Ring, read_repair: ReadRepair) -> Future<(), ()> {
+    // Connections to storage nodes are shared by every client.
+    let mut coordinator = Coordinator::with_read_repair(Arc::new(ConnectionPool::new()), read_repair);
+    coordinator.clock = Arc::new(Clock::new(hlc::node_id(address)));
+    coordinator.node = vclock::node_id(address);
+    listen_with_coordinator(address, ring, coordinator)
+}
+
+/// Like `listen`, performing requests with `coordinator`.
+pub fn listen_with_coordinator(address: &SocketAddrV4, ring: &Ring, coordinator: Coordinator) -> Future<(), ()> {
     let address = address.to_owned();
-    let shards = shards.clone();
+    let topology = Arc::new(RwLock::new(Topology::new(ring.to_owned())));
 
-    let read_timeout = Some(Duration::from_millis(300));
+    // Client connections are long lived, so they may sit idle for a while
+    // between requests.
     let write_timeout = Some(Duration::from_millis(300));
 
     Future::spawn(move || {
         match TcpListener::bind(&address) {
             Ok(listener) => {
+                {
+                    // Make sure neither storage nodes nor other handlers have
+                    // this handler's ID.
+                    let ring = topology.read().unwrap().ring.clone();
+                    let coordinator = coordinator.clone();
+                    thread::spawn(move || {
+                        coordinator::claim_node_id(&coordinator.pool, &ring, coordinator.node, &coordinator.clock)
+                    });
+                }
                 // Accept connections and process them, spawning a new thread for each one.
                 for stream in listener.incoming() {
-                    let shards = shards.to_owned();
+                    let topology = topology.clone();
+                    let coordinator = coordinator.clone();
                     match stream {
                         Ok(stream) => {
-                            let _ = stream.set_read_timeout(read_timeout);
                             let _ = stream.set_write_timeout(write_timeout);
                             thread::spawn(move || {
                                 // connection succeeded
                                 let mut stream = stream;
-                                stream.handle(&shards);
+                                stream.handle(&topology, &coordinator);
                             });
                         }
                         Err(e) => error!("Connection failed!: {:?}", e),
@@ -0,0 +1,319 @@
+use bincode::SizeLimit;
+use bincode::rustc_serialize::{encode, decode};
+use constants::{HINT_MAX_AGE_MS, HINT_MAX_SIZE, HINT_PROBE_MS, STORAGE_TIMEOUT_MS};
+use eventual::*;
+use hlc;
+use message::{Error, InternodeRequest, InternodeResponse, Key, Value};
+use metrics::Metrics;
+use pool::ConnectionPool;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::SocketAddrV4;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use storage;
+
+/// How much `HintedHandoff` keeps around for nodes that are down.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HintLimits {
+    /// Hints older than this are dropped instead of replayed.
+    pub max_age: Duration,
+    /// Size in bytes of all the hints kept. Hints past it are dropped.
+    pub max_size: u64,
+}
+
+impl Default for HintLimits {
+    fn default() -> HintLimits {
+        HintLimits {
+            max_age: Duration::from_millis(HINT_MAX_AGE_MS),
+            max_size: HINT_MAX_SIZE,
+        }
+    }
+}
+
+/// A write a replica missed, and when it did.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+struct Hint {
+    key: Key,
+    value: Value,
+    /// Microseconds since the epoch.
+    stored: u64,
+}
+
+impl Hint {
+    fn record(&self) -> Vec<u8> {
+        match encode(self, SizeLimit::Infinite) {
+            Ok(payload) => storage::record(&payload),
+            Err(e) => panic!("[Hints] Hint encoding error! {:?}", e),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct HintsState {
+    /// The hints of every node that is down, oldest first, along with their
+    /// size.
+    nodes: HashMap<SocketAddrV4, VecDeque<(Hint, u64)>>,
+    /// Size of all the hints.
+    size: u64,
+}
+
+#[derive(Debug)]
+struct HintsInner {
+    pool: Arc<ConnectionPool>,
+    metrics: Arc<Metrics>,
+    limits: HintLimits,
+    /// Where hints are kept, one file per node, when they're durable.
+    dir: Option<PathBuf>,
+    state: Mutex<HintsState>,
+}
+
+/// Keeps the writes replicas missed while they were down, and replays them
+/// once they're back.
+///
+/// Every `HINT_PROBE_MS` the oldest hint of each node is sent to it; a node
+/// acknowledging it is back, and gets the rest of its hints right away, as
+/// `InternodeRequest::Write`s keeping their original timestamps.
+#[derive(Debug, Clone)]
+pub struct HintedHandoff {
+    inner: Arc<HintsInner>,
+}
+
+impl HintedHandoff {
+    /// Hints kept in memory, replayed through `pool` and counted in `metrics`
+    /// until every clone is dropped.
+    pub fn new(pool: Arc<ConnectionPool>, metrics: Arc<Metrics>, limits: HintLimits) -> HintedHandoff {
+        HintedHandoff::start(pool, metrics, limits, None, HashMap::new(), 0)
+    }
+
+    /// Hints kept in `dir`, which survive restarts: whatever a previous run
+    /// left there is replayed too.
+    pub fn open(dir: &Path,
+                pool: Arc<ConnectionPool>,
+                metrics: Arc<Metrics>,
+                limits: HintLimits)
+                -> io::Result<HintedHandoff> {
+        try!(fs::create_dir_all(dir));
+        let mut nodes = HashMap::new();
+        let mut size = 0;
+        for entry in try!(fs::read_dir(dir)) {
+            let path = try!(entry).path();
+            let node = match path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+                Some(node) if path.extension().map_or(false, |e| e == "hints") => node,
+                _ => continue,
+            };
+            let mut buf = vec![];
+            try!(try!(File::open(&path)).read_to_end(&mut buf));
+            let (records, _) = storage::read_records(&buf);
+            let mut hints = VecDeque::new();
+            for (offset, payload) in records {
+                match decode::<Hint>(payload) {
+                    Ok(hint) => {
+                        let len = storage::record(payload).len() as u64;
+                        size += len;
+                        hints.push_back((hint, len));
+                    }
+                    Err(e) => error!("[Hints] Undecodable hint at {} in {:?}: {:?}", offset, path, e),
+                }
+            }
+            info!("[Hints] Recovered {} hints for {:?}", hints.len(), node);
+            nodes.insert(node, hints);
+        }
+        Ok(HintedHandoff::start(pool, metrics, limits, Some(dir.to_owned()), nodes, size))
+    }
+
+    fn start(pool: Arc<ConnectionPool>,
+             metrics: Arc<Metrics>,
+             limits: HintLimits,
+             dir: Option<PathBuf>,
+             nodes: HashMap<SocketAddrV4, VecDeque<(Hint, u64)>>,
+             size: u64)
+             -> HintedHandoff {
+        let inner = Arc::new(HintsInner {
+            pool: pool,
+            metrics: metrics,
+            limits: limits,
+            dir: dir,
+            state: Mutex::new(HintsState {
+                nodes: nodes,
+                size: size,
+            }),
+        });
+        let weak = Arc::downgrade(&inner);
+        thread::spawn(move || HintsInner::replay(weak));
+        HintedHandoff { inner: inner }
+    }
+
+    /// Keep the write of `value` under `key` that `node` missed, unless that
+    /// would go past the size limit.
+    pub fn submit(&self, node: &SocketAddrV4, key: &Key, value: &Value) {
+        let hint = Hint {
+            key: key.to_owned(),
+            value: value.to_owned(),
+            stored: hlc::wall_clock_us(),
+        };
+        let record = hint.record();
+        let len = record.len() as u64;
+
+        let mut state = self.inner.state.lock().unwrap();
+        if state.size + len > self.inner.limits.max_size {
+            error!("[Hints] Dropping hint of {:?} for {:?}, {} bytes of hints kept already",
+                   key,
+                   node,
+                   state.size);
+            self.inner.metrics.dropped_hints.fetch_add(1, Ordering::SeqCst);
+            return;
+        }
+        if let Some(path) = self.inner.path(node) {
+            let appended = OpenOptions::new()
+                               .append(true)
+                               .create(true)
+                               .open(&path)
+                               .and_then(|mut f| f.write_all(&record).and_then(|_| f.sync_data()));
+            if let Err(e) = appended {
+                error!("[Hints] Couldn't store hint of {:?} in {:?}: {:?}", key, path, e);
+            }
+        }
+        debug!("[Hints] Keeping hint of {:?} for {:?}", key, node);
+        state.size += len;
+        state.nodes.entry(node.to_owned()).or_insert_with(VecDeque::new).push_back((hint, len));
+    }
+
+    /// Amount of hints not replayed or dropped yet.
+    pub fn pending(&self) -> usize {
+        self.inner.state.lock().unwrap().nodes.values().map(|h| h.len()).sum()
+    }
+}
+
+impl HintsInner {
+    /// The file `node`'s hints are kept in, when they're durable.
+    fn path(&self, node: &SocketAddrV4) -> Option<PathBuf> {
+        self.dir.as_ref().map(|d| d.join(format!("{}.hints", node)))
+    }
+
+    /// Rewrite `node`'s file with the hints it has left.
+    fn persist(&self, node: &SocketAddrV4, hints: Option<&VecDeque<(Hint, u64)>>) {
+        let path = match self.path(node) {
+            Some(p) => p,
+            None => return,
+        };
+        let result = match hints {
+            Some(hints) if !hints.is_empty() => {
+                let mut buf = vec![];
+                for &(ref hint, _) in hints {
+                    buf.extend(hint.record());
+                }
+                File::create(&path).and_then(|mut f| f.write_all(&buf).and_then(|_| f.sync_data()))
+            }
+            _ => fs::remove_file(&path).or_else(|e| {
+                if e.kind() == io::ErrorKind::NotFound {
+                    Ok(())
+                } else {
+                    Err(e)
+                }
+            }),
+        };
+        if let Err(e) = result {
+            error!("[Hints] Couldn't rewrite {:?}: {:?}", path, e);
+        }
+    }
+
+    /// Drop `node`'s hints past their age limit.
+    fn expire(&self, node: &SocketAddrV4) {
+        let mut state = self.state.lock().unwrap();
+        let oldest = hlc::wall_clock_us().saturating_sub(micros(self.limits.max_age));
+        let mut dropped = 0;
+        {
+            let HintsState {ref mut nodes, ref mut size} = *state;
+            if let Some(hints) = nodes.get_mut(node) {
+                while hints.front().map_or(false, |&(ref h, _)| h.stored < oldest) {
+                    let (_, len) = hints.pop_front().unwrap();
+                    *size -= len;
+                    dropped += 1;
+                }
+            }
+        }
+        if dropped > 0 {
+            error!("[Hints] Dropping {} hints for {:?} past their age", dropped, node);
+            self.metrics.dropped_hints.fetch_add(dropped, Ordering::SeqCst);
+            self.persist(node, state.nodes.get(node));
+        }
+        if state.nodes.get(node).map_or(false, |h| h.is_empty()) {
+            state.nodes.remove(node);
+        }
+    }
+
+    /// Send `node` its hints, oldest first, until it can't be reached. Hints
+    /// it answers with an error are dropped.
+    fn replay_node(&self, node: &SocketAddrV4) {
+        let timeout = Some(Duration::from_millis(STORAGE_TIMEOUT_MS));
+        let mut replayed = 0;
+        loop {
+            // Hints are only ever taken from the front here, so the oldest
+            // one is still there after sending it.
+            let hint = match self.state.lock().unwrap().nodes.get(node).and_then(|h| h.front()) {
+                Some(&(ref hint, _)) => hint.to_owned(),
+                None => break,
+            };
+            let request = InternodeRequest::Write {
+                key: hint.key.to_owned(),
+                value: hint.value,
+            };
+            let response: Future<InternodeResponse, Error> = self.pool.send_to_node(node, &request, timeout);
+            match response.await() {
+                Ok(InternodeResponse::WriteAck {..}) => {
+                    self.metrics.delivered_hints.fetch_add(1, Ordering::SeqCst);
+                }
+                Ok(r) => {
+                    // The node is up but won't take the hint, so sending it
+                    // again would only hold back the ones behind it.
+                    error!("[Hints] {:?} rejected the hint for {:?}, dropping it: {:?}", node, hint.key, r);
+                    self.metrics.rejected_hints.fetch_add(1, Ordering::SeqCst);
+                }
+                Err(e) => {
+                    debug!("[Hints] {:?} is still down: {:?}", node, e.take());
+                    break;
+                }
+            }
+            let mut state = self.state.lock().unwrap();
+            let HintsState {ref mut nodes, ref mut size} = *state;
+            if let Some((_, len)) = nodes.get_mut(node).and_then(|h| h.pop_front()) {
+                *size -= len;
+            }
+            replayed += 1;
+        }
+        if replayed > 0 {
+            info!("[Hints] Replayed {} hints to {:?}", replayed, node);
+            let mut state = self.state.lock().unwrap();
+            self.persist(node, state.nodes.get(node));
+            if state.nodes.get(node).map_or(false, |h| h.is_empty()) {
+                state.nodes.remove(node);
+            }
+        }
+    }
+
+    /// Replay the hints of the nodes that came back every `HINT_PROBE_MS`,
+    /// until `HintedHandoff` is dropped.
+    fn replay(hints: Weak<HintsInner>) {
+        loop {
+            thread::sleep(Duration::from_millis(HINT_PROBE_MS));
+            let inner = match hints.upgrade() {
+                Some(i) => i,
+                None => return,
+            };
+            let nodes: Vec<SocketAddrV4> = inner.state.lock().unwrap().nodes.keys().cloned().collect();
+            for node in nodes {
+                inner.expire(&node);
+                inner.replay_node(&node);
+            }
+        }
+    }
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + d.subsec_nanos() as u64 / 1000
+}
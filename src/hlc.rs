@@ -0,0 +1,186 @@
+use coordinator;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher, SipHasher};
+use std::io::{self, Read, Write};
+use std::net::SocketAddrV4;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use time;
+
+/// Bits of a timestamp holding the logical counter.
+const LOGICAL_BITS: u32 = 6;
+/// Bits of a timestamp holding the ID of the node that made it.
+const NODE_BITS: u32 = 16;
+/// File in a node's data directory keeping its ID.
+const NODE_ID_FILE: &'static str = "node-id";
+
+/// A hybrid logical clock timestamp: milliseconds since the epoch in the top
+/// 42 bits, a logical counter in the next 6 and the ID of the node that made
+/// it in the last 16. Timestamps compare by time first, and concurrent ones
+/// made on different nodes by their ID.
+pub fn timestamp(physical_ms: u64, logical: u64, node: u16) -> u64 {
+    (physical_ms << (LOGICAL_BITS + NODE_BITS)) | (logical << NODE_BITS) | node as u64
+}
+
+/// Milliseconds since the epoch `timestamp` was made at, as far as its clock
+/// knew.
+pub fn physical_ms(timestamp: u64) -> u64 {
+    timestamp >> (LOGICAL_BITS + NODE_BITS)
+}
+
+/// Microseconds since the epoch `timestamp` was made at, as far as its clock
+/// knew.
+pub fn to_micros(timestamp: u64) -> u64 {
+    physical_ms(timestamp).saturating_mul(1000)
+}
+
+/// Unix time, in milliseconds, at which what's written at `timestamp`
+/// expires when it has `ttl` milliseconds to live.
+pub fn expiry(timestamp: u64, ttl: u64) -> u64 {
+    physical_ms(timestamp).saturating_add(ttl)
+}
+
+/// ID of the node that made `timestamp`.
+pub fn node(timestamp: u64) -> u16 {
+    timestamp as u16
+}
+
+/// ID for the node listening at `address`, for nodes that neither have one
+/// configured nor a data directory to keep one in. Different addresses may
+/// get the same ID, until `coordinator::claim_node_id` finds out.
+pub fn node_id(address: &SocketAddrV4) -> u16 {
+    let mut h = SipHasher::new();
+    address.hash(&mut h);
+    h.finish() as u16
+}
+
+/// ID of the node keeping its data in `dir`. A random one is picked and
+/// stored there the first time, so that it stays the same across restarts.
+pub fn persisted_node_id(dir: &Path) -> io::Result<u16> {
+    let path = dir.join(NODE_ID_FILE);
+    match File::open(&path) {
+        Ok(mut file) => {
+            let mut id = String::new();
+            try!(file.read_to_string(&mut id));
+            id.trim().parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            let id = coordinator::random() as u16;
+            try!(persist_node_id(dir, id));
+            info!("Picked {} as the node ID kept in {:?}", id, dir);
+            Ok(id)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Keep `id` in `dir` as the ID of the node keeping its data there.
+fn persist_node_id(dir: &Path, id: u16) -> io::Result<()> {
+    let path = dir.join(NODE_ID_FILE);
+    try!(fs::create_dir_all(dir));
+    let tmp = path.with_extension("tmp");
+    {
+        let mut file = try!(File::create(&tmp));
+        try!(writeln!(file, "{}", id));
+        try!(file.sync_all());
+    }
+    fs::rename(&tmp, &path)
+}
+
+/// Milliseconds since the epoch, as far as the wall clock knows.
+pub fn wall_clock_ms() -> u64 {
+    let now = time::get_time();
+    ((now.sec as u64) * 1000) + (now.nsec as u64 / 1_000_000)
+}
+
+/// Microseconds since the epoch, as far as the wall clock knows.
+pub fn wall_clock_us() -> u64 {
+    let now = time::get_time();
+    ((now.sec as u64) * 1_000_000) + (now.nsec as u64 / 1000)
+}
+
+/// A hybrid logical clock. The timestamps it makes are newer than any it made
+/// or saw before, and stay close to the wall clock, so that clock skew between
+/// nodes doesn't decide which of two writes made in order wins.
+#[derive(Debug)]
+pub struct Clock {
+    /// ID of this node, only changed when another node has it already.
+    node: AtomicUsize,
+    /// Directory the node ID is kept in, if any.
+    dir: Option<PathBuf>,
+    /// How far ahead of the wall clock a timestamp seen may be.
+    max_drift: Option<Duration>,
+    /// Newest timestamp made or seen, without a node ID.
+    last: Mutex<u64>,
+}
+
+impl Clock {
+    pub fn new(node: u16) -> Clock {
+        Clock::with_max_drift(node, None)
+    }
+
+    /// A clock refusing timestamps more than `max_drift` ahead of its wall
+    /// clock, if there's a bound.
+    pub fn with_max_drift(node: u16, max_drift: Option<Duration>) -> Clock {
+        Clock {
+            node: AtomicUsize::new(node as usize),
+            dir: None,
+            max_drift: max_drift,
+            last: Mutex::new(0),
+        }
+    }
+
+    /// A clock for the node keeping its data in `dir`, with the ID kept
+    /// there.
+    pub fn persisted(dir: &Path) -> io::Result<Clock> {
+        let mut clock = Clock::new(try!(persisted_node_id(dir)));
+        clock.dir = Some(dir.to_owned());
+        Ok(clock)
+    }
+
+    /// ID of the node this clock belongs to.
+    pub fn node(&self) -> u16 {
+        self.node.load(Ordering::SeqCst) as u16
+    }
+
+    /// Switch to `node` as this node's ID, keeping it in the node's data
+    /// directory if it has one.
+    pub fn set_node(&self, node: u16) -> io::Result<()> {
+        if let Some(ref dir) = self.dir {
+            try!(persist_node_id(dir, node));
+        }
+        self.node.store(node as usize, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// A timestamp for an event happening on this node.
+    pub fn now(&self) -> u64 {
+        let wall = wall_clock_ms() << LOGICAL_BITS;
+        let mut last = self.last.lock().unwrap();
+        // Running out of logical counter moves on to the next millisecond.
+        *last = if wall > *last { wall } else { *last + 1 };
+        (*last << NODE_BITS) | self.node() as u64
+    }
+
+    /// Take into account `timestamp`, made by another node. Fails with how
+    /// many milliseconds ahead of the wall clock it is if that's more than
+    /// the drift allowed.
+    pub fn update(&self, timestamp: u64) -> Result<(), u64> {
+        if let Some(max_drift) = self.max_drift {
+            let wall = wall_clock_ms();
+            let ahead = physical_ms(timestamp).saturating_sub(wall);
+            let max_drift = max_drift.as_secs() * 1000 + max_drift.subsec_nanos() as u64 / 1_000_000;
+            if ahead > max_drift {
+                return Err(ahead);
+            }
+        }
+        let mut last = self.last.lock().unwrap();
+        let seen = timestamp >> NODE_BITS;
+        if seen > *last {
+            *last = seen;
+        }
+        Ok(())
+    }
+}
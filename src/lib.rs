@@ -5,10 +5,22 @@ extern crate log;
 extern crate rustc_serialize;
 extern crate time;
 
+pub mod anti_entropy;
 pub mod client;
 pub mod constants;
+pub mod coordinator;
 pub mod handler;
+pub mod hints;
+pub mod hlc;
+pub mod lsm;
 pub mod message;
+pub mod metrics;
 pub mod network;
+pub mod paxos;
+pub mod pool;
+pub mod raft;
+pub mod rebalance;
 pub mod storage;
 pub mod storage_node;
+pub mod vclock;
+pub mod wal;
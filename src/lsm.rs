@@ -0,0 +1,774 @@
+use bincode::SizeLimit;
+use bincode::rustc_serialize::{encode, decode};
+use constants::{BLOCK_SIZE, MEMTABLE_SIZE, TIER_FANOUT, TOMBSTONE_GRACE_PERIOD};
+use hlc;
+use message::{Buffer, Key, Value};
+use rustc_serialize::{Decodable, Encodable};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher, SipHasher};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use storage::{self, StorageBackend};
+
+/// Trailing bytes of every SSTable: the offset of its `TableMeta` and a magic
+/// number.
+const FOOTER_SIZE: u64 = 12;
+const MAGIC: u32 = 0x5b4a1e55;
+/// Bits of the bloom filter per key in an SSTable.
+const BLOOM_BITS_PER_KEY: usize = 10;
+/// Amount of hashes each key sets in the bloom filter.
+const BLOOM_HASHES: u32 = 7;
+
+fn invalid_data<E: ::std::fmt::Debug>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))
+}
+
+fn to_bytes<T: Encodable>(t: &T) -> io::Result<Buffer> {
+    encode(t, SizeLimit::Infinite).map_err(invalid_data)
+}
+
+fn from_bytes<T: Decodable>(buf: &[u8]) -> io::Result<T> {
+    decode(buf).map_err(invalid_data)
+}
+
+/// Tuning knobs for an `LsmBackend`.
+#[derive(Debug, Clone)]
+pub struct LsmConfig {
+    /// Size in bytes the memtable grows to before being flushed.
+    pub memtable_size: usize,
+    /// Size in bytes of the blocks an SSTable's entries are grouped in.
+    pub block_size: usize,
+    /// Amount of SSTables of the same tier merged together by compaction.
+    pub tier_fanout: usize,
+    /// Microseconds a `Value::Tombstone` is kept for before compaction may
+    /// drop it.
+    pub tombstone_grace_period: u64,
+}
+
+impl Default for LsmConfig {
+    fn default() -> LsmConfig {
+        LsmConfig {
+            memtable_size: MEMTABLE_SIZE,
+            block_size: BLOCK_SIZE,
+            tier_fanout: TIER_FANOUT,
+            tombstone_grace_period: TOMBSTONE_GRACE_PERIOD,
+        }
+    }
+}
+
+/// A probabilistic set of `Key`s, without false negatives.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// An empty filter sized for `keys` keys.
+    pub fn new(keys: usize) -> BloomFilter {
+        BloomFilter {
+            bits: vec![0; (keys * BLOOM_BITS_PER_KEY) / 64 + 1],
+            hashes: BLOOM_HASHES,
+        }
+    }
+
+    fn positions(&self, key: &Key) -> Vec<usize> {
+        let mut h1 = SipHasher::new_with_keys(0, 0);
+        Hash::hash(key, &mut h1);
+        let mut h2 = SipHasher::new_with_keys(1, 1);
+        Hash::hash(key, &mut h2);
+        let (h1, h2) = (h1.finish(), h2.finish());
+        let len = (self.bits.len() * 64) as u64;
+        (0..self.hashes as u64).map(|i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize).collect()
+    }
+
+    pub fn insert(&mut self, key: &Key) {
+        for p in self.positions(key) {
+            self.bits[p / 64] |= 1 << (p % 64);
+        }
+    }
+
+    /// Whether `key` might have been inserted. `false` means it certainly
+    /// wasn't.
+    pub fn may_contain(&self, key: &Key) -> bool {
+        self.positions(key).iter().all(|&p| self.bits[p / 64] & (1 << (p % 64)) != 0)
+    }
+}
+
+/// Location of one of an SSTable's blocks.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+struct BlockHandle {
+    /// Smallest `Key` in the block.
+    first_key: Key,
+    offset: u64,
+    len: u64,
+}
+
+/// Everything needed to look `Key`s up in an SSTable, stored after its
+/// blocks.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+struct TableMeta {
+    tier: u32,
+    index: Vec<BlockHandle>,
+    bloom: BloomFilter,
+}
+
+/// An immutable file of entries sorted by `Key`.
+///
+/// Entries are stored as records, as in `LogBackend`, grouped in blocks of
+/// about `block_size` bytes. The blocks are followed by a `TableMeta` record
+/// indexing the first `Key` of each block, and by a footer pointing to it.
+///
+/// Each SSTable holds the data of a range of memtables, named by their
+/// sequence numbers.
+#[derive(Debug)]
+struct SsTable {
+    path: PathBuf,
+    min_seq: u64,
+    max_seq: u64,
+    /// Amount of compactions the data went through.
+    tier: u32,
+    file: File,
+    /// Size of the blocks.
+    data_len: u64,
+    index: Vec<BlockHandle>,
+    bloom: BloomFilter,
+}
+
+impl SsTable {
+    fn file_name(min_seq: u64, max_seq: u64) -> String {
+        format!("sstable-{:08}-{:08}.sst", min_seq, max_seq)
+    }
+
+    /// Sequence number range of the SSTable named `name`.
+    fn parse_name(name: &str) -> Option<(u64, u64)> {
+        if !name.starts_with("sstable-") || !name.ends_with(".sst") {
+            return None;
+        }
+        let seqs: Vec<&str> = name[8..name.len() - 4].split('-').collect();
+        if seqs.len() != 2 {
+            return None;
+        }
+        match (seqs[0].parse(), seqs[1].parse()) {
+            (Ok(min), Ok(max)) => Some((min, max)),
+            _ => None,
+        }
+    }
+
+    /// Write `entries`, sorted by `Key`, to a new SSTable in `dir`. The file
+    /// is synced and only shows up under its name once complete.
+    fn write(dir: &Path,
+             min_seq: u64,
+             max_seq: u64,
+             tier: u32,
+             entries: &Vec<(Key, Value)>,
+             block_size: usize)
+             -> io::Result<SsTable> {
+        let mut data = vec![];
+        let mut index = vec![];
+        let mut bloom = BloomFilter::new(entries.len());
+        let mut block_start = 0;
+        for &(ref key, ref value) in entries {
+            if data.len() == block_start || data.len() - block_start >= block_size {
+                if data.len() > block_start {
+                    let last: &mut BlockHandle = index.last_mut().unwrap();
+                    last.len = (data.len() - block_start) as u64;
+                }
+                block_start = data.len();
+                index.push(BlockHandle {
+                    first_key: key.to_owned(),
+                    offset: block_start as u64,
+                    len: 0,
+                });
+            }
+            bloom.insert(key);
+            data.extend(storage::record(&try!(to_bytes(&(key, value)))));
+        }
+        if let Some(last) = index.last_mut() {
+            last.len = (data.len() - block_start) as u64;
+        }
+        let data_len = data.len() as u64;
+
+        let meta = TableMeta {
+            tier: tier,
+            index: index,
+            bloom: bloom,
+        };
+        data.extend(storage::record(&try!(to_bytes(&meta))));
+        for i in 0..8 {
+            data.push((data_len >> (56 - 8 * i)) as u8);
+        }
+        for i in 0..4 {
+            data.push((MAGIC >> (24 - 8 * i)) as u8);
+        }
+
+        let path = dir.join(SsTable::file_name(min_seq, max_seq));
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = try!(File::create(&tmp));
+            try!(file.write_all(&data));
+            try!(file.sync_all());
+        }
+        try!(fs::rename(&tmp, &path));
+        debug!("[LsmBackend] Wrote {} entries to {:?}", entries.len(), path);
+        SsTable::open(&path)
+    }
+
+    fn open(path: &Path) -> io::Result<SsTable> {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let (min_seq, max_seq) = match SsTable::parse_name(&name) {
+            Some(r) => r,
+            None => return Err(invalid_data(format!("not an SSTable: {:?}", path))),
+        };
+        let mut file = try!(File::open(path));
+        let mut buf = vec![];
+        try!(file.read_to_end(&mut buf));
+        let len = buf.len() as u64;
+        if len < FOOTER_SIZE {
+            return Err(invalid_data(format!("truncated SSTable {:?}", path)));
+        }
+        let footer = &buf[(len - FOOTER_SIZE) as usize..];
+        let data_len = footer[..8].iter().fold(0, |acc, &b| (acc << 8) | b as u64);
+        let magic = footer[8..].iter().fold(0, |acc, &b| (acc << 8) | b as u32);
+        if magic != MAGIC || data_len > len - FOOTER_SIZE {
+            return Err(invalid_data(format!("bad SSTable footer in {:?}", path)));
+        }
+        let meta: TableMeta = match storage::read_records(&buf[data_len as usize..(len - FOOTER_SIZE) as usize]).0.pop() {
+            Some((_, payload)) => try!(from_bytes(payload)),
+            None => return Err(invalid_data(format!("corrupt SSTable index in {:?}", path))),
+        };
+        Ok(SsTable {
+            path: path.to_owned(),
+            min_seq: min_seq,
+            max_seq: max_seq,
+            tier: meta.tier,
+            file: file,
+            data_len: data_len,
+            index: meta.index,
+            bloom: meta.bloom,
+        })
+    }
+
+    fn decode_entries(buf: &[u8]) -> io::Result<Vec<(Key, Value)>> {
+        let (records, end) = storage::read_records(buf);
+        if end != buf.len() as u64 {
+            return Err(invalid_data("corrupt SSTable block"));
+        }
+        records.into_iter().map(|(_, payload)| from_bytes(payload)).collect()
+    }
+
+    fn read(&mut self, offset: u64, len: u64) -> io::Result<Vec<(Key, Value)>> {
+        try!(self.file.seek(SeekFrom::Start(offset)));
+        let mut buf = vec![0; len as usize];
+        try!(self.file.read_exact(&mut buf));
+        SsTable::decode_entries(&buf)
+    }
+
+    fn get(&mut self, key: &Key) -> io::Result<Option<Value>> {
+        if !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        let block = match self.index.binary_search_by(|b| b.first_key.cmp(key)) {
+            Ok(i) => self.index[i].to_owned(),
+            Err(0) => return Ok(None),
+            Err(i) => self.index[i - 1].to_owned(),
+        };
+        let entries = try!(self.read(block.offset, block.len));
+        Ok(entries.into_iter().find(|&(ref k, _)| k == key).map(|(_, v)| v))
+    }
+
+    fn entries(&mut self) -> io::Result<Vec<(Key, Value)>> {
+        let len = self.data_len;
+        self.read(0, len)
+    }
+
+    /// Up to `limit` entries with a `Key` from `start` (inclusive) to `end`
+    /// (exclusive), the last ones if `reverse`, in `Key` order. Only the
+    /// blocks holding them are read.
+    fn range(&mut self, start: &Key, end: &Key, limit: usize, reverse: bool) -> io::Result<Vec<(Key, Value)>> {
+        let first = match self.index.binary_search_by(|b| b.first_key.cmp(start)) {
+            Ok(i) => i,
+            Err(0) => 0,
+            Err(i) => i - 1,
+        };
+        let mut blocks: Vec<BlockHandle> = self.index[first..]
+                                               .iter()
+                                               .take_while(|b| &b.first_key < end)
+                                               .cloned()
+                                               .collect();
+        if reverse {
+            blocks.reverse();
+        }
+        let mut entries = vec![];
+        for block in blocks {
+            let mut found: Vec<(Key, Value)> = try!(self.read(block.offset, block.len))
+                                                   .into_iter()
+                                                   .filter(|&(ref k, _)| k >= start && k < end)
+                                                   .collect();
+            if reverse {
+                found.reverse();
+            }
+            entries.extend(found);
+            if entries.len() >= limit {
+                break;
+            }
+        }
+        entries.truncate(limit);
+        if reverse {
+            entries.reverse();
+        }
+        Ok(entries)
+    }
+}
+
+#[derive(Debug)]
+struct LsmState {
+    dir: PathBuf,
+    config: LsmConfig,
+    memtable: BTreeMap<Key, Value>,
+    /// Approximate size in bytes of the memtable's entries.
+    memtable_size: usize,
+    /// Sequence numbers of the logs holding the memtable's entries, in case
+    /// they were replayed from more than one.
+    memtable_seqs: (u64, u64),
+    log: File,
+    /// Every SSTable, newest first.
+    tables: Vec<SsTable>,
+    /// Whether a background thread is compacting SSTables.
+    compacting: bool,
+}
+
+/// SSTables of a full tier to merge into one of the next tier.
+struct Compaction {
+    dir: PathBuf,
+    inputs: Vec<PathBuf>,
+    min_seq: u64,
+    max_seq: u64,
+    tier: u32,
+    /// Whether the inputs are the oldest SSTables.
+    oldest: bool,
+    block_size: usize,
+    tombstone_grace_period: u64,
+}
+
+impl Compaction {
+    fn new(state: &LsmState, start: usize, end: usize) -> Compaction {
+        Compaction {
+            dir: state.dir.to_owned(),
+            inputs: state.tables[start..end].iter().map(|t| t.path.to_owned()).collect(),
+            min_seq: state.tables[end - 1].min_seq,
+            max_seq: state.tables[start].max_seq,
+            tier: state.tables[start].tier + 1,
+            oldest: end == state.tables.len(),
+            block_size: state.config.block_size,
+            tombstone_grace_period: state.config.tombstone_grace_period,
+        }
+    }
+
+    /// Merge the inputs into a new SSTable, keeping only the latest entry
+    /// for each `Key`.
+    fn run(&self) -> io::Result<SsTable> {
+        let mut merged = BTreeMap::new();
+        for path in self.inputs.iter().rev() {
+            for (key, value) in try!(try!(SsTable::open(path)).entries()) {
+                merged.insert(key, value);
+            }
+        }
+        let now = hlc::wall_clock_us();
+        let grace = self.tombstone_grace_period;
+        let oldest = self.oldest;
+        let entries: Vec<(Key, Value)> = merged.into_iter()
+            .filter(|&(_, ref value)| {
+                match *value {
+                    Value::Tombstone {timestamp} => !oldest || hlc::to_micros(timestamp).saturating_add(grace) > now,
+                    _ => true,
+                }
+            })
+            .collect();
+        debug!("[LsmBackend] Compacting {} SSTables into tier {}", self.inputs.len(), self.tier);
+        SsTable::write(&self.dir, self.min_seq, self.max_seq, self.tier, &entries, self.block_size)
+    }
+}
+
+/// A log-structured merge tree backend.
+///
+/// Inserts go to an in-memory memtable, and to a log to recover it from
+/// after a restart. Once the memtable grows over `memtable_size` it is
+/// written to disk as an immutable SSTable, and a new log is started. Reads
+/// check the memtable and then every SSTable from newest to oldest, using
+/// their bloom filters to skip the ones that can't have the `Key`.
+///
+/// SSTables are compacted size-tiered: once there are `tier_fanout` SSTables
+/// of the same tier they are merged into a single one of the next tier,
+/// keeping only the latest entry for each `Key`. Compaction runs on a
+/// background thread, which only holds the lock to pick the SSTables to
+/// merge and to swap the result in. Tombstones older than
+/// `tombstone_grace_period` are dropped when merging into the oldest
+/// SSTable, as there is nothing older left for them to shadow.
+///
+/// As with `LogBackend`, writes return I/O errors and reads panic on them.
+#[derive(Debug)]
+pub struct LsmBackend {
+    inner: Arc<LsmInner>,
+}
+
+#[derive(Debug)]
+struct LsmInner {
+    state: Mutex<LsmState>,
+    /// Notified when a background compaction finishes.
+    compacted: Condvar,
+}
+
+impl LsmBackend {
+    /// Open the tree in `dir` with the given `config`.
+    pub fn open_with_config(dir: &Path, config: LsmConfig) -> io::Result<LsmBackend> {
+        try!(fs::create_dir_all(dir));
+        let mut tables = vec![];
+        let mut logs = vec![];
+        for entry in try!(fs::read_dir(dir)) {
+            let path = try!(entry).path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            if name.ends_with(".tmp") {
+                // An SSTable that was being written when the process stopped.
+                try!(fs::remove_file(&path));
+            } else if SsTable::parse_name(&name).is_some() {
+                tables.push(try!(SsTable::open(&path)));
+            } else if name.starts_with("memtable-") && name.ends_with(".log") {
+                if let Ok(seq) = name[9..name.len() - 4].parse::<u64>() {
+                    logs.push(seq);
+                }
+            }
+        }
+
+        // A compaction that didn't finish removing its input leaves SSTables
+        // behind whose data is already in a larger one.
+        let ranges: Vec<(u64, u64)> = tables.iter().map(|t| (t.min_seq, t.max_seq)).collect();
+        let (obsolete, mut tables): (Vec<SsTable>, Vec<SsTable>) = tables.into_iter().partition(|t| {
+            ranges.iter().any(|&(min, max)| {
+                min <= t.min_seq && t.max_seq <= max && (min, max) != (t.min_seq, t.max_seq)
+            })
+        });
+        for table in obsolete {
+            try!(fs::remove_file(&table.path));
+        }
+        tables.sort_by(|a, b| b.max_seq.cmp(&a.max_seq));
+        let flushed = tables.first().map(|t| t.max_seq).unwrap_or(0);
+
+        logs.sort();
+        let mut memtable = BTreeMap::new();
+        let mut memtable_size = 0;
+        let mut replayed = vec![];
+        for seq in logs {
+            let path = dir.join(format!("memtable-{:08}.log", seq));
+            if seq <= flushed && !tables.is_empty() {
+                // Already in an SSTable.
+                try!(fs::remove_file(&path));
+                continue;
+            }
+            let mut file = try!(OpenOptions::new().read(true).write(true).open(&path));
+            let mut buf = vec![];
+            try!(file.read_to_end(&mut buf));
+            let (records, end) = storage::read_records(&buf);
+            for (_, payload) in records {
+                let (key, value): (Key, Value) = try!(from_bytes(payload));
+                memtable_size += payload.len();
+                memtable.insert(key, value);
+            }
+            if end < buf.len() as u64 {
+                error!("[LsmBackend] Discarding {} bytes of torn record at the end of {:?}",
+                       buf.len() as u64 - end,
+                       path);
+                try!(file.set_len(end));
+            }
+            replayed.push(seq);
+        }
+
+        let (seqs, log) = match (replayed.first(), replayed.last()) {
+            (Some(&first), Some(&last)) => {
+                let log = try!(OpenOptions::new().append(true).open(dir.join(format!("memtable-{:08}.log", last))));
+                ((first, last), log)
+            }
+            _ => {
+                let seq = if tables.is_empty() { 0 } else { flushed + 1 };
+                let log = try!(OpenOptions::new()
+                                   .append(true)
+                                   .create(true)
+                                   .open(dir.join(format!("memtable-{:08}.log", seq))));
+                ((seq, seq), log)
+            }
+        };
+
+        let mut state = LsmState {
+            dir: dir.to_owned(),
+            config: config,
+            memtable: memtable,
+            memtable_size: memtable_size,
+            memtable_seqs: seqs,
+            log: log,
+            tables: tables,
+            compacting: false,
+        };
+        if state.memtable_seqs.0 != state.memtable_seqs.1 {
+            try!(state.flush());
+        }
+        debug!("[LsmBackend] Opened {:?} with {} SSTables", dir, state.tables.len());
+        Ok(LsmBackend {
+            inner: Arc::new(LsmInner {
+                state: Mutex::new(state),
+                compacted: Condvar::new(),
+            }),
+        })
+    }
+
+    /// Block until no background compaction is running.
+    pub fn wait_for_compaction(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+        while state.compacting {
+            state = self.inner.compacted.wait(state).unwrap();
+        }
+    }
+
+    /// Start compacting on a background thread if a tier is full and no
+    /// compaction is running yet.
+    fn maybe_compact(&self, state: &mut LsmState) {
+        if state.compacting || state.full_tier().is_none() {
+            return;
+        }
+        state.compacting = true;
+        let inner = Arc::downgrade(&self.inner);
+        thread::spawn(move || LsmBackend::compact(inner));
+    }
+
+    /// Merge full tiers for as long as there are any.
+    fn compact(inner: Weak<LsmInner>) {
+        loop {
+            let inner = match inner.upgrade() {
+                Some(inner) => inner,
+                None => return,
+            };
+            let compaction = {
+                let mut state = inner.state.lock().unwrap();
+                match state.full_tier() {
+                    Some((start, end)) => Compaction::new(&state, start, end),
+                    None => {
+                        state.compacting = false;
+                        inner.compacted.notify_all();
+                        return;
+                    }
+                }
+            };
+            let result = compaction.run();
+            let mut state = inner.state.lock().unwrap();
+            if let Err(e) = result.and_then(|table| state.finish(compaction, table)) {
+                error!("[LsmBackend] Couldn't compact {:?}: {:?}", state.dir, e);
+                state.compacting = false;
+                inner.compacted.notify_all();
+                return;
+            }
+        }
+    }
+}
+
+impl LsmState {
+    fn log_path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("memtable-{:08}.log", seq))
+    }
+
+    fn insert(&mut self, key: Key, value: Value) -> io::Result<()> {
+        let payload = try!(to_bytes(&(&key, &value)));
+        let len = try!(self.log.metadata()).len();
+        if let Err(e) = self.log.write_all(&storage::record(&payload)).and_then(|_| self.log.flush()) {
+            // Cut off what was written, so that the next record follows the
+            // last intact one.
+            let _ = self.log.set_len(len);
+            return Err(e);
+        }
+        self.memtable_size += payload.len();
+        self.memtable.insert(key, value);
+        if self.memtable_size >= self.config.memtable_size {
+            // The write is logged already; flushing is retried on the next.
+            if let Err(e) = self.flush() {
+                error!("[LsmBackend] Couldn't flush the memtable to {:?}: {:?}", self.dir, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the memtable to a new SSTable, and start a new log.
+    fn flush(&mut self) -> io::Result<()> {
+        let (min_seq, max_seq) = self.memtable_seqs;
+        let entries: Vec<(Key, Value)> = self.memtable
+                                             .iter()
+                                             .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                                             .collect();
+        let table = try!(SsTable::write(&self.dir, min_seq, max_seq, 0, &entries, self.config.block_size));
+        self.tables.insert(0, table);
+
+        let seq = max_seq + 1;
+        self.log = try!(OpenOptions::new().append(true).create(true).open(self.log_path(seq)));
+        for old in min_seq..max_seq + 1 {
+            let _ = fs::remove_file(self.log_path(old));
+        }
+        self.memtable = BTreeMap::new();
+        self.memtable_size = 0;
+        self.memtable_seqs = (seq, seq);
+        Ok(())
+    }
+
+    /// Position of the oldest `tier_fanout` SSTables of the newest full
+    /// tier, if any. Flushes may go on while they are merged, so a tier can
+    /// hold more SSTables than that.
+    fn full_tier(&self) -> Option<(usize, usize)> {
+        // Tiers only grow from the newest to the oldest SSTable, so the
+        // SSTables of a tier are next to each other.
+        let mut start = 0;
+        for i in 0..self.tables.len() + 1 {
+            if i == self.tables.len() || self.tables[i].tier != self.tables[start].tier {
+                if i - start >= self.config.tier_fanout {
+                    return Some((i - self.config.tier_fanout, i));
+                }
+                start = i;
+            }
+        }
+        None
+    }
+
+    /// Replace the SSTables `compaction` merged with its output, and remove
+    /// their files. SSTables flushed in the meantime went in front of them.
+    fn finish(&mut self, compaction: Compaction, table: SsTable) -> io::Result<()> {
+        let start = self.tables.iter().position(|t| t.max_seq == compaction.max_seq).unwrap();
+        let inputs: Vec<SsTable> = self.tables.drain(start..start + compaction.inputs.len()).collect();
+        self.tables.insert(start, table);
+        for input in inputs {
+            try!(fs::remove_file(&input.path));
+        }
+        Ok(())
+    }
+
+    fn get(&mut self, key: &Key) -> io::Result<Option<Value>> {
+        if let Some(value) = self.memtable.get(key) {
+            return Ok(Some(value.to_owned()));
+        }
+        for table in self.tables.iter_mut() {
+            if let Some(value) = try!(table.get(key)) {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    fn entries(&mut self) -> io::Result<Vec<(Key, Value)>> {
+        let mut merged = BTreeMap::new();
+        for table in self.tables.iter_mut().rev() {
+            for (key, value) in try!(table.entries()) {
+                merged.insert(key, value);
+            }
+        }
+        for (key, value) in self.memtable.iter() {
+            merged.insert(key.to_owned(), value.to_owned());
+        }
+        Ok(merged.into_iter().collect())
+    }
+
+    /// Up to `limit` entries from `start` to `end`, the last ones if
+    /// `reverse`. The first (or last) `limit` entries of the merged tree are
+    /// among the first (or last) `limit` of each SSTable and the memtable,
+    /// so no more than that is read from each.
+    fn scan(&mut self, start: &Key, end: &Key, limit: usize, reverse: bool) -> io::Result<Vec<(Key, Value)>> {
+        if start >= end {
+            return Ok(vec![]);
+        }
+        let mut merged = BTreeMap::new();
+        for table in self.tables.iter_mut().rev() {
+            for (key, value) in try!(table.range(start, end, limit, reverse)) {
+                merged.insert(key, value);
+            }
+        }
+        let range = self.memtable.range(start.to_owned()..end.to_owned());
+        let newest: Vec<(&Key, &Value)> = if reverse {
+            range.rev().take(limit).collect()
+        } else {
+            range.take(limit).collect()
+        };
+        for (key, value) in newest {
+            merged.insert(key.to_owned(), value.to_owned());
+        }
+        if reverse {
+            Ok(merged.into_iter().rev().take(limit).collect())
+        } else {
+            Ok(merged.into_iter().take(limit).collect())
+        }
+    }
+}
+
+impl StorageBackend for LsmBackend {
+    /// A tree in a new temporary directory.
+    fn new() -> LsmBackend {
+        let dir = storage::temp_dir();
+        match LsmBackend::open(&dir) {
+            Ok(l) => l,
+            Err(e) => panic!("Couldn't create LSM tree at {:?}: {:?}", dir, e),
+        }
+    }
+
+    fn open(dir: &Path) -> io::Result<LsmBackend> {
+        LsmBackend::open_with_config(dir, LsmConfig::default())
+    }
+
+    fn insert(&self, key: Key, value: Value) -> io::Result<()> {
+        debug!("[LsmBackend] Going to insert {:?}, {:?}", key, value);
+        let mut state = self.inner.state.lock().unwrap();
+        try!(state.insert(key, value));
+        self.maybe_compact(&mut state);
+        Ok(())
+    }
+
+    fn compare_and_set(&self, key: Key, value: Value, expected: &Fn(Option<&Value>) -> bool) -> io::Result<bool> {
+        let mut state = self.inner.state.lock().unwrap();
+        let current = try!(state.get(&key));
+        if !expected(current.as_ref()) {
+            return Ok(false);
+        }
+        try!(state.insert(key, value));
+        self.maybe_compact(&mut state);
+        Ok(true)
+    }
+
+    fn get(&self, key: &Key) -> Option<Value> {
+        debug!("[LsmBackend] Going to read {:?}", key);
+        let result = self.inner.state.lock().unwrap().get(key);
+        match result {
+            Ok(value) => value,
+            Err(e) => panic!("[LsmBackend] Couldn't read {:?}: {:?}", key, e),
+        }
+    }
+
+    fn entries(&self) -> Vec<(Key, Value)> {
+        let result = self.inner.state.lock().unwrap().entries();
+        match result {
+            Ok(entries) => entries,
+            Err(e) => panic!("[LsmBackend] Couldn't read entries: {:?}", e),
+        }
+    }
+
+    fn scan(&self, start: &Key, end: &Key, limit: usize, reverse: bool) -> Vec<(Key, Value)> {
+        let result = self.inner.state.lock().unwrap().scan(start, end, limit, reverse);
+        match result {
+            Ok(entries) => entries,
+            Err(e) => panic!("[LsmBackend] Couldn't scan from {:?}: {:?}", start, e),
+        }
+    }
+
+    fn is_persistent(&self) -> bool {
+        true
+    }
+
+    /// SSTables are synced as they are written, so only the memtable's log
+    /// needs to be.
+    fn sync(&self) -> io::Result<()> {
+        self.inner.state.lock().unwrap().log.sync_data()
+    }
+}
@@ -1,5 +1,9 @@
+use hlc;
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
 use std::result;
 use std::hash::{Hash, SipHasher, Hasher};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use vclock::VectorClock;
 
 
 pub type Buffer = Vec<u8>;
@@ -10,6 +14,28 @@ pub type Buffer = Vec<u8>;
 pub struct Request {
     pub action: Action,
     pub consistency: Consistency,
+    /// `hlc` timestamp to write with instead of one from the handler's clock.
+    /// Set by clients retrying a write, so that every attempt stores the very
+    /// same `Value`.
+    pub timestamp: Option<u64>,
+    /// Unix time, in microseconds, after which the client no longer waits
+    /// for the `Response`, so the request needn't be performed anymore.
+    pub deadline: Option<u64>,
+    /// Causal context of a write to a versioned dataset: the one a read of
+    /// the `Key` came back with. The write supersedes the siblings it covers.
+    pub context: Option<VectorClock>,
+}
+
+impl Request {
+    pub fn new(action: Action, consistency: Consistency) -> Request {
+        Request {
+            action: action,
+            consistency: consistency,
+            timestamp: None,
+            deadline: None,
+            context: None,
+        }
+    }
 }
 
 /// `Request`'s possible actions to be performed by a `handler`.
@@ -20,14 +46,85 @@ pub enum Action {
         key: Key,
     },
     /// Write the given `Value` for `Key` and receive a `Response::WriteAck`.
+    /// It expires `ttl` milliseconds after it's written, if there's one.
     Write {
         key: Key,
         content: Buffer,
+        ttl: Option<u64>,
     },
     /// Delete the given `Key` and receive a `Response::WriteAck`.
     Delete {
         key: Key,
     },
+    /// Write `content` for `Key` only if the `Value` stored has the
+    /// `expected` timestamp, or if there's none (or it's deleted) when no
+    /// timestamp is expected. Receive a `Response::WriteAck`, or a
+    /// `Response::Conflict` if it doesn't match. Linearizable among the
+    /// compare-and-sets of the `Key`, as they agree on their order through
+    /// Paxos on a majority of its replicas, whatever the `Consistency`.
+    CompareAndSet {
+        key: Key,
+        expected: Option<u64>,
+        content: Buffer,
+    },
+    /// Have the `Value` stored for `Key` expire `ttl` milliseconds from now,
+    /// or never if there's no `ttl`, without the client sending it again.
+    /// Receive a `Response::WriteAck` with the timestamp it was written
+    /// with, which it keeps: only the write that was read has its expiry
+    /// changed, so a write or a deletion racing it wins. The expiry can
+    /// only be pushed back.
+    Touch {
+        key: Key,
+        ttl: Option<u64>,
+    },
+    /// Read the rows of the `pkey` partition of `dataset` whose `lkey` is
+    /// within `start_lkey` (inclusive) and `end_lkey` (exclusive, or up to
+    /// the end of the partition), ordered by `lkey` in descending order if
+    /// `reverse`. Receive a `Response::Rows` with at most `limit` rows.
+    Scan {
+        dataset: Buffer,
+        pkey: Buffer,
+        start_lkey: Buffer,
+        end_lkey: Option<Buffer>,
+        limit: usize,
+        reverse: bool,
+    },
+    /// List the `Key`s stored in `dataset`, in order, starting right after
+    /// `cursor`. Receive a `Response::Keys` with at most `page_size` of them.
+    /// Deleted `Key`s are only listed if `include_tombstones`.
+    ListKeys {
+        dataset: Buffer,
+        cursor: Option<Key>,
+        page_size: usize,
+        include_tombstones: bool,
+    },
+    /// Start moving to the `ring` topology: write to the replicas of both the
+    /// current and the new ring, and fall back to the current ring's replicas
+    /// on reads. Receive a `Response::TopologyAck`.
+    PrepareTopology {
+        ring: Ring,
+    },
+    /// Route every request with `ring` from now on. Receive a
+    /// `Response::TopologyAck`.
+    CommitTopology {
+        ring: Ring,
+    },
+    /// Receive the topology requests are routed with, as a
+    /// `Response::Topology`.
+    GetTopology,
+    /// Receive the handler's `Metrics`, as a `Response::Metrics`.
+    GetMetrics,
+}
+
+impl Action {
+    /// Whether performing the action twice has the same effect as performing
+    /// it once. Writes and deletes aren't, as each gets its own timestamp.
+    pub fn is_idempotent(&self) -> bool {
+        match *self {
+            Action::Write {..} | Action::Delete {..} | Action::CompareAndSet {..} | Action::Touch {..} => false,
+            _ => true,
+        }
+    }
 }
 
 /// A `Request`'s `Response` message envelope.
@@ -41,10 +138,14 @@ pub struct ResponseMessage {
 #[derive(Debug, Hash, Clone, PartialEq, RustcEncodable, RustcDecodable)]
 pub enum Response {
     /// An stored value, stored in the shard's `StorageNode`s, according to the
-    /// required `Consistency`.
+    /// required `Consistency`. Versioned values come along with the causal
+    /// `context` to write with to resolve their siblings, and expiring ones
+    /// with the milliseconds they have left to live.
     Value {
         key: Key,
         value: Value,
+        context: Option<VectorClock>,
+        ttl: Option<u64>,
     },
     /// The `Request`ed write for a `Value` has been stored in the `Key`'s
     /// shard's `StorageNode`s, according to the required `Consistency`.
@@ -52,15 +153,56 @@ pub enum Response {
         key: Key,
         timestamp: u64,
     },
+    /// The `Value` stored for `Key` didn't match what an
+    /// `Action::CompareAndSet` expected, so nothing was written.
+    Conflict {
+        key: Key,
+        value: Value,
+    },
     /// There was an error performing the operation on `Key`.
     Error {
         key: Key,
         message: String,
     },
+    /// Rows found by an `Action::Scan`, according to the required
+    /// `Consistency`. Unless the scan is exhausted, `last_lkey` is the `lkey`
+    /// to resume it from: the next scan starts right after it, or ends right
+    /// before it if `reverse`.
+    Rows {
+        rows: Vec<(Key, Value)>,
+        last_lkey: Option<Buffer>,
+    },
+    /// `Key`s listed by an `Action::ListKeys`, according to the required
+    /// `Consistency`. Unless every `Key` has been listed, `cursor` is the one
+    /// to continue listing after.
+    Keys {
+        keys: Vec<Key>,
+        cursor: Option<Key>,
+    },
+    /// The ring requests are routed with, along with the ring being moved
+    /// to while resharding.
+    Topology {
+        ring: Ring,
+        pending: Option<Ring>,
+    },
+    /// Answer to a `ClaimNodeId`.
+    NodeIdClaimed {
+        granted: bool,
+    },
+    /// A step of a topology change has been performed.
+    TopologyAck,
+    /// A step of a topology change couldn't be performed.
+    TopologyError {
+        message: String,
+    },
+    /// The handler's `Metrics` counters, by name.
+    Metrics {
+        counters: Vec<(String, u64)>,
+    },
 }
 
 /// The `Key` used to lookup a given `Value`.
-#[derive(Debug, Hash, Clone, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+#[derive(Debug, Hash, Clone, PartialEq, Eq, PartialOrd, Ord, RustcEncodable, RustcDecodable)]
 pub struct Key {
     pub dataset: Buffer,
     pub pkey: Buffer,
@@ -75,10 +217,180 @@ impl Key {
         s.finish()
     }
 
-    /// Return the corresponding shard for this key, given a `shard_count`
-    /// amount of shards.
-    pub fn shard(&self, shard_count: usize) -> usize {
-        (self.hash() % (shard_count as u64)) as usize
+    /// Bounds of the `Key`s of the `pkey` partition of `dataset` with an
+    /// `lkey` from `start_lkey` (inclusive) to `end_lkey` (exclusive), or to
+    /// the end of the partition if there's no `end_lkey`.
+    pub fn lkey_range(dataset: &Buffer,
+                      pkey: &Buffer,
+                      start_lkey: &Buffer,
+                      end_lkey: &Option<Buffer>)
+                      -> (Key, Key) {
+        let start = Key {
+            dataset: dataset.to_owned(),
+            pkey: pkey.to_owned(),
+            lkey: start_lkey.to_owned(),
+        };
+        let end = match *end_lkey {
+            Some(ref lkey) => {
+                Key {
+                    dataset: dataset.to_owned(),
+                    pkey: pkey.to_owned(),
+                    lkey: lkey.to_owned(),
+                }
+            }
+            None => {
+                // The first `Key` of the partition right after this one.
+                let mut next_pkey = pkey.to_owned();
+                next_pkey.push(0);
+                Key {
+                    dataset: dataset.to_owned(),
+                    pkey: next_pkey,
+                    lkey: vec![],
+                }
+            }
+        };
+        (start, end)
+    }
+
+    /// The `Key` right after this one, with nothing in between.
+    pub fn successor(&self) -> Key {
+        let mut lkey = self.lkey.to_owned();
+        lkey.push(0);
+        Key {
+            dataset: self.dataset.to_owned(),
+            pkey: self.pkey.to_owned(),
+            lkey: lkey,
+        }
+    }
+}
+
+/// A consistent hashing token ring, placing `Key`s on shards.
+///
+/// Every storage node owns `vnodes` tokens spread over the ring, each of them
+/// pointing back to the node's shard. A `Key` belongs to the shard owning the
+/// first token at or after the `Key`'s hash, so adding or removing a shard
+/// only moves the keys next to its tokens.
+#[derive(Debug, Clone, PartialEq, Hash)]
+pub struct Ring {
+    vnodes: usize,
+    shards: Vec<Vec<SocketAddrV4>>,
+    /// `(token, shard, node)` triples, sorted by token. `node` is the
+    /// position of the token's owner in its shard.
+    tokens: Vec<(u64, usize, usize)>,
+}
+
+impl Ring {
+    /// Place every storage node of `shards` on a new ring, with `vnodes`
+    /// tokens each.
+    pub fn new(shards: Vec<Vec<SocketAddrV4>>, vnodes: usize) -> Ring {
+        let mut tokens = vec![];
+        for (shard, nodes) in shards.iter().enumerate() {
+            for (node, address) in nodes.iter().enumerate() {
+                for vnode in 0..vnodes {
+                    tokens.push((Ring::token(address, vnode), shard, node));
+                }
+            }
+        }
+        tokens.sort();
+        Ring {
+            vnodes: vnodes,
+            shards: shards,
+            tokens: tokens,
+        }
+    }
+
+    /// Token for the `vnode`th virtual node of the storage node at `address`.
+    fn token(address: &SocketAddrV4, vnode: usize) -> u64 {
+        let mut s = SipHasher::new();
+        address.hash(&mut s);
+        vnode.hash(&mut s);
+        s.finish()
+    }
+
+    /// Amount of virtual nodes each storage node has on this ring.
+    pub fn vnodes(&self) -> usize {
+        self.vnodes
+    }
+
+    /// Storage node addresses, grouped by shard.
+    pub fn shards(&self) -> &Vec<Vec<SocketAddrV4>> {
+        &self.shards
+    }
+
+    /// Every storage node on this ring.
+    pub fn nodes(&self) -> Vec<SocketAddrV4> {
+        self.shards.iter().flat_map(|s| s.iter().cloned()).collect()
+    }
+
+    /// Position of the first token at or after `key`'s hash, wrapping
+    /// around the ring.
+    fn token_position(&self, key: &Key) -> Option<usize> {
+        if self.tokens.is_empty() {
+            return None;
+        }
+        let hash = key.hash();
+        let pos = match self.tokens.binary_search_by(|&(t, _, _)| t.cmp(&hash)) {
+            Ok(pos) => pos,
+            Err(pos) => pos,
+        };
+        Some(pos % self.tokens.len())
+    }
+
+    /// Return the shard `key` belongs to, if the ring has any storage node.
+    pub fn shard(&self, key: &Key) -> Option<usize> {
+        self.token_position(key).map(|pos| self.tokens[pos].1)
+    }
+
+    /// Return the replicas responsible for `key`, in order of preference:
+    /// the storage node owning `key`'s token first, followed by the rest of
+    /// its shard.
+    pub fn preference_list(&self, key: &Key) -> Vec<SocketAddrV4> {
+        match self.token_position(key) {
+            Some(pos) => {
+                let (_, shard, node) = self.tokens[pos];
+                let nodes = &self.shards[shard];
+                nodes[node..].iter().chain(nodes[..node].iter()).cloned().collect()
+            }
+            None => vec![],
+        }
+    }
+
+    /// Whether the storage node at `address` is a replica for `key`.
+    pub fn owns(&self, address: &SocketAddrV4, key: &Key) -> bool {
+        match self.shard(key) {
+            Some(shard) => self.shards[shard].contains(address),
+            None => false,
+        }
+    }
+}
+
+/// `Ring`s travel as their storage node addresses and amount of virtual nodes,
+/// the tokens are recomputed on arrival.
+impl Encodable for Ring {
+    fn encode<S: Encoder>(&self, s: &mut S) -> result::Result<(), S::Error> {
+        let shards: Vec<Vec<(u32, u16)>> = self.shards
+                                               .iter()
+                                               .map(|nodes| {
+                                                   nodes.iter()
+                                                        .map(|a| (u32::from(*a.ip()), a.port()))
+                                                        .collect()
+                                               })
+                                               .collect();
+        (self.vnodes, shards).encode(s)
+    }
+}
+
+impl Decodable for Ring {
+    fn decode<D: Decoder>(d: &mut D) -> result::Result<Ring, D::Error> {
+        let (vnodes, shards): (usize, Vec<Vec<(u32, u16)>>) = try!(Decodable::decode(d));
+        let shards = shards.into_iter()
+                           .map(|nodes| {
+                               nodes.into_iter()
+                                    .map(|(ip, port)| SocketAddrV4::new(Ipv4Addr::from(ip), port))
+                                    .collect()
+                           })
+                           .collect();
+        Ok(Ring::new(shards, vnodes))
     }
 }
 
@@ -87,15 +399,166 @@ impl Key {
 pub enum Value {
     /// There's no value stored for the given `Key` in the `StorageNode`s.
     None,
-    /// The value stored for the given `Key` in the `StorageNode`s.
+    /// The value stored for the given `Key` in the `StorageNode`s. It's
+    /// deleted once the Unix time, in milliseconds, `expires` if it's set.
     Value {
         content: Buffer,
         timestamp: u64,
+        expires: Option<u64>,
     },
     /// A deleted value for the given `Key` in the `StorageNode`s.
     Tombstone {
         timestamp: u64,
     },
+    /// The concurrent versions stored for a `Key` of a versioned dataset,
+    /// oldest first.
+    Versioned {
+        siblings: Vec<Sibling>,
+    },
+}
+
+impl Value {
+    /// If the `Value` has a timestamp, return it. That of a `Versioned` one
+    /// is its newest sibling's.
+    pub fn get_timestamp(&self) -> Option<u64> {
+        match *self {
+            Value::None => None,
+            Value::Value {timestamp, ..} => Some(timestamp),
+            Value::Tombstone {timestamp} => Some(timestamp),
+            Value::Versioned {ref siblings} => siblings.iter().map(|s| s.timestamp).max(),
+        }
+    }
+
+    /// Whether the `Value` was deleted, by every sibling if it's `Versioned`.
+    pub fn is_deleted(&self) -> bool {
+        match *self {
+            Value::Tombstone {..} => true,
+            Value::Versioned {ref siblings} => siblings.iter().all(|s| s.content.is_none()),
+            _ => false,
+        }
+    }
+
+    /// Content of the `Value`, or of every sibling that isn't a deletion if
+    /// it's `Versioned`, oldest first.
+    pub fn into_contents(self) -> Vec<Buffer> {
+        match self {
+            Value::Value {content, ..} => vec![content],
+            Value::Versioned {siblings} => siblings.into_iter().filter_map(|s| s.content).collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Milliseconds the `Value` has left to live at `now_ms`, Unix time,
+    /// unless it doesn't expire.
+    pub fn ttl(&self, now_ms: u64) -> Option<u64> {
+        match *self {
+            Value::Value {expires: Some(expires), ..} => Some(expires.saturating_sub(now_ms)),
+            _ => None,
+        }
+    }
+
+    /// Whether the `Value` expired by `now_ms`, Unix time in milliseconds.
+    pub fn is_expired(&self, now_ms: u64) -> bool {
+        match *self {
+            Value::Value {expires: Some(expires), ..} => expires <= now_ms,
+            _ => false,
+        }
+    }
+
+    /// What reading the `Value` at `now_ms` gives: once it expired, a
+    /// deletion at its timestamp.
+    pub fn unexpired(self, now_ms: u64) -> Value {
+        match self {
+            Value::Value {timestamp, expires: Some(expires), ..} if expires <= now_ms => {
+                Value::Tombstone { timestamp: timestamp }
+            }
+            value => value,
+        }
+    }
+
+    /// Causal context of a `Versioned` value: every write it has seen.
+    pub fn context(&self) -> Option<VectorClock> {
+        match *self {
+            Value::Versioned {ref siblings} => {
+                let mut context = VectorClock::new();
+                for sibling in siblings {
+                    context.merge(&sibling.context);
+                    context.witness(sibling.node, sibling.timestamp);
+                }
+                Some(context)
+            }
+            _ => None,
+        }
+    }
+
+    /// What storing `incoming` over `current` results in, or `None` if it
+    /// changes nothing. `Versioned` values keep every sibling of both that
+    /// no other sibling supersedes. Otherwise the newest one wins, so that a
+    /// dataset can change modes. A write keeps its timestamp as it's touched
+    /// or swept once it expired: its deletion wins over it then, and a later
+    /// expiry over an earlier one, so that replicas agree either way.
+    pub fn merge(current: Option<&Value>, incoming: &Value) -> Option<Value> {
+        match (current, incoming) {
+            (Some(&Value::Versioned {siblings: ref ours}), &Value::Versioned {siblings: ref theirs}) => {
+                let mut siblings = ours.to_owned();
+                for sibling in theirs {
+                    if !siblings.iter().any(|s| s.is(sibling)) {
+                        siblings.push(sibling.to_owned());
+                    }
+                }
+                let all = siblings.clone();
+                siblings.retain(|s| !all.iter().any(|other| other.supersedes(s)));
+                siblings.sort_by(|a, b| (a.timestamp, a.node).cmp(&(b.timestamp, b.node)));
+                if siblings == *ours {
+                    None
+                } else {
+                    Some(Value::Versioned { siblings: siblings })
+                }
+            }
+            _ => {
+                if incoming.precedence() > current.map_or((None, 0, 0), |v| v.precedence()) {
+                    Some(incoming.to_owned())
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Order in which `merge` keeps unversioned `Value`s: by timestamp, then
+    /// deletions, then by expiry, those that never expire last.
+    fn precedence(&self) -> (Option<u64>, u8, u64) {
+        match *self {
+            Value::Value {timestamp, expires, ..} => (Some(timestamp), 0, expires.unwrap_or(u64::max_value())),
+            Value::Tombstone {timestamp} => (Some(timestamp), 1, 0),
+            _ => (self.get_timestamp(), 0, 0),
+        }
+    }
+}
+
+/// One of the concurrent versions of a `Value::Versioned`.
+#[derive(Debug, Hash, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct Sibling {
+    /// ID of the coordinator that wrote it.
+    pub node: u64,
+    /// `hlc` timestamp it was written at.
+    pub timestamp: u64,
+    /// What its writer had read: it supersedes the siblings this covers.
+    pub context: VectorClock,
+    /// `None` for a deletion.
+    pub content: Option<Buffer>,
+}
+
+impl Sibling {
+    /// Whether both are the very same write.
+    fn is(&self, other: &Sibling) -> bool {
+        self.node == other.node && self.timestamp == other.timestamp
+    }
+
+    /// Whether `other` had been read before writing this one.
+    pub fn supersedes(&self, other: &Sibling) -> bool {
+        !self.is(other) && self.context.contains(other.node, other.timestamp)
+    }
 }
 
 /// Request operations performed by a `handler` to the `StorageNode`s.
@@ -108,6 +571,137 @@ pub enum InternodeRequest {
         key: Key,
         value: Value,
     },
+    /// Have the `Value::Value` written for `key` with `timestamp` expire at
+    /// `expires`, Unix time in milliseconds, or never, keeping its timestamp
+    /// and content. Answered with a `WriteAck` like a `Write` of it, unless
+    /// the node doesn't have that write nor a newer one.
+    Touch {
+        key: Key,
+        timestamp: u64,
+        expires: Option<u64>,
+    },
+    /// Bulk write of `entries` streamed from another `StorageNode` while
+    /// resharding. Entries older than what's already stored are ignored.
+    Transfer {
+        entries: Vec<(Key, Value)>,
+    },
+    /// Also accept `Key`s belonging to `ring`, which is about to become the
+    /// cluster topology.
+    PrepareTopology {
+        ring: Ring,
+    },
+    /// Stream every stored entry to the replicas `ring` adds for its `Key`.
+    Rebalance {
+        ring: Ring,
+    },
+    /// Adopt `ring` as the cluster topology.
+    CommitTopology {
+        ring: Ring,
+    },
+    /// Read the stored entries, tombstones included, of an `Action::Scan`.
+    Scan {
+        dataset: Buffer,
+        pkey: Buffer,
+        start_lkey: Buffer,
+        end_lkey: Option<Buffer>,
+        limit: usize,
+        reverse: bool,
+    },
+    /// Read up to `limit` of the stored entries of `dataset`, tombstones
+    /// included, starting right after `cursor`. The content of the entries
+    /// isn't sent back.
+    ListKeys {
+        dataset: Buffer,
+        cursor: Option<Key>,
+        limit: usize,
+    },
+    /// Hashes of the `MerkleTree` of `depth` over the entries this node is a
+    /// replica for, tombstones included.
+    MerkleTree {
+        depth: u32,
+    },
+    /// Read the stored entries, tombstones included, in `leaves` of the
+    /// `MerkleTree` of `depth`.
+    MerkleLeaves {
+        depth: u32,
+        leaves: Vec<usize>,
+    },
+    /// Promise not to take part in the Paxos rounds for `key` with a lower
+    /// ballot than `ballot`.
+    Prepare {
+        key: Key,
+        ballot: u64,
+    },
+    /// Accept `value` as the outcome of the Paxos round for `key` with
+    /// `ballot`, unless a higher ballot was promised.
+    Propose {
+        key: Key,
+        ballot: u64,
+        value: Value,
+    },
+    /// Store `value`, the outcome of the Paxos round for `key` with `ballot`.
+    Commit {
+        key: Key,
+        ballot: u64,
+        value: Value,
+    },
+    /// Read `key` through the shard's Raft group, as its leader, which is
+    /// found by this node unless the request was `forwarded` already.
+    RaftRead {
+        key: Key,
+        forwarded: bool,
+    },
+    /// Write `content` for `key` through the shard's Raft group, expiring
+    /// after `ttl` milliseconds if there's one, or delete it if there's no
+    /// `content`, like `RaftRead`.
+    RaftWrite {
+        key: Key,
+        content: Option<Buffer>,
+        ttl: Option<u64>,
+        forwarded: bool,
+    },
+    /// Vote for the `candidate`th node of the shard as the leader of `term`,
+    /// if its log is at least as up to date.
+    RequestVote {
+        term: u64,
+        candidate: usize,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    /// Append `entries` right after the one at `prev_log_index`, if it's from
+    /// `prev_log_term`, as told by the `leader`th node of the shard.
+    AppendEntries {
+        term: u64,
+        leader: usize,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+    },
+    /// Store `entries`, the state of the `leader`'s backend up to at least
+    /// the log entry at `last_index`, for a node missing entries dropped
+    /// from the log.
+    InstallSnapshot {
+        term: u64,
+        leader: usize,
+        last_index: u64,
+        last_term: u64,
+        entries: Vec<(Key, Value)>,
+    },
+    /// Claim `node` as the HLC node ID of the node whose vector clock ID is
+    /// `claimant`, which fails if this node has it or gave it to another one.
+    ClaimNodeId {
+        node: u16,
+        claimant: u64,
+    },
+}
+
+/// An entry of a shard's Raft log: the write of a `Value`, or nothing for
+/// those appended by leaders as they're elected.
+#[derive(Debug, Hash, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct LogEntry {
+    pub term: u64,
+    pub write: Option<(Key, Value)>,
 }
 
 /// Request Response for a `handler` from a `StorageNode`.
@@ -117,42 +711,96 @@ pub enum InternodeResponse {
         key: Key,
         value: Value,
     },
+    /// The write of a `Value` was performed. It's only `applied` if no newer
+    /// `Value` was stored already, otherwise it was superseded by the one
+    /// with `timestamp`.
     WriteAck {
         key: Key,
         timestamp: u64,
+        applied: bool,
     },
     Error {
         key: Key,
         message: String,
     },
+    /// Entries found by an `InternodeRequest::Scan`, in scan order.
+    Rows {
+        rows: Vec<(Key, Value)>,
+    },
+    /// `count` entries have been streamed or stored.
+    TransferAck {
+        count: usize,
+    },
+    /// Hashes of a `MerkleTree`, root first.
+    MerkleTree {
+        hashes: Vec<u64>,
+    },
+    /// A `Prepare` was promised. Comes along with the `Value` stored, and
+    /// the latest proposal accepted and not committed yet, with its ballot.
+    Promise {
+        key: Key,
+        value: Value,
+        accepted: Option<(u64, Value)>,
+    },
+    /// The proposal with `ballot` was accepted.
+    Accepted {
+        key: Key,
+        ballot: u64,
+    },
+    /// A `Prepare` or `Propose` was refused, having `promised` a higher
+    /// ballot.
+    Rejected {
+        key: Key,
+        promised: u64,
+    },
+    /// Answer to a `RequestVote`, with the voter's term.
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    /// Answer to an `AppendEntries` or an `InstallSnapshot`, with the
+    /// follower's term. The follower's log matches the leader's up to
+    /// `match_index` if it succeeded, or at least up to there otherwise.
+    AppendAck {
+        term: u64,
+        success: bool,
+        match_index: u64,
+    },
+    /// Answer to a `ClaimNodeId`.
+    NodeIdClaimed {
+        granted: bool,
+    },
+    /// A step of a topology change has been performed.
+    TopologyAck,
+    /// A step of a topology change couldn't be performed.
+    TopologyError {
+        message: String,
+    },
 }
 
 impl InternodeResponse {
     /// If the `Value` has a timestamp, return it.
     pub fn get_timestamp(&self) -> Option<u64> {
         match self {
-            &InternodeResponse::Value {ref value, ..} => {
-                match *value {
-                    Value::None => None,
-                    Value::Value {timestamp, ..} => Some(timestamp),
-                    Value::Tombstone {timestamp} => Some(timestamp),
-                }
-            }
+            &InternodeResponse::Value {ref value, ..} => value.get_timestamp(),
             &InternodeResponse::WriteAck {ref timestamp, ..} => Some(*timestamp),
-            &InternodeResponse::Error {..} => None,
+            _ => None,
         }
     }
 
-    /// Cast this `InternodeResponse` into a `handler` -> `Client` `Response`.
-    pub fn to_response(self) -> Response {
+    /// Cast this `InternodeResponse` to `key` into a `handler` -> `Client`
+    /// `Response`. Replies that only make sense between nodes become errors.
+    pub fn to_response(self, key: &Key) -> Response {
         match self {
             InternodeResponse::Value {key, value} => {
                 Response::Value {
                     key: key,
+                    context: value.context(),
+                    ttl: value.ttl(hlc::wall_clock_ms()),
                     value: value,
                 }
             }
-            InternodeResponse::WriteAck {key, timestamp} => {
+            InternodeResponse::WriteAck {key, timestamp, ..} => {
                 Response::WriteAck {
                     key: key,
                     timestamp: timestamp,
@@ -164,6 +812,44 @@ impl InternodeResponse {
                     message: message,
                 }
             }
+            InternodeResponse::Rows {rows} => {
+                Response::Rows {
+                    rows: rows,
+                    last_lkey: None,
+                }
+            }
+            InternodeResponse::Promise {key, value, ..} => {
+                Response::Value {
+                    key: key,
+                    context: value.context(),
+                    ttl: value.ttl(hlc::wall_clock_ms()),
+                    value: value,
+                }
+            }
+            InternodeResponse::Accepted {key, ballot} => {
+                Response::WriteAck {
+                    key: key,
+                    timestamp: ballot,
+                }
+            }
+            InternodeResponse::Rejected {key, promised} => {
+                Response::Error {
+                    key: key,
+                    message: format!("Ballot {} was promised already", promised),
+                }
+            }
+            InternodeResponse::TransferAck {..} |
+            InternodeResponse::MerkleTree {..} |
+            InternodeResponse::Vote {..} |
+            InternodeResponse::AppendAck {..} |
+            InternodeResponse::NodeIdClaimed {..} => {
+                Response::Error {
+                    key: key.to_owned(),
+                    message: "unexpected internode response".to_string(),
+                }
+            }
+            InternodeResponse::TopologyAck => Response::TopologyAck,
+            InternodeResponse::TopologyError {message} => Response::TopologyError { message: message },
         }
     }
 }
@@ -176,8 +862,37 @@ pub enum Consistency {
     /// Only wait for one `StorageNode` to successfully reply before responding.
     One,
     /// Wait for all `StorageNode`s to reply and send a `Response` with the
-    /// newest `Value`.
+    /// newest `Value`, which a majority of them must have succeeded with.
     Latest,
+    /// Like `Latest`.
+    Quorum,
+    /// Like `Latest`, but every `StorageNode` must succeed.
+    All,
+    /// Like `Latest`, but the given amount of `StorageNode`s must succeed.
+    Count(usize),
+    /// Reads and writes go through the shard's Raft group, so that they're
+    /// linearizable, as long as the shard's `StorageNode`s have Raft enabled
+    /// and the `Key` is only ever written this way. Like `Quorum` for
+    /// anything else.
+    Linearizable,
+}
+
+impl Consistency {
+    /// Amount of a shard's `replicas` that must succeed, or `None` if there
+    /// aren't enough of them.
+    pub fn required(&self, replicas: usize) -> Option<usize> {
+        let required = match *self {
+            Consistency::One => 1,
+            Consistency::Latest | Consistency::Quorum | Consistency::Linearizable => replicas / 2 + 1,
+            Consistency::All => replicas,
+            Consistency::Count(n) => n,
+        };
+        if required == 0 || required > replicas {
+            None
+        } else {
+            Some(required)
+        }
+    }
 }
 
 #[derive(Debug, Hash, Clone, PartialEq)]
@@ -188,6 +903,16 @@ pub enum Error {
     DecodeError,
     /// Connection error.
     ConnectionError,
+    /// No response arrived in time.
+    Timeout,
+    /// A node couldn't perform a topology change step.
+    TopologyError,
+    /// The request couldn't be performed, for the given reason.
+    RequestError(String),
+    /// A response that doesn't match its request.
+    UnexpectedResponse,
+    /// There are no handlers to send the request to.
+    NoHandlers,
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -195,6 +920,7 @@ pub type Result<T> = result::Result<T, Error>;
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
 
     #[test]
     fn key_hash() {
@@ -204,6 +930,78 @@ mod tests {
             lkey: vec![1],
         };
         assert_eq!(8934463522374858327, key.hash());
-        assert_eq!(0, key.shard(1 as usize));
+    }
+
+    fn address(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port)
+    }
+
+    fn key(pkey: u32) -> Key {
+        Key {
+            dataset: vec![1],
+            pkey: vec![(pkey >> 24) as u8, (pkey >> 16) as u8, (pkey >> 8) as u8, pkey as u8],
+            lkey: vec![1],
+        }
+    }
+
+    #[test]
+    fn ring_preference_list() {
+        let shards = vec![vec![address(1), address(2), address(3)],
+                          vec![address(4), address(5), address(6)]];
+        let ring = Ring::new(shards.clone(), 8);
+        for i in 0..100 {
+            let key = key(i);
+            let shard = ring.shard(&key).unwrap();
+            let list = ring.preference_list(&key);
+            assert_eq!(list.len(), 3);
+            for node in &shards[shard] {
+                assert!(list.contains(node));
+                assert!(ring.owns(node, &key));
+            }
+            for node in &shards[1 - shard] {
+                assert!(!ring.owns(node, &key));
+            }
+        }
+    }
+
+    #[test]
+    fn ring_empty() {
+        let ring = Ring::new(vec![], 8);
+        assert_eq!(ring.shard(&key(1)), None);
+        assert_eq!(ring.preference_list(&key(1)), vec![]);
+    }
+
+    #[test]
+    fn ring_adding_shard_moves_few_keys() {
+        let mut shards = vec![vec![address(1)], vec![address(2)], vec![address(3)]];
+        let before = Ring::new(shards.clone(), 64);
+        shards.push(vec![address(4)]);
+        let after = Ring::new(shards, 64);
+
+        let mut moved = 0;
+        for i in 0..1000 {
+            let key = key(i);
+            match (before.shard(&key), after.shard(&key)) {
+                (Some(b), Some(a)) if a != b => {
+                    // Keys only ever move to the new shard.
+                    assert_eq!(a, 3);
+                    moved += 1;
+                }
+                _ => (),
+            }
+        }
+        // Roughly a quarter of the keys move, compared to three quarters
+        // when sharding by `hash % shard_count`.
+        assert!(moved > 100 && moved < 400, "moved {} keys", moved);
+    }
+
+    #[test]
+    fn internal_responses_are_errors_to_clients() {
+        let response = InternodeResponse::Vote { term: 1, granted: true };
+        assert_eq!(response.to_response(&key(1)),
+                   Response::Error {
+                       key: key(1),
+                       message: "unexpected internode response".to_string(),
+                   });
     }
 }
@@ -0,0 +1,32 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counters of the repairs a node performed.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Stale replicas a read sent the newest `Value` to.
+    pub read_repairs: AtomicUsize,
+    /// Read repairs the stale replica failed.
+    pub failed_read_repairs: AtomicUsize,
+    /// Writes replicas missed and got replayed later on.
+    pub delivered_hints: AtomicUsize,
+    /// Writes replicas missed and never got, past the hint limits.
+    pub dropped_hints: AtomicUsize,
+    /// Hints the replica answered with an error when replayed, and that were
+    /// dropped instead of being sent again.
+    pub rejected_hints: AtomicUsize,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Every counter, by name.
+    pub fn counters(&self) -> Vec<(String, u64)> {
+        vec![("read_repairs".to_string(), self.read_repairs.load(Ordering::SeqCst) as u64),
+             ("failed_read_repairs".to_string(), self.failed_read_repairs.load(Ordering::SeqCst) as u64),
+             ("delivered_hints".to_string(), self.delivered_hints.load(Ordering::SeqCst) as u64),
+             ("dropped_hints".to_string(), self.dropped_hints.load(Ordering::SeqCst) as u64),
+             ("rejected_hints".to_string(), self.rejected_hints.load(Ordering::SeqCst) as u64)]
+    }
+}
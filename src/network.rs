@@ -1,26 +1,107 @@
-use constants::BUFFER_SIZE;
-use std::io::Read;
-use std::io::Result;
-use std::net::TcpStream;
+use constants::{HEADER_SIZE, MAX_FRAME_SIZE, PROTOCOL_VERSION};
+use message::Buffer;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+/// Header sent in front of every message exchanged between sbahn nodes.
+///
+/// On the wire it is laid out as `version: u8`, `flags: u8`,
+/// `request_id: u64` and `length: u32` (all big endian), followed by `length`
+/// bytes of payload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameHeader {
+    /// Wire protocol version, `PROTOCOL_VERSION` for every frame we send.
+    pub version: u8,
+    /// Reserved for future use, always `0` in this protocol version.
+    pub flags: u8,
+    /// Identifier of the request this frame belongs to. A response carries
+    /// the same id as its request, so that many requests can be in flight
+    /// on a single connection and be answered in any order.
+    pub request_id: u64,
+    /// Size of the payload following this header.
+    pub length: u32,
+}
+
+impl FrameHeader {
+    /// Header for a payload of `length` bytes belonging to `request_id`.
+    pub fn new(request_id: u64, length: u32) -> FrameHeader {
+        FrameHeader {
+            version: PROTOCOL_VERSION,
+            flags: 0,
+            request_id: request_id,
+            length: length,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut buf = [0; HEADER_SIZE];
+        buf[0] = self.version;
+        buf[1] = self.flags;
+        for i in 0..8 {
+            buf[2 + i] = (self.request_id >> (56 - 8 * i)) as u8;
+        }
+        for i in 0..4 {
+            buf[10 + i] = (self.length >> (24 - 8 * i)) as u8;
+        }
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; HEADER_SIZE]) -> FrameHeader {
+        let request_id = buf[2..10].iter().fold(0, |acc, &b| (acc << 8) | b as u64);
+        let length = buf[10..14].iter().fold(0, |acc, &b| (acc << 8) | b as u32);
+        FrameHeader {
+            version: buf[0],
+            flags: buf[1],
+            request_id: request_id,
+            length: length,
+        }
+    }
+}
 
 pub trait NetworkRead {
-    fn read_to_message_end(&mut self, buf: &mut Vec<u8>) -> Result<usize>;
+    /// Read a whole framed message, blocking until all of its payload arrived.
+    /// Returns the message's request id along with its payload.
+    fn read_message(&mut self) -> Result<(u64, Buffer)>;
+}
+
+pub trait NetworkWrite {
+    /// Write `payload` as a single framed message tagged with `request_id`.
+    fn write_message(&mut self, request_id: u64, payload: &[u8]) -> Result<()>;
+}
+
+impl<T: Read> NetworkRead for T {
+    fn read_message(&mut self) -> Result<(u64, Buffer)> {
+        let mut header_buf = [0; HEADER_SIZE];
+        try!(self.read_exact(&mut header_buf));
+        let header = FrameHeader::from_bytes(&header_buf);
+        debug!("read frame header {:?}", header);
+        if header.version != PROTOCOL_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("unsupported protocol version {}", header.version)));
+        }
+        if header.length > MAX_FRAME_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("frame of {} bytes is too large", header.length)));
+        }
+        let mut buf = vec![0; header.length as usize];
+        try!(self.read_exact(&mut buf));
+        info!("read {:?} bytes", buf.len());
+        Ok((header.request_id, buf))
+    }
 }
 
-impl NetworkRead for TcpStream {
-    fn read_to_message_end(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
-        let mut total_size: usize = 0;
-        loop {
-            let mut fixed_buf = [0; BUFFER_SIZE];
-            info!("before read_to_end");
-            let bytes_read = try!(self.read(&mut fixed_buf));
-            buf.extend(fixed_buf[..bytes_read].iter());
-            info!("read {:?} bytes", bytes_read);
-            total_size += bytes_read;
-            if bytes_read != BUFFER_SIZE || bytes_read == 0 {
-                break;
-            }
+impl<T: Write> NetworkWrite for T {
+    fn write_message(&mut self, request_id: u64, payload: &[u8]) -> Result<()> {
+        if payload.len() > MAX_FRAME_SIZE as usize {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  format!("message of {} bytes is too large", payload.len())));
         }
-        Ok(total_size)
+        let header = FrameHeader::new(request_id, payload.len() as u32);
+        // Send header and payload in one write so they don't go out as
+        // separate segments.
+        let mut buf = Vec::with_capacity(HEADER_SIZE + payload.len());
+        buf.extend(header.to_bytes().iter());
+        buf.extend(payload.iter());
+        try!(self.write_all(&buf));
+        self.flush()
     }
 }
@@ -0,0 +1,120 @@
+use constants::PAXOS_LOG_SIZE;
+use message::{Key, Value};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use wal::{SyncPolicy, Wal};
+
+/// What a replica promised and accepted for a `Key`.
+#[derive(Debug, Default, Clone, RustcEncodable, RustcDecodable)]
+struct Ballots {
+    /// Highest ballot promised.
+    promised: u64,
+    /// Latest proposal accepted and not committed yet, with its ballot.
+    accepted: Option<(u64, Value)>,
+}
+
+/// A replica's side of the Paxos rounds compare-and-sets agree on their order
+/// through, one for each `Key`.
+///
+/// Ballots are `hlc` timestamps, and the committed `Value` is written with
+/// the round's. Promises outlive their round, so that a proposer that fell
+/// behind can't get a proposal accepted by replicas that moved on. An
+/// acceptor that is `open`ed logs every change before answering with it, so
+/// that it keeps its word across restarts.
+#[derive(Debug, Default)]
+pub struct Acceptor {
+    ballots: Mutex<HashMap<Key, Ballots>>,
+    /// Log of the latest `Ballots` of each `Key`, if they're kept at all.
+    wal: Option<Wal>,
+}
+
+impl Acceptor {
+    /// An acceptor keeping its promises in memory only.
+    pub fn new() -> Acceptor {
+        Acceptor::default()
+    }
+
+    /// An acceptor keeping its promises and accepted proposals in the log at
+    /// `path`, recovering those logged there before.
+    pub fn open(path: &Path) -> io::Result<Acceptor> {
+        let (wal, records) = try!(Wal::open_records::<(Key, Ballots)>(path, SyncPolicy::Always));
+        let mut ballots = HashMap::new();
+        for (key, b) in records {
+            ballots.insert(key, b);
+        }
+        info!("[Acceptor] Recovered the ballots of {} keys from {:?}", ballots.len(), path);
+        {
+            // Only the latest record of each `Key` is still needed.
+            let latest: Vec<(&Key, &Ballots)> = ballots.iter().collect();
+            try!(wal.rewrite(&latest));
+        }
+        Ok(Acceptor {
+            ballots: Mutex::new(ballots),
+            wal: Some(wal),
+        })
+    }
+
+    /// Promise not to accept proposals for `key` with a lower ballot than
+    /// `ballot`. Returns the proposal accepted and not committed yet, if
+    /// any, or the higher ballot promised already.
+    pub fn prepare(&self, key: &Key, ballot: u64) -> io::Result<Result<Option<(u64, Value)>, u64>> {
+        let mut ballots = self.ballots.lock().unwrap();
+        let mut b = ballots.get(key).cloned().unwrap_or_default();
+        if ballot <= b.promised {
+            return Ok(Err(b.promised));
+        }
+        b.promised = ballot;
+        let accepted = b.accepted.clone();
+        try!(self.persist(&mut ballots, key, b));
+        Ok(Ok(accepted))
+    }
+
+    /// Accept `value` for `key` with `ballot`, unless a higher ballot was
+    /// promised, which is returned.
+    pub fn propose(&self, key: &Key, ballot: u64, value: &Value) -> io::Result<Result<(), u64>> {
+        let mut ballots = self.ballots.lock().unwrap();
+        let promised = ballots.get(key).map_or(0, |b| b.promised);
+        if ballot < promised {
+            return Ok(Err(promised));
+        }
+        let b = Ballots {
+            promised: ballot,
+            accepted: Some((ballot, value.to_owned())),
+        };
+        try!(self.persist(&mut ballots, key, b));
+        Ok(Ok(()))
+    }
+
+    /// Forget the proposal accepted for `key`, now that the round with
+    /// `ballot` committed, unless it's from a later round.
+    pub fn commit(&self, key: &Key, ballot: u64) -> io::Result<()> {
+        let mut ballots = self.ballots.lock().unwrap();
+        let promised = match ballots.get(key) {
+            Some(b) if b.accepted.as_ref().map_or(false, |&(accepted, _)| accepted <= ballot) => b.promised,
+            _ => return Ok(()),
+        };
+        let b = Ballots {
+            promised: promised,
+            accepted: None,
+        };
+        self.persist(&mut ballots, key, b)
+    }
+
+    /// Store `b` as what's promised and accepted for `key`, once it's logged
+    /// if there's a log.
+    fn persist(&self, ballots: &mut HashMap<Key, Ballots>, key: &Key, b: Ballots) -> io::Result<()> {
+        if let Some(ref wal) = self.wal {
+            try!(wal.append_record(&(key, &b), || ()));
+        }
+        ballots.insert(key.to_owned(), b);
+        if let Some(ref wal) = self.wal {
+            if wal.len() > PAXOS_LOG_SIZE {
+                let latest: Vec<(&Key, &Ballots)> = ballots.iter().collect();
+                try!(wal.rewrite(&latest));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+use bincode::SizeLimit;
+use bincode::rustc_serialize::{encode, decode};
+use eventual::*;
+use message::{Buffer, Error, Result};
+use network::{NetworkRead, NetworkWrite};
+use rustc_serialize::{Encodable, Decodable};
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::net::{Shutdown, SocketAddr, SocketAddrV4, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+type Pending = Arc<Mutex<HashMap<u64, Complete<Buffer, Error>>>>;
+
+/// A long-lived connection to an sbahn node, carrying many concurrent
+/// requests. Every request is tagged with its own id, and responses are
+/// matched back to their request as they arrive, in any order.
+pub struct Connection {
+    target: SocketAddrV4,
+    stream: Mutex<TcpStream>,
+    pending: Pending,
+    next_id: AtomicUsize,
+    closed: Arc<AtomicBool>,
+    timer: Timer,
+}
+
+impl Connection {
+    /// Connect to `target`, waiting at most `timeout` for the connection to
+    /// be established and for each write to complete.
+    pub fn connect(target: &SocketAddrV4, timeout: Duration, timer: Timer) -> Result<Connection> {
+        let stream = match TcpStream::connect_timeout(&SocketAddr::V4(*target), timeout) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Could not connect to {:?}: {:?}", target, e);
+                return Err(Error::ConnectionError);
+            }
+        };
+        let _ = stream.set_write_timeout(Some(timeout));
+        let _ = stream.set_nodelay(true);
+        let reader = match stream.try_clone() {
+            Ok(s) => s,
+            Err(_) => return Err(Error::ConnectionError),
+        };
+
+        let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+        {
+            let pending = pending.clone();
+            let closed = closed.clone();
+            let target = target.to_owned();
+            thread::spawn(move || Self::read_responses(target, reader, pending, closed));
+        }
+
+        Ok(Connection {
+            target: target.to_owned(),
+            stream: Mutex::new(stream),
+            pending: pending,
+            next_id: AtomicUsize::new(1),
+            closed: closed,
+            timer: timer,
+        })
+    }
+
+    /// Dispatch every response read from `stream` to its pending request.
+    /// Once the connection breaks, every request still waiting fails.
+    fn read_responses(target: SocketAddrV4, mut stream: TcpStream, pending: Pending, closed: Arc<AtomicBool>) {
+        loop {
+            match stream.read_message() {
+                Ok((id, buf)) => {
+                    let complete = pending.lock().unwrap().remove(&id);
+                    match complete {
+                        Some(c) => c.complete(buf),
+                        None => debug!("Dropping late response {} from {:?}", id, target),
+                    }
+                }
+                Err(e) => {
+                    debug!("Connection to {:?} closed: {:?}", target, e);
+                    break;
+                }
+            }
+        }
+        closed.store(true, Ordering::SeqCst);
+        let mut pending = pending.lock().unwrap();
+        for (_, c) in pending.drain() {
+            c.fail(Error::ConnectionError);
+        }
+    }
+
+    /// Whether this connection is no longer usable.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Send a binary encoded message and get a future for its response. The
+    /// future fails if no response arrives within `timeout`.
+    pub fn send(&self, message: &[u8], timeout: Option<Duration>) -> Future<Buffer, Error> {
+        if self.is_closed() {
+            return Future::error(Error::ConnectionError);
+        }
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) as u64;
+        let (complete, future) = Future::pair();
+        self.pending.lock().unwrap().insert(id, complete);
+
+        let written = self.stream.lock().unwrap().write_message(id, message);
+        if let Err(e) = written {
+            error!("Error sending request {} to {:?}: {:?}", id, self.target, e);
+            self.closed.store(true, Ordering::SeqCst);
+            let _ = self.stream.lock().unwrap().shutdown(Shutdown::Both);
+            if let Some(c) = self.pending.lock().unwrap().remove(&id) {
+                c.fail(Error::ConnectionError);
+            }
+            return future;
+        }
+
+        if let Some(timeout) = timeout {
+            let pending = self.pending.clone();
+            let target = self.target;
+            let ms = (timeout.as_secs() * 1000) as u32 + timeout.subsec_nanos() / 1_000_000;
+            self.timer.timeout_ms(ms).receive(move |_| {
+                if let Some(c) = pending.lock().unwrap().remove(&id) {
+                    debug!("Request {} to {:?} timed out", id, target);
+                    c.fail(Error::Timeout);
+                }
+            });
+        }
+        future
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // Wake up the reader thread so that it can finish.
+        let _ = self.stream.lock().unwrap().shutdown(Shutdown::Both);
+    }
+}
+
+/// A set of persistent `Connection`s, one per remote node.
+pub struct ConnectionPool {
+    connections: Mutex<HashMap<SocketAddrV4, Arc<Connection>>>,
+    connect_timeout: Duration,
+    timer: Timer,
+}
+
+impl fmt::Debug for ConnectionPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let connections = self.connections.lock().unwrap();
+        write!(f, "ConnectionPool {{ connections: {:?} }}", connections.keys().collect::<Vec<_>>())
+    }
+}
+
+impl ConnectionPool {
+    pub fn new() -> ConnectionPool {
+        ConnectionPool::with_connect_timeout(Duration::from_millis(300))
+    }
+
+    pub fn with_connect_timeout(connect_timeout: Duration) -> ConnectionPool {
+        ConnectionPool {
+            connections: Mutex::new(HashMap::new()),
+            connect_timeout: connect_timeout,
+            timer: Timer::with_capacity(1),
+        }
+    }
+
+    /// Get an open connection to `target`, reconnecting if the previous one
+    /// broke. Connecting doesn't hold up requests to other nodes; of the
+    /// connections opened to `target` at the same time, the first one to be
+    /// established is kept.
+    pub fn get(&self, target: &SocketAddrV4) -> Result<Arc<Connection>> {
+        if let Some(c) = self.open_connection(target) {
+            return Ok(c);
+        }
+        debug!("Opening new connection to {:?}", target);
+        let c = Arc::new(try!(Connection::connect(target, self.connect_timeout, self.timer.clone())));
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(other) = connections.get(target) {
+            if !other.is_closed() {
+                return Ok(other.clone());
+            }
+        }
+        connections.insert(target.to_owned(), c.clone());
+        Ok(c)
+    }
+
+    fn open_connection(&self, target: &SocketAddrV4) -> Option<Arc<Connection>> {
+        match self.connections.lock().unwrap().get(target) {
+            Some(c) if !c.is_closed() => Some(c.clone()),
+            _ => None,
+        }
+    }
+
+    /// Sends a binary encoded message to the node at `target`.
+    pub fn send_buffer(&self,
+                       target: &SocketAddrV4,
+                       message: &[u8],
+                       timeout: Option<Duration>)
+                       -> Future<Buffer, Error> {
+        match self.get(target) {
+            Ok(c) => c.send(message, timeout),
+            Err(e) => Future::error(e),
+        }
+    }
+
+    /// Sends a message that can be binary encoded to the node at `target`.
+    pub fn send_to_node<T, K>(&self,
+                              target: &SocketAddrV4,
+                              message: &T,
+                              timeout: Option<Duration>)
+                              -> Future<K, Error>
+        where T: Debug + Encodable,
+              K: Debug + Decodable + Send
+    {
+        debug!("sending message {:?} to node {:?}", message, target);
+        match encode(&message, SizeLimit::Infinite) {
+            Ok(content) => {
+                self.send_buffer(target, &content, timeout).and_then(|x| {
+                    match decode(&x) {
+                        Ok(m) => Ok(m),
+                        Err(_) => Err(Error::DecodeError),
+                    }
+                })
+            }
+            Err(_) => Future::error(Error::EncodeError),
+        }
+    }
+}
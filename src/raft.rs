@@ -0,0 +1,951 @@
+use constants::{RAFT_COMMIT_TIMEOUT_MS, RAFT_ELECTION_TIMEOUT_MS, RAFT_HEARTBEAT_MS, RAFT_MAX_LOG};
+use coordinator;
+use eventual::*;
+use hlc::{self, Clock};
+use message::{Buffer, Error, InternodeRequest, InternodeResponse, Key, LogEntry, Value};
+use pool::ConnectionPool;
+use rebalance::Topology;
+use std::cmp;
+use std::io;
+use std::net::SocketAddrV4;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use storage::StorageBackend;
+use wal::{self, SyncPolicy, Wal};
+
+/// Timing of a shard's Raft group.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RaftConfig {
+    /// Time between a leader's heartbeats.
+    pub heartbeat: Duration,
+    /// Time a follower waits at least to hear from its leader before standing
+    /// for election, up to twice as long at random.
+    pub election_timeout: Duration,
+    /// Entries the log grows to before those applied to the backend are
+    /// dropped from it.
+    pub max_log: usize,
+    /// Time a leader waits for a read or a write to be committed.
+    pub commit_timeout: Duration,
+}
+
+impl Default for RaftConfig {
+    fn default() -> RaftConfig {
+        RaftConfig {
+            heartbeat: Duration::from_millis(RAFT_HEARTBEAT_MS),
+            election_timeout: Duration::from_millis(RAFT_ELECTION_TIMEOUT_MS),
+            max_log: RAFT_MAX_LOG,
+            commit_timeout: Duration::from_millis(RAFT_COMMIT_TIMEOUT_MS),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// Why a Raft group couldn't perform a read or a write.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RaftError {
+    /// This node isn't the leader. The one it follows, if it knows of any.
+    NotLeader(Option<SocketAddrV4>),
+    /// The leader couldn't get a majority of the group to go along.
+    Unavailable(String),
+}
+
+/// A change to what a Raft node must not forget, as kept in its log.
+#[derive(Debug, RustcEncodable, RustcDecodable)]
+enum RaftRecord {
+    /// The node moved on to `term`, voting for `voted_for` in it.
+    Term {
+        term: u64,
+        voted_for: Option<usize>,
+    },
+    /// `entries` took the place of those from `index` on.
+    Entries {
+        index: u64,
+        entries: Vec<LogEntry>,
+    },
+    /// The entries up to `index`, of `term`, were applied to the backend and
+    /// dropped from the log.
+    Snapshot {
+        index: u64,
+        term: u64,
+    },
+}
+
+/// What a leader sends a follower to bring it up to date.
+enum Update {
+    Append(InternodeRequest),
+    /// The backend's entries, as of at least the entry at `last_index`.
+    Snapshot {
+        last_index: u64,
+        last_term: u64,
+    },
+}
+
+#[derive(Debug)]
+struct RaftState {
+    term: u64,
+    voted_for: Option<usize>,
+    role: Role,
+    leader: Option<usize>,
+    /// Entries past the snapshot, the first one at `snapshot_index + 1`.
+    log: Vec<LogEntry>,
+    /// Last entry applied to the backend and dropped from the log, and its
+    /// term. Nodes that need entries up to there get a snapshot instead.
+    snapshot_index: u64,
+    snapshot_term: u64,
+    commit_index: u64,
+    last_applied: u64,
+    /// As a leader, for every node: the next entry to send it, and the last
+    /// one known to match the leader's.
+    next_index: Vec<u64>,
+    match_index: Vec<u64>,
+    /// When to stand for election, unless a leader is heard from before.
+    election_deadline: Instant,
+    /// Log the term, the vote and the log are kept in, if they're kept at
+    /// all.
+    journal: Option<Wal>,
+}
+
+impl RaftState {
+    fn last_index(&self) -> u64 {
+        self.snapshot_index + self.log.len() as u64
+    }
+
+    /// Term of the entry at `index`, unless it's not in the log.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            Some(self.snapshot_term)
+        } else if index < self.snapshot_index || index > self.last_index() {
+            None
+        } else {
+            Some(self.log[(index - self.snapshot_index - 1) as usize].term)
+        }
+    }
+
+    fn last_term(&self) -> u64 {
+        self.term_at(self.last_index()).unwrap_or(0)
+    }
+
+    /// Move on to `term` as a follower, once it's logged if it's newer.
+    fn step_down(&mut self, term: u64) -> io::Result<()> {
+        self.role = Role::Follower;
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.leader = None;
+            try!(self.save_term());
+        }
+        Ok(())
+    }
+
+    fn save(&self, record: &RaftRecord) -> io::Result<()> {
+        match self.journal {
+            Some(ref journal) => journal.append_record(record, || ()),
+            None => Ok(()),
+        }
+    }
+
+    /// Log the term, and the vote cast in it.
+    fn save_term(&self) -> io::Result<()> {
+        self.save(&RaftRecord::Term {
+            term: self.term,
+            voted_for: self.voted_for,
+        })
+    }
+
+    /// Log the entries from `index` on.
+    fn save_entries(&self, index: u64) -> io::Result<()> {
+        let start = (index - self.snapshot_index - 1) as usize;
+        self.save(&RaftRecord::Entries {
+            index: index,
+            entries: self.log[start..].to_vec(),
+        })
+    }
+
+    /// Replace the log with just what it takes to recover the current state.
+    fn save_all(&self) -> io::Result<()> {
+        match self.journal {
+            Some(ref journal) => {
+                journal.rewrite(&[RaftRecord::Term {
+                                      term: self.term,
+                                      voted_for: self.voted_for,
+                                  },
+                                  RaftRecord::Snapshot {
+                                      index: self.snapshot_index,
+                                      term: self.snapshot_term,
+                                  },
+                                  RaftRecord::Entries {
+                                      index: self.snapshot_index + 1,
+                                      entries: self.log.clone(),
+                                  }])
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Drop the entries up to `index`, of `term`, from the log, or all of
+    /// them if it doesn't have that one.
+    fn compact(&mut self, index: u64, term: u64) {
+        if self.term_at(index) == Some(term) {
+            let covered = (index - self.snapshot_index) as usize;
+            self.log.drain(..covered);
+        } else {
+            self.log.clear();
+        }
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+    }
+
+    fn replay(&mut self, record: RaftRecord) {
+        match record {
+            RaftRecord::Term {term, voted_for} => {
+                self.term = term;
+                self.voted_for = voted_for;
+            }
+            RaftRecord::Entries {index, entries} => {
+                let mut index = index;
+                for entry in entries {
+                    if index > self.snapshot_index {
+                        self.log.truncate((index - self.snapshot_index - 1) as usize);
+                        self.log.push(entry);
+                    }
+                    index += 1;
+                }
+            }
+            RaftRecord::Snapshot {index, term} => self.compact(index, term),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RaftInner<Backend: StorageBackend + 'static> {
+    /// Position of this node in `nodes`.
+    me: usize,
+    /// Every node of the shard.
+    nodes: Vec<SocketAddrV4>,
+    map: Arc<Backend>,
+    /// Write-ahead log of the storage node, which committed writes are
+    /// applied through.
+    wal: Option<Wal>,
+    topology: Arc<RwLock<Topology>>,
+    pool: Arc<ConnectionPool>,
+    clock: Arc<Clock>,
+    config: RaftConfig,
+    state: Mutex<RaftState>,
+    /// Notified whenever entries are applied to the backend.
+    applied: Condvar,
+}
+
+/// A storage node's part in the Raft group of its shard, which the writes and
+/// reads with `Consistency::Linearizable` go through.
+///
+/// Committed writes are applied to the `StorageBackend`, which doubles as
+/// the snapshot of the entries dropped from the log: nodes missing them get
+/// the backend's entries instead. Those may be newer than the snapshot's
+/// last entry, but applying a write twice changes nothing, as the leader
+/// timestamps writes in log order.
+///
+/// Once `open_log` is called, terms, votes and the log are logged and synced
+/// before any request is answered or any entry counted as replicated, and
+/// recovered on restart. The entries past the snapshot are applied again
+/// then.
+#[derive(Debug)]
+pub struct Raft<Backend: StorageBackend + 'static> {
+    inner: Arc<RaftInner<Backend>>,
+}
+
+impl<Backend: StorageBackend + 'static> Clone for Raft<Backend> {
+    fn clone(&self) -> Raft<Backend> {
+        Raft { inner: self.inner.clone() }
+    }
+}
+
+impl<Backend: StorageBackend + 'static> Raft<Backend> {
+    /// The Raft group of the `me`th of `nodes`, applying writes to `map`
+    /// through `wal` if there's one. Its state is kept in memory only until
+    /// `open_log` is called.
+    pub fn new(me: usize,
+               nodes: Vec<SocketAddrV4>,
+               map: Arc<Backend>,
+               wal: Option<Wal>,
+               topology: Arc<RwLock<Topology>>,
+               pool: Arc<ConnectionPool>,
+               clock: Arc<Clock>,
+               config: RaftConfig)
+               -> Raft<Backend> {
+        let n = nodes.len();
+        Raft {
+            inner: Arc::new(RaftInner {
+                me: me,
+                nodes: nodes,
+                map: map,
+                wal: wal,
+                topology: topology,
+                pool: pool,
+                clock: clock,
+                state: Mutex::new(RaftState {
+                    term: 0,
+                    voted_for: None,
+                    role: Role::Follower,
+                    leader: None,
+                    log: vec![],
+                    snapshot_index: 0,
+                    snapshot_term: 0,
+                    commit_index: 0,
+                    last_applied: 0,
+                    next_index: vec![1; n],
+                    match_index: vec![0; n],
+                    election_deadline: Instant::now() + election_timeout(&config),
+                    journal: None,
+                }),
+                config: config,
+                applied: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Keep the term, the vote and the log in the log at `path`, recovering
+    /// those kept there before. To be called before `run`.
+    pub fn open_log(&self, path: &Path) -> io::Result<()> {
+        let (journal, records) = try!(Wal::open_records::<RaftRecord>(path, SyncPolicy::Always));
+        let mut s = self.inner.state.lock().unwrap();
+        for record in records {
+            s.replay(record);
+        }
+        // Entries past the snapshot might not have been applied.
+        s.commit_index = s.snapshot_index;
+        s.last_applied = s.snapshot_index;
+        info!("[Raft] Recovered term {} and entries up to {} from {:?}", s.term, s.last_index(), path);
+        s.journal = Some(journal);
+        s.save_all()
+    }
+
+    /// Start keeping up with the rest of the group: sending heartbeats as a
+    /// leader, or standing for election once the leader is silent for too
+    /// long. Goes on until every clone is dropped.
+    pub fn run(&self) {
+        let raft = Arc::downgrade(&self.inner);
+        let heartbeat = self.inner.config.heartbeat;
+        thread::spawn(move || Raft::tick(raft, heartbeat));
+    }
+
+    fn tick(raft: Weak<RaftInner<Backend>>, heartbeat: Duration) {
+        loop {
+            thread::sleep(heartbeat);
+            let raft = match raft.upgrade() {
+                Some(inner) => Raft { inner: inner },
+                None => return,
+            };
+            let (role, expired) = {
+                let s = raft.inner.state.lock().unwrap();
+                (s.role, Instant::now() >= s.election_deadline)
+            };
+            if role == Role::Leader {
+                raft.replicate();
+            } else if expired {
+                raft.campaign();
+            }
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        self.inner.state.lock().unwrap().role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.inner.state.lock().unwrap().term
+    }
+
+    /// The leader this node follows, or itself if it's the leader.
+    pub fn leader(&self) -> Option<SocketAddrV4> {
+        self.inner.state.lock().unwrap().leader.map(|l| self.inner.nodes[l])
+    }
+
+    fn majority(&self) -> usize {
+        self.inner.nodes.len() / 2 + 1
+    }
+
+    fn rpc_timeout(&self) -> Option<Duration> {
+        Some(self.inner.config.election_timeout)
+    }
+
+    fn others(&self) -> Vec<usize> {
+        (0..self.inner.nodes.len()).filter(|&i| i != self.inner.me).collect()
+    }
+
+    fn reset_election_timer(&self, s: &mut RaftState) {
+        s.election_deadline = Instant::now() + election_timeout(&self.inner.config);
+    }
+
+    /// Stand for election in the next term.
+    fn campaign(&self) {
+        let (term, request) = {
+            let mut s = self.inner.state.lock().unwrap();
+            s.term += 1;
+            s.role = Role::Candidate;
+            s.voted_for = Some(self.inner.me);
+            s.leader = None;
+            self.reset_election_timer(&mut s);
+            if let Err(e) = s.save_term() {
+                error!("[Raft] Couldn't log term {}: {:?}", s.term, e);
+                s.role = Role::Follower;
+                return;
+            }
+            let request = InternodeRequest::RequestVote {
+                term: s.term,
+                candidate: self.inner.me,
+                last_log_index: s.last_index(),
+                last_log_term: s.last_term(),
+            };
+            if self.majority() == 1 {
+                self.become_leader(&mut s);
+                return;
+            }
+            (s.term, request)
+        };
+        debug!("[Raft] {:?} standing for election in term {}", self.inner.nodes[self.inner.me], term);
+        let votes = Arc::new(AtomicUsize::new(1));
+        for i in self.others() {
+            let raft = self.clone();
+            let votes = votes.clone();
+            let response: Future<InternodeResponse, Error> = self.inner.pool.send_to_node(&self.inner.nodes[i],
+                                                                                         &request,
+                                                                                         self.rpc_timeout());
+            response.receive(move |r| {
+                if let Ok(InternodeResponse::Vote {term: t, granted}) = r {
+                    raft.on_vote(term, t, granted, &votes);
+                }
+            });
+        }
+    }
+
+    fn on_vote(&self, election_term: u64, term: u64, granted: bool, votes: &AtomicUsize) {
+        let mut s = self.inner.state.lock().unwrap();
+        if term > s.term {
+            self.step_down(&mut s, term);
+            return;
+        }
+        if s.term != election_term || s.role != Role::Candidate || !granted {
+            return;
+        }
+        if votes.fetch_add(1, Ordering::SeqCst) + 1 >= self.majority() {
+            self.become_leader(&mut s);
+            drop(s);
+            self.replicate();
+        }
+    }
+
+    /// Move on to a newer `term` heard of as a leader or a candidate.
+    fn step_down(&self, s: &mut RaftState, term: u64) {
+        if let Err(e) = s.step_down(term) {
+            error!("[Raft] Couldn't log term {}: {:?}", term, e);
+        }
+    }
+
+    /// Take over, appending an empty entry so that whatever previous leaders
+    /// left uncommitted gets committed along with it.
+    fn become_leader(&self, s: &mut RaftState) {
+        let term = s.term;
+        s.log.push(LogEntry {
+            term: term,
+            write: None,
+        });
+        let next = s.last_index();
+        if let Err(e) = s.save_entries(next) {
+            error!("[Raft] Couldn't log the first entry of term {}: {:?}", term, e);
+            s.log.pop();
+            s.role = Role::Follower;
+            return;
+        }
+        info!("[Raft] {:?} is the leader of term {}", self.inner.nodes[self.inner.me], term);
+        s.role = Role::Leader;
+        s.leader = Some(self.inner.me);
+        s.next_index = vec![next; self.inner.nodes.len()];
+        s.match_index = vec![0; self.inner.nodes.len()];
+        self.advance_commit(s);
+    }
+
+    /// What brings the `i`th node up to date, as far as the leader knows:
+    /// the entries it's missing, or a snapshot if some of them were dropped
+    /// from the log already. The node is taken to have the snapshot once it's
+    /// sent, so that it's not sent again with every heartbeat, unless the
+    /// next `AppendEntries` finds it still missing entries.
+    fn update(&self, s: &mut RaftState, i: usize) -> Update {
+        let next = s.next_index[i];
+        if next <= s.snapshot_index {
+            s.next_index[i] = s.snapshot_index + 1;
+            Update::Snapshot {
+                last_index: s.snapshot_index,
+                last_term: s.snapshot_term,
+            }
+        } else {
+            let start = (next - s.snapshot_index - 1) as usize;
+            Update::Append(InternodeRequest::AppendEntries {
+                term: s.term,
+                leader: self.inner.me,
+                prev_log_index: next - 1,
+                prev_log_term: s.term_at(next - 1).unwrap_or(0),
+                entries: s.log[start..].iter().take(self.inner.config.max_log).cloned().collect(),
+                leader_commit: s.commit_index,
+            })
+        }
+    }
+
+    /// The `InstallSnapshot` of the backend's entries the `i`th node owns, as
+    /// of at least the entry at `last_index`. Built without holding on to
+    /// the state, as it goes through the whole backend.
+    fn snapshot(&self, i: usize, term: u64, last_index: u64, last_term: u64) -> InternodeRequest {
+        let topology = self.inner.topology.read().unwrap().clone();
+        let node = self.inner.nodes[i];
+        let mut entries = vec![];
+        self.inner.map.for_each(&mut |key, value| {
+            if topology.owns(&node, key) {
+                entries.push((key.to_owned(), value.to_owned()));
+            }
+        });
+        InternodeRequest::InstallSnapshot {
+            term: term,
+            leader: self.inner.me,
+            last_index: last_index,
+            last_term: last_term,
+            entries: entries,
+        }
+    }
+
+    /// An `AppendEntries` without entries to the `i`th node, which only has
+    /// it acknowledge the leader.
+    fn heartbeat(&self, s: &RaftState, i: usize) -> InternodeRequest {
+        let prev = s.match_index[i];
+        InternodeRequest::AppendEntries {
+            term: s.term,
+            leader: self.inner.me,
+            prev_log_index: prev,
+            prev_log_term: s.term_at(prev).unwrap_or(0),
+            entries: vec![],
+            leader_commit: s.commit_index,
+        }
+    }
+
+    /// Send every other node what it's missing, or a heartbeat.
+    fn replicate(&self) {
+        for (i, term, update) in self.updates() {
+            let request = match update {
+                Update::Append(request) => request,
+                Update::Snapshot {last_index, last_term} => self.snapshot(i, term, last_index, last_term),
+            };
+            let raft = self.clone();
+            let response: Future<InternodeResponse, Error> = self.inner.pool.send_to_node(&self.inner.nodes[i],
+                                                                                         &request,
+                                                                                         self.rpc_timeout());
+            response.receive(move |r| {
+                if let Ok(InternodeResponse::AppendAck {term: t, success, match_index}) = r {
+                    raft.on_append_ack(i, term, t, success, match_index);
+                }
+            });
+        }
+    }
+
+    fn updates(&self) -> Vec<(usize, u64, Update)> {
+        let mut s = self.inner.state.lock().unwrap();
+        if s.role != Role::Leader {
+            return vec![];
+        }
+        let term = s.term;
+        self.others().into_iter().map(|i| (i, term, self.update(&mut s, i))).collect()
+    }
+
+    fn on_append_ack(&self, i: usize, sent_term: u64, term: u64, success: bool, match_index: u64) {
+        let mut s = self.inner.state.lock().unwrap();
+        if term > s.term {
+            self.step_down(&mut s, term);
+            return;
+        }
+        if s.role != Role::Leader || s.term != sent_term {
+            return;
+        }
+        if success {
+            if match_index > s.match_index[i] {
+                s.match_index[i] = match_index;
+            }
+            s.next_index[i] = s.match_index[i] + 1;
+            self.advance_commit(&mut s);
+        } else {
+            // The follower matches at least up to `match_index`.
+            s.next_index[i] = cmp::max(1, cmp::min(s.next_index[i], match_index + 1));
+        }
+    }
+
+    /// Commit the newest entry of this term a majority has, along with every
+    /// one before it.
+    fn advance_commit(&self, s: &mut RaftState) {
+        let mut n = s.last_index();
+        while n > s.commit_index {
+            let replicated = 1 + self.others().into_iter().filter(|&i| s.match_index[i] >= n).count();
+            if s.term_at(n) == Some(s.term) && replicated >= self.majority() {
+                s.commit_index = n;
+                break;
+            }
+            n -= 1;
+        }
+        self.apply(s);
+    }
+
+    /// Store `value` under `key` like any other write to the storage node.
+    fn store(&self, key: Key, value: Value) -> io::Result<bool> {
+        wal::store(self.inner.wal.as_ref(), &*self.inner.map, key, value)
+    }
+
+    /// Apply the committed entries to the backend, dropping them from the log
+    /// once it's too long.
+    fn apply(&self, s: &mut RaftState) {
+        while s.last_applied < s.commit_index {
+            let index = s.last_applied + 1;
+            let write = s.log[(index - s.snapshot_index - 1) as usize].write.clone();
+            if let Some((key, value)) = write {
+                if let Err(e) = self.store(key, value) {
+                    // Tried again as more entries get committed.
+                    error!("[Raft] Couldn't apply entry {}: {:?}", index, e);
+                    break;
+                }
+            }
+            s.last_applied = index;
+        }
+        if s.log.len() > self.inner.config.max_log && s.last_applied > s.snapshot_index {
+            debug!("[Raft] Dropping entries up to {} from the log", s.last_applied);
+            let (index, term) = (s.last_applied, s.term_at(s.last_applied).unwrap_or(0));
+            let result = self.sync_backend().and_then(|()| {
+                s.compact(index, term);
+                s.save_all()
+            });
+            if let Err(e) = result {
+                error!("[Raft] Couldn't log the snapshot up to {}: {:?}", index, e);
+            }
+        }
+        self.inner.applied.notify_all();
+    }
+
+    /// Make the writes applied so far durable, if the storage node's
+    /// write-ahead log doesn't already.
+    fn sync_backend(&self) -> io::Result<()> {
+        if self.inner.wal.is_none() && self.inner.map.is_persistent() {
+            self.inner.map.sync()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Take `term`'s `leader` as the one to follow.
+    fn follow(&self, s: &mut RaftState, term: u64, leader: usize) -> io::Result<()> {
+        try!(s.step_down(term));
+        s.leader = Some(leader);
+        self.reset_election_timer(s);
+        Ok(())
+    }
+
+    fn observe(&self, value: &Value) {
+        if let Some(timestamp) = value.get_timestamp() {
+            let _ = self.inner.clock.update(timestamp);
+        }
+    }
+
+    pub fn request_vote(&self, term: u64, candidate: usize, last_log_index: u64, last_log_term: u64) -> InternodeResponse {
+        let mut s = self.inner.state.lock().unwrap();
+        let up_to_date = (last_log_term, last_log_index) >= (s.last_term(), s.last_index());
+        let mut result = if term > s.term {
+            s.step_down(term)
+        } else {
+            Ok(())
+        };
+        let granted = term == s.term && up_to_date && s.voted_for.map_or(true, |v| v == candidate);
+        if granted && result.is_ok() && s.voted_for.is_none() {
+            s.voted_for = Some(candidate);
+            result = s.save_term();
+            if result.is_err() {
+                s.voted_for = None;
+            }
+        }
+        if let Err(e) = result {
+            error!("[Raft] Couldn't log the vote for {} in term {}: {:?}", candidate, term, e);
+            return InternodeResponse::Vote {
+                term: s.term,
+                granted: false,
+            };
+        }
+        if granted {
+            self.reset_election_timer(&mut s);
+        }
+        InternodeResponse::Vote {
+            term: s.term,
+            granted: granted,
+        }
+    }
+
+    pub fn append_entries(&self,
+                          term: u64,
+                          leader: usize,
+                          prev_log_index: u64,
+                          prev_log_term: u64,
+                          entries: Vec<LogEntry>,
+                          leader_commit: u64)
+                          -> InternodeResponse {
+        let mut s = self.inner.state.lock().unwrap();
+        let fail = |s: &RaftState| {
+            InternodeResponse::AppendAck {
+                term: s.term,
+                success: false,
+                match_index: s.commit_index,
+            }
+        };
+        if term < s.term {
+            return fail(&s);
+        }
+        if let Err(e) = self.follow(&mut s, term, leader) {
+            error!("[Raft] Couldn't log term {}: {:?}", term, e);
+            return fail(&s);
+        }
+        // Entries before the snapshot are committed, and so match.
+        if prev_log_index > s.last_index() || s.term_at(prev_log_index).map_or(false, |t| t != prev_log_term) {
+            return fail(&s);
+        }
+
+        let mut index = prev_log_index;
+        let mut changed = None;
+        for entry in entries {
+            index += 1;
+            if index <= s.snapshot_index {
+                continue;
+            }
+            if let Some((_, ref value)) = entry.write {
+                // Leaders to come timestamp writes after every one in their
+                // log.
+                self.observe(value);
+            }
+            match s.term_at(index) {
+                Some(t) if t == entry.term => continue,
+                Some(_) => {
+                    let conflict = (index - s.snapshot_index - 1) as usize;
+                    s.log.truncate(conflict);
+                    s.log.push(entry);
+                }
+                None => s.log.push(entry),
+            }
+            changed = changed.or(Some(index));
+        }
+        if let Some(changed) = changed {
+            if let Err(e) = s.save_entries(changed) {
+                error!("[Raft] Couldn't log the entries from {}: {:?}", changed, e);
+                // Entries that were there before are still logged, which
+                // does no harm as long as they aren't acknowledged.
+                let kept = (changed - s.snapshot_index - 1) as usize;
+                s.log.truncate(kept);
+                return fail(&s);
+            }
+        }
+        if leader_commit > s.commit_index {
+            s.commit_index = cmp::max(s.commit_index, cmp::min(leader_commit, index));
+            self.apply(&mut s);
+        }
+        InternodeResponse::AppendAck {
+            term: s.term,
+            success: true,
+            match_index: index,
+        }
+    }
+
+    pub fn install_snapshot(&self,
+                            term: u64,
+                            leader: usize,
+                            last_index: u64,
+                            last_term: u64,
+                            entries: Vec<(Key, Value)>)
+                            -> InternodeResponse {
+        let mut s = self.inner.state.lock().unwrap();
+        let fail = |s: &RaftState| {
+            InternodeResponse::AppendAck {
+                term: s.term,
+                success: false,
+                match_index: s.commit_index,
+            }
+        };
+        if term < s.term {
+            return fail(&s);
+        }
+        if let Err(e) = self.follow(&mut s, term, leader) {
+            error!("[Raft] Couldn't log term {}: {:?}", term, e);
+            return fail(&s);
+        }
+        if last_index > s.last_applied {
+            debug!("[Raft] Installing a snapshot up to {} with {} entries", last_index, entries.len());
+            for (key, value) in entries {
+                self.observe(&value);
+                if let Err(e) = self.store(key, value) {
+                    error!("[Raft] Couldn't install the snapshot up to {}: {:?}", last_index, e);
+                    return fail(&s);
+                }
+            }
+            if let Err(e) = self.sync_backend() {
+                error!("[Raft] Couldn't install the snapshot up to {}: {:?}", last_index, e);
+                return fail(&s);
+            }
+            s.compact(last_index, last_term);
+            s.commit_index = cmp::max(s.commit_index, last_index);
+            s.last_applied = last_index;
+            if let Err(e) = s.save_all() {
+                error!("[Raft] Couldn't log the snapshot up to {}: {:?}", last_index, e);
+                return fail(&s);
+            }
+            self.apply(&mut s);
+        }
+        InternodeResponse::AppendAck {
+            term: s.term,
+            success: true,
+            match_index: last_index,
+        }
+    }
+
+    fn not_leader(&self, s: &RaftState) -> RaftError {
+        RaftError::NotLeader(s.leader.map(|l| self.inner.nodes[l]))
+    }
+
+    /// Wait for the entry at `index` to be applied.
+    fn wait_applied<'a>(&'a self, index: u64) -> Result<MutexGuard<'a, RaftState>, RaftError> {
+        let deadline = Instant::now() + self.inner.config.commit_timeout;
+        let mut s = self.inner.state.lock().unwrap();
+        while s.last_applied < index {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RaftError::Unavailable(format!("Entry {} wasn't committed in time", index)));
+            }
+            s = self.inner.applied.wait_timeout(s, deadline - now).unwrap().0;
+        }
+        Ok(s)
+    }
+
+    /// Write `content` for `key`, expiring after `ttl` milliseconds if there's
+    /// one, or delete it if there's none. Returns the timestamp it was
+    /// written with once it's applied.
+    pub fn write(&self, key: &Key, content: Option<Buffer>, ttl: Option<u64>) -> Result<u64, RaftError> {
+        let (index, term, timestamp) = {
+            let mut s = self.inner.state.lock().unwrap();
+            if s.role != Role::Leader {
+                return Err(self.not_leader(&s));
+            }
+            let timestamp = self.inner.clock.now();
+            let value = match content {
+                Some(content) => {
+                    Value::Value {
+                        content: content,
+                        timestamp: timestamp,
+                        expires: ttl.map(|ttl| hlc::expiry(timestamp, ttl)),
+                    }
+                }
+                None => Value::Tombstone { timestamp: timestamp },
+            };
+            let term = s.term;
+            s.log.push(LogEntry {
+                term: term,
+                write: Some((key.to_owned(), value)),
+            });
+            let index = s.last_index();
+            if let Err(e) = s.save_entries(index) {
+                s.log.pop();
+                return Err(RaftError::Unavailable(format!("Couldn't log the write: {:?}", e)));
+            }
+            self.advance_commit(&mut s);
+            (s.last_index(), term, timestamp)
+        };
+        self.replicate();
+        let s = try!(self.wait_applied(index));
+        match s.term_at(index) {
+            Some(t) if t == term => Ok(timestamp),
+            // Another leader's entry took its place.
+            Some(_) => Err(RaftError::Unavailable("Leadership was lost before committing".to_string())),
+            // Compacted away, which only tells whose entry it was if this
+            // node never stopped leading since: leaders don't overwrite their
+            // own entries.
+            None if s.role == Role::Leader && s.term == term => Ok(timestamp),
+            None => Err(RaftError::Unavailable("Leadership was lost before knowing if the write committed".to_string())),
+        }
+    }
+
+    /// Read `key` once every write committed before is applied, after making
+    /// sure with a round of heartbeats that this node is still the leader.
+    pub fn read(&self, key: &Key) -> Result<Value, RaftError> {
+        let (term, read_index, requests) = {
+            // Only once it committed an entry of its own term does a leader
+            // know for sure what's committed.
+            let deadline = Instant::now() + self.inner.config.commit_timeout;
+            let mut s = self.inner.state.lock().unwrap();
+            loop {
+                if s.role != Role::Leader {
+                    return Err(self.not_leader(&s));
+                }
+                if s.term_at(s.commit_index) == Some(s.term) {
+                    break;
+                }
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(RaftError::Unavailable("Nothing was committed in this term yet".to_string()));
+                }
+                s = self.inner.applied.wait_timeout(s, deadline - now).unwrap().0;
+            }
+            let requests: Vec<(usize, InternodeRequest)> = self.others()
+                                                               .into_iter()
+                                                               .map(|i| (i, self.heartbeat(&s, i)))
+                                                               .collect();
+            (s.term, s.commit_index, requests)
+        };
+
+        // A majority still following this node means no other leader could
+        // have committed anything since.
+        let (sender, receiver) = mpsc::channel();
+        for (i, request) in requests {
+            let raft = self.clone();
+            let sender = sender.clone();
+            let response: Future<InternodeResponse, Error> = self.inner.pool.send_to_node(&self.inner.nodes[i],
+                                                                                         &request,
+                                                                                         self.rpc_timeout());
+            response.receive(move |r| {
+                let mut follows = false;
+                if let Ok(InternodeResponse::AppendAck {term: t, success, match_index}) = r {
+                    raft.on_append_ack(i, term, t, success, match_index);
+                    follows = t == term;
+                }
+                let _ = sender.send(follows);
+            });
+        }
+        drop(sender);
+        let mut acks = 1;
+        if acks < self.majority() {
+            for follows in receiver.iter() {
+                if follows {
+                    acks += 1;
+                    if acks >= self.majority() {
+                        break;
+                    }
+                }
+            }
+        }
+        if acks < self.majority() {
+            return Err(RaftError::Unavailable("A majority didn't confirm the leadership".to_string()));
+        }
+        let _s = try!(self.wait_applied(read_index));
+        Ok(self.inner.map.get(key).unwrap_or(Value::None))
+    }
+}
+
+/// How long to wait to hear from a leader before standing for election.
+fn election_timeout(config: &RaftConfig) -> Duration {
+    let base = config.election_timeout;
+    let millis = base.as_secs() * 1000 + base.subsec_nanos() as u64 / 1_000_000;
+    base + Duration::from_millis(coordinator::random() % (millis + 1))
+}
@@ -0,0 +1,189 @@
+use constants::{REBALANCE_TIMEOUT_MS, TRANSFER_BATCH_SIZE};
+use eventual::*;
+use message::{Action, Consistency, Error, InternodeRequest, InternodeResponse, Key, Request,
+              Response, ResponseMessage, Result, Ring, Value};
+use pool::ConnectionPool;
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+use std::time::Duration;
+use storage::StorageBackend;
+
+/// The cluster topology a node routes requests with.
+#[derive(Debug, Clone)]
+pub struct Topology {
+    /// The ring in effect.
+    pub ring: Arc<Ring>,
+    /// The ring being moved to, while a reshard is in progress.
+    pub pending: Option<Arc<Ring>>,
+}
+
+impl Topology {
+    pub fn new(ring: Ring) -> Topology {
+        Topology {
+            ring: Arc::new(ring),
+            pending: None,
+        }
+    }
+
+    /// Whether the storage node at `address` is a replica for `key` in either
+    /// the current or the pending ring.
+    pub fn owns(&self, address: &SocketAddrV4, key: &Key) -> bool {
+        self.ring.owns(address, key) ||
+        self.pending.as_ref().map(|r| r.owns(address, key)).unwrap_or(false)
+    }
+
+    /// Replicas for `key` in the current ring, followed by its replicas in the
+    /// pending ring when those differ.
+    pub fn replica_sets(&self, key: &Key) -> Vec<Vec<SocketAddrV4>> {
+        let current = self.ring.preference_list(key);
+        let mut sets = vec![];
+        if let Some(ref pending) = self.pending {
+            let mut next = pending.preference_list(key);
+            let mut sorted = current.clone();
+            sorted.sort();
+            next.sort();
+            if next != sorted {
+                sets.push(pending.preference_list(key));
+            }
+        }
+        sets.insert(0, current);
+        sets
+    }
+
+    /// Start moving to `ring`.
+    pub fn prepare(&mut self, ring: Ring) {
+        info!("Preparing topology change to {:?}", ring);
+        self.pending = Some(Arc::new(ring));
+    }
+
+    /// Finish moving to `ring`.
+    pub fn commit(&mut self, ring: Ring) {
+        info!("Committing topology {:?}", ring);
+        self.ring = Arc::new(ring);
+        self.pending = None;
+    }
+}
+
+fn timeout() -> Option<Duration> {
+    Some(Duration::from_millis(REBALANCE_TIMEOUT_MS))
+}
+
+/// Send `request` to every storage node in `nodes`, failing if any of them
+/// doesn't acknowledge it. Returns the sum of all transferred entries.
+fn send_to_nodes(pool: &ConnectionPool,
+                 nodes: &Vec<SocketAddrV4>,
+                 request: &InternodeRequest)
+                 -> Result<usize> {
+    let responses: Vec<Future<InternodeResponse, Error>> = nodes.iter()
+        .map(|node| pool.send_to_node(node, request, timeout()))
+        .collect();
+    let mut count = 0;
+    for (node, response) in nodes.iter().zip(responses) {
+        match response.await() {
+            Ok(InternodeResponse::TopologyAck) => (),
+            Ok(InternodeResponse::TransferAck {count: c}) => count += c,
+            r => {
+                error!("Storage node {:?} failed {:?}: {:?}", node, request, r);
+                return Err(Error::TopologyError);
+            }
+        }
+    }
+    Ok(count)
+}
+
+/// Send `action` to every handler in `handlers`, failing if any of them
+/// doesn't acknowledge it.
+fn send_to_handlers(pool: &ConnectionPool, handlers: &Vec<SocketAddrV4>, action: Action) -> Result<()> {
+    let request = Request::new(action, Consistency::Latest);
+    for handler in handlers {
+        let r: Future<ResponseMessage, Error> = pool.send_to_node(handler, &request, timeout());
+        match r.await() {
+            Ok(ResponseMessage {message: Response::TopologyAck, ..}) => (),
+            r => {
+                error!("Handler {:?} failed {:?}: {:?}", handler, request, r);
+                return Err(Error::TopologyError);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Move the cluster served by `handlers` from the `old` to the `new` ring.
+///
+/// Every storage node is first told to accept `Key`s of the new ring, and
+/// every handler to double-write to the replicas of both rings. Then the
+/// storage nodes of the old ring stream their entries to the replicas the
+/// new ring adds, and finally the new ring is committed everywhere.
+///
+/// Storage nodes that only appear in `new` must already be listening. If
+/// any step fails the cluster is left double-writing, and `reshard` can
+/// safely be run again.
+pub fn reshard(pool: &ConnectionPool,
+               handlers: &Vec<SocketAddrV4>,
+               old: &Ring,
+               new: &Ring)
+               -> Result<usize> {
+    let mut nodes = old.nodes();
+    for node in new.nodes() {
+        if !nodes.contains(&node) {
+            nodes.push(node);
+        }
+    }
+
+    try!(send_to_nodes(pool, &nodes, &InternodeRequest::PrepareTopology { ring: new.to_owned() }));
+    try!(send_to_handlers(pool, handlers, Action::PrepareTopology { ring: new.to_owned() }));
+    let count = try!(send_to_nodes(pool,
+                                   &old.nodes(),
+                                   &InternodeRequest::Rebalance { ring: new.to_owned() }));
+    info!("Streamed {} entries to their new replicas", count);
+    try!(send_to_handlers(pool, handlers, Action::CommitTopology { ring: new.to_owned() }));
+    try!(send_to_nodes(pool, &nodes, &InternodeRequest::CommitTopology { ring: new.to_owned() }));
+    Ok(count)
+}
+
+/// Stream every entry in `map` that `new` assigns to replicas it didn't have
+/// in `current` to those replicas. Returns the amount of entries sent.
+pub fn stream_entries<Backend: StorageBackend>(pool: &ConnectionPool,
+                                                map: &Backend,
+                                                current: &Ring,
+                                                new: &Ring)
+                                                -> Result<usize> {
+    let mut batches: HashMap<SocketAddrV4, Vec<(Key, Value)>> = HashMap::new();
+    let mut responses: Vec<Future<InternodeResponse, Error>> = vec![];
+    let mut count = 0;
+
+    for (key, value) in map.entries() {
+        let old_replicas = current.preference_list(&key);
+        for node in new.preference_list(&key) {
+            if old_replicas.contains(&node) {
+                continue;
+            }
+            let full = {
+                let batch = batches.entry(node).or_insert(vec![]);
+                batch.push((key.to_owned(), value.to_owned()));
+                batch.len() >= TRANSFER_BATCH_SIZE
+            };
+            if full {
+                let entries = batches.remove(&node).unwrap();
+                count += entries.len();
+                responses.push(pool.send_to_node(&node, &InternodeRequest::Transfer { entries: entries }, timeout()));
+            }
+        }
+    }
+    for (node, entries) in batches {
+        count += entries.len();
+        responses.push(pool.send_to_node(&node, &InternodeRequest::Transfer { entries: entries }, timeout()));
+    }
+
+    for response in responses {
+        match response.await() {
+            Ok(InternodeResponse::TransferAck {..}) => (),
+            r => {
+                error!("Transfer failed: {:?}", r);
+                return Err(Error::TopologyError);
+            }
+        }
+    }
+    Ok(count)
+}
@@ -1,41 +1,126 @@
-use std::collections::HashMap;
-use message::{Key, Value};
+use bincode::SizeLimit;
+use bincode::rustc_serialize::{encode, decode};
+use constants::SEGMENT_SIZE;
+use std::collections::BTreeMap;
+use message::{Buffer, Key, Value};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 use std::fmt::Debug;
 
 /// A generic storage backend for `StorageNode`s to use as persistence layer.
 pub trait StorageBackend where Self: Debug + Send + Sync {
     fn new() -> Self;
+    /// Open a backend persisting its data in `dir`, recovering whatever was
+    /// stored there before. Backends that don't persist anything ignore
+    /// `dir`.
+    fn open(_dir: &Path) -> io::Result<Self>
+        where Self: Sized
+    {
+        Ok(Self::new())
+    }
     /// Persist `value` under `key`.
-    fn insert(&self, key: Key, value: Value);
+    fn insert(&self, key: Key, value: Value) -> io::Result<()>;
+    /// Persist `value` under `key` only if `expected` accepts the `Value`
+    /// stored there, if any, with nothing written to `key` in between.
+    /// Returns whether it was stored.
+    fn compare_and_set(&self, key: Key, value: Value, expected: &Fn(Option<&Value>) -> bool) -> io::Result<bool>;
+    /// Persist `value` under `key` only if it's newer than the `Value` stored
+    /// there, so that the last writer wins no matter the order writes arrive
+    /// in. Returns whether it was stored.
+    fn insert_if_newer(&self, key: Key, value: Value) -> io::Result<bool> {
+        let timestamp = value.get_timestamp();
+        self.compare_and_set(key, value, &|current| current.and_then(|v| v.get_timestamp()) < timestamp)
+    }
+    /// Persist what `Value::merge` makes of `value` and the `Value` stored
+    /// under `key`: the newest of both, or every sibling of both that isn't
+    /// superseded if they're versioned. Returns whether anything changed.
+    fn merge(&self, key: Key, value: Value) -> io::Result<bool> {
+        loop {
+            let current = self.get(&key);
+            let merged = match Value::merge(current.as_ref(), &value) {
+                Some(merged) => merged,
+                None => return Ok(false),
+            };
+            // Someone else might have written in between, so try again.
+            if try!(self.compare_and_set(key.to_owned(), merged, &|v| v == current.as_ref())) {
+                return Ok(true);
+            }
+        }
+    }
     /// Get a `Value` for the given `key`.
     fn get(&self, key: &Key) -> Option<Value>;
+    /// Get a snapshot of every stored entry.
+    fn entries(&self) -> Vec<(Key, Value)>;
+    /// Call `f` with every stored entry, in no particular order. Backends
+    /// able to walk their entries in place avoid copying them all.
+    fn for_each(&self, f: &mut FnMut(&Key, &Value)) {
+        for (key, value) in self.entries() {
+            f(&key, &value);
+        }
+    }
+    /// Get up to `limit` entries with a `Key` from `start` (inclusive) to
+    /// `end` (exclusive), in `Key` order, or in reverse order if `reverse`.
+    fn scan(&self, start: &Key, end: &Key, limit: usize, reverse: bool) -> Vec<(Key, Value)> {
+        let mut entries: Vec<(Key, Value)> = self.entries()
+                                                 .into_iter()
+                                                 .filter(|&(ref k, _)| k >= start && k < end)
+                                                 .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        if reverse {
+            entries.reverse();
+        }
+        entries.truncate(limit);
+        entries
+    }
+    /// Whether entries survive a restart once `sync` returned.
+    fn is_persistent(&self) -> bool {
+        false
+    }
+    /// Make every insert so far durable.
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
-/// A basic `HashMap` based backend for in-memory `StorageNode`s.
+/// A basic backend for in-memory `StorageNode`s. Entries are kept sorted by
+/// `Key` in a `BTreeMap`, so that scans don't have to copy and sort them all.
 #[derive(Debug)]
 pub struct HashMapBackend {
-    hashmap: Mutex<HashMap<Key, Value>>,
+    map: Mutex<BTreeMap<Key, Value>>,
 }
 
 impl StorageBackend for HashMapBackend {
     fn new() -> HashMapBackend {
         debug!("New HashMapBackend");
-        let hm = HashMap::new();
-        HashMapBackend { hashmap: Mutex::new(hm) }
+        HashMapBackend { map: Mutex::new(BTreeMap::new()) }
     }
 
-    fn insert(&self, key: Key, value: Value) {
+    fn insert(&self, key: Key, value: Value) -> io::Result<()> {
         debug!("[HashMapBackend] Going to insert {:?}, {:?}", key, value);
-        let lock = self.hashmap.lock();
+        let lock = self.map.lock();
         let mut map = lock.unwrap();
         map.insert(key, value.clone());
         debug!("[HashMapBackend] inserted {:?}", value);
+        Ok(())
+    }
+
+    fn compare_and_set(&self, key: Key, value: Value, expected: &Fn(Option<&Value>) -> bool) -> io::Result<bool> {
+        let mut map = self.map.lock().unwrap();
+        if !expected(map.get(&key)) {
+            return Ok(false);
+        }
+        map.insert(key, value);
+        Ok(true)
     }
 
     fn get(&self, key: &Key) -> Option<Value> {
         debug!("[HashMapBackend] Going to read {:?}", key);
-        let lock = self.hashmap.lock();
+        let lock = self.map.lock();
         let map = lock.unwrap();
         let value = map.get(key);
         debug!("[HashMapBackend] Value read {:?}", value);
@@ -44,6 +129,366 @@ impl StorageBackend for HashMapBackend {
             None => None,
         }
     }
+
+    fn entries(&self) -> Vec<(Key, Value)> {
+        let lock = self.map.lock();
+        let map = lock.unwrap();
+        map.iter().map(|(k, v)| (k.to_owned(), v.to_owned())).collect()
+    }
+
+    fn for_each(&self, f: &mut FnMut(&Key, &Value)) {
+        let map = self.map.lock().unwrap();
+        for (key, value) in map.iter() {
+            f(key, value);
+        }
+    }
+
+    fn scan(&self, start: &Key, end: &Key, limit: usize, reverse: bool) -> Vec<(Key, Value)> {
+        if start >= end {
+            return vec![];
+        }
+        let map = self.map.lock().unwrap();
+        let range = map.range(start.to_owned()..end.to_owned());
+        if reverse {
+            range.rev().take(limit).map(|(k, v)| (k.to_owned(), v.to_owned())).collect()
+        } else {
+            range.take(limit).map(|(k, v)| (k.to_owned(), v.to_owned())).collect()
+        }
+    }
 }
 
 unsafe impl Sync for HashMapBackend {}
+
+/// Size in bytes of a `LogBackend` record header: payload length and CRC-32.
+const RECORD_HEADER_SIZE: u64 = 8;
+
+static TEMP_DIRS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// CRC-32 (IEEE) checksum of `buf`.
+pub fn crc32(buf: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for &byte in buf {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xedb88320 & mask);
+        }
+    }
+    !crc
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    buf.iter().take(4).fold(0, |acc, &b| (acc << 8) | b as u32)
+}
+
+fn write_u32(buf: &mut Vec<u8>, n: u32) {
+    for i in 0..4 {
+        buf.push((n >> (24 - 8 * i)) as u8);
+    }
+}
+
+/// Frame `payload` as a record: its length and CRC-32 followed by the
+/// payload itself.
+pub fn record(payload: &[u8]) -> Buffer {
+    let mut record = Vec::with_capacity(RECORD_HEADER_SIZE as usize + payload.len());
+    write_u32(&mut record, payload.len() as u32);
+    write_u32(&mut record, crc32(payload));
+    record.extend(payload.iter());
+    record
+}
+
+/// Split `buf` into the payloads of the records it holds, along with the
+/// offset of each payload. Stops at the first torn or corrupt record, and
+/// also returns where the last intact one ends.
+pub fn read_records(buf: &[u8]) -> (Vec<(u64, &[u8])>, u64) {
+    let mut records = vec![];
+    let mut offset = 0;
+    while offset + RECORD_HEADER_SIZE as usize <= buf.len() {
+        let len = read_u32(&buf[offset..]) as usize;
+        let checksum = read_u32(&buf[offset + 4..]);
+        let start = offset + RECORD_HEADER_SIZE as usize;
+        if start + len > buf.len() || crc32(&buf[start..start + len]) != checksum {
+            break;
+        }
+        records.push((start as u64, &buf[start..start + len]));
+        offset = start + len;
+    }
+    (records, offset as u64)
+}
+
+/// A new empty directory for a backend to keep its data in.
+pub fn temp_dir() -> PathBuf {
+    let dir = env::temp_dir().join(format!("sbahn-{}-{}",
+                                           process::id(),
+                                           TEMP_DIRS.fetch_add(1, Ordering::SeqCst)));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+/// Location of a record's payload in a `LogBackend` segment.
+#[derive(Debug, Clone, Copy)]
+struct RecordPointer {
+    segment: u64,
+    offset: u64,
+    len: u32,
+}
+
+#[derive(Debug)]
+struct LogState {
+    dir: PathBuf,
+    segment_size: u64,
+    /// Every segment file, by id.
+    segments: BTreeMap<u64, File>,
+    /// Id of the segment being appended to.
+    active: u64,
+    /// Size of the segment being appended to.
+    active_len: u64,
+    /// Where to find the latest record for each `Key`.
+    index: BTreeMap<Key, RecordPointer>,
+}
+
+/// A durable backend appending every insert to a log of segment files.
+///
+/// Each record holds a binary encoded `(Key, Value)`, preceded by its length
+/// and CRC-32. Only the location of each `Key`'s latest record is kept in
+/// memory, and it is rebuilt when opening the log by replaying every
+/// segment. A torn or corrupt record at the end of a segment, left behind by
+/// a crash in the middle of a write, is discarded.
+///
+/// Writes return I/O errors, leaving the log as it was before. Reads panic on
+/// them, once the log is unlocked for other requests.
+#[derive(Debug)]
+pub struct LogBackend {
+    state: Mutex<LogState>,
+}
+
+impl LogBackend {
+    /// Open the log in `dir`, starting a new segment once the current one
+    /// grows over `segment_size` bytes.
+    pub fn open_with_segment_size(dir: &Path, segment_size: u64) -> io::Result<LogBackend> {
+        try!(fs::create_dir_all(dir));
+        let mut ids: Vec<u64> = vec![];
+        for entry in try!(fs::read_dir(dir)) {
+            let name = try!(entry).file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with("segment-") && name.ends_with(".log") {
+                if let Ok(id) = name[8..name.len() - 4].parse() {
+                    ids.push(id);
+                }
+            }
+        }
+        ids.sort();
+
+        let mut state = LogState {
+            dir: dir.to_owned(),
+            segment_size: segment_size,
+            segments: BTreeMap::new(),
+            active: 0,
+            active_len: 0,
+            index: BTreeMap::new(),
+        };
+        for id in ids {
+            let mut file = try!(OpenOptions::new().read(true).write(true).open(state.segment_path(id)));
+            let len = try!(state.replay(id, &mut file));
+            state.segments.insert(id, file);
+            state.active = id;
+            state.active_len = len;
+        }
+        if state.segments.is_empty() {
+            try!(state.create_segment(0));
+        }
+        debug!("[LogBackend] Opened {:?} with {} keys", dir, state.index.len());
+        Ok(LogBackend { state: Mutex::new(state) })
+    }
+}
+
+impl LogState {
+    fn segment_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("segment-{:08}.log", id))
+    }
+
+    fn create_segment(&mut self, id: u64) -> io::Result<()> {
+        let file = try!(OpenOptions::new()
+                            .read(true)
+                            .write(true)
+                            .create(true)
+                            .open(self.segment_path(id)));
+        self.segments.insert(id, file);
+        self.active = id;
+        self.active_len = 0;
+        Ok(())
+    }
+
+    /// Index every valid record in segment `id`, truncating the segment
+    /// after the last one. Returns the segment's resulting size.
+    fn replay(&mut self, id: u64, file: &mut File) -> io::Result<u64> {
+        let mut buf = vec![];
+        try!(file.read_to_end(&mut buf));
+        let (records, end) = read_records(&buf);
+        for (offset, payload) in records {
+            let (key, _): (Key, Value) = match decode(payload) {
+                Ok(r) => r,
+                Err(_) => panic!("[LogBackend] Undecodable record at {} in segment {}", offset, id),
+            };
+            self.index.insert(key,
+                              RecordPointer {
+                                  segment: id,
+                                  offset: offset,
+                                  len: payload.len() as u32,
+                              });
+        }
+        if end < buf.len() as u64 {
+            error!("[LogBackend] Discarding {} bytes of torn record at the end of segment {}",
+                   buf.len() as u64 - end,
+                   id);
+            try!(file.set_len(end));
+        }
+        Ok(end)
+    }
+
+    fn append(&mut self, key: Key, value: Value) -> io::Result<()> {
+        let payload = match encode(&(&key, &value), SizeLimit::Infinite) {
+            Ok(p) => p,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))),
+        };
+        let record_len = RECORD_HEADER_SIZE + payload.len() as u64;
+        if self.active_len > 0 && self.active_len + record_len > self.segment_size {
+            let next = self.active + 1;
+            try!(self.create_segment(next));
+        }
+
+        let record = record(&payload);
+        let offset = self.active_len;
+        {
+            let file = self.segments.get_mut(&self.active).unwrap();
+            try!(file.seek(SeekFrom::Start(offset)));
+            try!(file.write_all(&record));
+            try!(file.flush());
+        }
+        self.active_len += record_len;
+        self.index.insert(key,
+                          RecordPointer {
+                              segment: self.active,
+                              offset: offset + RECORD_HEADER_SIZE,
+                              len: payload.len() as u32,
+                          });
+        Ok(())
+    }
+
+    fn read_all(&mut self, pointers: Vec<(Key, RecordPointer)>) -> io::Result<Vec<(Key, Value)>> {
+        let mut entries = Vec::with_capacity(pointers.len());
+        for (key, pointer) in pointers {
+            let value = try!(self.read(pointer));
+            entries.push((key, value));
+        }
+        Ok(entries)
+    }
+
+    fn read(&mut self, pointer: RecordPointer) -> io::Result<Value> {
+        let file = self.segments.get_mut(&pointer.segment).unwrap();
+        try!(file.seek(SeekFrom::Start(pointer.offset)));
+        let mut payload = vec![0; pointer.len as usize];
+        try!(file.read_exact(&mut payload));
+        match decode::<(Key, Value)>(&payload) {
+            Ok((_, value)) => Ok(value),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))),
+        }
+    }
+}
+
+impl StorageBackend for LogBackend {
+    /// A log in a new temporary directory.
+    fn new() -> LogBackend {
+        let dir = temp_dir();
+        match LogBackend::open(&dir) {
+            Ok(l) => l,
+            Err(e) => panic!("Couldn't create log at {:?}: {:?}", dir, e),
+        }
+    }
+
+    fn open(dir: &Path) -> io::Result<LogBackend> {
+        LogBackend::open_with_segment_size(dir, SEGMENT_SIZE)
+    }
+
+    fn insert(&self, key: Key, value: Value) -> io::Result<()> {
+        debug!("[LogBackend] Going to insert {:?}, {:?}", key, value);
+        self.state.lock().unwrap().append(key, value)
+    }
+
+    fn compare_and_set(&self, key: Key, value: Value, expected: &Fn(Option<&Value>) -> bool) -> io::Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        let current = match state.index.get(&key).cloned() {
+            Some(pointer) => Some(try!(state.read(pointer))),
+            None => None,
+        };
+        if !expected(current.as_ref()) {
+            return Ok(false);
+        }
+        try!(state.append(key, value));
+        Ok(true)
+    }
+
+    fn get(&self, key: &Key) -> Option<Value> {
+        debug!("[LogBackend] Going to read {:?}", key);
+        let result = {
+            let mut state = self.state.lock().unwrap();
+            match state.index.get(key).cloned() {
+                Some(pointer) => state.read(pointer).map(Some),
+                None => Ok(None),
+            }
+        };
+        match result {
+            Ok(value) => value,
+            Err(e) => panic!("[LogBackend] Couldn't read {:?}: {:?}", key, e),
+        }
+    }
+
+    fn entries(&self) -> Vec<(Key, Value)> {
+        let result = {
+            let mut state = self.state.lock().unwrap();
+            let pointers: Vec<(Key, RecordPointer)> = state.index
+                                                           .iter()
+                                                           .map(|(k, p)| (k.to_owned(), *p))
+                                                           .collect();
+            state.read_all(pointers)
+        };
+        match result {
+            Ok(entries) => entries,
+            Err(e) => panic!("[LogBackend] Couldn't read entries: {:?}", e),
+        }
+    }
+
+    fn scan(&self, start: &Key, end: &Key, limit: usize, reverse: bool) -> Vec<(Key, Value)> {
+        if start >= end {
+            return vec![];
+        }
+        let result = {
+            let mut state = self.state.lock().unwrap();
+            let pointers: Vec<(Key, RecordPointer)> = {
+                let range = state.index.range(start.to_owned()..end.to_owned());
+                if reverse {
+                    range.rev().take(limit).map(|(k, p)| (k.to_owned(), *p)).collect()
+                } else {
+                    range.take(limit).map(|(k, p)| (k.to_owned(), *p)).collect()
+                }
+            };
+            state.read_all(pointers)
+        };
+        match result {
+            Ok(entries) => entries,
+            Err(e) => panic!("[LogBackend] Couldn't scan from {:?}: {:?}", start, e),
+        }
+    }
+
+    fn is_persistent(&self) -> bool {
+        true
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let state = self.state.lock().unwrap();
+        for segment in state.segments.values() {
+            try!(segment.sync_data());
+        }
+        Ok(())
+    }
+}
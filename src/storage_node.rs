@@ -1,78 +1,684 @@
+use anti_entropy::MerkleTree;
 use bincode::SizeLimit;
+use constants::{ANTI_ENTROPY_INTERVAL_MS, ANTI_ENTROPY_TIMEOUT_MS, EXPIRY_SWEEP_INTERVAL_MS, MAX_MERKLE_DEPTH,
+                MERKLE_DEPTH, RAFT_COMMIT_TIMEOUT_MS};
 use bincode::rustc_serialize::{encode, decode};
-use message::{Buffer, Key, Value, InternodeRequest, InternodeResponse};
-use network::NetworkRead;
-use std::io::Write;
+use coordinator;
+use eventual::*;
+use hlc::{self, Clock};
+use message::{Buffer, Error, Key, Value, InternodeRequest, InternodeResponse, Result, Ring};
+use network::{NetworkRead, NetworkWrite};
+use paxos::Acceptor;
+use raft::{Raft, RaftConfig, RaftError};
+use pool::ConnectionPool;
+use rebalance::{self, Topology};
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::net::{SocketAddrV4, TcpListener, TcpStream};
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::Duration;
 use storage::StorageBackend;
+use vclock;
+use wal::{self, SyncPolicy, Wal};
 
 pub struct StorageNode<Backend: StorageBackend + 'static> {
-    pub shard: usize,
-    pub shard_count: usize,
+    /// Cluster topology, used to check which `Key`s this node is a replica
+    /// for.
+    pub topology: Arc<RwLock<Topology>>,
     pub address: SocketAddrV4,
     pub map: Arc<Backend>,
+    /// Directory the node keeps its data in, if any.
+    pub data_dir: Option<PathBuf>,
+    /// Connections to other storage nodes, used while resharding.
+    pub pool: Arc<ConnectionPool>,
+    /// Log every write goes through before being acknowledged, if any.
+    pub wal: Option<Wal>,
+    /// Time between anti-entropy rounds with the other replicas of the
+    /// shard, if they're performed at all.
+    pub anti_entropy_interval: Option<Duration>,
+    /// Time between sweeps replacing expired values with deletions, if
+    /// they're swept at all. Reads ignore expired values either way.
+    pub expiry_sweep_interval: Option<Duration>,
+    /// Keeps up with the timestamps of the writes received, refusing those
+    /// too far ahead of it. Its node ID is kept in the data directory if
+    /// there's one; a configured one can be set before listening.
+    pub clock: Arc<Clock>,
+    /// Takes part in the Paxos rounds of compare-and-sets.
+    pub acceptor: Arc<Acceptor>,
+    /// Takes part in the Raft group of the shard, if enabled.
+    pub raft: Option<Raft<Backend>>,
+    /// Vector clock IDs of the nodes this one granted a node ID to, by ID.
+    node_ids: Arc<Mutex<HashMap<u16, u64>>>,
 }
 
+/// Performs the `InternodeRequest`s sent to a `StorageNode`.
 #[derive(Debug)]
 struct ClientHandler<Backend: StorageBackend + 'static> {
-    stream: TcpStream,
-    shard: usize,
-    shard_count: usize,
+    address: SocketAddrV4,
+    topology: Arc<RwLock<Topology>>,
     map: Arc<Backend>,
+    pool: Arc<ConnectionPool>,
+    wal: Option<Wal>,
+    clock: Arc<Clock>,
+    acceptor: Arc<Acceptor>,
+    raft: Option<Raft<Backend>>,
+    node_ids: Arc<Mutex<HashMap<u16, u64>>>,
+}
+
+impl<Backend: StorageBackend + 'static> Clone for ClientHandler<Backend> {
+    fn clone(&self) -> ClientHandler<Backend> {
+        ClientHandler::new(self.map.clone(),
+                           &self.address,
+                           self.topology.clone(),
+                           self.pool.clone(),
+                           self.wal.clone(),
+                           self.clock.clone(),
+                           self.acceptor.clone(),
+                           self.raft.clone(),
+                           self.node_ids.clone())
+    }
 }
 
 impl<Backend: StorageBackend + 'static> ClientHandler<Backend> {
-    fn new(stream: TcpStream,
-           map: Arc<Backend>,
-           shard_number: usize,
-           shard_count: usize)
+    fn new(map: Arc<Backend>,
+           address: &SocketAddrV4,
+           topology: Arc<RwLock<Topology>>,
+           pool: Arc<ConnectionPool>,
+           wal: Option<Wal>,
+           clock: Arc<Clock>,
+           acceptor: Arc<Acceptor>,
+           raft: Option<Raft<Backend>>,
+           node_ids: Arc<Mutex<HashMap<u16, u64>>>)
            -> ClientHandler<Backend> {
         ClientHandler {
-            stream: stream,
-            shard: shard_number,
-            shard_count: shard_count,
+            address: address.to_owned(),
+            topology: topology,
             map: map,
+            pool: pool,
+            wal: wal,
+            clock: clock,
+            acceptor: acceptor,
+            raft: raft,
+            node_ids: node_ids,
         }
     }
 
-    pub fn handle_client(&mut self) {
-        let mut value: Buffer = vec![];
-        if self.stream.read_to_message_end(&mut value).is_err() {
-            panic!("Couldn't read from stream.");
-        }
+    /// Merge `value` into the one stored under `key`, through the write-ahead
+    /// log if there is one. Returns whether anything changed, once the write
+    /// is durable.
+    fn store(&self, key: Key, value: Value) -> io::Result<bool> {
+        wal::store(self.wal.as_ref(), &*self.map, key, value)
+    }
 
-        let m: InternodeRequest = match decode(&value) {
-            Ok(m) => m,
-            Err(_) => panic!("decoding error!"),
+    /// Perform every request received through `stream`. Each one is handled
+    /// on its own thread and answered with its request id as soon as it's
+    /// done.
+    pub fn handle_client(&self, mut stream: TcpStream) {
+        let writer = match stream.try_clone() {
+            Ok(s) => Arc::new(Mutex::new(s)),
+            Err(e) => {
+                error!("Couldn't clone stream: {:?}", e);
+                return;
+            }
         };
 
-        debug!("Message received: {:?}", m);
-        let response: InternodeResponse = self.handle_message(m);
-        let encoded = encode(&response, SizeLimit::Infinite);
-        let _ = match encoded {
-            Ok(b) => self.stream.write(&b),
-            Err(_) => panic!("encoding error! {:?}", response),
-        };
+        loop {
+            let (request_id, value): (u64, Buffer) = match stream.read_message() {
+                Ok(m) => m,
+                Err(e) => {
+                    debug!("Connection closed: {:?}", e);
+                    break;
+                }
+            };
+
+            let m: InternodeRequest = match decode(&value) {
+                Ok(m) => m,
+                Err(_) => panic!("decoding error!"),
+            };
+
+            debug!("Message received: {:?}", m);
+            let handler = self.clone();
+            let writer = writer.clone();
+            thread::spawn(move || {
+                let response: InternodeResponse = handler.handle_message(m);
+                let encoded = encode(&response, SizeLimit::Infinite);
+                let _ = match encoded {
+                    Ok(b) => writer.lock().unwrap().write_message(request_id, &b),
+                    Err(_) => panic!("encoding error! {:?}", response),
+                };
+            });
+        }
     }
 
-    pub fn handle_message(&mut self, message: InternodeRequest) -> InternodeResponse {
+    pub fn handle_message(&self, message: InternodeRequest) -> InternodeResponse {
         match message {
             InternodeRequest::Read {key} => self.get(key),
             InternodeRequest::Write {key, value} => self.insert(key, value),
+            InternodeRequest::Touch {key, timestamp, expires} => self.touch(key, timestamp, expires),
+            InternodeRequest::Transfer {entries} => self.transfer(entries),
+            InternodeRequest::PrepareTopology {ring} => {
+                self.topology.write().unwrap().prepare(ring);
+                InternodeResponse::TopologyAck
+            }
+            InternodeRequest::Rebalance {ring} => self.rebalance(ring),
+            InternodeRequest::CommitTopology {ring} => {
+                self.topology.write().unwrap().commit(ring);
+                InternodeResponse::TopologyAck
+            }
+            InternodeRequest::Scan {dataset, pkey, start_lkey, end_lkey, limit, reverse} => {
+                let (start, end) = Key::lkey_range(&dataset, &pkey, &start_lkey, &end_lkey);
+                self.scan(start, end, limit, reverse)
+            }
+            InternodeRequest::ListKeys {dataset, cursor, limit} => self.list_keys(dataset, cursor, limit),
+            InternodeRequest::MerkleTree {depth} => {
+                if depth > MAX_MERKLE_DEPTH {
+                    return InternodeResponse::TopologyError {
+                        message: format!("Merkle trees can't be deeper than {}", MAX_MERKLE_DEPTH),
+                    };
+                }
+                InternodeResponse::MerkleTree { hashes: self.merkle_tree(depth).hashes().to_owned() }
+            }
+            InternodeRequest::MerkleLeaves {depth, leaves} => {
+                InternodeResponse::Rows { rows: self.merkle_leaves(depth, &leaves) }
+            }
+            InternodeRequest::Prepare {key, ballot} => self.prepare(key, ballot),
+            InternodeRequest::Propose {key, ballot, value} => self.propose(key, ballot, value),
+            InternodeRequest::Commit {key, ballot, value} => self.commit(key, ballot, value),
+            InternodeRequest::RaftRead {key, forwarded} => self.raft_read(key, forwarded),
+            InternodeRequest::RaftWrite {key, content, ttl, forwarded} => self.raft_write(key, content, ttl, forwarded),
+            InternodeRequest::RequestVote {term, candidate, last_log_index, last_log_term} => {
+                match self.raft.as_ref() {
+                    Some(raft) => raft.request_vote(term, candidate, last_log_index, last_log_term),
+                    None => raft_disabled(),
+                }
+            }
+            InternodeRequest::AppendEntries {term, leader, prev_log_index, prev_log_term, entries, leader_commit} => {
+                match self.raft.as_ref() {
+                    Some(raft) => {
+                        raft.append_entries(term, leader, prev_log_index, prev_log_term, entries, leader_commit)
+                    }
+                    None => raft_disabled(),
+                }
+            }
+            InternodeRequest::InstallSnapshot {term, leader, last_index, last_term, entries} => {
+                match self.raft.as_ref() {
+                    Some(raft) => raft.install_snapshot(term, leader, last_index, last_term, entries),
+                    None => raft_disabled(),
+                }
+            }
+            InternodeRequest::ClaimNodeId {node, claimant} => self.claim_node_id(node, claimant),
+        }
+    }
+
+    /// Grant `node` as the node ID of `claimant`, unless this node has it or
+    /// granted it to another node already.
+    fn claim_node_id(&self, node: u16, claimant: u64) -> InternodeResponse {
+        let mut node_ids = self.node_ids.lock().unwrap();
+        let granted = if node == self.clock.node() {
+            claimant == vclock::node_id(&self.address)
+        } else {
+            node_ids.get(&node).map_or(true, |c| *c == claimant)
+        };
+        if granted {
+            // A node switching IDs gives up the one it had.
+            let previous: Vec<u16> = node_ids.iter()
+                                             .filter(|&(_, c)| *c == claimant)
+                                             .map(|(n, _)| *n)
+                                             .collect();
+            for n in previous {
+                node_ids.remove(&n);
+            }
+            node_ids.insert(node, claimant);
+        } else {
+            info!("Refused node ID {} to {}, which another node has", node, claimant);
+        }
+        InternodeResponse::NodeIdClaimed { granted: granted }
+    }
+
+    fn raft_read(&self, key: Key, forwarded: bool) -> InternodeResponse {
+        if !self.owns(&key) {
+            return self.not_owned(key);
+        }
+        let result = match self.raft.as_ref() {
+            Some(raft) => raft.read(&key),
+            None => Err(RaftError::Unavailable("Raft isn't enabled on this node".to_string())),
+        };
+        match result {
+            Ok(value) => {
+                InternodeResponse::Value {
+                    key: key,
+                    value: value.unexpired(hlc::wall_clock_ms()),
+                }
+            }
+            Err(e) => {
+                let request = InternodeRequest::RaftRead {
+                    key: key.to_owned(),
+                    forwarded: true,
+                };
+                self.forward_to_leader(key, forwarded, e, &request)
+            }
+        }
+    }
+
+    fn raft_write(&self, key: Key, content: Option<Buffer>, ttl: Option<u64>, forwarded: bool) -> InternodeResponse {
+        if !self.owns(&key) {
+            return self.not_owned(key);
+        }
+        let result = match self.raft.as_ref() {
+            Some(raft) => raft.write(&key, content.to_owned(), ttl),
+            None => Err(RaftError::Unavailable("Raft isn't enabled on this node".to_string())),
+        };
+        match result {
+            Ok(timestamp) => {
+                InternodeResponse::WriteAck {
+                    key: key,
+                    timestamp: timestamp,
+                    applied: true,
+                }
+            }
+            Err(e) => {
+                let request = InternodeRequest::RaftWrite {
+                    key: key.to_owned(),
+                    content: content,
+                    ttl: ttl,
+                    forwarded: true,
+                };
+                self.forward_to_leader(key, forwarded, e, &request)
+            }
+        }
+    }
+
+    /// Have the leader perform `request`, which this node couldn't because
+    /// of `error`. Requests are forwarded only once, so that nodes that
+    /// disagree on who the leader is don't send them back and forth.
+    fn forward_to_leader(&self,
+                         key: Key,
+                         forwarded: bool,
+                         error: RaftError,
+                         request: &InternodeRequest)
+                         -> InternodeResponse {
+        match error {
+            RaftError::NotLeader(Some(leader)) if !forwarded && leader != self.address => {
+                debug!("Forwarding {:?} to the Raft leader at {:?}", request, leader);
+                let timeout = Some(Duration::from_millis(RAFT_COMMIT_TIMEOUT_MS));
+                let response: Future<InternodeResponse, Error> = self.pool.send_to_node(&leader, request, timeout);
+                match response.await() {
+                    Ok(r) => r,
+                    Err(e) => {
+                        InternodeResponse::Error {
+                            key: key,
+                            message: format!("Couldn't reach the Raft leader at {:?}: {:?}",
+                                             leader,
+                                             e.take()),
+                        }
+                    }
+                }
+            }
+            e => {
+                InternodeResponse::Error {
+                    key: key,
+                    message: format!("Raft group couldn't perform the request: {:?}", e),
+                }
+            }
+        }
+    }
+
+    fn prepare(&self, key: Key, ballot: u64) -> InternodeResponse {
+        if !self.owns(&key) {
+            return self.not_owned(key);
+        }
+        match self.acceptor.prepare(&key, ballot) {
+            Ok(Ok(accepted)) => {
+                InternodeResponse::Promise {
+                    value: self.map.get(&key).map_or(Value::None, |v| v.unexpired(hlc::wall_clock_ms())),
+                    key: key,
+                    accepted: accepted,
+                }
+            }
+            Ok(Err(promised)) => {
+                InternodeResponse::Rejected {
+                    key: key,
+                    promised: promised,
+                }
+            }
+            Err(e) => self.not_logged(key, e),
+        }
+    }
+
+    fn propose(&self, key: Key, ballot: u64, value: Value) -> InternodeResponse {
+        if !self.owns(&key) {
+            return self.not_owned(key);
+        }
+        match self.acceptor.propose(&key, ballot, &value) {
+            Ok(Ok(())) => {
+                InternodeResponse::Accepted {
+                    key: key,
+                    ballot: ballot,
+                }
+            }
+            Ok(Err(promised)) => {
+                InternodeResponse::Rejected {
+                    key: key,
+                    promised: promised,
+                }
+            }
+            Err(e) => self.not_logged(key, e),
+        }
+    }
+
+    /// Store the outcome of a Paxos round like any other write, and only then
+    /// forget the proposal, so that it's never lost in between.
+    fn commit(&self, key: Key, ballot: u64, value: Value) -> InternodeResponse {
+        let response = self.insert(key.to_owned(), value);
+        if let InternodeResponse::WriteAck {..} = response {
+            if let Err(e) = self.acceptor.commit(&key, ballot) {
+                // The proposal is only finished again by a later round.
+                error!("Couldn't log the commit of ballot {} for {:?}: {:?}", ballot, key, e);
+            }
+        }
+        response
+    }
+
+    /// The Paxos acceptor couldn't log what it was about to answer with.
+    fn not_logged(&self, key: Key, e: io::Error) -> InternodeResponse {
+        error!("Couldn't log the Paxos ballots of {:?}: {:?}", key, e);
+        InternodeResponse::Error {
+            key: key,
+            message: format!("Couldn't log the Paxos ballots: {:?}", e),
+        }
+    }
+
+    fn not_owned(&self, key: Key) -> InternodeResponse {
+        let error = format!("{:?} doesn't belong to this shard!", key);
+        error!("{}", error);
+        InternodeResponse::Error {
+            key: key,
+            message: error,
+        }
+    }
+
+    /// The `MerkleTree` of `depth` over the entries this node is a replica
+    /// for.
+    fn merkle_tree(&self, depth: u32) -> MerkleTree {
+        let topology = self.topology.read().unwrap().clone();
+        let address = self.address;
+        MerkleTree::build(&*self.map, depth, &|key| topology.owns(&address, key))
+    }
+
+    /// The entries this node is a replica for in `leaves` of the `MerkleTree`
+    /// of `depth`.
+    fn merkle_leaves(&self, depth: u32, leaves: &Vec<usize>) -> Vec<(Key, Value)> {
+        let topology = self.topology.read().unwrap().clone();
+        let leaves: HashSet<&usize> = leaves.iter().collect();
+        let mut rows = vec![];
+        self.map.for_each(&mut |key, value| {
+            if leaves.contains(&MerkleTree::leaf(key, depth)) && topology.owns(&self.address, key) {
+                rows.push((key.to_owned(), value.to_owned()));
+            }
+        });
+        rows
+    }
+
+    /// Replace every expired value with a deletion at its timestamp, which
+    /// wins over it wherever it's merged, unless it's been overwritten
+    /// meanwhile. Returns how many were.
+    fn sweep_expired(&self) -> usize {
+        let now = hlc::wall_clock_ms();
+        let mut expired = vec![];
+        self.map.for_each(&mut |key, value| {
+            if let Some(timestamp) = value.get_timestamp() {
+                if value.is_expired(now) {
+                    expired.push((key.to_owned(), timestamp));
+                }
+            }
+        });
+        let mut count = 0;
+        for (key, timestamp) in expired {
+            match self.store(key.to_owned(), Value::Tombstone { timestamp: timestamp }) {
+                Ok(true) => count += 1,
+                Ok(false) => (),
+                Err(e) => {
+                    error!("Couldn't sweep {:?}: {:?}", key, e);
+                    break;
+                }
+            }
+        }
+        count
+    }
+
+    /// Compare this node's entries with those of the other replicas of its
+    /// shard, and exchange the ones that differ, merging them as writes are.
+    /// Returns the amount of entries either side stored.
+    fn anti_entropy(&self) -> usize {
+        let topology = self.topology.read().unwrap().clone();
+        if topology.pending.is_some() {
+            debug!("Skipping anti-entropy while resharding");
+            return 0;
+        }
+        let peers: Vec<SocketAddrV4> = match topology.ring.shards().iter().find(|s| s.contains(&self.address)) {
+            Some(shard) => shard.iter().filter(|&n| n != &self.address).cloned().collect(),
+            None => return 0,
+        };
+        let tree = self.merkle_tree(MERKLE_DEPTH);
+        let mut count = 0;
+        for peer in peers {
+            match self.anti_entropy_with(&peer, &tree) {
+                Ok(n) => count += n,
+                Err(e) => error!("Anti-entropy with {:?} failed: {:?}", peer, e),
+            }
         }
+        count
     }
 
-    fn get(&mut self, key: Key) -> InternodeResponse {
+    /// Exchange the entries that differ between `tree` and `peer`'s tree.
+    fn anti_entropy_with(&self, peer: &SocketAddrV4, tree: &MerkleTree) -> Result<usize> {
+        let timeout = Some(Duration::from_millis(ANTI_ENTROPY_TIMEOUT_MS));
+        let depth = tree.depth();
+
+        let request = InternodeRequest::MerkleTree { depth: depth };
+        let response: Future<InternodeResponse, Error> = self.pool.send_to_node(peer, &request, timeout);
+        let theirs = match response.await() {
+            Ok(InternodeResponse::MerkleTree {hashes}) => {
+                match MerkleTree::from_hashes(depth, hashes) {
+                    Some(t) => t,
+                    None => return Err(Error::UnexpectedResponse),
+                }
+            }
+            r => {
+                debug!("Couldn't get the Merkle tree of {:?}: {:?}", peer, r);
+                return Err(Error::UnexpectedResponse);
+            }
+        };
+        let leaves = tree.differing_leaves(&theirs);
+        if leaves.is_empty() {
+            return Ok(0);
+        }
+        debug!("{} Merkle tree leaves differ from {:?}", leaves.len(), peer);
+
+        let ours = self.merkle_leaves(depth, &leaves);
+        let request = InternodeRequest::MerkleLeaves {
+            depth: depth,
+            leaves: leaves,
+        };
+        let response: Future<InternodeResponse, Error> = self.pool.send_to_node(peer, &request, timeout);
+        let rows = match response.await() {
+            Ok(InternodeResponse::Rows {rows}) => rows,
+            r => {
+                debug!("Couldn't get the differing entries of {:?}: {:?}", peer, r);
+                return Err(Error::UnexpectedResponse);
+            }
+        };
+
+        // Only entries that would change what the other side has are sent
+        // over.
+        let their_values: HashMap<&Key, &Value> = rows.iter().map(|&(ref k, ref v)| (k, v)).collect();
+        let newer: Vec<(Key, Value)> = ours.into_iter()
+                                           .filter(|&(ref k, ref v)| {
+                                               Value::merge(their_values.get(k).cloned(), v).is_some()
+                                           })
+                                           .collect();
+        let mut count = 0;
+        if !newer.is_empty() {
+            let request = InternodeRequest::Transfer { entries: newer };
+            let response: Future<InternodeResponse, Error> = self.pool.send_to_node(peer, &request, timeout);
+            match response.await() {
+                Ok(InternodeResponse::TransferAck {count: c}) => count += c,
+                r => {
+                    debug!("Couldn't send differing entries to {:?}: {:?}", peer, r);
+                    return Err(Error::UnexpectedResponse);
+                }
+            }
+        }
+        match self.transfer(rows) {
+            InternodeResponse::TransferAck {count: c} => count += c,
+            r => {
+                debug!("Couldn't store differing entries from {:?}: {:?}", peer, r);
+                return Err(Error::UnexpectedResponse);
+            }
+        }
+        Ok(count)
+    }
+
+    /// Up to `limit` of the entries of `dataset` this node is a replica for,
+    /// without their content.
+    fn list_keys(&self, dataset: Buffer, cursor: Option<Key>, limit: usize) -> InternodeResponse {
+        let mut start = match cursor {
+            Some(key) => key.successor(),
+            None => {
+                Key {
+                    dataset: dataset.to_owned(),
+                    pkey: vec![],
+                    lkey: vec![],
+                }
+            }
+        };
+        let mut next_dataset = dataset.to_owned();
+        next_dataset.push(0);
+        let end = Key {
+            dataset: next_dataset,
+            pkey: vec![],
+            lkey: vec![],
+        };
+
+        // Entries left over from before a reshard aren't listed, so keep
+        // reading until there are enough of the ones this node owns.
+        let now = hlc::wall_clock_ms();
+        let mut rows = vec![];
+        while rows.len() < limit {
+            let page = self.map.scan(&start, &end, limit, false);
+            let exhausted = page.len() < limit;
+            if let Some(&(ref last, _)) = page.last() {
+                start = last.successor();
+            }
+            for (key, value) in page {
+                if rows.len() < limit && self.owns(&key) {
+                    let value = match value.unexpired(now) {
+                        Value::Value {timestamp, expires, ..} => {
+                            Value::Value {
+                                content: vec![],
+                                timestamp: timestamp,
+                                expires: expires,
+                            }
+                        }
+                        Value::Versioned {siblings} => {
+                            let siblings = siblings.into_iter()
+                                                   .map(|mut s| {
+                                                       s.content = s.content.map(|_| vec![]);
+                                                       s
+                                                   })
+                                                   .collect();
+                            Value::Versioned { siblings: siblings }
+                        }
+                        v => v,
+                    };
+                    rows.push((key, value));
+                }
+            }
+            if exhausted {
+                break;
+            }
+        }
+        InternodeResponse::Rows { rows: rows }
+    }
+
+    fn scan(&self, start: Key, end: Key, limit: usize, reverse: bool) -> InternodeResponse {
+        debug!("Scanning from {:?} to {:?}", start, end);
+        // Every `Key` in the range belongs to the same partition, and so to
+        // the same replicas.
+        if self.owns(&start) {
+            let now = hlc::wall_clock_ms();
+            let rows = self.map
+                           .scan(&start, &end, limit, reverse)
+                           .into_iter()
+                           .map(|(key, value)| (key, value.unexpired(now)))
+                           .collect();
+            InternodeResponse::Rows { rows: rows }
+        } else {
+            let error = format!("{:?} doesn't belong to this shard!", start);
+            error!("{}", error);
+            InternodeResponse::Error {
+                key: start,
+                message: error,
+            }
+        }
+    }
+
+    fn owns(&self, key: &Key) -> bool {
+        self.topology.read().unwrap().owns(&self.address, key)
+    }
+
+    /// Store every streamed entry belonging to this node that changes the one
+    /// already stored.
+    fn transfer(&self, entries: Vec<(Key, Value)>) -> InternodeResponse {
+        let mut count = 0;
+        for (key, value) in entries {
+            if !self.owns(&key) {
+                error!("Transferred {:?} doesn't belong to this shard!", key);
+                continue;
+            }
+            if let Some(Err(ahead)) = value.get_timestamp().map(|t| self.clock.update(t)) {
+                error!("Transferred {:?} is {}ms ahead of this node's clock", key, ahead);
+                continue;
+            }
+            if Value::merge(self.map.get(&key).as_ref(), &value).is_some() {
+                match self.store(key, value) {
+                    Ok(true) => count += 1,
+                    Ok(false) => (),
+                    Err(e) => {
+                        error!("Couldn't store transferred entries: {:?}", e);
+                        return InternodeResponse::TopologyError {
+                            message: format!("Couldn't store transferred entries: {:?}", e),
+                        };
+                    }
+                }
+            }
+        }
+        debug!("Stored {} transferred entries", count);
+        InternodeResponse::TransferAck { count: count }
+    }
+
+    /// Stream the entries `ring` assigns to new replicas to them.
+    fn rebalance(&self, ring: Ring) -> InternodeResponse {
+        let current = self.topology.read().unwrap().ring.clone();
+        match rebalance::stream_entries(&self.pool, &*self.map, &current, &ring) {
+            Ok(count) => InternodeResponse::TransferAck { count: count },
+            Err(e) => {
+                InternodeResponse::TopologyError {
+                    message: format!("Couldn't stream entries to new replicas: {:?}", e),
+                }
+            }
+        }
+    }
+
+    fn get(&self, key: Key) -> InternodeResponse {
         debug!("Reading {:?}", key);
-        let key_shard = key.shard(self.shard_count.clone());
-        let this_shard = self.shard;
-        if key_shard == this_shard {
+        if self.owns(&key) {
 
             debug!("get self {:?}", self);
             debug!("get self.map {:?}", self.map);
-            let v = self.map.get(&key);
+            let v = self.map.get(&key).map(|v| v.unexpired(hlc::wall_clock_ms()));
             debug!("get value {:?}", v);
             // let ref mut map: Backend = *match Arc::get_mut(&mut self.map);
             match v {
@@ -99,31 +705,107 @@ impl<Backend: StorageBackend + 'static> ClientHandler<Backend> {
         }
     }
 
-    fn insert(&mut self, key: Key, value: Value) -> InternodeResponse {
+    /// Change the expiry of the write of `key` with `timestamp` to `expires`,
+    /// unless it's been superseded.
+    fn touch(&self, key: Key, timestamp: u64, expires: Option<u64>) -> InternodeResponse {
+        if !self.owns(&key) {
+            return self.not_owned(key);
+        }
+        let content = match self.map.get(&key) {
+            Some(Value::Value {ref content, timestamp: t, ..}) if t == timestamp => content.to_owned(),
+            Some(ref v) if v.get_timestamp() >= Some(timestamp) => {
+                debug!("Touch of {:?} at {} superseded", key, timestamp);
+                return InternodeResponse::WriteAck {
+                    key: key,
+                    timestamp: v.get_timestamp().unwrap_or(timestamp),
+                    applied: false,
+                };
+            }
+            _ => {
+                return InternodeResponse::Error {
+                    key: key,
+                    message: format!("The write at {} to touch isn't stored here", timestamp),
+                };
+            }
+        };
+        let touched = Value::Value {
+            content: content,
+            timestamp: timestamp,
+            expires: expires,
+        };
+        // A write or a deletion racing it wins the merge, as it's newer.
+        match self.store(key.to_owned(), touched) {
+            Ok(applied) => {
+                InternodeResponse::WriteAck {
+                    key: key,
+                    timestamp: timestamp,
+                    applied: applied,
+                }
+            }
+            Err(e) => {
+                let error = format!("Couldn't persist touch of {:?}: {:?}", key, e);
+                error!("{}", error);
+                InternodeResponse::Error {
+                    key: key,
+                    message: error,
+                }
+            }
+        }
+    }
+
+    fn insert(&self, key: Key, value: Value) -> InternodeResponse {
         debug!("Writing {:?} -> {:?}", key, value);
-        let key_shard = key.shard(self.shard_count.clone());
-        let this_shard = self.shard;
-        if key_shard == this_shard {
-            match value {
-                Value::None => {
-                    let error = format!("Write operation at {:?} with None.This should have been \
+        if self.owns(&key) {
+            match value.get_timestamp() {
+                None => {
+                    let error = format!("Write operation at {:?} with {:?}.This should have been \
                                          a Tombstone",
-                                        key);
+                                        key,
+                                        value);
                     error!("{}", error);
                     InternodeResponse::Error {
                         key: key.to_owned(),
                         message: error,
                     }
                 }
-                Value::Value {timestamp, ..} | Value::Tombstone {timestamp} => {
+                Some(timestamp) => {
                     debug!("set self.map {:?}", self.map);
                     debug!("key: {:?}", key);
                     debug!("timestamp: {:?}", timestamp);
-                    let map = &self.map;
-                    map.insert(key.to_owned(), value.to_owned());
-                    InternodeResponse::WriteAck {
-                        key: key.to_owned(),
-                        timestamp: timestamp.to_owned(),
+                    if let Err(ahead) = self.clock.update(timestamp) {
+                        let error = format!("Write to {:?} is {}ms ahead of this node's clock", key, ahead);
+                        error!("{}", error);
+                        return InternodeResponse::Error {
+                            key: key.to_owned(),
+                            message: error,
+                        };
+                    }
+                    match self.store(key.to_owned(), value.to_owned()) {
+                        Ok(applied) => {
+                            // A superseded write is answered with the timestamp
+                            // of the newer `Value`, for the writer's clock to
+                            // catch up with. So is one the stored siblings
+                            // already had.
+                            let timestamp = if applied {
+                                timestamp
+                            } else {
+                                debug!("Write of {:?} at {} superseded", key, timestamp);
+                                self.map.get(&key).and_then(|v| v.get_timestamp()).unwrap_or(timestamp)
+                            };
+                            InternodeResponse::WriteAck {
+                                key: key.to_owned(),
+                                timestamp: timestamp,
+                                applied: applied,
+                            }
+                        }
+                        Err(e) => {
+                            let error = format!("Couldn't persist write to {:?}: {:?}", key, e);
+                            error!("{}", error);
+                            InternodeResponse::Error {
+                                key: key.to_owned(),
+                                message: error,
+                            }
+                        }
                     }
                 }
             }
@@ -138,18 +820,121 @@ impl<Backend: StorageBackend + 'static> ClientHandler<Backend> {
     }
 }
 
+fn raft_disabled() -> InternodeResponse {
+    InternodeResponse::TopologyError { message: "Raft isn't running on this node".to_string() }
+}
+
 impl<Backend: StorageBackend + 'static> StorageNode<Backend> {
-    pub fn new(local_address: &SocketAddrV4,
-               shard_number: usize,
-               shard_count: usize)
-               -> StorageNode<Backend> {
-        let map = Arc::new(Backend::new());
+    pub fn new(local_address: &SocketAddrV4, ring: &Ring) -> StorageNode<Backend> {
+        StorageNode::with_backend(local_address, ring, Backend::new())
+    }
+
+    /// A storage node keeping its data in `data_dir`, recovering whatever a
+    /// previous run left there.
+    pub fn with_data_dir(local_address: &SocketAddrV4,
+                         ring: &Ring,
+                         data_dir: &Path)
+                         -> io::Result<StorageNode<Backend>> {
+        let backend = try!(Backend::open(data_dir));
+        let mut sn = StorageNode::with_backend(local_address, ring, backend);
+        sn.data_dir = Some(data_dir.to_owned());
+        sn.clock = Arc::new(try!(Clock::persisted(data_dir)));
+        Ok(sn)
+    }
+
+    /// A storage node keeping its data in `data_dir`, where every write is
+    /// also logged to `wal.log` before being acknowledged, made durable as
+    /// `policy` says. The log is replayed into the backend first. Paxos
+    /// ballots are logged to `paxos.log`, always synced.
+    pub fn with_wal(local_address: &SocketAddrV4,
+                    ring: &Ring,
+                    data_dir: &Path,
+                    policy: SyncPolicy)
+                    -> io::Result<StorageNode<Backend>> {
+        let backend = try!(Backend::open(data_dir));
+        let (wal, entries) = try!(Wal::open(&data_dir.join("wal.log"), policy));
+        for (key, value) in entries {
+            try!(backend.merge(key, value));
+        }
+        if backend.is_persistent() {
+            try!(wal.checkpoint(|| backend.sync()));
+        }
+        let mut sn = StorageNode::with_backend(local_address, ring, backend);
+        sn.wal = Some(wal);
+        sn.data_dir = Some(data_dir.to_owned());
+        sn.clock = Arc::new(try!(Clock::persisted(data_dir)));
+        sn.acceptor = Arc::new(try!(Acceptor::open(&data_dir.join("paxos.log"))));
+        Ok(sn)
+    }
+
+    fn with_backend(local_address: &SocketAddrV4, ring: &Ring, backend: Backend) -> StorageNode<Backend> {
+        let map = Arc::new(backend);
         StorageNode {
-            shard: shard_number,
-            shard_count: shard_count,
+            topology: Arc::new(RwLock::new(Topology::new(ring.to_owned()))),
+            pool: Arc::new(ConnectionPool::new()),
             address: local_address.to_owned(),
             map: map,
+            data_dir: None,
+            wal: None,
+            anti_entropy_interval: Some(Duration::from_millis(ANTI_ENTROPY_INTERVAL_MS)),
+            expiry_sweep_interval: Some(Duration::from_millis(EXPIRY_SWEEP_INTERVAL_MS)),
+            clock: Arc::new(Clock::new(hlc::node_id(local_address))),
+            acceptor: Arc::new(Acceptor::new()),
+            raft: None,
+            node_ids: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Have this node take part in the Raft group of its shard, configured by
+    /// `config`, once it listens. Its state is kept in `raft.log` if there's
+    /// a data directory. Returns a handle to it, unless the node isn't on the
+    /// ring.
+    pub fn enable_raft(&mut self, config: RaftConfig) -> io::Result<Option<Raft<Backend>>> {
+        let shard = {
+            let topology = self.topology.read().unwrap();
+            match topology.ring.shards().iter().find(|s| s.contains(&self.address)) {
+                Some(shard) => shard.to_owned(),
+                None => return Ok(None),
+            }
+        };
+        let me = shard.iter().position(|n| n == &self.address).unwrap();
+        let raft = Raft::new(me,
+                             shard,
+                             self.map.clone(),
+                             self.wal.clone(),
+                             self.topology.clone(),
+                             self.pool.clone(),
+                             self.clock.clone(),
+                             config);
+        if let Some(ref data_dir) = self.data_dir {
+            try!(raft.open_log(&data_dir.join("raft.log")));
         }
+        self.raft = Some(raft.clone());
+        Ok(Some(raft))
+    }
+
+    /// Perform an anti-entropy round with the other replicas of this node's
+    /// shard right away. Returns the amount of entries either side stored.
+    pub fn anti_entropy(&self) -> usize {
+        self.client_handler().anti_entropy()
+    }
+
+    /// Replace every expired value with a deletion right away. Returns how
+    /// many were.
+    pub fn sweep_expired(&self) -> usize {
+        self.client_handler().sweep_expired()
+    }
+
+    fn client_handler(&self) -> ClientHandler<Backend> {
+        ClientHandler::new(self.map.clone(),
+                           &self.address,
+                           self.topology.clone(),
+                           self.pool.clone(),
+                           self.wal.clone(),
+                           self.clock.clone(),
+                           self.acceptor.clone(),
+                           self.raft.clone(),
+                           self.node_ids.clone())
     }
 
     pub fn listen(&mut self) {
@@ -162,26 +947,62 @@ impl<Backend: StorageBackend + 'static> StorageNode<Backend> {
             }
         };
 
+        {
+            // Now that it can answer the claims of others, make sure none
+            // of them has this node's ID.
+            let pool = self.pool.clone();
+            let ring = self.topology.read().unwrap().ring.clone();
+            let claimant = vclock::node_id(&self.address);
+            let clock = self.clock.clone();
+            thread::spawn(move || coordinator::claim_node_id(&pool, &ring, claimant, &clock));
+        }
+
+        if let Some(ref raft) = self.raft {
+            raft.run();
+        }
+
+        if let Some(interval) = self.anti_entropy_interval {
+            let ch = self.client_handler();
+            thread::spawn(move || {
+                loop {
+                    thread::sleep(interval);
+                    let count = ch.anti_entropy();
+                    if count > 0 {
+                        info!("Anti-entropy repaired {} entries on {:?}", count, ch.address);
+                    }
+                }
+            });
+        }
+
+        if let Some(interval) = self.expiry_sweep_interval {
+            let ch = self.client_handler();
+            thread::spawn(move || {
+                loop {
+                    thread::sleep(interval);
+                    let count = ch.sweep_expired();
+                    if count > 0 {
+                        debug!("Swept {} expired entries on {:?}", count, ch.address);
+                    }
+                }
+            });
+        }
+
         // accept connections and process them, spawning a new thread for each one
         for stream in listener.incoming() {
             match stream {
                 Ok(stream) => {
                     debug!("Starting listener stream: {:?}", stream);
-                    let shard = self.shard;
-                    let shard_count = self.shard_count;
-                    let map = self.map.clone();
+                    let ch = self.client_handler();
                     thread::spawn(move || {
-                        let mut ch = ClientHandler::new(stream, map, shard, shard_count);
                         // connection succeeded
-                        ch.handle_client();
+                        ch.handle_client(stream);
                     });
                 }
                 Err(e) => {
                     error!("connection failed!: {:?}", e);
                 }
             }
-            debug!("Contents of shard {:?} @ {:?} map: {:?}",
-                   self.shard,
+            debug!("Contents of storage node @ {:?} map: {:?}",
                    self.address,
                    self.map);
         }
@@ -0,0 +1,53 @@
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher, SipHasher};
+use std::net::SocketAddrV4;
+
+/// A vector clock: for every coordinator, the newest of its writes to a `Key`
+/// that have been seen. Writes are told apart by the coordinator's ID and
+/// the `hlc` timestamp it made them at.
+#[derive(Debug, Hash, Clone, PartialEq, Default, RustcEncodable, RustcDecodable)]
+pub struct VectorClock {
+    counters: BTreeMap<u64, u64>,
+}
+
+impl VectorClock {
+    pub fn new() -> VectorClock {
+        VectorClock::default()
+    }
+
+    /// Newest write of `node` seen, or 0 if none was.
+    pub fn get(&self, node: u64) -> u64 {
+        self.counters.get(&node).cloned().unwrap_or(0)
+    }
+
+    /// Take into account the write `node` made at `counter`.
+    pub fn witness(&mut self, node: u64, counter: u64) {
+        if counter > self.get(node) {
+            self.counters.insert(node, counter);
+        }
+    }
+
+    /// Take into account every write `other` has seen.
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (&node, &counter) in &other.counters {
+            self.witness(node, counter);
+        }
+    }
+
+    /// Whether the write `node` made at `counter` has been seen.
+    pub fn contains(&self, node: u64, counter: u64) -> bool {
+        self.get(node) >= counter
+    }
+
+    /// Whether every write `other` has seen has been seen too.
+    pub fn descends(&self, other: &VectorClock) -> bool {
+        other.counters.iter().all(|(&node, &counter)| self.contains(node, counter))
+    }
+}
+
+/// ID in vector clocks of the coordinator at `address`.
+pub fn node_id(address: &SocketAddrV4) -> u64 {
+    let mut h = SipHasher::new();
+    address.hash(&mut h);
+    h.finish()
+}
@@ -0,0 +1,290 @@
+use bincode::SizeLimit;
+use bincode::rustc_serialize::{encode, decode};
+use constants::WAL_CHECKPOINT_SIZE;
+use message::{Key, Value};
+use rustc_serialize::{Decodable, Encodable};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+use storage::{self, StorageBackend};
+
+/// When a `Wal` makes appended writes durable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    /// `fsync` after every write, before acknowledging it.
+    Always,
+    /// `fsync` every so often, acknowledging all the writes appended since
+    /// the last one together.
+    GroupCommit(Duration),
+    /// Never `fsync`, leaving it to the OS. Writes survive the process
+    /// crashing, but not the machine.
+    Never,
+}
+
+#[derive(Debug)]
+struct WalState {
+    file: File,
+    /// Size of the log.
+    len: u64,
+    /// Amount of writes appended so far.
+    written: u64,
+    /// Amount of those writes known to be durable.
+    synced: u64,
+    /// Set once an `fsync` failed, or a torn record couldn't be discarded;
+    /// nothing is acknowledged after that.
+    failed: bool,
+}
+
+#[derive(Debug)]
+struct WalInner {
+    path: PathBuf,
+    policy: SyncPolicy,
+    state: Mutex<WalState>,
+    /// Signaled after every group commit.
+    synced: Condvar,
+}
+
+/// A write-ahead log of a storage node's writes.
+///
+/// Every write is appended to the log, using `LogBackend`'s record format,
+/// before it's applied to the node's `StorageBackend`, and only acknowledged
+/// once it's durable according to the `SyncPolicy`. On restart the log is
+/// replayed into the backend.
+///
+/// A persistent backend can be `checkpoint`ed: synced, after which the log
+/// is emptied. Otherwise the log keeps every write ever made.
+///
+/// Logs of other kinds of records, such as the state Paxos and Raft must not
+/// forget, use `open_records` and `append_record` instead, and `rewrite`
+/// themselves with only what's still needed.
+#[derive(Debug, Clone)]
+pub struct Wal {
+    inner: Arc<WalInner>,
+}
+
+impl Wal {
+    /// Open the log at `path`, returning it along with every write it holds
+    /// in the order they were made. A torn record at the end of the log,
+    /// from a write that was never acknowledged, is discarded.
+    pub fn open(path: &Path, policy: SyncPolicy) -> io::Result<(Wal, Vec<(Key, Value)>)> {
+        Wal::open_records(path, policy)
+    }
+
+    /// Like `open`, for a log of records of any type.
+    pub fn open_records<T: Decodable>(path: &Path, policy: SyncPolicy) -> io::Result<(Wal, Vec<T>)> {
+        if let Some(dir) = path.parent() {
+            try!(fs::create_dir_all(dir));
+        }
+        let mut file = try!(OpenOptions::new().read(true).write(true).create(true).open(path));
+        let mut buf = vec![];
+        try!(file.read_to_end(&mut buf));
+        let (records, end) = storage::read_records(&buf);
+        let mut entries = vec![];
+        for (offset, payload) in records {
+            match decode(payload) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => panic!("[Wal] Undecodable record at {} in {:?}: {:?}", offset, path, e),
+            }
+        }
+        if end < buf.len() as u64 {
+            error!("[Wal] Discarding {} bytes of torn record at the end of {:?}",
+                   buf.len() as u64 - end,
+                   path);
+            try!(file.set_len(end));
+        }
+        try!(file.seek(SeekFrom::Start(end)));
+        info!("[Wal] Replaying {} writes from {:?}", entries.len(), path);
+
+        let inner = Arc::new(WalInner {
+            path: path.to_owned(),
+            policy: policy,
+            state: Mutex::new(WalState {
+                file: file,
+                len: end,
+                written: 0,
+                synced: 0,
+                failed: false,
+            }),
+            synced: Condvar::new(),
+        });
+        if let SyncPolicy::GroupCommit(interval) = policy {
+            let weak = Arc::downgrade(&inner);
+            thread::spawn(move || WalInner::group_commit(weak, interval));
+        }
+        Ok((Wal { inner: inner }, entries))
+    }
+
+    /// Append a write of `value` under `key`, then `apply` it while no other
+    /// write can be appended, after syncing it with `SyncPolicy::Always`.
+    /// Returns once the write is durable. A write that can't be appended
+    /// isn't applied.
+    pub fn append<F: FnOnce()>(&self, key: &Key, value: &Value, apply: F) -> io::Result<()> {
+        self.append_record(&(key, value), apply)
+    }
+
+    /// Like `append`, for a record of any type.
+    pub fn append_record<T: Encodable, F: FnOnce()>(&self, record: &T, apply: F) -> io::Result<()> {
+        let payload = match encode(record, SizeLimit::Infinite) {
+            Ok(p) => p,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))),
+        };
+        let record = storage::record(&payload);
+
+        let mut state = self.inner.state.lock().unwrap();
+        if state.failed {
+            return Err(io::Error::new(io::ErrorKind::Other, "write-ahead log failed"));
+        }
+        if let Err(e) = state.file.write_all(&record) {
+            self.discard_torn(&mut state);
+            return Err(e);
+        }
+        state.len += record.len() as u64;
+        state.written += 1;
+        let seq = state.written;
+
+        if self.inner.policy == SyncPolicy::Always {
+            if let Err(e) = state.file.sync_data() {
+                // What made it to disk is unknown.
+                error!("[Wal] Couldn't sync {:?}: {:?}", self.inner.path, e);
+                state.failed = true;
+                return Err(e);
+            }
+            state.synced = seq;
+        }
+        apply();
+
+        match self.inner.policy {
+            SyncPolicy::Always => (),
+            SyncPolicy::GroupCommit(_) => {
+                while state.synced < seq && !state.failed {
+                    state = self.inner.synced.wait(state).unwrap();
+                }
+                if state.synced < seq {
+                    return Err(io::Error::new(io::ErrorKind::Other, "write-ahead log failed to sync"));
+                }
+            }
+            SyncPolicy::Never => (),
+        }
+        Ok(())
+    }
+
+    /// Cut off what was written of a record that couldn't be appended
+    /// whole, so the next one follows the last intact one. Nothing more is
+    /// appended if that fails too.
+    fn discard_torn(&self, state: &mut WalState) {
+        let len = state.len;
+        if let Err(e) = state.file.set_len(len).and_then(|_| state.file.seek(SeekFrom::Start(len))) {
+            error!("[Wal] Couldn't discard torn record at {} in {:?}: {:?}", len, self.inner.path, e);
+            state.failed = true;
+        }
+    }
+
+    /// Size in bytes of the log.
+    pub fn len(&self) -> u64 {
+        self.inner.state.lock().unwrap().len
+    }
+
+    /// Empty the log, once `sync` made every write applied so far durable
+    /// elsewhere.
+    pub fn checkpoint<F: FnOnce() -> io::Result<()>>(&self, sync: F) -> io::Result<()> {
+        let mut state = self.inner.state.lock().unwrap();
+        try!(sync());
+        try!(state.file.set_len(0));
+        try!(state.file.seek(SeekFrom::Start(0)));
+        try!(state.file.sync_all());
+        debug!("[Wal] Checkpointed {:?} at {} bytes", self.inner.path, state.len);
+        state.len = 0;
+        state.synced = state.written;
+        self.inner.synced.notify_all();
+        Ok(())
+    }
+
+    /// Replace what the log holds with `records`, durably. The new log is
+    /// written aside and only takes the old one's place once complete.
+    pub fn rewrite<T: Encodable>(&self, records: &[T]) -> io::Result<()> {
+        let mut data = vec![];
+        for record in records {
+            match encode(record, SizeLimit::Infinite) {
+                Ok(payload) => data.extend(storage::record(&payload)),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))),
+            }
+        }
+        let mut state = self.inner.state.lock().unwrap();
+        let path = &self.inner.path;
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = try!(File::create(&tmp));
+            try!(file.write_all(&data));
+            try!(file.sync_all());
+        }
+        try!(fs::rename(&tmp, path));
+        let mut file = try!(OpenOptions::new().read(true).write(true).open(path));
+        try!(file.seek(SeekFrom::End(0)));
+        debug!("[Wal] Rewrote {:?} from {} to {} bytes", path, state.len, data.len());
+        state.file = file;
+        state.len = data.len() as u64;
+        state.synced = state.written;
+        self.inner.synced.notify_all();
+        Ok(())
+    }
+}
+
+/// Merge `value` into the one `map` stores under `key`, through `wal` if
+/// there is one. Returns whether anything changed, once the write is
+/// durable.
+pub fn store<Backend: StorageBackend>(wal: Option<&Wal>, map: &Backend, key: Key, value: Value) -> io::Result<bool> {
+    match wal {
+        Some(wal) => {
+            let mut applied = Ok(false);
+            // Superseded writes are logged too, but replaying them is just
+            // as harmless.
+            try!(wal.append(&key,
+                            &value,
+                            || applied = map.merge(key.to_owned(), value.to_owned())));
+            let applied = try!(applied);
+            if wal.len() > WAL_CHECKPOINT_SIZE && map.is_persistent() {
+                try!(wal.checkpoint(|| map.sync()));
+            }
+            Ok(applied)
+        }
+        None => map.merge(key, value),
+    }
+}
+
+impl WalInner {
+    /// Sync the log every `interval` until it's dropped.
+    fn group_commit(wal: Weak<WalInner>, interval: Duration) {
+        loop {
+            thread::sleep(interval);
+            let inner = match wal.upgrade() {
+                Some(i) => i,
+                None => return,
+            };
+            let (file, seq) = {
+                let state = inner.state.lock().unwrap();
+                if state.synced == state.written || state.failed {
+                    continue;
+                }
+                (state.file.try_clone(), state.written)
+            };
+            // Writes keep being appended while syncing.
+            let result = file.and_then(|f| f.sync_data());
+            let mut state = inner.state.lock().unwrap();
+            match result {
+                Ok(()) => {
+                    if seq > state.synced {
+                        state.synced = seq;
+                    }
+                }
+                Err(e) => {
+                    error!("[Wal] Couldn't sync {:?}: {:?}", inner.path, e);
+                    state.failed = true;
+                }
+            }
+            inner.synced.notify_all();
+        }
+    }
+}
@@ -0,0 +1,57 @@
+extern crate eventual;
+extern crate sbahn;
+
+mod common;
+
+use common::{get_shard, key, read_value, start_storage_node_with_interval, value, write_to_storage_node};
+use sbahn::anti_entropy::MerkleTree;
+use sbahn::constants::{DEFAULT_VNODES, MERKLE_DEPTH};
+use sbahn::message::*;
+use sbahn::storage::{HashMapBackend, StorageBackend};
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn merkle_trees_find_differing_leaves() {
+    let ours = HashMapBackend::new();
+    let theirs = HashMapBackend::new();
+    for i in 0..50 {
+        ours.insert(key(i), value(i, 1)).unwrap();
+        theirs.insert(key(i), value(i, 1)).unwrap();
+    }
+    let all = |_: &Key| true;
+    let leaves = MerkleTree::build(&ours, MERKLE_DEPTH, &all)
+                     .differing_leaves(&MerkleTree::build(&theirs, MERKLE_DEPTH, &all));
+    assert_eq!(leaves, vec![]);
+
+    theirs.insert(key(7), value(7, 2)).unwrap();
+    let leaves = MerkleTree::build(&ours, MERKLE_DEPTH, &all)
+                     .differing_leaves(&MerkleTree::build(&theirs, MERKLE_DEPTH, &all));
+    assert_eq!(leaves, vec![MerkleTree::leaf(&key(7), MERKLE_DEPTH)]);
+}
+
+#[test]
+fn anti_entropy_converges_replicas() {
+    let shard = get_shard();
+    let ring = Ring::new(vec![shard.clone()], DEFAULT_VNODES);
+    start_storage_node_with_interval(&shard[0], &ring, Some(Duration::from_millis(300)));
+    for node in &shard[1..] {
+        start_storage_node_with_interval(node, &ring, None);
+    }
+
+    // Each replica missed some writes, and has stale values for others.
+    write_to_storage_node(&shard[0], &key(1), &value(1, 1));
+    write_to_storage_node(&shard[1], &key(2), &value(2, 1));
+    write_to_storage_node(&shard[2], &key(3), &Value::Tombstone { timestamp: 1 });
+    for (i, node) in shard.iter().enumerate() {
+        write_to_storage_node(node, &key(4), &value(i as u8, i as u64 + 1));
+    }
+
+    thread::sleep(Duration::from_millis(1000));
+    for node in &shard {
+        assert_eq!(read_value(node, &key(1)), value(1, 1));
+        assert_eq!(read_value(node, &key(2)), value(2, 1));
+        assert_eq!(read_value(node, &key(3)), Value::Tombstone { timestamp: 1 });
+        assert_eq!(read_value(node, &key(4)), value(2, 3));
+    }
+}
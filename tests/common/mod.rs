@@ -0,0 +1,158 @@
+//! Helpers shared by the integration tests.
+#![allow(dead_code)]
+
+use eventual::{Async, Future};
+use sbahn::client::Client;
+use sbahn::constants::ANTI_ENTROPY_INTERVAL_MS;
+use sbahn::handler;
+use sbahn::message::{Error, InternodeRequest, InternodeResponse, Key, Ring, Value};
+use sbahn::storage::HashMapBackend;
+use sbahn::storage_node::StorageNode;
+use std::env;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+// Milis to wait before trying to connect to any node.
+pub static DELAY: u64 = 100;
+
+static mut PORT: u16 = 1100;
+/// Obtain an open port
+pub fn get_port() -> u16 {
+    loop {
+        let port;
+        unsafe {
+            PORT += 1;
+            port = PORT;
+        }
+        let addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port);
+        if TcpListener::bind(&addr).is_ok() {
+            // Check wether the port is open, and only return it if it is.
+            return port;
+        }
+    }
+}
+
+pub fn get_address() -> SocketAddrV4 {
+    SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), get_port())
+}
+
+/// Three fresh addresses to make a shard of.
+pub fn get_shard() -> Vec<SocketAddrV4> {
+    (0..3).map(|_| get_address()).collect()
+}
+
+pub fn start_storage_node(addr: &SocketAddrV4, ring: &Ring) {
+    let interval = Some(Duration::from_millis(ANTI_ENTROPY_INTERVAL_MS));
+    start_storage_node_with_interval(addr, ring, interval);
+}
+
+/// Start a storage node, performing anti-entropy every `interval` if any.
+pub fn start_storage_node_with_interval(addr: &SocketAddrV4, ring: &Ring, interval: Option<Duration>) {
+    let mut sn: StorageNode<HashMapBackend> = StorageNode::new(addr, ring);
+    sn.anti_entropy_interval = interval;
+    thread::spawn(move || {
+        &sn.listen();
+    });
+    thread::sleep(Duration::from_millis(DELAY));  // Wait for storage node to start listening
+}
+
+pub fn write_to_storage_node(target: &SocketAddrV4, key: &Key, value: &Value) {
+    let request = InternodeRequest::Write {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    };
+    let r: Future<InternodeResponse, Error> = Client::send_to_node(target, &request);
+    r.await().unwrap();
+}
+
+pub fn read_from_storage_node(target: &SocketAddrV4, key: &Key) -> InternodeResponse {
+    let request = InternodeRequest::Read { key: key.to_owned() };
+    let r: Future<InternodeResponse, Error> = Client::send_to_node(target, &request);
+    r.await().unwrap()
+}
+
+/// The `Value` stored for `key` on `target`, panicking on any other response.
+pub fn read_value(target: &SocketAddrV4, key: &Key) -> Value {
+    match read_from_storage_node(target, key) {
+        InternodeResponse::Value {value, ..} => value,
+        r => panic!("{:?}", r),
+    }
+}
+
+pub fn setup_handler_node(ring: &Ring) -> SocketAddrV4 {
+    let ring = ring.clone();
+    let addr = get_address();
+    thread::spawn(move || {
+        let _ = handler::listen(&addr, &ring);
+    });
+    thread::sleep(Duration::from_millis(DELAY));  // Wait for handler node to start listening
+    addr
+}
+
+/// Listen on `address` for incoming client requests, and do nothing.
+pub fn dead_node(address: &SocketAddrV4) -> Future<(), ()> {
+    let address = address.to_owned();
+
+    let read_timeout = Some(Duration::from_millis(300));
+    let write_timeout = Some(Duration::from_millis(300));
+
+    Future::spawn(move || {
+        match TcpListener::bind(&address) {
+            Ok(listener) => {
+                // Accept connections and process them, spawning a new thread for each one.
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            let _ = stream.set_read_timeout(read_timeout);
+                            let _ = stream.set_write_timeout(write_timeout);
+                            thread::spawn(|| {
+                                thread::sleep(Duration::from_millis(500));
+                            });
+                        }
+                        Err(e) => panic!("Connection failed!: {:?}", e),
+                    }
+                }
+            }
+            Err(e) => panic!("Error while binding to {:?}: {:?}", address, e),
+        }
+    })
+}
+
+pub fn start_dead_storage_node(addr: &SocketAddrV4) {
+    let addr = addr.to_owned();
+    thread::spawn(move || {
+        let _ = dead_node(&addr);
+    });
+    thread::sleep(Duration::from_millis(DELAY));  // Wait for storage node to start listening
+}
+
+/// An empty directory for a test to keep its data in.
+pub fn data_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("sbahn-test-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+pub fn key(i: u8) -> Key {
+    Key {
+        dataset: vec![1, 2, 3],
+        pkey: vec![i],
+        lkey: vec![7, 8, 9],
+    }
+}
+
+pub fn value(content: u8, timestamp: u64) -> Value {
+    versioned(&[content], timestamp)
+}
+
+pub fn versioned(content: &[u8], timestamp: u64) -> Value {
+    Value::Value {
+        content: content.to_vec(),
+        timestamp: timestamp,
+        expires: None,
+    }
+}
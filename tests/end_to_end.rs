@@ -3,6 +3,7 @@ extern crate sbahn;
 
 use eventual::*;
 use sbahn::client;
+use sbahn::constants::DEFAULT_VNODES;
 use sbahn::handler;
 use sbahn::message::*;
 use sbahn::storage::HashMapBackend;
@@ -22,33 +23,33 @@ fn get_port() -> u16 {
     p
 }
 
-fn get_storage_node<'a>(pos: usize, shard_count: usize) -> SocketAddrV4 {
-    let addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), get_port());
-    let mut sn: StorageNode<HashMapBackend> = StorageNode::new(&addr, pos, shard_count);
+fn start_storage_node(addr: &SocketAddrV4, ring: &Ring) {
+    let mut sn: StorageNode<HashMapBackend> = StorageNode::new(addr, ring);
     thread::spawn(move || {
         &sn.listen();
     });
     thread::sleep(Duration::from_millis(100));  // Wait for storage node to start listening
-    addr
 }
 
 #[test]
 fn end_to_end() {
     let mut shards: Vec<Vec<SocketAddrV4>> = vec![];
-    for i in 0..3 {
+    for _ in 0..3 {
         let mut shard: Vec<SocketAddrV4> = vec![];
         for _ in 0..3 {
-            shard.push(get_storage_node(i, 3));
+            shard.push(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), get_port()));
         }
         shards.push(shard);
     }
+    let ring = Ring::new(shards, DEFAULT_VNODES);
+    for node in ring.nodes() {
+        start_storage_node(&node, &ring);
+    }
 
-    let e = &shards;
-    let z = e.clone();
+    let z = ring.clone();
     let addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), get_port());
     thread::spawn(move || {
-        let _shards = &z.to_owned();
-        let _ = handler::listen(&addr, &_shards);
+        let _ = handler::listen(&addr, &z);
     });
     thread::sleep(Duration::from_millis(100));  // Wait for handler node to start listening
 
@@ -64,8 +65,12 @@ fn end_to_end() {
                 action: Action::Write {
                     key: insert_key.to_owned(),
                     content: vec![1],
+                    ttl: None,
                 },
                 consistency: Consistency::Latest,
+                timestamp: None,
+                deadline: None,
+                context: None,
             };
             let r = client.send(&content).await().unwrap();
             match r.message {
@@ -79,10 +84,13 @@ fn end_to_end() {
                     key: insert_key.to_owned(),
                 },
                 consistency: Consistency::Latest,
+                timestamp: None,
+                deadline: None,
+                context: None,
             };
             let r = client.send(&content).await().unwrap();
             match r.message {
-                Response::Value {key, value} => {
+                Response::Value {key, value, ..} => {
                     assert_eq!(key, insert_key);
                     match value {
                         Value::Value {content, ..} => assert_eq!(&content[..], &vec![1][..]),
@@ -98,6 +106,9 @@ fn end_to_end() {
                     key: insert_key.to_owned(),
                 },
                 consistency: Consistency::Latest,
+                timestamp: None,
+                deadline: None,
+                context: None,
             };
             let r = client.send(&content).await().unwrap();
             match r.message {
@@ -111,10 +122,13 @@ fn end_to_end() {
                     key: insert_key.to_owned(),
                 },
                 consistency: Consistency::Latest,
+                timestamp: None,
+                deadline: None,
+                context: None,
             };
             let r = client.send(&content).await().unwrap();
             match r.message {
-                Response::Value {key, value} => {
+                Response::Value {key, value, ..} => {
                     assert_eq!(key, insert_key);
                     match value {
                         Value::Tombstone {..} => assert!(true),
@@ -0,0 +1,115 @@
+extern crate eventual;
+extern crate sbahn;
+
+mod common;
+
+use common::{data_dir, get_address, key, read_value, start_storage_node, value};
+use sbahn::constants::{DEFAULT_VNODES, HINT_PROBE_MS};
+use sbahn::hints::{HintLimits, HintedHandoff};
+use sbahn::message::*;
+use sbahn::metrics::Metrics;
+use sbahn::pool::ConnectionPool;
+use sbahn::storage::HashMapBackend;
+use sbahn::storage_node::StorageNode;
+use std::fs;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn hints_are_replayed_once_node_is_back() {
+    let node = get_address();
+    let metrics = Arc::new(Metrics::new());
+    let hints = HintedHandoff::new(Arc::new(ConnectionPool::new()), metrics.clone(), HintLimits::default());
+    for i in 0..3 {
+        hints.submit(&node, &key(i), &value(i, i as u64 + 1));
+    }
+    thread::sleep(Duration::from_millis(HINT_PROBE_MS * 2));
+    assert_eq!(hints.pending(), 3);
+
+    start_storage_node(&node, &Ring::new(vec![vec![node]], DEFAULT_VNODES));
+    thread::sleep(Duration::from_millis(HINT_PROBE_MS * 2));
+    assert_eq!(hints.pending(), 0);
+    assert_eq!(metrics.delivered_hints.load(Ordering::SeqCst), 3);
+    for i in 0..3 {
+        assert_eq!(read_value(&node, &key(i)), value(i, i as u64 + 1));
+    }
+}
+
+#[test]
+fn hints_survive_restarts() {
+    let dir = data_dir("hints_survive_restarts");
+    let node = get_address();
+    {
+        let hints = HintedHandoff::open(&dir,
+                                        Arc::new(ConnectionPool::new()),
+                                        Arc::new(Metrics::new()),
+                                        HintLimits::default())
+                        .unwrap();
+        hints.submit(&node, &key(1), &value(1, 2));
+        hints.submit(&node, &key(2), &value(2, 3));
+    }
+    // Give the first run's replay a chance to notice it's gone.
+    thread::sleep(Duration::from_millis(HINT_PROBE_MS * 2));
+
+    let hints = HintedHandoff::open(&dir,
+                                    Arc::new(ConnectionPool::new()),
+                                    Arc::new(Metrics::new()),
+                                    HintLimits::default())
+                    .unwrap();
+    assert_eq!(hints.pending(), 2);
+    start_storage_node(&node, &Ring::new(vec![vec![node]], DEFAULT_VNODES));
+    thread::sleep(Duration::from_millis(HINT_PROBE_MS * 2));
+    assert_eq!(hints.pending(), 0);
+    assert_eq!(read_value(&node, &key(2)), value(2, 3));
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+}
+
+#[test]
+fn hints_past_their_limits_are_dropped() {
+    let node = get_address();
+    let metrics = Arc::new(Metrics::new());
+    let limits = HintLimits {
+        max_age: Duration::from_millis(HINT_PROBE_MS / 2),
+        max_size: 100,
+    };
+    let hints = HintedHandoff::new(Arc::new(ConnectionPool::new()), metrics.clone(), limits);
+    hints.submit(&node, &key(1), &value(1, 2));
+    // Too large to fit along with the first one.
+    hints.submit(&node, &key(2), &Value::Value {
+        content: vec![0; 100],
+        timestamp: 1,
+        expires: None,
+    });
+    assert_eq!(hints.pending(), 1);
+    assert_eq!(metrics.dropped_hints.load(Ordering::SeqCst), 1);
+
+    thread::sleep(Duration::from_millis(HINT_PROBE_MS * 2));
+    assert_eq!(hints.pending(), 0);
+    assert_eq!(metrics.dropped_hints.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn hints_the_node_rejects_are_dropped() {
+    let node = get_address();
+    let other = get_address();
+    let ring = Ring::new(vec![vec![node], vec![other]], DEFAULT_VNODES);
+    let not_owned = (0..255).map(key).find(|k| ring.shard(k) == Some(1)).unwrap();
+    let owned = (0..255).map(key).find(|k| ring.shard(k) == Some(0)).unwrap();
+    let metrics = Arc::new(Metrics::new());
+    let hints = HintedHandoff::new(Arc::new(ConnectionPool::new()), metrics.clone(), HintLimits::default());
+    hints.submit(&node, &not_owned, &value(1, 2));
+    hints.submit(&node, &owned, &value(2, 3));
+
+    let mut sn: StorageNode<HashMapBackend> = StorageNode::new(&node, &ring);
+    thread::spawn(move || {
+        &sn.listen();
+    });
+    thread::sleep(Duration::from_millis(HINT_PROBE_MS * 2));
+    // The rejected hint doesn't hold back the one behind it.
+    assert_eq!(hints.pending(), 0);
+    assert_eq!(metrics.rejected_hints.load(Ordering::SeqCst), 1);
+    assert_eq!(metrics.delivered_hints.load(Ordering::SeqCst), 1);
+    assert_eq!(read_value(&node, &owned), value(2, 3));
+}
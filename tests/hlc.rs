@@ -0,0 +1,114 @@
+extern crate eventual;
+extern crate sbahn;
+
+mod common;
+
+use common::{DELAY, data_dir, get_address, get_shard};
+use sbahn::constants::DEFAULT_VNODES;
+use sbahn::coordinator::Coordinator;
+use sbahn::handler;
+use sbahn::hlc::{self, Clock};
+use sbahn::message::Ring;
+use sbahn::pool::ConnectionPool;
+use sbahn::storage::{HashMapBackend, LogBackend};
+use sbahn::storage_node::StorageNode;
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn clock_only_moves_forward() {
+    let clock = Clock::new(1);
+    let mut last = 0;
+    for _ in 0..10000 {
+        let now = clock.now();
+        assert!(now > last);
+        assert_eq!(hlc::node(now), 1);
+        last = now;
+    }
+}
+
+#[test]
+fn clock_catches_up_with_timestamps_seen() {
+    let clock = Clock::new(1);
+    let ahead = hlc::timestamp(hlc::physical_ms(clock.now()) + 60_000, 5, 2);
+    clock.update(ahead).unwrap();
+    let now = clock.now();
+    assert!(now > ahead);
+    assert_eq!(hlc::physical_ms(now), hlc::physical_ms(ahead));
+
+    // Concurrent timestamps are told apart by node.
+    assert!(hlc::timestamp(10, 0, 2) > hlc::timestamp(10, 0, 1));
+    assert!(hlc::timestamp(10, 1, 1) > hlc::timestamp(10, 0, 2));
+}
+
+#[test]
+fn clock_refuses_timestamps_past_drift() {
+    let clock = Clock::with_max_drift(1, Some(Duration::from_millis(1000)));
+    let wall = hlc::physical_ms(clock.now());
+    assert!(clock.update(hlc::timestamp(wall + 100, 0, 2)).is_ok());
+    assert!(clock.update(hlc::timestamp(wall + 60_000, 0, 2)).is_err());
+    assert!(hlc::physical_ms(clock.now()) < wall + 60_000);
+}
+
+#[test]
+fn node_ids_are_kept_in_the_data_directory() {
+    let dir = data_dir("node-id");
+    let id = hlc::persisted_node_id(&dir).unwrap();
+    assert_eq!(hlc::persisted_node_id(&dir).unwrap(), id);
+
+    // IDs take 16 bits.
+    let clock = Clock::new(40000);
+    assert_eq!(hlc::node(clock.now()), 40000);
+}
+
+#[test]
+fn storage_nodes_with_data_directories_keep_their_ids() {
+    let addr = get_address();
+    let ring = Ring::new(vec![vec![addr]], DEFAULT_VNODES);
+    let dir = data_dir("node-id-storage");
+    let id = {
+        let sn: StorageNode<LogBackend> = StorageNode::with_data_dir(&addr, &ring, &dir).unwrap();
+        sn.clock.node()
+    };
+    let sn: StorageNode<LogBackend> = StorageNode::with_data_dir(&addr, &ring, &dir).unwrap();
+    assert_eq!(sn.clock.node(), id);
+    assert_eq!(hlc::persisted_node_id(&dir).unwrap(), id);
+}
+
+#[test]
+fn nodes_joining_with_a_taken_id_switch_to_another() {
+    let shard = get_shard();
+    let ring = Ring::new(vec![shard.clone()], DEFAULT_VNODES);
+    let mut first: StorageNode<HashMapBackend> = StorageNode::new(&shard[0], &ring);
+    first.clock = Arc::new(Clock::new(7));
+    thread::spawn(move || first.listen());
+    thread::sleep(Duration::from_millis(DELAY));
+
+    // A storage node keeping its ID switches to another one, and keeps it.
+    let dir = data_dir("node-id-taken");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("node-id"), "7\n").unwrap();
+    let mut second: StorageNode<LogBackend> = StorageNode::with_data_dir(&shard[1], &ring, &dir).unwrap();
+    assert_eq!(second.clock.node(), 7);
+    let clock = second.clock.clone();
+    thread::spawn(move || second.listen());
+    thread::sleep(Duration::from_millis(DELAY * 3));
+    let second_id = clock.node();
+    assert!(second_id != 7);
+    assert_eq!(hlc::persisted_node_id(&dir).unwrap(), second_id);
+
+    // A handler can't take an ID another handler claimed either.
+    let mut ids = vec![];
+    for _ in 0..2 {
+        let mut coordinator = Coordinator::new(Arc::new(ConnectionPool::new()));
+        coordinator.clock = Arc::new(Clock::new(8));
+        let clock = coordinator.clock.clone();
+        let _ = handler::listen_with_coordinator(&get_address(), &ring, coordinator);
+        thread::sleep(Duration::from_millis(DELAY * 3));
+        ids.push(clock.node());
+    }
+    assert_eq!(ids[0], 8);
+    assert!(ids[1] != 8 && ids[1] != 7 && ids[1] != second_id);
+}
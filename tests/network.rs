@@ -0,0 +1,232 @@
+extern crate eventual;
+extern crate sbahn;
+
+mod common;
+
+use common::{DELAY, get_address};
+use eventual::*;
+use sbahn::client;
+use sbahn::constants::{DEFAULT_VNODES, HEADER_SIZE};
+use sbahn::message::*;
+use sbahn::network::{FrameHeader, NetworkRead, NetworkWrite};
+use sbahn::pool::ConnectionPool;
+use sbahn::storage::HashMapBackend;
+use sbahn::storage_node::StorageNode;
+use std::io::Write;
+use std::net::{SocketAddrV4, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Listen on a new port and echo back every framed message received.
+fn echo_node() -> SocketAddrV4 {
+    let addr = get_address();
+    let listener = TcpListener::bind(&addr).unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                while let Ok((id, message)) = stream.read_message() {
+                    stream.write_message(id, &message).unwrap();
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// Listen on a new port and echo back every framed message received after
+/// waiting for as many milliseconds as the message's first byte, so that
+/// responses go out in a different order than their requests.
+fn delayed_echo_node() -> SocketAddrV4 {
+    let addr = get_address();
+    let listener = TcpListener::bind(&addr).unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let writer = Arc::new(Mutex::new(stream.try_clone().unwrap()));
+            thread::spawn(move || {
+                while let Ok((id, message)) = stream.read_message() {
+                    let writer = writer.clone();
+                    thread::spawn(move || {
+                        thread::sleep(Duration::from_millis(message[0] as u64));
+                        writer.lock().unwrap().write_message(id, &message).unwrap();
+                    });
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// Accept a single connection, answer one message and then hang up.
+fn one_shot_node() -> SocketAddrV4 {
+    let addr = get_address();
+    let listener = TcpListener::bind(&addr).unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            if let Ok((id, message)) = stream.read_message() {
+                stream.write_message(id, &message).unwrap();
+            }
+        }
+    });
+    addr
+}
+
+#[test]
+fn frame_header_round_trip() {
+    let header = FrameHeader::new(0x0102030405060708, 0x090a0b0c);
+    let bytes = header.to_bytes();
+    assert_eq!(bytes.len(), HEADER_SIZE);
+    assert_eq!(&bytes[2..], &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12][..]);
+    assert_eq!(FrameHeader::from_bytes(&bytes), header);
+}
+
+#[test]
+fn boundary_sized_messages() {
+    let addr = echo_node();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    for size in vec![0, 1, 1023, 1024, 1025, 2048, 4096, 65536, 100000] {
+        let message: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        stream.write_message(size as u64, &message).unwrap();
+        let (id, response) = stream.read_message().unwrap();
+        assert_eq!(id, size as u64);
+        assert_eq!(response.len(), size);
+        assert_eq!(response, message);
+    }
+}
+
+#[test]
+fn message_split_across_reads() {
+    let addr = echo_node();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let message = vec![7; 3000];
+    let mut buf = FrameHeader::new(1, message.len() as u32).to_bytes().to_vec();
+    buf.extend(message.iter());
+    // Trickle the frame so the receiving end sees several short reads.
+    for chunk in buf.chunks(700) {
+        stream.write_all(chunk).unwrap();
+        stream.flush().unwrap();
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(stream.read_message().unwrap(), (1, message));
+}
+
+#[test]
+fn unknown_protocol_version() {
+    let mut header = FrameHeader::new(1, 3).to_bytes().to_vec();
+    header[0] = 42;
+    header.extend(vec![1, 2, 3]);
+    assert!((&header[..]).read_message().is_err());
+}
+
+#[test]
+fn truncated_message() {
+    let mut buf = FrameHeader::new(1, 10).to_bytes().to_vec();
+    buf.extend(vec![1, 2, 3]);
+    assert!((&buf[..]).read_message().is_err());
+}
+
+#[test]
+fn multiplexed_out_of_order_responses() {
+    let addr = delayed_echo_node();
+    let pool = ConnectionPool::new();
+    // The first request is answered last.
+    let delays: Vec<u8> = vec![200, 150, 100, 50, 0];
+    let responses: Vec<Future<Vec<u8>, Error>> = delays.iter()
+        .map(|&d| pool.send_buffer(&addr, &[d, 1, 2, 3], None))
+        .collect();
+    for (delay, response) in delays.iter().zip(responses) {
+        assert_eq!(response.await().unwrap(), vec![*delay, 1, 2, 3]);
+    }
+}
+
+#[test]
+fn pool_reuses_connections() {
+    let addr = echo_node();
+    let pool = ConnectionPool::new();
+    let first = pool.get(&addr).unwrap();
+    assert_eq!(pool.send_buffer(&addr, &[1], None).await().unwrap(), vec![1]);
+    assert_eq!(pool.send_buffer(&addr, &[2], None).await().unwrap(), vec![2]);
+    let second = pool.get(&addr).unwrap();
+    assert!(Arc::ptr_eq(&first, &second));
+}
+
+#[test]
+fn pool_keeps_one_of_the_connections_opened_at_once() {
+    let addr = echo_node();
+    let pool = Arc::new(ConnectionPool::new());
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            let pool = pool.clone();
+            thread::spawn(move || pool.get(&addr).unwrap())
+        })
+        .collect();
+    let connections: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+    let kept = pool.get(&addr).unwrap();
+    for c in connections {
+        assert!(Arc::ptr_eq(&c, &kept));
+    }
+}
+
+#[test]
+fn pool_reconnects_after_connection_closes() {
+    let addr = one_shot_node();
+    let pool = ConnectionPool::new();
+    assert_eq!(pool.send_buffer(&addr, &[1], None).await().unwrap(), vec![1]);
+    thread::sleep(Duration::from_millis(DELAY));  // Wait for the node to hang up
+    assert_eq!(pool.send_buffer(&addr, &[2], None).await().unwrap(), vec![2]);
+}
+
+#[test]
+fn pool_request_timeout() {
+    let addr = delayed_echo_node();
+    let pool = ConnectionPool::new();
+    let r = pool.send_buffer(&addr, &[250], Some(Duration::from_millis(50)));
+    assert_eq!(r.await().map_err(|e| e.take()), Err(Some(Error::Timeout)));
+    // A late response doesn't affect later requests.
+    let r = pool.send_buffer(&addr, &[0, 1], Some(Duration::from_millis(300)));
+    assert_eq!(r.await().unwrap(), vec![0, 1]);
+}
+
+#[test]
+fn storage_node_2048_byte_value() {
+    let addr = get_address();
+    let ring = Ring::new(vec![vec![addr]], DEFAULT_VNODES);
+    let mut sn: StorageNode<HashMapBackend> = StorageNode::new(&addr, &ring);
+    thread::spawn(move || {
+        &sn.listen();
+    });
+    thread::sleep(Duration::from_millis(DELAY));  // Wait for storage node to start listening
+
+    let key = Key {
+        dataset: vec![1, 2, 3],
+        pkey: vec![4, 5, 0],
+        lkey: vec![7, 8, 9],
+    };
+    for size in vec![1024, 2048] {
+        let request = InternodeRequest::Write {
+            key: key.to_owned(),
+            value: Value::Value {
+                content: vec![0; size],
+                timestamp: size as u64,
+                expires: None,
+            },
+        };
+        let r: Future<InternodeResponse, Error> = client::Client::send_to_node(&addr, &request);
+        match r.await().unwrap() {
+            InternodeResponse::WriteAck {timestamp, ..} => assert_eq!(timestamp, size as u64),
+            e => panic!("{:?}", e),
+        }
+
+        let request = InternodeRequest::Read { key: key.to_owned() };
+        let r: Future<InternodeResponse, Error> = client::Client::send_to_node(&addr, &request);
+        match r.await().unwrap() {
+            InternodeResponse::Value {value: Value::Value {content, ..}, ..} => {
+                assert_eq!(content, vec![0; size]);
+            }
+            e => panic!("{:?}", e),
+        }
+    }
+}
@@ -0,0 +1,194 @@
+extern crate eventual;
+extern crate sbahn;
+
+mod common;
+
+use common::{DELAY, data_dir, get_address, key, setup_handler_node, start_dead_storage_node};
+use eventual::*;
+use sbahn::client;
+use sbahn::constants::DEFAULT_VNODES;
+use sbahn::message::*;
+use sbahn::raft::{Raft, RaftConfig, Role};
+use sbahn::storage::HashMapBackend;
+use sbahn::storage_node::StorageNode;
+use sbahn::wal::SyncPolicy;
+use std::env;
+use std::net::SocketAddrV4;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Start a storage node taking part in the Raft group of its shard.
+fn start_raft_node(addr: &SocketAddrV4, ring: &Ring, config: RaftConfig) -> Raft<HashMapBackend> {
+    let mut sn: StorageNode<HashMapBackend> = StorageNode::new(addr, ring);
+    let raft = sn.enable_raft(config).unwrap().unwrap();
+    thread::spawn(move || {
+        &sn.listen();
+    });
+    thread::sleep(Duration::from_millis(DELAY));  // Wait for storage node to start listening
+    raft
+}
+
+/// A storage node running in a process of its own, killed once dropped.
+struct NodeProcess(Child);
+
+impl Drop for NodeProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Start the `i`th node of `shard` in a process of its own, running
+/// `raft_node_process`. It stands for election before any node with the
+/// default configuration would.
+fn spawn_raft_node(shard: &Vec<SocketAddrV4>, i: usize) -> NodeProcess {
+    let addresses: Vec<String> = shard.iter().map(|n| n.to_string()).collect();
+    let child = Command::new(env::current_exe().unwrap())
+                    .args(&["raft_node_process", "--exact", "--ignored"])
+                    .env("SBAHN_RAFT_SHARD", addresses.join(","))
+                    .env("SBAHN_RAFT_NODE", i.to_string())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .spawn()
+                    .unwrap();
+    thread::sleep(Duration::from_millis(DELAY));  // Wait for storage node to start listening
+    NodeProcess(child)
+}
+
+#[test]
+#[ignore]
+fn raft_node_process() {
+    let (shard, i) = match (env::var("SBAHN_RAFT_SHARD"), env::var("SBAHN_RAFT_NODE")) {
+        (Ok(shard), Ok(i)) => {
+            let shard: Vec<SocketAddrV4> = shard.split(',').map(|n| n.parse().unwrap()).collect();
+            (shard, i.parse::<usize>().unwrap())
+        }
+        // Only run by `spawn_raft_node`.
+        _ => return,
+    };
+    let ring = Ring::new(vec![shard.clone()], DEFAULT_VNODES);
+    let mut sn: StorageNode<HashMapBackend> = StorageNode::new(&shard[i], &ring);
+    let config = RaftConfig { election_timeout: Duration::from_millis(50), ..RaftConfig::default() };
+    sn.enable_raft(config).unwrap().unwrap();
+    sn.listen();
+}
+
+/// Wait for one of the running `nodes` to be elected leader, and return its
+/// position.
+fn wait_for_leader(nodes: &Vec<Raft<HashMapBackend>>) -> usize {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(5) {
+        let leaders: Vec<usize> = (0..nodes.len())
+                                      .filter(|&i| nodes[i].role() == Role::Leader)
+                                      .collect();
+        if leaders.len() == 1 {
+            return leaders[0];
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("No leader was elected");
+}
+
+#[test]
+fn linearizable_reads_see_acknowledged_writes() {
+    let shard: Vec<SocketAddrV4> = (0..3).map(|_| get_address()).collect();
+    let ring = Ring::new(vec![shard.clone()], DEFAULT_VNODES);
+    let nodes: Vec<_> = shard.iter().map(|n| start_raft_node(n, &ring, RaftConfig::default())).collect();
+    let leader = wait_for_leader(&nodes);
+    for node in &nodes {
+        assert_eq!(node.leader(), Some(shard[leader]));
+    }
+
+    let client = client::Client::with_consistency(vec![setup_handler_node(&ring)], Consistency::Linearizable);
+    let first = client.insert(&key(1), &vec![1]).await().unwrap();
+    assert_eq!(client.get(&key(1)).await().unwrap(), Some(vec![1]));
+    let second = client.insert(&key(1), &vec![2]).await().unwrap();
+    assert!(second > first);
+    assert_eq!(client.get(&key(1)).await().unwrap(), Some(vec![2]));
+    client.delete(&key(1)).await().unwrap();
+    assert_eq!(client.get(&key(1)).await().unwrap(), None);
+    assert_eq!(wait_for_leader(&nodes), leader);
+}
+
+#[test]
+fn another_leader_takes_over() {
+    let shard: Vec<SocketAddrV4> = (0..5).map(|_| get_address()).collect();
+    let ring = Ring::new(vec![shard.clone()], DEFAULT_VNODES);
+    start_dead_storage_node(&shard[4]);
+    let config = RaftConfig { election_timeout: Duration::from_millis(1000), ..RaftConfig::default() };
+    let nodes: Vec<_> = shard[1..4].iter().map(|n| start_raft_node(n, &ring, config)).collect();
+    let leader = spawn_raft_node(&shard, 0);
+    let client = client::Client::with_consistency(vec![setup_handler_node(&ring)], Consistency::Linearizable);
+    let start = Instant::now();
+    while nodes.iter().any(|n| n.leader() != Some(shard[0])) {
+        assert!(start.elapsed() < Duration::from_secs(5), "The node in its own process wasn't elected");
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    client.insert(&key(1), &vec![1]).await().unwrap();
+    let term = nodes[0].term();
+    drop(leader);
+    start_dead_storage_node(&shard[0]);
+
+    // Three nodes of five are left, just enough to elect a leader and commit.
+    let next = wait_for_leader(&nodes);
+    assert!(nodes[next].term() > term);
+    assert_eq!(client.get(&key(1)).await().unwrap(), Some(vec![1]));
+    client.insert(&key(2), &vec![2]).await().unwrap();
+    assert_eq!(client.get(&key(2)).await().unwrap(), Some(vec![2]));
+}
+
+#[test]
+fn lagging_nodes_catch_up_from_snapshots() {
+    let shard: Vec<SocketAddrV4> = (0..3).map(|_| get_address()).collect();
+    let ring = Ring::new(vec![shard.clone()], DEFAULT_VNODES);
+    let config = RaftConfig { max_log: 5, ..RaftConfig::default() };
+    let mut nodes: Vec<_> = shard[..2].iter().map(|n| start_raft_node(n, &ring, config)).collect();
+    let client = client::Client::with_consistency(vec![setup_handler_node(&ring)], Consistency::Linearizable);
+    wait_for_leader(&nodes);
+    for i in 0..20 {
+        client.insert(&key(i), &vec![i]).await().unwrap();
+    }
+
+    // The entries are long gone from the log by the time the third node starts.
+    nodes.push(start_raft_node(&shard[2], &ring, config));
+    thread::sleep(Duration::from_millis(1000));
+    for i in 0..20 {
+        let request = InternodeRequest::Read { key: key(i) };
+        let r: Future<InternodeResponse, Error> = client::Client::send_to_node(&shard[2], &request);
+        match r.await().unwrap() {
+            InternodeResponse::Value {value: Value::Value {content, ..}, ..} => assert_eq!(content, vec![i]),
+            r => panic!("{:?}", r),
+        }
+    }
+}
+
+#[test]
+fn terms_and_logs_survive_restarts() {
+    let dir = data_dir("raft-restart");
+    let address = get_address();
+    let ring = Ring::new(vec![vec![address]], DEFAULT_VNODES);
+    let start = || {
+        let mut sn: StorageNode<HashMapBackend> = StorageNode::with_wal(&address, &ring, &dir, SyncPolicy::Always)
+                                                      .unwrap();
+        sn.enable_raft(RaftConfig::default()).unwrap().unwrap()
+    };
+
+    let raft = start();
+    raft.run();
+    wait_for_leader(&vec![raft.clone()]);
+    raft.write(&key(1), Some(vec![1]), None).unwrap();
+    let term = raft.term();
+    drop(raft);
+
+    let raft = start();
+    assert_eq!(raft.term(), term);
+    raft.run();
+    wait_for_leader(&vec![raft.clone()]);
+    assert!(raft.term() > term);
+    match raft.read(&key(1)) {
+        Ok(Value::Value {content, ..}) => assert_eq!(content, vec![1]),
+        r => panic!("{:?}", r),
+    }
+}
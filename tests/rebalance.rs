@@ -0,0 +1,177 @@
+extern crate eventual;
+extern crate sbahn;
+
+mod common;
+
+use common::{get_shard, key, read_from_storage_node, setup_handler_node, start_storage_node};
+use eventual::*;
+use sbahn::client;
+use sbahn::constants::DEFAULT_VNODES;
+use sbahn::message::*;
+use sbahn::pool::ConnectionPool;
+use sbahn::rebalance;
+
+fn send_to_handler(client: &client::Client, action: Action) -> Response {
+    let request = Request {
+        action: action,
+        consistency: Consistency::Latest,
+        timestamp: None,
+        deadline: None,
+        context: None,
+    };
+    client.send(&request).await().unwrap().message
+}
+
+fn assert_value(response: InternodeResponse, content: &Vec<u8>) {
+    match response {
+        InternodeResponse::Value {value: Value::Value {content: c, ..}, ..} => assert_eq!(&c, content),
+        r => panic!("{:?}", r),
+    }
+}
+
+#[test]
+fn reshard_moves_keys_to_new_shard() {
+    let old_shard = get_shard();
+    let old = Ring::new(vec![old_shard.clone()], DEFAULT_VNODES);
+    for node in &old_shard {
+        start_storage_node(node, &old);
+    }
+    let handler_addr = setup_handler_node(&old);
+    let client = client::Client::new(vec![handler_addr]);
+
+    for i in 0..40 {
+        client.insert(&key(i), &vec![i]).await().unwrap();
+    }
+
+    let new_shard = get_shard();
+    let new = Ring::new(vec![old_shard.clone(), new_shard.clone()], DEFAULT_VNODES);
+    for node in &new_shard {
+        start_storage_node(node, &new);
+    }
+
+    let pool = ConnectionPool::new();
+    let count = rebalance::reshard(&pool, &vec![handler_addr], &old, &new).unwrap();
+    assert!(count > 0);
+
+    let mut moved = 0;
+    for i in 0..40 {
+        assert_eq!(client.get(&key(i)).await().unwrap(), Some(vec![i]));
+        if new.shard(&key(i)) == Some(1) {
+            moved += 1;
+            // Every new replica got the entry...
+            for node in &new_shard {
+                assert_value(read_from_storage_node(node, &key(i)), &vec![i]);
+            }
+            // ...and the old ones don't serve it anymore.
+            match read_from_storage_node(&old_shard[0], &key(i)) {
+                InternodeResponse::Error {..} => (),
+                r => panic!("{:?}", r),
+            }
+        }
+    }
+    assert!(moved > 0);
+}
+
+#[test]
+fn double_write_and_read_fallback_during_handover() {
+    let old_shard = get_shard();
+    let old = Ring::new(vec![old_shard.clone()], DEFAULT_VNODES);
+    for node in &old_shard {
+        start_storage_node(node, &old);
+    }
+    let handler_addr = setup_handler_node(&old);
+    let client = client::Client::new(vec![handler_addr]);
+
+    let new_shard = get_shard();
+    let new = Ring::new(vec![old_shard.clone(), new_shard.clone()], DEFAULT_VNODES);
+    for node in &new_shard {
+        start_storage_node(node, &new);
+    }
+
+    // Two keys that move to the new shard.
+    let moving: Vec<Key> = (0..255).map(key).filter(|k| new.shard(k) == Some(1)).take(2).collect();
+    let (before, during) = (&moving[0], &moving[1]);
+
+    // Written before the handover starts, only the old replicas have it.
+    client.insert(before, &vec![1]).await().unwrap();
+
+    for node in &old_shard {
+        let request = InternodeRequest::PrepareTopology { ring: new.to_owned() };
+        let r: Future<InternodeResponse, Error> = client::Client::send_to_node(node, &request);
+        assert_eq!(r.await().unwrap(), InternodeResponse::TopologyAck);
+    }
+    assert_eq!(send_to_handler(&client, Action::PrepareTopology { ring: new.to_owned() }),
+               Response::TopologyAck);
+
+    // Written during the handover, both the old and new replicas have it.
+    client.insert(during, &vec![2]).await().unwrap();
+    for node in old_shard.iter().chain(new_shard.iter()) {
+        assert_value(read_from_storage_node(node, during), &vec![2]);
+    }
+
+    // The new replicas don't have it yet, so the old ones are read.
+    assert_eq!(client.get(before).await().unwrap(), Some(vec![1]));
+}
+
+#[test]
+fn token_aware_client_follows_reshard() {
+    let old_shard = get_shard();
+    let old = Ring::new(vec![old_shard.clone()], DEFAULT_VNODES);
+    for node in &old_shard {
+        start_storage_node(node, &old);
+    }
+    let handler_addr = setup_handler_node(&old);
+    let mut client = client::Client::token_aware(vec![handler_addr]).unwrap();
+    // Writes turned away by the old replicas are only retried if allowed.
+    client.retry_policy.retry_writes = true;
+
+    for i in 0..40 {
+        client.insert(&key(i), &vec![i]).await().unwrap();
+    }
+
+    let new_shard = get_shard();
+    let new = Ring::new(vec![old_shard.clone(), new_shard.clone()], DEFAULT_VNODES);
+    for node in &new_shard {
+        start_storage_node(node, &new);
+    }
+    let pool = ConnectionPool::new();
+    rebalance::reshard(&pool, &vec![handler_addr], &old, &new).unwrap();
+
+    // The client's topology is stale, until the old replicas turn it away.
+    for i in 0..40 {
+        client.insert(&key(i), &vec![i + 1]).await().unwrap();
+        assert_eq!(client.get(&key(i)).await().unwrap(), Some(vec![i + 1]));
+        if new.shard(&key(i)) == Some(1) {
+            for node in &new_shard {
+                assert_value(read_from_storage_node(node, &key(i)), &vec![i + 1]);
+            }
+        }
+    }
+}
+
+#[test]
+fn token_aware_client_doesnt_retry_writes_unless_allowed() {
+    let old_shard = get_shard();
+    let old = Ring::new(vec![old_shard.clone()], DEFAULT_VNODES);
+    for node in &old_shard {
+        start_storage_node(node, &old);
+    }
+    let handler_addr = setup_handler_node(&old);
+    let client = client::Client::token_aware(vec![handler_addr]).unwrap();
+
+    let new_shard = get_shard();
+    let new = Ring::new(vec![old_shard.clone(), new_shard.clone()], DEFAULT_VNODES);
+    for node in &new_shard {
+        start_storage_node(node, &new);
+    }
+    let pool = ConnectionPool::new();
+    rebalance::reshard(&pool, &vec![handler_addr], &old, &new).unwrap();
+
+    // The write fails on the stale topology, which is refreshed for the next.
+    let moved = (0..40).map(key).find(|k| new.shard(k) == Some(1)).unwrap();
+    assert!(client.insert(&moved, &vec![1]).await().is_err());
+    client.insert(&moved, &vec![2]).await().unwrap();
+    for node in &new_shard {
+        assert_value(read_from_storage_node(node, &moved), &vec![2]);
+    }
+}
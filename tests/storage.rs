@@ -0,0 +1,482 @@
+extern crate eventual;
+extern crate sbahn;
+
+mod common;
+
+use common::{DELAY, data_dir, get_address, key, value};
+use eventual::*;
+use sbahn::client;
+use sbahn::constants::DEFAULT_VNODES;
+use sbahn::hlc::Clock;
+use sbahn::lsm::{LsmBackend, LsmConfig};
+use sbahn::message::*;
+use sbahn::storage::{HashMapBackend, LogBackend, StorageBackend};
+use sbahn::storage_node::StorageNode;
+use sbahn::vclock::VectorClock;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+fn segments(dir: &PathBuf) -> Vec<PathBuf> {
+    let mut segments: Vec<PathBuf> = fs::read_dir(dir).unwrap().map(|e| e.unwrap().path()).collect();
+    segments.sort();
+    segments
+}
+
+fn sstables(dir: &PathBuf) -> usize {
+    segments(dir).iter().filter(|p| p.extension().unwrap() == "sst").count()
+}
+
+/// An `LsmConfig` flushing the memtable on every insert, so that each
+/// insert lands in its own SSTable.
+fn tiny_lsm_config(tier_fanout: usize) -> LsmConfig {
+    LsmConfig {
+        memtable_size: 1,
+        block_size: 64,
+        tier_fanout: tier_fanout,
+        ..LsmConfig::default()
+    }
+}
+
+#[test]
+fn log_backend_insert_and_get() {
+    let log = LogBackend::open(&data_dir("insert")).unwrap();
+    assert_eq!(log.get(&key(1)), None);
+    log.insert(key(1), value(1, 1)).unwrap();
+    log.insert(key(2), value(2, 1)).unwrap();
+    log.insert(key(1), value(3, 2)).unwrap();
+    assert_eq!(log.get(&key(1)), Some(value(3, 2)));
+    assert_eq!(log.get(&key(2)), Some(value(2, 1)));
+
+    let mut entries = log.entries();
+    entries.sort_by(|a, b| a.0.pkey.cmp(&b.0.pkey));
+    assert_eq!(entries, vec![(key(1), value(3, 2)), (key(2), value(2, 1))]);
+}
+
+#[test]
+fn log_backend_recovers_after_reopen() {
+    let dir = data_dir("reopen");
+    {
+        let log = LogBackend::open(&dir).unwrap();
+        for i in 0..20 {
+            log.insert(key(i), value(i, 1)).unwrap();
+        }
+        log.insert(key(0), value(42, 2)).unwrap();
+    }
+    let log = LogBackend::open(&dir).unwrap();
+    assert_eq!(log.get(&key(0)), Some(value(42, 2)));
+    for i in 1..20 {
+        assert_eq!(log.get(&key(i)), Some(value(i, 1)));
+    }
+    assert_eq!(log.entries().len(), 20);
+}
+
+#[test]
+fn log_backend_skips_torn_tail() {
+    let dir = data_dir("torn");
+    {
+        let log = LogBackend::open(&dir).unwrap();
+        log.insert(key(1), value(1, 1)).unwrap();
+        log.insert(key(2), value(2, 1)).unwrap();
+    }
+    // A crash in the middle of appending a record.
+    let path = segments(&dir).pop().unwrap();
+    let len = fs::metadata(&path).unwrap().len();
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&[0, 0, 0, 50, 1, 2, 3, 4, 5, 6]).unwrap();
+
+    let log = LogBackend::open(&dir).unwrap();
+    assert_eq!(log.get(&key(1)), Some(value(1, 1)));
+    assert_eq!(log.get(&key(2)), Some(value(2, 1)));
+    assert_eq!(fs::metadata(&path).unwrap().len(), len);
+
+    // Later writes are appended after the last intact record.
+    log.insert(key(3), value(3, 1)).unwrap();
+    drop(log);
+    let log = LogBackend::open(&dir).unwrap();
+    assert_eq!(log.get(&key(3)), Some(value(3, 1)));
+    assert_eq!(log.entries().len(), 3);
+}
+
+#[test]
+fn log_backend_skips_corrupt_tail() {
+    let dir = data_dir("corrupt");
+    {
+        let log = LogBackend::open(&dir).unwrap();
+        log.insert(key(1), value(1, 1)).unwrap();
+        log.insert(key(2), value(2, 1)).unwrap();
+    }
+    // Flip the last byte of the last record, so that its checksum fails.
+    let path = segments(&dir).pop().unwrap();
+    let mut content = fs::read(&path).unwrap();
+    let last = content.len() - 1;
+    content[last] ^= 0xff;
+    fs::write(&path, &content).unwrap();
+
+    let log = LogBackend::open(&dir).unwrap();
+    assert_eq!(log.get(&key(1)), Some(value(1, 1)));
+    assert_eq!(log.get(&key(2)), None);
+}
+
+/// Fail to insert a write too large for the file size limit, then check
+/// that `backend` keeps working.
+fn fill<B: StorageBackend>(backend: B) {
+    backend.insert(key(1), value(1, 1)).unwrap();
+    let large = Value::Value {
+        content: vec![2; 64 * 1024],
+        timestamp: 1,
+        expires: None,
+    };
+    assert!(backend.insert(key(2), large).is_err());
+    assert_eq!(backend.get(&key(1)), Some(value(1, 1)));
+    backend.insert(key(3), value(3, 1)).unwrap();
+}
+
+/// Not a test on its own: fills a backend in `SBAHN_FULL_DIR` when started
+/// by `backend_survives_failed_write` with a file size limit.
+#[test]
+fn full_backend() {
+    let (backend, dir) = match (env::var("SBAHN_FULL_BACKEND"), env::var("SBAHN_FULL_DIR")) {
+        (Ok(b), Ok(d)) => (b, PathBuf::from(d)),
+        _ => return,
+    };
+    match &backend[..] {
+        "log" => fill(LogBackend::open(&dir).unwrap()),
+        _ => fill(LsmBackend::open(&dir).unwrap()),
+    }
+}
+
+#[test]
+fn backend_survives_failed_write() {
+    for backend in &["log", "lsm"] {
+        let dir = data_dir(&format!("full-{}", backend));
+        // Writing past the limit fails instead of killing the process.
+        let status = Command::new("sh")
+                         .args(&["-c", "ulimit -f 16; trap '' XFSZ; exec \"$0\" full_backend --exact"])
+                         .arg(env::current_exe().unwrap())
+                         .env("SBAHN_FULL_BACKEND", backend)
+                         .env("SBAHN_FULL_DIR", &dir)
+                         .stdout(Stdio::null())
+                         .stderr(Stdio::null())
+                         .status()
+                         .unwrap();
+        assert!(status.success(), "{} backend", backend);
+
+        let entries = match *backend {
+            "log" => LogBackend::open(&dir).unwrap().entries(),
+            _ => LsmBackend::open(&dir).unwrap().entries(),
+        };
+        assert_eq!(entries, vec![(key(1), value(1, 1)), (key(3), value(3, 1))]);
+    }
+}
+
+#[test]
+fn log_backend_rotates_segments() {
+    let dir = data_dir("rotate");
+    {
+        let log = LogBackend::open_with_segment_size(&dir, 256).unwrap();
+        for i in 0..50 {
+            log.insert(key(i), value(i, 1)).unwrap();
+        }
+    }
+    assert!(segments(&dir).len() > 1);
+    let log = LogBackend::open_with_segment_size(&dir, 256).unwrap();
+    for i in 0..50 {
+        assert_eq!(log.get(&key(i)), Some(value(i, 1)));
+    }
+}
+
+#[test]
+fn storage_node_with_data_dir() {
+    let dir = data_dir("storage-node");
+    let addr = get_address();
+    let ring = Ring::new(vec![vec![addr]], DEFAULT_VNODES);
+    {
+        let sn: StorageNode<LogBackend> = StorageNode::with_data_dir(&addr, &ring, &dir).unwrap();
+        sn.map.insert(key(1), value(1, 1)).unwrap();
+    }
+    let sn: StorageNode<LogBackend> = StorageNode::with_data_dir(&addr, &ring, &dir).unwrap();
+    assert_eq!(sn.map.get(&key(1)), Some(value(1, 1)));
+}
+
+#[test]
+fn lsm_backend_reads_across_memtable_and_sstables() {
+    let dir = data_dir("lsm-read");
+    let config = LsmConfig {
+        memtable_size: 200,
+        block_size: 64,
+        tier_fanout: 100,
+        ..LsmConfig::default()
+    };
+    let lsm = LsmBackend::open_with_config(&dir, config).unwrap();
+    for i in 0..50 {
+        lsm.insert(key(i), value(i, 1)).unwrap();
+    }
+    lsm.insert(key(0), value(42, 2)).unwrap();
+    lsm.insert(key(1), Value::Tombstone { timestamp: 2 }).unwrap();
+    assert!(sstables(&dir) > 1);
+
+    assert_eq!(lsm.get(&key(0)), Some(value(42, 2)));
+    assert_eq!(lsm.get(&key(1)), Some(Value::Tombstone { timestamp: 2 }));
+    for i in 2..50 {
+        assert_eq!(lsm.get(&key(i)), Some(value(i, 1)));
+    }
+    assert_eq!(lsm.get(&key(200)), None);
+    assert_eq!(lsm.entries().len(), 50);
+}
+
+#[test]
+fn lsm_backend_recovers_after_reopen() {
+    let dir = data_dir("lsm-reopen");
+    let config = LsmConfig {
+        memtable_size: 200,
+        ..LsmConfig::default()
+    };
+    {
+        let lsm = LsmBackend::open_with_config(&dir, config.clone()).unwrap();
+        for i in 0..20 {
+            lsm.insert(key(i), value(i, 1)).unwrap();
+        }
+    }
+    // Some of the entries were only in the memtable, and are replayed from
+    // its log.
+    let lsm = LsmBackend::open_with_config(&dir, config).unwrap();
+    for i in 0..20 {
+        assert_eq!(lsm.get(&key(i)), Some(value(i, 1)));
+    }
+    lsm.insert(key(20), value(20, 1)).unwrap();
+    assert_eq!(lsm.entries().len(), 21);
+}
+
+#[test]
+fn lsm_backend_compaction_merges_tiers() {
+    let dir = data_dir("lsm-compaction");
+    let lsm = LsmBackend::open_with_config(&dir, tiny_lsm_config(4)).unwrap();
+    for round in 0..4 {
+        for i in 0..4 {
+            lsm.insert(key(i), value(round, round as u64)).unwrap();
+        }
+    }
+    // Sixteen flushes make four SSTables of tier 1, merged into one of tier 2.
+    lsm.wait_for_compaction();
+    assert_eq!(sstables(&dir), 1);
+    for i in 0..4 {
+        assert_eq!(lsm.get(&key(i)), Some(value(3, 3)));
+    }
+    // Overwritten entries are gone.
+    assert_eq!(lsm.entries().len(), 4);
+    drop(lsm);
+
+    let lsm = LsmBackend::open_with_config(&dir, tiny_lsm_config(4)).unwrap();
+    for i in 0..4 {
+        assert_eq!(lsm.get(&key(i)), Some(value(3, 3)));
+    }
+}
+
+#[test]
+fn lsm_backend_compaction_drops_expired_tombstones() {
+    let dir = data_dir("lsm-tombstones");
+    let lsm = LsmBackend::open_with_config(&dir, tiny_lsm_config(2)).unwrap();
+    lsm.insert(key(1), value(1, 1)).unwrap();
+    lsm.insert(key(1), Value::Tombstone { timestamp: 2 }).unwrap();
+    // Merged into the oldest SSTable, where there's nothing left to shadow.
+    lsm.wait_for_compaction();
+    assert_eq!(sstables(&dir), 1);
+    assert_eq!(lsm.get(&key(1)), None);
+
+    // Tombstones within the grace period are kept.
+    let recent = Clock::new(0).now();
+    lsm.insert(key(2), value(2, 1)).unwrap();
+    lsm.insert(key(2), Value::Tombstone { timestamp: recent }).unwrap();
+    lsm.wait_for_compaction();
+    assert_eq!(lsm.get(&key(2)), Some(Value::Tombstone { timestamp: recent }));
+    assert_eq!(lsm.entries(), vec![(key(2), Value::Tombstone { timestamp: recent })]);
+}
+
+#[test]
+fn storage_node_with_lsm_backend() {
+    let addr = get_address();
+    let ring = Ring::new(vec![vec![addr]], DEFAULT_VNODES);
+    let mut sn: StorageNode<LsmBackend> = StorageNode::new(&addr, &ring);
+    thread::spawn(move || {
+        &sn.listen();
+    });
+    thread::sleep(Duration::from_millis(DELAY));  // Wait for storage node to start listening
+
+    let request = InternodeRequest::Write {
+        key: key(1),
+        value: value(1, 1),
+    };
+    let r: Future<InternodeResponse, Error> = client::Client::send_to_node(&addr, &request);
+    match r.await().unwrap() {
+        InternodeResponse::WriteAck {timestamp, ..} => assert_eq!(timestamp, 1),
+        e => panic!("{:?}", e),
+    }
+
+    let request = InternodeRequest::Read { key: key(1) };
+    let r: Future<InternodeResponse, Error> = client::Client::send_to_node(&addr, &request);
+    match r.await().unwrap() {
+        InternodeResponse::Value {value: v, ..} => assert_eq!(v, value(1, 1)),
+        e => panic!("{:?}", e),
+    }
+}
+
+fn check_scan<B: StorageBackend>(backend: B) {
+    let row = |pkey: Vec<u8>, lkey: u8| {
+        Key {
+            dataset: vec![1],
+            pkey: pkey,
+            lkey: vec![lkey],
+        }
+    };
+    for i in (0..20).rev() {
+        backend.insert(row(vec![5], i), value(i, 1)).unwrap();
+    }
+    backend.insert(row(vec![5], 4), Value::Tombstone { timestamp: 2 }).unwrap();
+    backend.insert(row(vec![4], 0), value(0, 1)).unwrap();
+    backend.insert(row(vec![5, 0], 0), value(0, 1)).unwrap();
+
+    let (start, end) = Key::lkey_range(&vec![1], &vec![5], &vec![2], &Some(vec![6]));
+    assert_eq!(backend.scan(&start, &end, 10, false),
+               vec![(row(vec![5], 2), value(2, 1)),
+                    (row(vec![5], 3), value(3, 1)),
+                    (row(vec![5], 4), Value::Tombstone { timestamp: 2 }),
+                    (row(vec![5], 5), value(5, 1))]);
+    assert_eq!(backend.scan(&start, &end, 2, true),
+               vec![(row(vec![5], 5), value(5, 1)), (row(vec![5], 4), Value::Tombstone { timestamp: 2 })]);
+
+    let (start, end) = Key::lkey_range(&vec![1], &vec![5], &vec![], &None);
+    let lkeys: Vec<u8> = backend.scan(&start, &end, 100, false).into_iter().map(|(k, _)| k.lkey[0]).collect();
+    assert_eq!(lkeys, (0..20).collect::<Vec<u8>>());
+}
+
+#[test]
+fn backends_scan_in_key_order() {
+    check_scan(HashMapBackend::new());
+    check_scan(LogBackend::open(&data_dir("scan-log")).unwrap());
+    check_scan(LsmBackend::open_with_config(&data_dir("scan-lsm"), tiny_lsm_config(3)).unwrap());
+}
+
+#[test]
+fn lsm_backend_scans_pages_across_sstables() {
+    let config = LsmConfig {
+        memtable_size: 400,
+        block_size: 64,
+        ..LsmConfig::default()
+    };
+    let lsm = LsmBackend::open_with_config(&data_dir("scan-lsm-pages"), config).unwrap();
+    for round in 0..3 {
+        for i in 0..100 {
+            if (i + round) % 3 == 0 {
+                lsm.insert(key(i), value(round, round as u64 + 1)).unwrap();
+            }
+        }
+    }
+    let start = key(0);
+    let end = key(200);
+    let mut paged = vec![];
+    let mut from = start.to_owned();
+    loop {
+        let page = lsm.scan(&from, &end, 7, false);
+        if let Some(&(ref last, _)) = page.last() {
+            from = last.successor();
+        }
+        let done = page.len() < 7;
+        paged.extend(page);
+        if done {
+            break;
+        }
+    }
+    assert_eq!(paged, lsm.entries());
+    let last: Vec<(Key, Value)> = lsm.entries().into_iter().rev().take(7).collect();
+    assert_eq!(lsm.scan(&start, &end, 7, true), last);
+}
+
+fn check_last_writer_wins<B: StorageBackend>(backend: B) {
+    assert!(backend.insert_if_newer(key(1), value(1, 2)).unwrap());
+    assert!(!backend.insert_if_newer(key(1), value(2, 1)).unwrap());
+    assert!(!backend.insert_if_newer(key(1), value(3, 2)).unwrap());
+    assert_eq!(backend.get(&key(1)), Some(value(1, 2)));
+
+    // A late write doesn't undo a delete.
+    assert!(backend.insert_if_newer(key(1), Value::Tombstone { timestamp: 3 }).unwrap());
+    assert!(!backend.insert_if_newer(key(1), value(4, 2)).unwrap());
+    assert_eq!(backend.get(&key(1)), Some(Value::Tombstone { timestamp: 3 }));
+
+    assert!(!backend.compare_and_set(key(2), value(5, 1), &|current| current.is_some()).unwrap());
+    assert!(backend.compare_and_set(key(2), value(5, 1), &|current| current.is_none()).unwrap());
+    assert_eq!(backend.get(&key(2)), Some(value(5, 1)));
+}
+
+#[test]
+fn backends_keep_the_newest_value() {
+    check_last_writer_wins(HashMapBackend::new());
+    check_last_writer_wins(LogBackend::open(&data_dir("lww-log")).unwrap());
+    check_last_writer_wins(LsmBackend::open_with_config(&data_dir("lww-lsm"), tiny_lsm_config(3)).unwrap());
+}
+
+fn sibling(node: u64, timestamp: u64, context: &[(u64, u64)], content: u8) -> Sibling {
+    let mut clock = VectorClock::new();
+    for &(n, c) in context {
+        clock.witness(n, c);
+    }
+    Sibling {
+        node: node,
+        timestamp: timestamp,
+        context: clock,
+        content: Some(vec![content]),
+    }
+}
+
+fn versioned(siblings: Vec<Sibling>) -> Value {
+    Value::Versioned { siblings: siblings }
+}
+
+fn check_siblings<B: StorageBackend>(backend: B) {
+    // Concurrent writes are both kept, a repeated one only once.
+    assert!(backend.merge(key(1), versioned(vec![sibling(1, 2, &[], 1)])).unwrap());
+    assert!(backend.merge(key(1), versioned(vec![sibling(2, 1, &[], 2)])).unwrap());
+    assert!(!backend.merge(key(1), versioned(vec![sibling(1, 2, &[], 1)])).unwrap());
+    assert_eq!(backend.get(&key(1)),
+               Some(versioned(vec![sibling(2, 1, &[], 2), sibling(1, 2, &[], 1)])));
+
+    // A write that read both supersedes them, and outlives a late sibling
+    // it had read too.
+    assert!(backend.merge(key(1), versioned(vec![sibling(1, 3, &[(1, 2), (2, 1)], 3)])).unwrap());
+    assert!(!backend.merge(key(1), versioned(vec![sibling(2, 1, &[], 2)])).unwrap());
+    assert_eq!(backend.get(&key(1)), Some(versioned(vec![sibling(1, 3, &[(1, 2), (2, 1)], 3)])));
+}
+
+#[test]
+fn backends_keep_concurrent_siblings() {
+    check_siblings(HashMapBackend::new());
+    check_siblings(LogBackend::open(&data_dir("siblings-log")).unwrap());
+    check_siblings(LsmBackend::open_with_config(&data_dir("siblings-lsm"), tiny_lsm_config(3)).unwrap());
+}
+
+#[test]
+fn storage_node_sweeps_expired_values() {
+    let addr = get_address();
+    let ring = Ring::new(vec![vec![addr]], DEFAULT_VNODES);
+    let sn: StorageNode<HashMapBackend> = StorageNode::new(&addr, &ring);
+    let expiring = |timestamp: u64, expires: u64| {
+        Value::Value {
+            content: vec![1],
+            timestamp: timestamp,
+            expires: Some(expires),
+        }
+    };
+    sn.map.insert(key(1), expiring(1, 1)).unwrap();
+    sn.map.insert(key(2), expiring(2, u64::max_value())).unwrap();
+    sn.map.insert(key(3), value(3, 3)).unwrap();
+
+    assert_eq!(sn.sweep_expired(), 1);
+    assert_eq!(sn.map.get(&key(1)), Some(Value::Tombstone { timestamp: 1 }));
+    assert_eq!(sn.map.get(&key(2)), Some(expiring(2, u64::max_value())));
+    assert_eq!(sn.map.get(&key(3)), Some(value(3, 3)));
+    assert_eq!(sn.sweep_expired(), 0);
+}
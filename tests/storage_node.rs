@@ -1,124 +1,75 @@
 extern crate eventual;
 extern crate sbahn;
 
+mod common;
+
+use common::{DELAY, get_address, get_shard, read_from_storage_node, setup_handler_node, start_dead_storage_node,
+             start_storage_node, versioned, write_to_storage_node};
 use eventual::*;
 use sbahn::client;
+use sbahn::constants::{DEFAULT_VNODES, HINT_PROBE_MS, STORAGE_TIMEOUT_MS};
+use sbahn::coordinator::{Coordinator, ReadRepair, RepairMode};
 use sbahn::handler;
+use sbahn::hlc::{self, Clock};
 use sbahn::message::*;
-use sbahn::storage::HashMapBackend;
-use sbahn::storage_node::StorageNode;
-use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+use sbahn::pool::ConnectionPool;
+use std::net::{SocketAddrV4, TcpListener};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
-
-// Milis to wait before trying to connect to any node.
-static DELAY: u64 = 100;
-
-static mut PORT: u16 = 1100;
-/// Obtain an open port
-fn get_port() -> u16 {
-    let mut port = 0;
-    loop {
-        unsafe {
-          PORT += 1;
-          port = PORT;
-        }
-        let addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port);
-        if TcpListener::bind(&addr).is_ok() {
-            // Check wether the port is open, and only return it if it is.
-            return port;
-        }
-    }
-}
-
-fn get_storage_node(pos: usize, shard_count: usize) -> SocketAddrV4 {
-    let addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), get_port());
-    let mut sn: StorageNode<HashMapBackend> = StorageNode::new(&addr, pos, shard_count);
-    thread::spawn(move || {
-        &sn.listen();
-    });
-    thread::sleep(Duration::from_millis(DELAY));  // Wait for storage node to start listening
-    addr
-}
+use std::time::{Duration, Instant};
 
-fn setup_handler_node(shards: &Vec<Vec<SocketAddrV4>>) -> SocketAddrV4 {
-    let shards = shards.clone();
-    let addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), get_port());
+/// Start a handler whose reads repair stale replicas before responding, with
+/// probability `chance`.
+fn setup_repairing_handler_node(ring: &Ring, chance: f64) -> SocketAddrV4 {
+    let ring = ring.clone();
+    let addr = get_address();
+    let read_repair = ReadRepair {
+        mode: RepairMode::Foreground,
+        chance: chance,
+    };
     thread::spawn(move || {
-        let _ = handler::listen(&addr, &shards);
+        let _ = handler::listen_with_read_repair(&addr, &ring, read_repair);
     });
     thread::sleep(Duration::from_millis(DELAY));  // Wait for handler node to start listening
     addr
 }
 
-/// Listen on `address` for incoming client requests, and do nothing.
-pub fn dead_node(address: &SocketAddrV4) -> Future<(), ()> {
-    let address = address.to_owned();
-
-    let read_timeout = Some(Duration::from_millis(300));
-    let write_timeout = Some(Duration::from_millis(300));
-
-    Future::spawn(move || {
-        match TcpListener::bind(&address) {
-            Ok(listener) => {
-                // Accept connections and process them, spawning a new thread for each one.
-                for stream in listener.incoming() {
-                    match stream {
-                        Ok(stream) => {
-                            let _ = stream.set_read_timeout(read_timeout);
-                            let _ = stream.set_write_timeout(write_timeout);
-                            thread::spawn(|| {
-                                thread::sleep(Duration::from_millis(500));
-                            });
-                        }
-                        Err(e) => panic!("Connection failed!: {:?}", e),
-                    }
-                }
-            }
-            Err(e) => panic!("Error while binding to {:?}: {:?}", address, e),
-        }
-    })
-}
-
-fn get_dead_storage_node() -> SocketAddrV4 {
-    let addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), get_port());
+/// Listen on `address` for incoming client requests, and never answer them.
+fn start_silent_node(address: &SocketAddrV4) {
+    let listener = TcpListener::bind(address).unwrap();
     thread::spawn(move || {
-        let _ = dead_node(&addr);
+        let streams: Vec<_> = listener.incoming().collect();
+        drop(streams);
     });
-    thread::sleep(Duration::from_millis(DELAY));  // Wait for storage node to start listening
-    addr
 }
 
-fn setup_cluster() -> (SocketAddrV4, Vec<Vec<SocketAddrV4>>) {
+fn setup_cluster() -> (SocketAddrV4, Ring) {
     setup_bad_cluster(0)
 }
 
-fn setup_bad_cluster(bad_nodes: u32) -> (SocketAddrV4, Vec<Vec<SocketAddrV4>>) {
+/// Start three shards of three storage nodes each, `bad_nodes` of which
+/// are dead in every shard, and a handler in front of them.
+fn setup_bad_cluster(bad_nodes: u32) -> (SocketAddrV4, Ring) {
     let mut shards: Vec<Vec<SocketAddrV4>> = vec![];
-    for i in 0..3 {
+    for _ in 0..3 {
         let mut shard: Vec<SocketAddrV4> = vec![];
-        for _ in 0..bad_nodes {
-            shard.push(get_dead_storage_node());
-        }
-        for _ in bad_nodes..3 {
-            shard.push(get_storage_node(i, 3));
+        for _ in 0..3 {
+            shard.push(get_address());
         }
         shards.push(shard);
     }
+    let ring = Ring::new(shards, DEFAULT_VNODES);
+    for shard in ring.shards() {
+        for (i, node) in shard.iter().enumerate() {
+            if (i as u32) < bad_nodes {
+                start_dead_storage_node(node);
+            } else {
+                start_storage_node(node, &ring);
+            }
+        }
+    }
 
-    (setup_handler_node(&shards), shards)
-}
-
-fn write_to_storage_node(target: &SocketAddrV4, key: &Key, value: &Vec<u8>, timestamp: u64) {
-    let request = InternodeRequest::Write {
-        key: key.to_owned(),
-        value: Value::Value {
-            content: value.to_owned(),
-            timestamp: timestamp,
-        },
-    };
-    let r: Future<InternodeResponse, Error> = client::Client::send_to_node(target, &request);
-    let _ = r.await();
+    (setup_handler_node(&ring), ring)
 }
 
 fn key_and_value() -> (Key, Vec<u8>) {
@@ -138,35 +89,19 @@ fn read_what_you_insert() {
 
     let client = client::Client::new(vec![handler_addr]);
     // Should succeed
-    let r = client.insert(&local_key, &local_value);
-    let r = r.await().unwrap();
-    match r.message {
-        Response::WriteAck {key, ..} => assert_eq!(key, local_key),
-        _ => assert!(false),
-    }
+    assert!(client.insert(&local_key, &local_value).await().unwrap() > 0);
     // Should succeed
-    let r = client.get(&local_key);
-    let r = r.await().unwrap();
-    match r.message {
-        Response::Value {key, value} => {
-            assert_eq!(key, local_key);
-            match value {
-                Value::Value {content, ..} => assert_eq!(content, local_value),
-                _ => assert!(false),
-            }
-        },
-        _ => assert!(false),
-    }
+    assert_eq!(client.get(&local_key).await().unwrap(), Some(local_value));
 }
 
 #[test]
 fn read_consistency_one_all_nodes_available() {
-    let (handler_addr, shards) = setup_cluster();
+    let (handler_addr, ring) = setup_cluster();
     let (local_key, local_value) = key_and_value();
 
-    for shard in shards {
+    for shard in ring.shards() {
         for node in shard {
-            write_to_storage_node(&node, &local_key, &local_value, 100000);
+            write_to_storage_node(&node, &local_key, &versioned(&local_value, 100000));
         }
     }
     thread::sleep(Duration::from_millis(DELAY));  // Wait for storage node to start listening
@@ -174,56 +109,34 @@ fn read_consistency_one_all_nodes_available() {
     let client = client::Client::with_consistency(vec![handler_addr], Consistency::One);
 
     // Should succeed
-    let r = client.get(&local_key);
-    let r = r.await().unwrap();
-    match r.message {
-        Response::Value {key, value} => {
-            assert_eq!(key, local_key);
-            match value {
-                Value::Value {content, ..} => assert_eq!(content, local_value),
-                _ => assert!(false),
-            }
-        },
-        _ => assert!(false),
-    }
+    assert_eq!(client.get(&local_key).await().unwrap(), Some(local_value));
 }
 
 #[test]
 fn read_consistency_one_one_node_available() {
-    let (handler_addr, shards) = setup_cluster();
+    let (handler_addr, ring) = setup_cluster();
     let (local_key, local_value) = key_and_value();
 
-    // Write to only one node (local_key corresponds to shard 2).
-    write_to_storage_node(&shards[2][0], &local_key, &local_value, 100000);
+    // Write to only one of local_key's replicas.
+    write_to_storage_node(&ring.preference_list(&local_key)[0], &local_key, &versioned(&local_value, 100000));
 
     thread::sleep(Duration::from_millis(DELAY*3));  // Wait for storage node to start listening
 
     let client = client::Client::with_consistency(vec![handler_addr], Consistency::One);
 
     // Should succeed
-    let r = client.get(&local_key);
-    let r = r.await().unwrap();
-    match r.message {
-        Response::Value {key, value} => {
-            assert_eq!(key, local_key);
-            match value {
-                Value::Value {content, ..} => assert_eq!(content, local_value),
-                _ => assert!(false),
-            }
-        },
-        _ => assert!(false),
-    }
+    assert_eq!(client.get(&local_key).await().unwrap(), Some(local_value));
 }
 
 #[test]
 fn read_consistency_latest_all_same() {
     // Should succeed
-    let (handler_addr, shards) = setup_cluster();
+    let (handler_addr, ring) = setup_cluster();
     let (local_key, local_value) = key_and_value();
 
-    for shard in shards {
+    for shard in ring.shards() {
         for node in shard {
-            write_to_storage_node(&node, &local_key, &local_value, 100000);
+            write_to_storage_node(&node, &local_key, &versioned(&local_value, 100000));
         }
     }
     thread::sleep(Duration::from_millis(DELAY));  // Wait for storage node to start listening
@@ -231,18 +144,7 @@ fn read_consistency_latest_all_same() {
     let client = client::Client::with_consistency(vec![handler_addr], Consistency::Latest);
 
     // Should succeed
-    let r = client.get(&local_key);
-    let r = r.await().unwrap();
-    match r.message {
-        Response::Value {key, value} => {
-            assert_eq!(key, local_key);
-            match value {
-                Value::Value {content, ..} => assert_eq!(content, local_value),
-                _ => assert!(false),
-            }
-        },
-        _ => assert!(false),
-    }
+    assert_eq!(client.get(&local_key).await().unwrap(), Some(local_value));
 }
 
 #[test]
@@ -252,28 +154,27 @@ fn read_consistency_latest_all_different() {
 
 #[test]
 fn read_consistency_latest_one_node_available() {
-    let (handler_addr, shards) = setup_cluster();
+    let (handler_addr, ring) = setup_cluster();
     let (local_key, local_value) = key_and_value();
 
-    // Write to only one node (local_key corresponds to shard 2).
-    write_to_storage_node(&shards[2][0], &local_key, &local_value, 100000);
+    // Write to only one of local_key's replicas.
+    write_to_storage_node(&ring.preference_list(&local_key)[0], &local_key, &versioned(&local_value, 100000));
 
     thread::sleep(Duration::from_millis(DELAY*3));  // Wait for storage node to start listening
 
     let client = client::Client::with_consistency(vec![handler_addr], Consistency::Latest);
 
-    // Should fail
-    match client.get(&local_key).await() {
-        Ok(r) => {
-            match r.message {
-                Response::Error {key, ..} => {
-                    assert_eq!(key, local_key);
-                }
-                _ => assert!(false),
-            }
-        }
-        Err(_) => assert!(true),
-    }
+    // The others replying they have nothing still makes a majority.
+    assert_eq!(client.get(&local_key).await().unwrap(), Some(local_value));
+}
+
+#[test]
+fn read_consistency_latest_missing_key() {
+    let (handler_addr, _) = setup_cluster();
+    let (local_key, _) = key_and_value();
+    let client = client::Client::with_consistency(vec![handler_addr], Consistency::Latest);
+
+    assert_eq!(client.get(&local_key).await().unwrap(), None);
 }
 
 #[test]
@@ -286,15 +187,8 @@ fn write_consistency_one_all_available() {
     let client = client::Client::with_consistency(vec![handler_addr], Consistency::Latest);
 
     // Should succeed
-    let r = client.insert(&local_key, &local_value);
-    let r = r.await().unwrap();
-    match r.message {
-        Response::WriteAck {key, timestamp} => {
-            assert_eq!(key, local_key);
-            assert!(timestamp > 0);
-        },
-        _ => assert!(false),
-    }
+    let timestamp = client.insert(&local_key, &local_value).await().unwrap();
+    assert!(timestamp > 0);
 }
 
 #[test]
@@ -307,17 +201,11 @@ fn write_consistency_one_none_available() {
     let client = client::Client::with_consistency(vec![handler_addr], Consistency::One);
 
     // Should fail
-    match client.insert(&local_key, &local_value).await() {
-        Ok(r) => {
-            match r.message {
-                Response::Error {key, message} => {
-                    assert_eq!(key, local_key);
-                    assert_eq!("Quorum write could not be accomplished.".to_string(), message);
-                },
-                _ => assert!(false),
-            }
+    match client.insert(&local_key, &local_value).await().map_err(|e| e.take()) {
+        Err(Some(Error::RequestError(message))) => {
+            assert_eq!("Quorum write could not be accomplished.".to_string(), message);
         }
-        Err(_) => assert!(true),
+        r => panic!("{:?}", r),
     }
 }
 
@@ -331,15 +219,8 @@ fn write_consistency_latest_all_available() {
     let client = client::Client::with_consistency(vec![handler_addr], Consistency::Latest);
 
     // Should succeed
-    let r = client.insert(&local_key, &local_value);
-    let r = r.await().unwrap();
-    match r.message {
-        Response::WriteAck {key, timestamp} => {
-            assert_eq!(key, local_key);
-            assert!(timestamp > 0);
-        },
-        _ => assert!(false),
-    }
+    let timestamp = client.insert(&local_key, &local_value).await().unwrap();
+    assert!(timestamp > 0);
 }
 
 #[test]
@@ -352,15 +233,8 @@ fn write_consistency_latest_quorum_available() {
     let client = client::Client::with_consistency(vec![handler_addr], Consistency::Latest);
 
     // Should succeed
-    let r = client.insert(&local_key, &local_value);
-    let r = r.await().unwrap();
-    match r.message {
-        Response::WriteAck {key, timestamp} => {
-            assert_eq!(key, local_key);
-            assert!(timestamp > 0);
-        },
-        _ => assert!(false),
-    }
+    let timestamp = client.insert(&local_key, &local_value).await().unwrap();
+    assert!(timestamp > 0);
 }
 
 #[test]
@@ -373,17 +247,11 @@ fn write_consistency_latest_one_available() {
     let client = client::Client::with_consistency(vec![handler_addr], Consistency::Latest);
 
     // Should fail
-    match client.insert(&local_key, &local_value).await() {
-        Ok(r) => {
-            match r.message {
-                Response::Error {key, message} => {
-                    assert_eq!(local_key, key);
-                    assert_eq!("Quorum write could not be accomplished.".to_string(), message);
-                }
-                _ => assert!(false),
-            }
+    match client.insert(&local_key, &local_value).await().map_err(|e| e.take()) {
+        Err(Some(Error::RequestError(message))) => {
+            assert_eq!("Quorum write could not be accomplished.".to_string(), message);
         }
-        Err(_) => assert!(true),
+        r => panic!("{:?}", r),
     }
 }
 
@@ -397,23 +265,100 @@ fn write_consistency_latest_none_available() {
     let client = client::Client::with_consistency(vec![handler_addr], Consistency::Latest);
 
     // Should fail
-    match client.insert(&local_key, &local_value).await() {
-        Ok(r) => {
-            match r.message {
-                Response::Error {key, message} => {
-                    assert_eq!(local_key, key);
-                    assert_eq!("Quorum write could not be accomplished.".to_string(), message);
-                }
-                _ => assert!(false),
-            }
+    match client.insert(&local_key, &local_value).await().map_err(|e| e.take()) {
+        Err(Some(Error::RequestError(message))) => {
+            assert_eq!("Quorum write could not be accomplished.".to_string(), message);
         }
-        Err(_) => assert!(true),
+        r => panic!("{:?}", r),
     }
 }
 
+/// Insert into a cluster with `bad_nodes` dead replicas in every shard, and
+/// read back what was inserted.
+fn write_then_read(bad_nodes: u32,
+                   write_consistency: Consistency,
+                   read_consistency: Consistency)
+                   -> (Result<u64>, Result<Option<Vec<u8>>>) {
+    let (handler_addr, _) = setup_bad_cluster(bad_nodes);
+    let (local_key, local_value) = key_and_value();
+    let client = client::Client::with_consistency_levels(vec![handler_addr],
+                                                         read_consistency,
+                                                         write_consistency);
+    let written = client.insert(&local_key, &local_value).await().map_err(|e| e.take().unwrap());
+    let read = client.get(&local_key).await().map_err(|e| e.take().unwrap());
+    (written, read)
+}
+
+fn quorum_write_error() -> Error {
+    Error::RequestError("Quorum write could not be accomplished.".to_string())
+}
+
+fn read_error() -> Error {
+    Error::RequestError("Not enough storage nodes succeeded to give a response".to_string())
+}
+
+#[test]
+fn write_consistency_one_one_available() {
+    let (written, read) = write_then_read(2, Consistency::One, Consistency::Count(1));
+    assert!(written.unwrap() > 0);
+    assert_eq!(read, Ok(Some(key_and_value().1)));
+}
+
+#[test]
+fn consistency_quorum_quorum_available() {
+    let (written, read) = write_then_read(1, Consistency::Quorum, Consistency::Quorum);
+    assert!(written.unwrap() > 0);
+    assert_eq!(read, Ok(Some(key_and_value().1)));
+}
+
+#[test]
+fn consistency_quorum_one_available() {
+    let (written, read) = write_then_read(2, Consistency::Quorum, Consistency::Quorum);
+    assert_eq!(written, Err(quorum_write_error()));
+    assert_eq!(read, Err(read_error()));
+}
+
+#[test]
+fn consistency_all_all_available() {
+    let (written, read) = write_then_read(0, Consistency::All, Consistency::All);
+    assert!(written.unwrap() > 0);
+    assert_eq!(read, Ok(Some(key_and_value().1)));
+}
+
+#[test]
+fn consistency_all_quorum_available() {
+    let (written, read) = write_then_read(1, Consistency::All, Consistency::All);
+    assert_eq!(written, Err(quorum_write_error()));
+    assert_eq!(read, Err(read_error()));
+}
+
+#[test]
+fn consistency_count_available() {
+    let (written, read) = write_then_read(1, Consistency::Count(2), Consistency::Count(2));
+    assert!(written.unwrap() > 0);
+    assert_eq!(read, Ok(Some(key_and_value().1)));
+}
+
+#[test]
+fn consistency_count_unavailable() {
+    let (written, read) = write_then_read(1, Consistency::Count(3), Consistency::Count(3));
+    assert_eq!(written, Err(quorum_write_error()));
+    assert_eq!(read, Err(read_error()));
+}
+
+#[test]
+fn unsatisfiable_consistency_is_rejected() {
+    let (written, read) = write_then_read(0, Consistency::Count(4), Consistency::Count(0));
+    assert_eq!(written,
+               Err(Error::RequestError("Count(4) consistency can't be satisfied by 3 replicas".to_string())));
+    assert_eq!(read,
+               Err(Error::RequestError("Count(0) consistency can't be satisfied by 3 replicas".to_string())));
+}
+
 #[test]
 fn single_node() {
-    let addr = get_storage_node(0, 1);
+    let addr = get_address();
+    start_storage_node(&addr, &Ring::new(vec![vec![addr]], DEFAULT_VNODES));
     let (insert_key, _) = key_and_value();
 
     {
@@ -422,12 +367,13 @@ fn single_node() {
             value: Value::Value {
                 content: vec![1],
                 timestamp: 10000000,
+                expires: None,
             },
         };
         let addr = &addr.to_owned();
         let r: Future<InternodeResponse, Error> = client::Client::send_to_node(addr, &content);
         match r.await().unwrap() {
-            InternodeResponse::WriteAck {key, timestamp} => {
+            InternodeResponse::WriteAck {key, timestamp, ..} => {
                 assert_eq!(key, insert_key);
                 assert_eq!(timestamp, 10000000);
             },
@@ -446,7 +392,7 @@ fn single_node() {
                 InternodeResponse::Value {key, value} => {
                     assert_eq!(key, insert_key);
                     match value {
-                        Value::Value {content, timestamp} => {
+                        Value::Value {content, timestamp, ..} => {
                             assert_eq!(&content[..], &[1][..]);
                             assert_eq!(timestamp, 10000000);
                         },
@@ -459,3 +405,776 @@ fn single_node() {
         }
     }
 }
+
+#[test]
+fn storage_node_rejects_keys_of_other_shards() {
+    let addr = get_address();
+    let other = get_address();
+    let ring = Ring::new(vec![vec![addr], vec![other]], DEFAULT_VNODES);
+    start_storage_node(&addr, &ring);
+
+    // Find a key that belongs to the other shard.
+    let (mut key, _) = key_and_value();
+    let mut i = 0;
+    while ring.owns(&addr, &key) {
+        i += 1;
+        key.pkey = vec![i];
+    }
+
+    let request = InternodeRequest::Read { key: key.to_owned() };
+    let r: Future<InternodeResponse, Error> = client::Client::send_to_node(&addr, &request);
+    match r.await().unwrap() {
+        InternodeResponse::Error {key: k, ..} => assert_eq!(k, key),
+        e => panic!("{:?}", e),
+    }
+}
+
+fn scan(client: &client::Client,
+        start_lkey: &Vec<u8>,
+        end_lkey: Option<&Vec<u8>>,
+        limit: usize,
+        reverse: bool)
+        -> (Vec<(Key, Vec<u8>)>, Option<Vec<u8>>) {
+    client.scan(&vec![1, 2, 3], &vec![4, 5, 6], start_lkey, end_lkey, limit, reverse).await().unwrap()
+}
+
+#[test]
+fn scan_pages_through_partition() {
+    let (handler_addr, _) = setup_cluster();
+    let client = client::Client::new(vec![handler_addr]);
+    let (key, _) = key_and_value();
+    for i in 0..25 {
+        let row = Key { lkey: vec![i], ..key.clone() };
+        client.insert(&row, &vec![i]).await().unwrap();
+    }
+    client.delete(&Key { lkey: vec![3], ..key.clone() }).await().unwrap();
+    // A row of another partition, right after this one's.
+    client.insert(&Key { pkey: vec![4, 5, 6, 0], lkey: vec![], ..key.clone() }, &vec![42]).await().unwrap();
+
+    let expected: Vec<u8> = (0..25).filter(|&i| i != 3).collect();
+
+    let mut found = vec![];
+    let mut start = vec![];
+    loop {
+        let (rows, last_lkey) = scan(&client, &start, None, 10, false);
+        assert!(rows.len() <= 10);
+        for (row, content) in rows {
+            assert_eq!(content, row.lkey);
+            found.push(row.lkey[0]);
+        }
+        match last_lkey {
+            Some(mut lkey) => {
+                // Resume right after the last row.
+                lkey.push(0);
+                start = lkey;
+            }
+            None => break,
+        }
+    }
+    assert_eq!(found, expected);
+
+    let mut found = vec![];
+    let mut end = None;
+    loop {
+        let (rows, last_lkey) = scan(&client, &vec![], end.as_ref(), 7, true);
+        found.extend(rows.into_iter().map(|(row, _)| row.lkey[0]));
+        match last_lkey {
+            Some(lkey) => end = Some(lkey),
+            None => break,
+        }
+    }
+    found.reverse();
+    assert_eq!(found, expected);
+
+    let (rows, _) = scan(&client, &vec![10], Some(&vec![13]), 10, false);
+    assert_eq!(rows.iter().map(|&(ref row, _)| row.lkey[0]).collect::<Vec<u8>>(), vec![10, 11, 12]);
+}
+
+#[test]
+fn scan_merges_newest_rows_from_replicas() {
+    let (handler_addr, ring) = setup_cluster();
+    let (key, _) = key_and_value();
+    let replicas = ring.preference_list(&key);
+    let row = |lkey: u8| Key { lkey: vec![lkey], ..key.clone() };
+    write_to_storage_node(&replicas[0], &row(1), &versioned(&[1], 1));
+    write_to_storage_node(&replicas[1], &row(1), &versioned(&[2], 2));
+    write_to_storage_node(&replicas[2], &row(2), &versioned(&[3], 1));
+
+    let client = client::Client::new(vec![handler_addr]);
+    let (rows, last_lkey) = scan(&client, &vec![], None, 10, false);
+    assert_eq!(rows, vec![(row(1), vec![2]), (row(2), vec![3])]);
+    assert_eq!(last_lkey, None);
+}
+
+fn list_keys(client: &client::Client, dataset: &Vec<u8>, page_size: usize, include_tombstones: bool) -> Vec<Key> {
+    let mut keys = vec![];
+    let mut cursor = None;
+    loop {
+        let (page, next) = client.list_keys(dataset, cursor.as_ref(), page_size, include_tombstones).await().unwrap();
+        assert!(page.len() <= page_size);
+        keys.extend(page);
+        match next {
+            Some(c) => cursor = Some(c),
+            None => return keys,
+        }
+    }
+}
+
+#[test]
+fn list_keys_pages_through_dataset() {
+    let (handler_addr, _) = setup_cluster();
+    let client = client::Client::new(vec![handler_addr]);
+    let key = |dataset: Vec<u8>, i: u8| {
+        Key {
+            dataset: dataset,
+            pkey: vec![i],
+            lkey: vec![i % 3],
+        }
+    };
+    let mut expected: Vec<Key> = (0..30).map(|i| key(vec![9], i)).collect();
+    for k in &expected {
+        client.insert(k, &vec![1]).await().unwrap();
+    }
+    // Keys of other datasets aren't listed.
+    client.insert(&key(vec![9, 0], 1), &vec![1]).await().unwrap();
+    client.insert(&key(vec![8], 1), &vec![1]).await().unwrap();
+    for i in vec![4, 20] {
+        client.delete(&key(vec![9], i)).await().unwrap();
+    }
+
+    expected.sort();
+    assert_eq!(list_keys(&client, &vec![9], 7, true), expected);
+    expected.retain(|k| k.pkey != vec![4] && k.pkey != vec![20]);
+    assert_eq!(list_keys(&client, &vec![9], 7, false), expected);
+    assert_eq!(list_keys(&client, &vec![9], 100, false), expected);
+}
+
+#[test]
+fn delete_then_read() {
+    let (handler_addr, _) = setup_cluster();
+    let (local_key, local_value) = key_and_value();
+    let client = client::Client::new(vec![handler_addr]);
+
+    let inserted = client.insert(&local_key, &local_value).await().unwrap();
+    assert_eq!(client.get_with_consistency(&local_key, &Consistency::One).await().unwrap(),
+               Some(local_value));
+    let deleted = client.delete(&local_key).await().unwrap();
+    assert!(deleted > inserted);
+    assert_eq!(client.get(&local_key).await().unwrap(), None);
+}
+
+#[test]
+fn reads_fail_over_to_live_handler() {
+    let (handler_addr, _) = setup_cluster();
+    let dead_handler = get_address();
+    start_dead_storage_node(&dead_handler);
+    let (local_key, local_value) = key_and_value();
+    let client = client::Client::new(vec![dead_handler, handler_addr]);
+
+    // Writes aren't retried by default, so the first one might fail...
+    let _ = client.insert(&local_key, &local_value).await();
+    // ...but the dead handler is avoided afterwards.
+    assert!(client.insert(&local_key, &local_value).await().unwrap() > 0);
+    for _ in 0..4 {
+        assert_eq!(client.get(&local_key).await().unwrap(), Some(local_value.clone()));
+    }
+}
+
+#[test]
+fn writes_are_retried_when_allowed() {
+    let (handler_addr, _) = setup_cluster();
+    let dead_handler = get_address();
+    start_dead_storage_node(&dead_handler);
+    let (local_key, local_value) = key_and_value();
+    let policy = client::RetryPolicy { retry_writes: true, ..client::RetryPolicy::default() };
+    let client = client::Client::with_retry_policy(vec![dead_handler, handler_addr], policy);
+
+    let first = client.insert(&local_key, &local_value).await().unwrap();
+    assert!(first > 0);
+    assert!(client.delete(&local_key).await().unwrap() > first);
+    assert_eq!(client.get(&local_key).await().unwrap(), None);
+}
+
+#[test]
+fn requests_fail_when_no_handler_is_reachable() {
+    let dead_handler = get_address();
+    start_dead_storage_node(&dead_handler);
+    let (local_key, _) = key_and_value();
+    let client = client::Client::new(vec![dead_handler]);
+
+    assert_eq!(client.get(&local_key).await().map_err(|e| e.take()),
+               Err(Some(Error::ConnectionError)));
+}
+
+#[test]
+fn requests_fail_without_handlers() {
+    let (local_key, _) = key_and_value();
+    let client = client::Client::new(vec![]);
+
+    assert_eq!(client.get(&local_key).await().map_err(|e| e.take()),
+               Err(Some(Error::NoHandlers)));
+}
+
+#[test]
+fn requests_time_out_on_silent_handler() {
+    let silent_handler = get_address();
+    start_silent_node(&silent_handler);
+    let (local_key, _) = key_and_value();
+    let mut client = client::Client::with_timeouts(vec![silent_handler],
+                                                   Duration::from_millis(100),
+                                                   Duration::from_millis(100));
+    client.request_timeout = Some(Duration::from_millis(250));
+
+    let start = Instant::now();
+    assert_eq!(client.get(&local_key).await().map_err(|e| e.take()),
+               Err(Some(Error::Timeout)));
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(200));
+    assert!(elapsed < Duration::from_millis(600));
+}
+
+#[test]
+fn handler_drops_requests_past_their_deadline() {
+    let (handler_addr, _) = setup_cluster();
+    let (local_key, local_value) = key_and_value();
+    let request = Request {
+        action: Action::Write {
+            key: local_key.to_owned(),
+            content: local_value,
+            ttl: None,
+        },
+        consistency: Consistency::Latest,
+        timestamp: None,
+        deadline: Some(1),
+        context: None,
+    };
+    let pool = ConnectionPool::new();
+    let r: Future<ResponseMessage, Error> = pool.send_to_node(&handler_addr, &request, Some(Duration::from_millis(200)));
+    assert_eq!(r.await().map_err(|e| e.take()), Err(Some(Error::Timeout)));
+
+    let client = client::Client::new(vec![handler_addr]);
+    assert_eq!(client.get(&local_key).await().unwrap(), None);
+}
+
+#[test]
+fn token_aware_client_skips_handlers() {
+    let (handler_addr, _) = setup_cluster();
+    let (local_key, local_value) = key_and_value();
+    let mut client = client::Client::token_aware(vec![handler_addr]).unwrap();
+    // Once it has the topology, the client doesn't need a handler anymore.
+    let dead_handler = get_address();
+    start_dead_storage_node(&dead_handler);
+    client.handlers = vec![dead_handler];
+
+    assert!(client.insert(&local_key, &local_value).await().unwrap() > 0);
+    assert_eq!(client.get(&local_key).await().unwrap(), Some(local_value.clone()));
+    let (rows, _) = scan(&client, &vec![], None, 10, false);
+    assert_eq!(rows, vec![(local_key.to_owned(), local_value)]);
+    assert_eq!(list_keys(&client, &local_key.dataset, 10, false), vec![local_key.to_owned()]);
+    client.delete(&local_key).await().unwrap();
+    assert_eq!(client.get(&local_key).await().unwrap(), None);
+}
+
+#[test]
+fn write_consistency_one_doesnt_wait_for_slow_replicas() {
+    let shard = get_shard();
+    let ring = Ring::new(vec![shard.clone()], DEFAULT_VNODES);
+    start_silent_node(&shard[0]);
+    for node in &shard[1..] {
+        start_storage_node(node, &ring);
+    }
+    let handler_addr = setup_handler_node(&ring);
+    let (local_key, local_value) = key_and_value();
+    let client = client::Client::with_consistency(vec![handler_addr], Consistency::One);
+
+    let start = Instant::now();
+    assert!(client.insert(&local_key, &local_value).await().unwrap() > 0);
+    assert!(start.elapsed() < Duration::from_millis(STORAGE_TIMEOUT_MS / 2));
+}
+
+#[test]
+fn missed_writes_are_repaired() {
+    let shard = get_shard();
+    let ring = Ring::new(vec![shard.clone()], DEFAULT_VNODES);
+    // The first replica isn't up yet.
+    for node in &shard[1..] {
+        start_storage_node(node, &ring);
+    }
+    let handler_addr = setup_handler_node(&ring);
+    let (local_key, local_value) = key_and_value();
+    let client = client::Client::with_consistency(vec![handler_addr], Consistency::One);
+    let timestamp = client.insert(&local_key, &local_value).await().unwrap();
+
+    start_storage_node(&shard[0], &ring);
+    thread::sleep(Duration::from_millis(HINT_PROBE_MS * 2));
+    match read_from_storage_node(&shard[0], &local_key) {
+        InternodeResponse::Value {value: Value::Value {content, timestamp: t, ..}, ..} => {
+            assert_eq!(content, local_value);
+            assert_eq!(t, timestamp);
+        }
+        r => panic!("{:?}", r),
+    }
+}
+
+#[test]
+fn read_consistency_one_doesnt_wait_for_slow_replicas() {
+    let shard = get_shard();
+    let ring = Ring::new(vec![shard.clone()], DEFAULT_VNODES);
+    start_silent_node(&shard[0]);
+    for node in &shard[1..] {
+        start_storage_node(node, &ring);
+    }
+    let handler_addr = setup_handler_node(&ring);
+    let (local_key, local_value) = key_and_value();
+    write_to_storage_node(&shard[2], &local_key, &versioned(&local_value, 100000));
+    let client = client::Client::with_consistency(vec![handler_addr], Consistency::One);
+
+    let start = Instant::now();
+    assert!(client.get(&local_key).await().is_ok());
+    assert!(start.elapsed() < Duration::from_millis(STORAGE_TIMEOUT_MS / 2));
+}
+
+#[test]
+fn read_consistency_one_none_available() {
+    let (handler_addr, _) = setup_bad_cluster(3);
+    let (local_key, _) = key_and_value();
+    let client = client::Client::with_consistency(vec![handler_addr], Consistency::One);
+
+    assert_eq!(client.get(&local_key).await().map_err(|e| e.take()),
+               Err(Some(Error::RequestError("All the storage nodes replied with errors.".to_string()))));
+}
+
+/// Write an older `Value` to one of `local_key`'s replicas than to the other
+/// two, read it through a handler repairing with probability `chance`, and
+/// return what the stale replica has afterwards along with the handler's
+/// `read_repairs` count.
+fn read_with_stale_replica(chance: f64) -> (InternodeResponse, u64) {
+    let shard = get_shard();
+    let ring = Ring::new(vec![shard.clone()], DEFAULT_VNODES);
+    for node in &shard {
+        start_storage_node(node, &ring);
+    }
+    let handler_addr = setup_repairing_handler_node(&ring, chance);
+    let (local_key, local_value) = key_and_value();
+    write_to_storage_node(&shard[0], &local_key, &versioned(&[1], 1));
+    for node in &shard[1..] {
+        write_to_storage_node(node, &local_key, &versioned(&local_value, 2));
+    }
+
+    let client = client::Client::with_consistency(vec![handler_addr], Consistency::Latest);
+    assert_eq!(client.get(&local_key).await().unwrap(), Some(local_value));
+    let metrics = client.metrics().await().unwrap();
+    let read_repairs = metrics.iter().find(|&&(ref name, _)| name == "read_repairs").unwrap().1;
+    (read_from_storage_node(&shard[0], &local_key), read_repairs)
+}
+
+#[test]
+fn reads_repair_stale_replicas() {
+    let (response, read_repairs) = read_with_stale_replica(1.0);
+    match response {
+        InternodeResponse::Value {value: Value::Value {content, timestamp, ..}, ..} => {
+            assert_eq!(content, vec![9, 8, 7]);
+            assert_eq!(timestamp, 2);
+        }
+        r => panic!("{:?}", r),
+    }
+    assert_eq!(read_repairs, 1);
+}
+
+#[test]
+fn reads_dont_repair_without_chance() {
+    let (response, read_repairs) = read_with_stale_replica(0.0);
+    match response {
+        InternodeResponse::Value {value: Value::Value {timestamp, ..}, ..} => assert_eq!(timestamp, 1),
+        r => panic!("{:?}", r),
+    }
+    assert_eq!(read_repairs, 0);
+}
+
+#[test]
+fn stale_writes_are_superseded() {
+    let node = get_address();
+    let ring = Ring::new(vec![vec![node]], DEFAULT_VNODES);
+    start_storage_node(&node, &ring);
+    let (local_key, local_value) = key_and_value();
+
+    let write = |value: Value| {
+        let request = InternodeRequest::Write {
+            key: local_key.to_owned(),
+            value: value,
+        };
+        let r: Future<InternodeResponse, Error> = client::Client::send_to_node(&node, &request);
+        match r.await().unwrap() {
+            InternodeResponse::WriteAck {applied, ..} => applied,
+            r => panic!("{:?}", r),
+        }
+    };
+    assert!(write(Value::Tombstone { timestamp: 2 }));
+    // A replayed write from before the delete doesn't resurrect the value.
+    assert!(!write(Value::Value {
+        content: local_value,
+        timestamp: 1,
+        expires: None,
+    }));
+    match read_from_storage_node(&node, &local_key) {
+        InternodeResponse::Value {value, ..} => assert_eq!(value, Value::Tombstone { timestamp: 2 }),
+        r => panic!("{:?}", r),
+    }
+}
+
+#[test]
+fn handlers_catch_up_with_newer_timestamps() {
+    let node = get_address();
+    let ring = Ring::new(vec![vec![node]], DEFAULT_VNODES);
+    start_storage_node(&node, &ring);
+    let handler_addr = setup_handler_node(&ring);
+    let (local_key, local_value) = key_and_value();
+
+    // Written by a node whose clock is a minute ahead.
+    let clock = Clock::new(0);
+    let ahead = hlc::timestamp(hlc::physical_ms(clock.now()) + 60_000, 0, 0);
+    write_to_storage_node(&node, &local_key, &versioned(&[1], ahead));
+
+    let client = client::Client::new(vec![handler_addr]);
+    client.insert(&local_key, &local_value).await().unwrap();
+    assert_eq!(client.get(&local_key).await().unwrap(), Some(vec![1]));
+    // The superseded write moved the handler's clock past it.
+    assert!(client.insert(&local_key, &local_value).await().unwrap() > ahead);
+    assert_eq!(client.get(&local_key).await().unwrap(), Some(local_value));
+}
+
+#[test]
+fn handlers_refuse_timestamps_past_drift() {
+    let node = get_address();
+    let ring = Ring::new(vec![vec![node]], DEFAULT_VNODES);
+    start_storage_node(&node, &ring);
+    let handler_addr = get_address();
+    let mut coordinator = Coordinator::new(Arc::new(ConnectionPool::new()));
+    coordinator.clock = Arc::new(Clock::with_max_drift(1, Some(Duration::from_millis(1000))));
+    {
+        let ring = ring.clone();
+        thread::spawn(move || {
+            let _ = handler::listen_with_coordinator(&handler_addr, &ring, coordinator);
+        });
+    }
+    thread::sleep(Duration::from_millis(DELAY));  // Wait for handler node to start listening
+    let (local_key, local_value) = key_and_value();
+
+    let client = client::Client::new(vec![handler_addr]);
+    let ahead = hlc::timestamp(hlc::physical_ms(Clock::new(0).now()) + 60_000, 0, 0);
+    let mut request = Request::new(Action::Write {
+                                       key: local_key.to_owned(),
+                                       content: local_value,
+                                       ttl: None,
+                                   },
+                                   Consistency::One);
+    request.timestamp = Some(ahead);
+    match client.send(&request).await().unwrap().message {
+        Response::Error {..} => (),
+        r => panic!("{:?}", r),
+    }
+    assert_eq!(client.get_with_consistency(&local_key, &Consistency::One).await().unwrap(), None);
+}
+
+/// Start a handler keeping concurrent writes to `dataset` as siblings.
+fn setup_versioning_handler_node(ring: &Ring, dataset: &Buffer) -> SocketAddrV4 {
+    let ring = ring.clone();
+    let addr = get_address();
+    let mut coordinator = Coordinator::new(Arc::new(ConnectionPool::new()));
+    coordinator.versioned_datasets.insert(dataset.to_owned());
+    thread::spawn(move || {
+        let _ = handler::listen_with_coordinator(&addr, &ring, coordinator);
+    });
+    thread::sleep(Duration::from_millis(DELAY));  // Wait for handler node to start listening
+    addr
+}
+
+#[test]
+fn versioned_datasets_keep_concurrent_writes() {
+    let (_, ring) = setup_cluster();
+    let (local_key, _) = key_and_value();
+    let first = client::Client::new(vec![setup_versioning_handler_node(&ring, &local_key.dataset)]);
+    let second = client::Client::new(vec![setup_versioning_handler_node(&ring, &local_key.dataset)]);
+
+    // Neither write read the other, so both are kept.
+    first.insert(&local_key, &vec![1]).await().unwrap();
+    second.insert(&local_key, &vec![2]).await().unwrap();
+    let (contents, context) = first.get_versions(&local_key).await().unwrap();
+    assert_eq!(contents, vec![vec![1], vec![2]]);
+    assert_eq!(first.get(&local_key).await().unwrap(), Some(vec![2]));
+
+    // A write with the context they were read with resolves them.
+    second.insert_with_context(&local_key, &vec![3], context.as_ref()).await().unwrap();
+    let (contents, context) = first.get_versions(&local_key).await().unwrap();
+    assert_eq!(contents, vec![vec![3]]);
+
+    first.delete_with_context(&local_key, context.as_ref()).await().unwrap();
+    assert_eq!(second.get_versions(&local_key).await().unwrap().0, Vec::<Buffer>::new());
+    assert_eq!(second.get(&local_key).await().unwrap(), None);
+}
+
+#[test]
+fn compare_and_set_only_writes_what_was_expected() {
+    let (handler_addr, _) = setup_cluster();
+    let (local_key, local_value) = key_and_value();
+    let client = client::Client::new(vec![handler_addr]);
+
+    let first = client.compare_and_set(&local_key, None, &vec![1]).await().unwrap().unwrap();
+    match client.compare_and_set(&local_key, None, &local_value).await().unwrap() {
+        Err(Value::Value {content, timestamp, ..}) => {
+            assert_eq!(content, vec![1]);
+            assert_eq!(timestamp, first);
+        }
+        r => panic!("{:?}", r),
+    }
+    let second = client.compare_and_set(&local_key, Some(first), &local_value).await().unwrap().unwrap();
+    assert!(second > first);
+    assert!(client.compare_and_set(&local_key, Some(first), &vec![2]).await().unwrap().is_err());
+    assert_eq!(client.get(&local_key).await().unwrap(), Some(local_value));
+
+    // Deleted keys count as absent.
+    client.delete(&local_key).await().unwrap();
+    assert!(client.compare_and_set(&local_key, None, &vec![3]).await().unwrap().is_ok());
+}
+
+#[test]
+fn concurrent_compare_and_sets_agree() {
+    let shard = vec![get_address(), get_address(), get_address()];
+    let ring = Ring::new(vec![shard.clone()], DEFAULT_VNODES);
+    for node in &shard {
+        start_storage_node(node, &ring);
+    }
+    let handler_addr = setup_handler_node(&ring);
+    let (local_key, _) = key_and_value();
+
+    // One compare-and-set got its ballot promised by every replica, but its
+    // proposal only reached one of them before another one started.
+    let ballot = Clock::new(0).now();
+    let value = Value::Value {
+        content: vec![1],
+        timestamp: ballot,
+        expires: None,
+    };
+    let propose = InternodeRequest::Propose {
+        key: local_key.to_owned(),
+        ballot: ballot,
+        value: value.to_owned(),
+    };
+    for node in &shard {
+        let prepare = InternodeRequest::Prepare {
+            key: local_key.to_owned(),
+            ballot: ballot,
+        };
+        let r: Future<InternodeResponse, Error> = client::Client::send_to_node(node, &prepare);
+        match r.await().unwrap() {
+            InternodeResponse::Promise {..} => (),
+            r => panic!("{:?}", r),
+        }
+    }
+    let r: Future<InternodeResponse, Error> = client::Client::send_to_node(&shard[0], &propose);
+    match r.await().unwrap() {
+        InternodeResponse::Accepted {..} => (),
+        r => panic!("{:?}", r),
+    }
+
+    // The other one finishes the first one's round, after which the value it
+    // expected isn't there any more.
+    let client = client::Client::new(vec![handler_addr]);
+    assert_eq!(client.compare_and_set(&local_key, None, &vec![2]).await().unwrap(), Err(value.to_owned()));
+
+    // The first one can't get its proposal accepted by the others any more,
+    // but they agree on it anyway.
+    for node in &shard[1..] {
+        let r: Future<InternodeResponse, Error> = client::Client::send_to_node(node, &propose);
+        match r.await().unwrap() {
+            InternodeResponse::Rejected {..} => (),
+            r => panic!("{:?}", r),
+        }
+    }
+    assert_eq!(client.get_with_consistency(&local_key, &Consistency::All).await().unwrap(),
+               Some(vec![1]));
+}
+
+#[test]
+fn compare_and_set_finishes_unfinished_rounds() {
+    let shard = vec![get_address(), get_address(), get_address()];
+    let ring = Ring::new(vec![shard.clone()], DEFAULT_VNODES);
+    for node in &shard {
+        start_storage_node(node, &ring);
+    }
+    let handler_addr = setup_handler_node(&ring);
+    let (local_key, local_value) = key_and_value();
+
+    // A proposal accepted by a majority, whose proposer never committed it.
+    let ballot = Clock::new(0).now();
+    let value = Value::Value {
+        content: local_value.to_owned(),
+        timestamp: ballot,
+        expires: None,
+    };
+    for node in &shard[..2] {
+        let prepare = InternodeRequest::Prepare {
+            key: local_key.to_owned(),
+            ballot: ballot,
+        };
+        let r: Future<InternodeResponse, Error> = client::Client::send_to_node(node, &prepare);
+        r.await().unwrap();
+        let propose = InternodeRequest::Propose {
+            key: local_key.to_owned(),
+            ballot: ballot,
+            value: value.to_owned(),
+        };
+        let r: Future<InternodeResponse, Error> = client::Client::send_to_node(node, &propose);
+        match r.await().unwrap() {
+            InternodeResponse::Accepted {..} => (),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    let client = client::Client::new(vec![handler_addr]);
+    assert_eq!(client.compare_and_set(&local_key, None, &vec![1]).await().unwrap(), Err(value.to_owned()));
+    assert_eq!(client.get_with_consistency(&local_key, &Consistency::All).await().unwrap(),
+               Some(local_value));
+}
+
+#[test]
+fn compare_and_set_refuses_proposals_without_timestamps() {
+    let shard = vec![get_address(), get_address(), get_address()];
+    let ring = Ring::new(vec![shard.clone()], DEFAULT_VNODES);
+    for node in &shard {
+        start_storage_node(node, &ring);
+    }
+    let handler_addr = setup_handler_node(&ring);
+    let (local_key, _) = key_and_value();
+
+    let ballot = Clock::new(0).now();
+    for node in &shard {
+        let propose = InternodeRequest::Propose {
+            key: local_key.to_owned(),
+            ballot: ballot,
+            value: Value::None,
+        };
+        let r: Future<InternodeResponse, Error> = client::Client::send_to_node(node, &propose);
+        r.await().unwrap();
+    }
+
+    let client = client::Client::new(vec![handler_addr]);
+    match client.compare_and_set(&local_key, None, &vec![1]).await() {
+        Err(e) => {
+            match e.take() {
+                Some(Error::RequestError(_)) => (),
+                e => panic!("{:?}", e),
+            }
+        }
+        r => panic!("{:?}", r),
+    }
+}
+
+#[test]
+fn values_expire_after_their_ttl() {
+    let (handler_addr, _) = setup_cluster();
+    let (local_key, local_value) = key_and_value();
+    let client = client::Client::new(vec![handler_addr]);
+
+    client.insert_with_ttl(&local_key, &local_value, Duration::from_millis(500)).await().unwrap();
+    match client.get_with_ttl(&local_key).await().unwrap() {
+        Some((content, Some(ttl))) => {
+            assert_eq!(content, local_value);
+            assert!(ttl <= Duration::from_millis(500));
+        }
+        r => panic!("{:?}", r),
+    }
+    thread::sleep(Duration::from_millis(600));
+    assert_eq!(client.get(&local_key).await().unwrap(), None);
+    // Replicas read it as deleted too, even before it's swept.
+    assert_eq!(client.get_with_consistency(&local_key, &Consistency::One).await().unwrap(), None);
+}
+
+#[test]
+fn touch_refreshes_the_ttl_only() {
+    let (handler_addr, _) = setup_cluster();
+    let (local_key, local_value) = key_and_value();
+    let client = client::Client::new(vec![handler_addr]);
+    assert!(client.touch(&local_key, None).await().is_err());
+
+    let written = client.insert_with_ttl(&local_key, &local_value, Duration::from_millis(300)).await().unwrap();
+    let touched = client.touch(&local_key, Some(Duration::from_secs(60))).await().unwrap();
+    assert_eq!(touched, written);
+    assert!(client.touch(&local_key, Some(Duration::from_secs(1))).await().is_err());
+    thread::sleep(Duration::from_millis(400));
+    match client.get_with_ttl(&local_key).await().unwrap() {
+        Some((content, Some(ttl))) => {
+            assert_eq!(content, local_value);
+            assert!(ttl > Duration::from_secs(50));
+        }
+        r => panic!("{:?}", r),
+    }
+
+    client.touch(&local_key, None).await().unwrap();
+    assert_eq!(client.get_with_ttl(&local_key).await().unwrap(), Some((local_value, None)));
+}
+
+#[test]
+fn touch_never_brings_back_a_deleted_value() {
+    let (handler_addr, ring) = setup_cluster();
+    let (local_key, local_value) = key_and_value();
+    let replicas = ring.shards()[ring.shard(&local_key).unwrap()].clone();
+    let client = client::Client::new(vec![handler_addr]);
+    let touch = |timestamp: u64| {
+        let request = InternodeRequest::Touch {
+            key: local_key.to_owned(),
+            timestamp: timestamp,
+            expires: None,
+        };
+        replicas.iter()
+                .map(|node| {
+                    let r: Future<InternodeResponse, Error> = client::Client::send_to_node(node, &request);
+                    r.await().unwrap()
+                })
+                .collect::<Vec<_>>()
+    };
+
+    // The touch reaches the replicas after the deletion.
+    let written = client.insert_with_ttl(&local_key, &local_value, Duration::from_secs(60)).await().unwrap();
+    let deleted = client.delete(&local_key).await().unwrap();
+    for r in touch(written) {
+        match r {
+            InternodeResponse::WriteAck {timestamp, applied: false, ..} => assert_eq!(timestamp, deleted),
+            r => panic!("{:?}", r),
+        }
+    }
+    assert_eq!(client.get_with_consistency(&local_key, &Consistency::All).await().unwrap(), None);
+
+    // It reaches them before the deletion.
+    let written = client.insert_with_ttl(&local_key, &local_value, Duration::from_secs(60)).await().unwrap();
+    for r in touch(written) {
+        match r {
+            InternodeResponse::WriteAck {timestamp, applied: true, ..} => assert_eq!(timestamp, written),
+            r => panic!("{:?}", r),
+        }
+    }
+    client.delete(&local_key).await().unwrap();
+    assert_eq!(client.get_with_consistency(&local_key, &Consistency::All).await().unwrap(), None);
+
+    // Some replicas get the deletion before the touch, and the rest after.
+    let written = client.insert_with_ttl(&local_key, &local_value, Duration::from_secs(60)).await().unwrap();
+    thread::sleep(Duration::from_millis(5));
+    let tombstone = Value::Tombstone { timestamp: Clock::new(0).now() };
+    let request = InternodeRequest::Write {
+        key: local_key.to_owned(),
+        value: tombstone.clone(),
+    };
+    let r: Future<InternodeResponse, Error> = client::Client::send_to_node(&replicas[0], &request);
+    r.await().unwrap();
+    touch(written);
+    for node in &replicas[1..] {
+        let r: Future<InternodeResponse, Error> = client::Client::send_to_node(node, &request);
+        r.await().unwrap();
+    }
+    for node in &replicas {
+        match read_from_storage_node(node, &local_key) {
+            InternodeResponse::Value {value, ..} => assert_eq!(value, tombstone),
+            r => panic!("{:?}", r),
+        }
+    }
+}
@@ -0,0 +1,267 @@
+extern crate eventual;
+extern crate sbahn;
+
+mod common;
+
+use common::{DELAY, data_dir, get_address};
+use eventual::*;
+use sbahn::constants::DEFAULT_VNODES;
+use sbahn::message::*;
+use sbahn::paxos::Acceptor;
+use sbahn::pool::ConnectionPool;
+use sbahn::storage::{HashMapBackend, LogBackend, StorageBackend};
+use sbahn::storage_node::StorageNode;
+use sbahn::wal::{SyncPolicy, Wal};
+use std::env;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::SocketAddrV4;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+fn key(i: u32) -> Key {
+    Key {
+        dataset: vec![1, 2, 3],
+        pkey: vec![(i >> 24) as u8, (i >> 16) as u8, (i >> 8) as u8, i as u8],
+        lkey: vec![7, 8, 9],
+    }
+}
+
+fn value(i: u32) -> Value {
+    Value::Value {
+        content: vec![i as u8; 100],
+        timestamp: i as u64 + 1,
+        expires: None,
+    }
+}
+
+fn policy(group_commit_ms: u64) -> SyncPolicy {
+    match group_commit_ms {
+        0 => SyncPolicy::Always,
+        ms => SyncPolicy::GroupCommit(Duration::from_millis(ms)),
+    }
+}
+
+/// Not a test on its own: runs a storage node with a write-ahead log when
+/// started by `spawn_node`, so that it can be killed.
+#[test]
+fn wal_node() {
+    let (addr, dir, ms) = match (env::var("SBAHN_WAL_NODE_ADDR"),
+                                 env::var("SBAHN_WAL_NODE_DIR"),
+                                 env::var("SBAHN_WAL_NODE_GROUP_COMMIT_MS")) {
+        (Ok(a), Ok(d), Ok(ms)) => (a.parse().unwrap(), PathBuf::from(d), ms.parse().unwrap()),
+        _ => return,
+    };
+    let ring = Ring::new(vec![vec![addr]], DEFAULT_VNODES);
+    let mut sn: StorageNode<HashMapBackend> = StorageNode::with_wal(&addr, &ring, &dir, policy(ms)).unwrap();
+    sn.listen();
+}
+
+/// Start a storage node in its own process.
+fn spawn_node(addr: &SocketAddrV4, dir: &PathBuf, group_commit_ms: u64) -> Child {
+    let child = Command::new(env::current_exe().unwrap())
+                    .args(&["wal_node", "--exact", "--nocapture"])
+                    .env("SBAHN_WAL_NODE_ADDR", format!("{}", addr))
+                    .env("SBAHN_WAL_NODE_DIR", dir)
+                    .env("SBAHN_WAL_NODE_GROUP_COMMIT_MS", format!("{}", group_commit_ms))
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .spawn()
+                    .unwrap();
+    thread::sleep(Duration::from_millis(5 * DELAY));  // Wait for the node to start listening
+    child
+}
+
+/// Stream writes to a node from several clients, kill the node and check
+/// that every write it acknowledged is there after restarting it.
+fn kill_mid_stream(name: &str, group_commit_ms: u64) {
+    let addr = get_address();
+    let dir = data_dir(name);
+    let mut child = spawn_node(&addr, &dir, group_commit_ms);
+
+    let acked = Arc::new(Mutex::new(vec![]));
+    let pool = Arc::new(ConnectionPool::new());
+    let writers: Vec<thread::JoinHandle<()>> = (0..4)
+        .map(|w| {
+            let acked = acked.clone();
+            let pool = pool.clone();
+            thread::spawn(move || {
+                for i in 0.. {
+                    let i = i * 4 + w;
+                    let request = InternodeRequest::Write {
+                        key: key(i),
+                        value: value(i),
+                    };
+                    let r: Future<InternodeResponse, Error> = pool.send_to_node(&addr, &request, None);
+                    match r.await() {
+                        Ok(InternodeResponse::WriteAck {..}) => acked.lock().unwrap().push(i),
+                        _ => break,
+                    }
+                }
+            })
+        })
+        .collect();
+
+    thread::sleep(Duration::from_millis(3 * DELAY));
+    child.kill().unwrap();
+    child.wait().unwrap();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    let acked = acked.lock().unwrap();
+    assert!(acked.len() > 0);
+    let ring = Ring::new(vec![vec![addr]], DEFAULT_VNODES);
+    let sn: StorageNode<HashMapBackend> = StorageNode::with_wal(&addr, &ring, &dir, policy(group_commit_ms))
+                                              .unwrap();
+    for &i in acked.iter() {
+        assert_eq!(sn.map.get(&key(i)), Some(value(i)));
+    }
+}
+
+#[test]
+fn acknowledged_writes_survive_crash_with_fsync_per_write() {
+    kill_mid_stream("wal-always", 0);
+}
+
+#[test]
+fn acknowledged_writes_survive_crash_with_group_commit() {
+    kill_mid_stream("wal-group-commit", 5);
+}
+
+#[test]
+fn wal_discards_torn_tail() {
+    let dir = data_dir("wal-torn");
+    let path = dir.join("wal.log");
+    {
+        let (wal, entries) = Wal::open(&path, SyncPolicy::Always).unwrap();
+        assert!(entries.is_empty());
+        for i in 0..3 {
+            wal.append(&key(i), &value(i), || ()).unwrap();
+        }
+    }
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&[0, 0, 1, 0, 42, 42]).unwrap();
+
+    let (wal, entries) = Wal::open(&path, SyncPolicy::Never).unwrap();
+    assert_eq!(entries, (0..3).map(|i| (key(i), value(i))).collect::<Vec<_>>());
+    wal.append(&key(3), &value(3), || ()).unwrap();
+    drop(wal);
+    let (_, entries) = Wal::open(&path, SyncPolicy::Never).unwrap();
+    assert_eq!(entries.len(), 4);
+}
+
+/// Not a test on its own: appends writes to a log in `SBAHN_WAL_FULL_DIR`
+/// when started by `failed_append_leaves_log_intact` with a file size limit,
+/// until the log is full.
+#[test]
+fn wal_full() {
+    let dir = match env::var("SBAHN_WAL_FULL_DIR") {
+        Ok(d) => PathBuf::from(d),
+        _ => return,
+    };
+    let path = dir.join("wal.log");
+    let (wal, _) = Wal::open(&path, SyncPolicy::Always).unwrap();
+    let map = HashMapBackend::new();
+    let mut i = 0;
+    while wal.append(&key(i), &value(i), || { map.merge(key(i), value(i)).unwrap(); }).is_ok() {
+        i += 1;
+    }
+    assert!(i > 1);
+    assert_eq!(map.get(&key(i)), None);
+    // Only the writes that were appended whole are in the log.
+    let record = wal.len() / i as u64;
+    assert_eq!(wal.len(), record * i as u64);
+    assert_eq!(path.metadata().unwrap().len(), wal.len());
+}
+
+#[test]
+fn failed_append_leaves_log_intact() {
+    let dir = data_dir("wal-full");
+    // Writing past the limit fails instead of killing the process.
+    let status = Command::new("sh")
+                     .args(&["-c", "ulimit -f 16; trap '' XFSZ; exec \"$0\" wal_full --exact"])
+                     .arg(env::current_exe().unwrap())
+                     .env("SBAHN_WAL_FULL_DIR", &dir)
+                     .stdout(Stdio::null())
+                     .stderr(Stdio::null())
+                     .status()
+                     .unwrap();
+    assert!(status.success());
+
+    let (wal, entries) = Wal::open(&dir.join("wal.log"), SyncPolicy::Never).unwrap();
+    let len = entries.len() as u32;
+    assert!(len > 1);
+    assert_eq!(entries, (0..len).map(|i| (key(i), value(i))).collect::<Vec<_>>());
+    wal.append(&key(len), &value(len), || ()).unwrap();
+    drop(wal);
+    let (_, entries) = Wal::open(&dir.join("wal.log"), SyncPolicy::Never).unwrap();
+    assert_eq!(entries.len() as u32, len + 1);
+}
+
+#[test]
+fn wal_is_checkpointed_into_persistent_backend() {
+    let dir = data_dir("wal-checkpoint");
+    {
+        let (wal, _) = Wal::open(&dir.join("wal.log"), SyncPolicy::Always).unwrap();
+        for i in 0..10 {
+            wal.append(&key(i), &value(i), || ()).unwrap();
+        }
+    }
+    let addr = get_address();
+    let ring = Ring::new(vec![vec![addr]], DEFAULT_VNODES);
+    {
+        let sn: StorageNode<LogBackend> = StorageNode::with_wal(&addr, &ring, &dir, SyncPolicy::Always).unwrap();
+        assert_eq!(sn.wal.as_ref().unwrap().len(), 0);
+    }
+    let sn: StorageNode<LogBackend> = StorageNode::with_data_dir(&addr, &ring, &dir).unwrap();
+    for i in 0..10 {
+        assert_eq!(sn.map.get(&key(i)), Some(value(i)));
+    }
+}
+
+#[test]
+fn acceptor_keeps_its_promises_across_restarts() {
+    let path = data_dir("paxos").join("paxos.log");
+    {
+        let acceptor = Acceptor::open(&path).unwrap();
+        assert_eq!(acceptor.prepare(&key(1), 5).unwrap(), Ok(None));
+        assert_eq!(acceptor.propose(&key(1), 5, &value(1)).unwrap(), Ok(()));
+        assert_eq!(acceptor.prepare(&key(2), 7).unwrap(), Ok(None));
+        assert_eq!(acceptor.prepare(&key(3), 3).unwrap(), Ok(None));
+        assert_eq!(acceptor.propose(&key(3), 3, &value(3)).unwrap(), Ok(()));
+        acceptor.commit(&key(3), 3).unwrap();
+    }
+    let acceptor = Acceptor::open(&path).unwrap();
+    assert_eq!(acceptor.prepare(&key(2), 6).unwrap(), Err(7));
+    assert_eq!(acceptor.propose(&key(2), 6, &value(2)).unwrap(), Err(7));
+    assert_eq!(acceptor.prepare(&key(1), 8).unwrap(), Ok(Some((5, value(1)))));
+    assert_eq!(acceptor.prepare(&key(3), 4).unwrap(), Ok(None));
+}
+
+#[test]
+fn swept_values_stay_deleted() {
+    let dir = data_dir("sweep");
+    let addr = get_address();
+    let ring = Ring::new(vec![vec![addr]], DEFAULT_VNODES);
+    let expired = Value::Value {
+        content: vec![1],
+        timestamp: 1,
+        expires: Some(1),
+    };
+    let swept = Value::Tombstone { timestamp: 1 };
+    {
+        let sn: StorageNode<HashMapBackend> = StorageNode::with_wal(&addr, &ring, &dir, SyncPolicy::Always).unwrap();
+        sn.map.insert(key(1), expired.clone()).unwrap();
+        assert_eq!(sn.sweep_expired(), 1);
+    }
+    let sn: StorageNode<HashMapBackend> = StorageNode::with_wal(&addr, &ring, &dir, SyncPolicy::Always).unwrap();
+    assert_eq!(sn.map.get(&key(1)), Some(swept.clone()));
+
+    // Replicas that didn't sweep it yet take the deletion, and keep it.
+    assert_eq!(Value::merge(Some(&expired), &swept), Some(swept.clone()));
+    assert_eq!(Value::merge(Some(&swept), &expired), None);
+}